@@ -0,0 +1,148 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use failure::Error;
+use libc::pid_t;
+use nix::unistd::getpid;
+use serde::Serialize;
+use serde_json;
+
+use command::Status;
+use sock::Peer;
+
+// long responses such as status are cut in the audit log
+const MAX_MESSAGE_LEN: usize = 256;
+
+/// A command received on a control socket and its result.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: String,
+    // the daemon or the monitor that received the command
+    pub pid: u32,
+    // "control", "http" or "worker"
+    pub socket: String,
+    pub peer: Peer,
+    pub command: String,
+    pub worker: Option<String>,
+    pub status: Status,
+    pub message: String,
+}
+
+/// The serde name of a command, such as `worker:upgrade`.
+pub fn command_name<T: Serialize>(command: &T) -> String {
+    match serde_json::to_value(command) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => "unknown".to_owned(),
+    }
+}
+
+fn truncate(message: &str) -> String {
+    if message.len() <= MAX_MESSAGE_LEN {
+        return message.to_owned();
+    }
+    let mut end = MAX_MESSAGE_LEN;
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &message[..end])
+}
+
+/// Appends the records as JSON lines. The daemon and the monitors write to the same file,
+/// so it is opened in append mode for each record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> AuditLog {
+        AuditLog {
+            path: PathBuf::from(path),
+        }
+    }
+
+    pub fn record(
+        &self,
+        socket: &str,
+        peer: &Peer,
+        command: String,
+        worker: Option<&str>,
+        status: &Status,
+        message: &str,
+    ) {
+        let record = AuditRecord {
+            time: Utc::now().to_rfc3339(),
+            pid: pid_t::from(getpid()) as u32,
+            socket: socket.to_owned(),
+            peer: peer.clone(),
+            command,
+            worker: worker.map(|w| w.to_owned()),
+            status: status.clone(),
+            message: truncate(message),
+        };
+        if let Err(e) = self.write(&record) {
+            warn!(
+                "fail write audit log {:?}. caused by: {} pid [{}]",
+                self.path,
+                e,
+                getpid()
+            );
+        }
+    }
+
+    fn write(&self, record: &AuditRecord) -> Result<(), Error> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        // a single write keeps the line whole with concurrent writers
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Prints the last `limit` records, optionally only of a worker. With `json`,
+/// the records are printed as a JSON array.
+pub fn show(path: &Path, worker: Option<&str>, limit: usize, json: bool) -> Result<(), Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let record: AuditRecord = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("skip broken audit record. caused by: {}", e);
+                continue;
+            }
+        };
+        if worker.is_some() && record.worker.as_ref().map(|w| w.as_str()) != worker {
+            continue;
+        }
+        records.push(record);
+    }
+    let skip = records.len().saturating_sub(limit);
+    if json {
+        let records: Vec<&AuditRecord> = records.iter().skip(skip).collect();
+        println!("{}", serde_json::to_string(&records)?);
+        return Ok(());
+    }
+    for record in records.iter().skip(skip) {
+        println!(
+            "{} {} [{}] {} {} {:?} {}",
+            record.time,
+            record.socket,
+            record.peer,
+            record.command,
+            record.worker.as_ref().map(|w| w.as_str()).unwrap_or("-"),
+            record.status,
+            record.message
+        );
+    }
+    Ok(())
+}
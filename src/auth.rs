@@ -0,0 +1,106 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+use failure::{err_msg, Error};
+use libc;
+
+use app::APP_NAME_UPPER;
+use config::AuthConfig;
+use sock::Peer;
+
+/// A shared token of the control api. It is never printed in logs.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(token: &str) -> Secret {
+        Secret(token.to_owned())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Compares in constant time, so that the token is not guessed from the response time.
+    pub fn matches(&self, other: &Secret) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.0.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// The token sent by the clients. It is read from the environment to keep it out of `ps`.
+pub fn token_from_env() -> Option<Secret> {
+    env::var(format!("{}_TOKEN", APP_NAME_UPPER))
+        .ok()
+        .map(|token| Secret::new(&token))
+}
+
+/// Checks the sender of a control command against the `[auth]` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Auth {
+    config: AuthConfig,
+    token: Option<Secret>,
+    // the user running the daemon can signal the workers anyway
+    own_uid: u32,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Result<Auth, Error> {
+        let token = match (&config.token, &config.token_file) {
+            (Some(_), Some(_)) => return Err(err_msg("auth token and token_file are exclusive")),
+            (Some(token), None) => Some(token.clone()),
+            (None, Some(path)) => {
+                let mut buf = String::new();
+                File::open(path)
+                    .and_then(|mut f| f.read_to_string(&mut buf))
+                    .map_err(|e| {
+                        err_msg(format!("fail read token_file {}. caused by: {}", path, e))
+                    })?;
+                Some(Secret::new(buf.trim()))
+            }
+            (None, None) => None,
+        };
+        if token.as_ref().map_or(false, |t| t.is_empty()) {
+            return Err(err_msg("auth token is empty"));
+        }
+        Ok(Auth {
+            config: config.clone(),
+            token,
+            own_uid: unsafe { libc::getuid() } as u32,
+        })
+    }
+
+    pub fn has_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Unix peers must match an allowed uid or gid when the allowlist is set.
+    /// Peers on tcp must send the token when it is set.
+    pub fn allow(&self, peer: &Peer, token: Option<&Secret>) -> bool {
+        match (peer.uid, peer.gid) {
+            (Some(uid), Some(gid)) => {
+                (self.config.uids.is_empty() && self.config.gids.is_empty())
+                    || uid == self.own_uid
+                    || self.config.uids.contains(&uid)
+                    || self.config.gids.contains(&gid)
+            }
+            _ if peer.addr.is_some() => match (&self.token, token) {
+                (Some(expected), Some(token)) => expected.matches(token),
+                (Some(_), None) => false,
+                (None, _) => true,
+            },
+            _ => false,
+        }
+    }
+}
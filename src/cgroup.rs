@@ -0,0 +1,133 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use failure::{err_msg, Error};
+use libc;
+use nix::unistd::getpid;
+
+use config::WorkerConfig;
+
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup/firestarter";
+
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    pub fn create(path: &Path) -> io::Result<Cgroup> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+            // controllers must be enabled on the parent to be usable by the child group
+            if let Err(e) = fs::write(parent.join("cgroup.subtree_control"), "+cpu +memory") {
+                warn!(
+                    "fail enable cgroup controllers {:?}. caused by: {}",
+                    parent, e
+                );
+            }
+        }
+        if !path.exists() {
+            fs::create_dir(path)?;
+        }
+        info!("create cgroup {:?}. pid [{}]", path, getpid());
+        Ok(Cgroup {
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn setup(name: &str, config: &WorkerConfig) -> io::Result<Option<Cgroup>> {
+        let path = match config.cgroup_path(name) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let cgroup = Cgroup::create(&path)?;
+        if let Some(ref memory_max) = config.memory_max {
+            cgroup.set("memory.max", memory_max)?;
+        }
+        if let Some(ref cpu_max) = config.cpu_max {
+            cgroup.set("cpu.max", cpu_max)?;
+        }
+        Ok(Some(cgroup))
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> io::Result<()> {
+        debug!("set cgroup {:?} {} = {}", self.path, key, value);
+        fs::write(self.path.join(key), value)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir(&self.path) {
+            warn!("fail remove cgroup {:?}. caused by: {}", self.path, e);
+        } else {
+            info!("remove cgroup {:?}", self.path);
+        }
+    }
+}
+
+/// The path of `cgroup.procs` of the cgroup, built before fork for `join`.
+pub fn procs_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.join("cgroup.procs").as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Moves the calling process into the cgroup. used from the forked child before exec,
+/// so it only makes async-signal-safe calls and does not allocate.
+pub fn join(procs: &CStr) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+        let res = if written < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        res
+    }
+}
+
+/// Checks the format of memory.max, `max` or bytes with an optional K, M, G or T suffix.
+pub fn check_memory_max(s: &str) -> Result<(), Error> {
+    let s = s.trim();
+    if s == "max" {
+        return Ok(());
+    }
+    let number = match s.chars().last() {
+        Some(c) if "KMGTkmgt".contains(c) => &s[..s.len() - 1],
+        _ => s,
+    };
+    number
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| err_msg(format!("invalid memory.max {}", s)))
+}
+
+/// Checks the format of cpu.max, `max` or the quota in microseconds, followed by an
+/// optional period in microseconds from 1000 to 1000000.
+pub fn check_cpu_max(s: &str) -> Result<(), Error> {
+    let invalid = || err_msg(format!("invalid cpu.max {}", s));
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let (quota, period) = match fields.as_slice() {
+        [quota] => (*quota, None),
+        [quota, period] => (*quota, Some(*period)),
+        _ => return Err(invalid()),
+    };
+    if quota != "max" && quota.parse::<u64>().map_err(|_| invalid())? == 0 {
+        return Err(invalid());
+    }
+    if let Some(period) = period {
+        let period = period.parse::<u64>().map_err(|_| invalid())?;
+        if period < 1000 || period > 1_000_000 {
+            return Err(invalid());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,328 @@
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Child;
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
+
+use failure::{err_msg, Error};
+use libc;
+use nix::unistd::close;
+use serde_json;
+
+use config::{Config, ConfigError, WorkerConfig};
+use sock::{is_abstract, ListenFd, SockOptions};
+use utils::{access, find_executable};
+use worker::Worker;
+
+// `check --spawn` kills a command run with `--spawn-arg` after this time
+const SPAWN_CHECK_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub enum Level {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub worker: String,
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.level {
+            Level::Ok => "ok",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        };
+        write!(
+            f,
+            "{:5} [{}] {}: {}",
+            level, self.worker, self.key, self.message
+        )
+    }
+}
+
+struct Checker {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn add(&mut self, level: Level, worker: &str, key: &str, message: String) {
+        self.diagnostics.push(Diagnostic {
+            level,
+            worker: worker.to_owned(),
+            key: key.to_owned(),
+            message,
+        });
+    }
+
+    fn check_command(&mut self, name: &str, key: &str, cmd: &[String], working_directory: &str) {
+        if cmd.is_empty() {
+            self.add(Level::Error, name, key, "command is empty".to_owned());
+            return;
+        }
+        match find_executable(&cmd[0], working_directory) {
+            Some(path) => self.add(Level::Ok, name, key, format!("{:?}", path)),
+            None => self.add(
+                Level::Error,
+                name,
+                key,
+                format!("{} not found or not executable", cmd[0]),
+            ),
+        }
+    }
+
+    fn check_socket(&mut self, name: &str, addr: &str, options: &SockOptions) {
+        let listen_fd: ListenFd = match addr.parse() {
+            Ok(listen_fd) => listen_fd,
+            Err(e) => {
+                self.add(Level::Error, name, "socket_address", format!("{}: {}", addr, e));
+                return;
+            }
+        };
+        let unix_path = match listen_fd {
+            ListenFd::UnixListener(ref path) if !is_abstract(path) => {
+                if path.exists() {
+                    self.add(
+                        Level::Warn,
+                        name,
+                        "socket_address",
+                        format!("{} already exists", addr),
+                    );
+                    return;
+                }
+                Some(path.to_owned())
+            }
+            _ => None,
+        };
+        match listen_fd.create_raw_fd(1, options) {
+            Ok(fd) => {
+                let _ = close(fd);
+                if let Some(path) = unix_path {
+                    let _ = ::std::fs::remove_file(path);
+                }
+                self.add(Level::Ok, name, "socket_address", format!("{} bindable", addr));
+            }
+            Err(e) => {
+                let in_use = e
+                    .downcast_ref::<::nix::Error>()
+                    .map(|e| *e == ::nix::Error::Sys(::nix::errno::Errno::EADDRINUSE))
+                    .unwrap_or(false);
+                let level = if in_use { Level::Warn } else { Level::Error };
+                self.add(level, name, "socket_address", format!("{}: {}", addr, e));
+            }
+        }
+    }
+
+    fn check_writable(&mut self, name: &str, key: &str, path: &str) {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => Path::new("/"),
+        };
+        if dir.is_dir() && access(dir, libc::W_OK) {
+            self.add(Level::Ok, name, key, format!("{} writable", path));
+        } else {
+            self.add(Level::Error, name, key, format!("{} not writable", path));
+        }
+    }
+
+    fn check_chroot_command(&mut self, name: &str, config: &WorkerConfig) {
+        let cmd = &config.cmd[0];
+        if !cmd.contains('/') {
+            self.add(
+                Level::Warn,
+                name,
+                "cmd",
+                format!("{} is resolved by PATH inside chroot", cmd),
+            );
+            return;
+        }
+        let path = if Path::new(cmd).is_absolute() {
+            config.root_path(cmd)
+        } else {
+            config.root_path(&config.working_directory).join(cmd)
+        };
+        if path.is_file() && access(&path, libc::X_OK) {
+            self.add(Level::Ok, name, "cmd", format!("{:?}", path));
+        } else {
+            self.add(
+                Level::Error,
+                name,
+                "cmd",
+                format!("{:?} not found or not executable", path),
+            );
+        }
+    }
+
+    fn check_spawn(&mut self, name: &str, config: &WorkerConfig, args: &[String]) {
+        let worker = Worker::new(name, config);
+        let args = if args.is_empty() { None } else { Some(args) };
+        let res = worker.check_process(args).and_then(wait_check_process);
+        match (res, args) {
+            (Ok(0), Some(args)) => {
+                self.add(Level::Ok, name, "spawn", format!("{:?} exited 0", args))
+            }
+            (Ok(0), None) => self.add(Level::Ok, name, "spawn", "executable".to_owned()),
+            (Ok(code), _) => self.add(Level::Error, name, "spawn", format!("exited {}", code)),
+            (Err(e), _) => self.add(Level::Error, name, "spawn", e.to_string()),
+        }
+    }
+
+    fn check_worker(&mut self, name: &str, config: &WorkerConfig) {
+        let working_directory = &config.working_directory;
+        if config.chroot.is_some() {
+            self.check_chroot_command(name, config);
+        } else {
+            self.check_command(name, "cmd", &config.cmd, working_directory);
+        }
+        if let Some(ref upgrader) = config.upgrader {
+            self.check_command(name, "upgrader", upgrader, working_directory);
+        }
+        for (key, check) in vec![
+            ("health_check", &config.health_check),
+            ("readiness_check", &config.readiness_check),
+        ] {
+            if let Some(ref check) = *check {
+                if !check.cmd.is_empty() {
+                    self.check_command(name, key, &check.cmd, working_directory);
+                }
+            }
+        }
+        for addr in &config.socket_address {
+            self.check_socket(name, addr, &config.socket_options(addr));
+        }
+        self.check_writable(name, "control_socket", &config.control_sock(name));
+    }
+}
+
+/// Waits the process of `check --spawn`, and kills it after `SPAWN_CHECK_TIMEOUT_SECS`.
+fn wait_check_process(mut child: Child) -> io::Result<i32> {
+    let now = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            // the same code as a shell
+            return Ok(status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)));
+        }
+        if now.elapsed() >= Duration::from_secs(SPAWN_CHECK_TIMEOUT_SECS) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("not exited in {} secs", SPAWN_CHECK_TIMEOUT_SECS),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The result of `check --json`.
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    path: &'a str,
+    ok: bool,
+    diagnostics: &'a [Diagnostic],
+}
+
+fn print_report(path: &str, diagnostics: &[Diagnostic]) -> Result<(), Error> {
+    let report = Report {
+        path,
+        ok: diagnostics.iter().all(|d| d.level != Level::Error),
+        diagnostics,
+    };
+    println!("{}", serde_json::to_string(&report)?);
+    Ok(())
+}
+
+/// With `spawn`, each worker command is also spawned once with the user, the root and
+/// the limits of the processes. See `Worker::check_process` for `spawn_args`.
+pub fn check_config(
+    path: &str,
+    profile: Option<&str>,
+    sock_path: Option<&str>,
+    spawn: bool,
+    spawn_args: &[String],
+    json: bool,
+) -> Result<(), Error> {
+    let config = Config::from_file(path, profile).map_err(|e| {
+        if json {
+            let diagnostic = Diagnostic {
+                level: Level::Error,
+                worker: String::new(),
+                key: "config".to_owned(),
+                message: e.to_string(),
+            };
+            let _ = print_report(path, &[diagnostic]);
+        } else if e.downcast_ref::<ConfigError>().is_some() {
+            println!("{:5} {}", "error", e);
+        } else {
+            println!("{:5} [{}] config: {}", "error", path, e);
+        }
+        e
+    })?;
+    let mut checker = Checker {
+        diagnostics: Vec::new(),
+    };
+    let sock_path = sock_path.unwrap_or(&config.control_sock);
+    match sock_path.parse() {
+        Ok(ListenFd::UnixListener(path)) => {
+            checker.check_writable("daemon", "control_sock", &path.to_string_lossy())
+        }
+        Ok(ListenFd::TcpListener(_)) => checker.add(
+            Level::Warn,
+            "daemon",
+            "control_sock",
+            format!("{} is tcp. anyone who can connect controls the daemon", sock_path),
+        ),
+        Ok(_) => checker.add(
+            Level::Error,
+            "daemon",
+            "control_sock",
+            format!("{} is not supported", sock_path),
+        ),
+        Err(e) => checker.add(
+            Level::Error,
+            "daemon",
+            "control_sock",
+            format!("{}: {}", sock_path, e),
+        ),
+    }
+    let mut names: Vec<&String> = config.workers.keys().collect();
+    names.sort();
+    for name in names {
+        checker.check_worker(name, &config.workers[name]);
+        if spawn {
+            checker.check_spawn(name, &config.workers[name], spawn_args);
+        }
+    }
+
+    let mut errors = 0;
+    for d in &checker.diagnostics {
+        if d.level == Level::Error {
+            errors += 1;
+        }
+        if !json {
+            println!("{}", d);
+        }
+    }
+    if json {
+        print_report(path, &checker.diagnostics)?;
+    }
+    if errors > 0 {
+        return Err(err_msg(format!("config check failed. {} errors", errors)));
+    }
+    if !json {
+        println!("config {} ok", path);
+    }
+    Ok(())
+}
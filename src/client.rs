@@ -1,25 +1,269 @@
-use failure::Error;
-use libc::pid_t;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::{env, io, thread, time};
+
+use failure::{err_msg, Error};
+use libc::{self, pid_t};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
 use nix::unistd::getpid;
+use serde::de::DeserializeOwned;
+use serde_json;
 
+use auth::token_from_env;
 use command::*;
+use exec::ExecOutput;
+use logs::LogChunk;
+use metrics::WorkerStats;
 use signal::Signal;
+use sock::CtrlStream;
+use top::Top;
+
+// `logs -f` asks for the new lines at this interval
+const FOLLOW_INTERVAL_MILLIS: u64 = 500;
+// `wait` asks again at this interval while the monitor is not up
+const WAIT_INTERVAL_MILLIS: u64 = 500;
+// seconds `wait` gives the monitor over the timeout to answer
+const WAIT_GRACE_SECS: u64 = 5;
+// Ctrl-] detaches the terminal from the worker
+const DETACH_KEY: u8 = 0x1d;
 
-pub struct Client {}
+pub struct Client {
+    // print the responses of the daemon as is
+    json: bool,
+}
 
 impl Client {
-    pub fn new() -> Self {
-        Client {}
+    pub fn new(json: bool) -> Self {
+        Client { json }
     }
 
-    pub fn list(&mut self, sock_path: &str) -> Result<(), Error> {
+    pub fn list(&mut self, sock_path: &str, quiet: bool) -> Result<(), Error> {
         info!("show worker names");
-        self.send_list(sock_path)
+        self.send_list(sock_path, quiet)
+    }
+
+    pub fn status(&mut self, sock_path: &str, group: Option<&str>) -> Result<(), Error> {
+        match group {
+            Some(group) => {
+                info!("show worker status of group [{}]", group);
+                self.send_group_status(sock_path, group)
+            }
+            None => {
+                info!("show worker status");
+                self.send_status(sock_path)
+            }
+        }
+    }
+
+    /// Refreshes the table of the processes of all workers every `interval` seconds until
+    /// interrupted, or `iterations` times.
+    pub fn top(
+        &mut self,
+        sock_path: &str,
+        interval: u64,
+        iterations: Option<u64>,
+    ) -> Result<(), Error> {
+        info!("show top");
+        let dcmd = status_command();
+        // print the tables one after another when not on a terminal
+        let clear = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        let mut top = Top::default();
+        let mut count = 0;
+        loop {
+            if self.json {
+                self.print_json(sock_path, &dcmd)?;
+            } else {
+                let payload = send_daemon_request(sock_path, &dcmd)?;
+                let res: Vec<CommandResponse> = serde_json::from_slice(&payload)?;
+                let workers: Vec<WorkerStatus> = res
+                    .iter()
+                    .filter_map(|r| serde_json::from_str(&r.message).ok())
+                    .collect();
+                let table = top.render(&workers);
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                if clear {
+                    // move the cursor home and clear the screen
+                    stdout.write_all(b"\x1b[H\x1b[2J")?;
+                }
+                stdout.write_all(table.as_bytes())?;
+                stdout.flush()?;
+            }
+            count += 1;
+            if iterations.map_or(false, |n| count >= n) {
+                return Ok(());
+            }
+            thread::sleep(time::Duration::from_secs(interval));
+        }
+    }
+
+    pub fn reload(&mut self, sock_path: &str) -> Result<(), Error> {
+        info!("reload config");
+        self.send_reload(sock_path)
+    }
+
+    /// Sends the whole config file to the daemon. The format is chosen by the extension.
+    pub fn apply(
+        &mut self,
+        sock_path: &str,
+        path: &str,
+        profile: Option<&str>,
+        dry_run: bool,
+    ) -> Result<(), Error> {
+        info!("apply config {}", path);
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        let format = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("toml");
+        let source = ConfigSource {
+            format: format.to_owned(),
+            content,
+            profile: profile.map(|p| p.to_owned()),
+            dry_run,
+        };
+        self.send_apply(sock_path, source)
+    }
+
+    /// Checks that a live daemon of the same protocol answers within `timeout` seconds.
+    pub fn ping(&mut self, sock_path: &str, timeout: u64) -> Result<(), Error> {
+        info!("ping daemon");
+        let pid = pid_t::from(getpid());
+        let dcmd = DaemonCommand {
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::Ping, pid as u32)
+        };
+        let payload = match send_ping(sock_path, &dcmd, timeout) {
+            Ok(payload) => payload,
+            Err(e) if self.json => {
+                println!("{}", json_error(dcmd.pid, e.to_string())?);
+                return Err(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if self.json {
+            println!("{}", String::from_utf8_lossy(&payload));
+        }
+        let res: PingResponse = match serde_json::from_slice(&payload) {
+            Ok(res) => res,
+            Err(_) => {
+                let res: CommandResponse = serde_json::from_slice(&payload)?;
+                return Err(err_msg(res.message));
+            }
+        };
+        if res.protocol_version != PROTOCOL_VERSION {
+            return Err(err_msg(format!(
+                "unsupported protocol version {}. expected {}",
+                res.protocol_version, PROTOCOL_VERSION
+            )));
+        }
+        if !self.json {
+            println!("{}", res);
+        }
+        Ok(())
+    }
+
+    pub fn shutdown(&mut self, sock_path: &str, timeout: u64) -> Result<(), Error> {
+        info!("shutdown daemon");
+        self.send_shutdown(sock_path, timeout)
+    }
+
+    pub fn upexec(&mut self, sock_path: &str, binary: Option<&str>) -> Result<(), Error> {
+        info!("re-exec daemon");
+        self.send_upexec(sock_path, binary)
+    }
+
+    pub fn start_worker(&mut self, sock_path: &str, name: &str) -> Result<(), Error> {
+        info!("start worker [{}]", name);
+        self.send_worker_command(sock_path, CommandType::StartWorker, name, None)
+    }
+
+    pub fn stop_worker(&mut self, sock_path: &str, name: &str, timeout: u64) -> Result<(), Error> {
+        info!("stop worker [{}]", name);
+        self.send_worker_command(sock_path, CommandType::StopWorker, name, Some(timeout))
+    }
+
+    pub fn restart_worker(
+        &mut self,
+        sock_path: &str,
+        name: &str,
+        timeout: u64,
+    ) -> Result<(), Error> {
+        info!("restart worker [{}]", name);
+        self.send_worker_command(sock_path, CommandType::RestartWorker, name, Some(timeout))
+    }
+
+    /// Replaces the processes of a worker in batches of max_parallel without stopping
+    /// the monitor.
+    pub fn rolling_restart(&mut self, sock_path: &str, name: &str) -> Result<(), Error> {
+        info!("rolling restart worker [{}]", name);
+        self.send_ctrl_command(sock_path, name, "rolling-restart", None, None)
+    }
+
+    /// Sends start, stop or restart to the workers of `group` one by one in the order of
+    /// their names. A failed worker does not stop the rest.
+    pub fn group_command(
+        &mut self,
+        sock_path: &str,
+        command_type: CommandType,
+        group: &str,
+        timeout: Option<u64>,
+    ) -> Result<(), Error> {
+        info!("send {:?} to group [{}]", command_type, group);
+        let mut failed = Vec::new();
+        for name in self.group_workers(sock_path, group)? {
+            if let Err(e) =
+                self.send_worker_command(sock_path, command_type.clone(), &name, timeout)
+            {
+                eprintln!("{}", e);
+                failed.push(name);
+            }
+        }
+        if !failed.is_empty() {
+            return Err(err_msg(format!(
+                "fail {:?} of group [{}] workers {:?}",
+                command_type, group, failed
+            )));
+        }
+        Ok(())
     }
 
-    pub fn status(&mut self, sock_path: &str) -> Result<(), Error> {
-        info!("show worker status");
-        self.send_status(sock_path)
+    /// Stops the worker after its connections are closed. Waits up to `timeout` for the
+    /// connections and then for the processes.
+    pub fn drain_worker(
+        &mut self,
+        sock_path: &str,
+        name: &str,
+        timeout: u64,
+        signal: Option<&str>,
+    ) -> Result<(), Error> {
+        info!("drain worker [{}]", name);
+        let signal: Option<Signal> = match signal {
+            Some(signal) => Some(signal.parse()?),
+            None => None,
+        };
+        let pid = pid_t::from(getpid());
+        let ctrl_cmd = CtrlCommand {
+            signal,
+            ..CtrlCommand::new(Command::Drain, pid as u32)
+        };
+        let dcmd = DaemonCommand {
+            worker: Some(name.to_owned()),
+            command: Some(ctrl_cmd),
+            timeout: Some(timeout),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::DrainWorker, pid as u32)
+        };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
     }
 
     pub fn run(
@@ -30,7 +274,403 @@ impl Client {
         signal: Option<&str>,
     ) -> Result<(), Error> {
         info!("start client. [{}] [{}]", name, command);
-        self.send_ctrl_command(sock_path, name, command, signal)
+        self.send_ctrl_command(sock_path, name, command, signal, None)
+    }
+
+    pub fn scale(&mut self, sock_path: &str, name: &str, count: u64) -> Result<(), Error> {
+        info!("scale worker [{}] to {}", name, count);
+        self.send_ctrl_command(sock_path, name, "scale", None, Some(count))
+    }
+
+    /// Upgrades a worker. `binary` and `args` replace the configured command line for this upgrade.
+    pub fn upgrade(
+        &mut self,
+        sock_path: &str,
+        name: &str,
+        signal: Option<&str>,
+        binary: Option<&str>,
+        args: Option<Vec<String>>,
+    ) -> Result<(), Error> {
+        info!("upgrade worker [{}]", name);
+        let signal: Option<Signal> = match signal {
+            Some(signal) => Some(signal.parse()?),
+            None => None,
+        };
+        let pid = pid_t::from(getpid());
+        let ctrl_cmd = CtrlCommand {
+            signal,
+            traceparent: env::var("TRACEPARENT").ok(),
+            args,
+            binary: binary.map(|b| b.to_owned()),
+            ..CtrlCommand::new(Command::Upgrade, pid as u32)
+        };
+        let dcmd = DaemonCommand {
+            worker: Some(name.to_owned()),
+            command: Some(ctrl_cmd),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::CtrlWorker, pid as u32)
+        };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("send ctrl command [upgrade] to [{}] worker", name);
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    pub fn rollback_worker(
+        &mut self,
+        sock_path: &str,
+        name: &str,
+        signal: Option<&str>,
+    ) -> Result<(), Error> {
+        info!("rollback worker [{}]", name);
+        let signal: Option<Signal> = match signal {
+            Some(signal) => Some(signal.parse()?),
+            None => None,
+        };
+        let pid = pid_t::from(getpid());
+        let ctrl_cmd = CtrlCommand {
+            signal,
+            traceparent: env::var("TRACEPARENT").ok(),
+            ..CtrlCommand::new(Command::Rollback, pid as u32)
+        };
+        let dcmd = DaemonCommand {
+            worker: Some(name.to_owned()),
+            command: Some(ctrl_cmd),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::RollbackWorker, pid as u32)
+        };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    pub fn signal(&mut self, sock_path: &str, name: &str, signal: &str) -> Result<(), Error> {
+        info!("send signal {} to worker [{}]", signal, name);
+        self.send_ctrl_command(sock_path, name, "signal", Some(signal), None)
+    }
+
+    /// Prints the last lines of the output of a worker. With `follow`, polls the new lines until killed.
+    pub fn logs(
+        &mut self,
+        sock_path: &str,
+        name: &str,
+        lines: u64,
+        follow: bool,
+    ) -> Result<(), Error> {
+        info!("show logs of worker [{}]", name);
+        let mut since = None;
+        loop {
+            let chunk = self.send_logs(sock_path, name, lines, since)?;
+            for line in &chunk.lines {
+                if self.json {
+                    println!("{}", serde_json::to_string(line)?);
+                } else if line.stream == "stderr" {
+                    eprintln!("{}", line.line);
+                } else {
+                    println!("{}", line.line);
+                }
+            }
+            if !follow {
+                return Ok(());
+            }
+            since = Some(chunk.last);
+            thread::sleep(time::Duration::from_millis(FOLLOW_INTERVAL_MILLIS));
+        }
+    }
+
+    /// Prints the reports of the last crashes of a worker, the oldest first.
+    pub fn crashes(&mut self, sock_path: &str, name: &str) -> Result<(), Error> {
+        info!("show crashes of worker [{}]", name);
+        let reports: Vec<CrashReport> =
+            self.send_query(sock_path, name, Command::Crashes, None, None)?;
+        if self.json {
+            println!("{}", serde_json::to_string(&reports)?);
+        } else if reports.is_empty() {
+            println!("[{}] no crashes", name);
+        } else {
+            for report in &reports {
+                println!("{}", report.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until a worker reaches `state` or `timeout` seconds passed. The monitor answers
+    /// as soon as the state is reached, so a short stop and start is not missed. `upgraded`
+    /// is an upgrade completed after the start of the wait.
+    pub fn wait(
+        &mut self,
+        sock_path: &str,
+        name: &str,
+        state: &str,
+        timeout: u64,
+    ) -> Result<(), Error> {
+        info!("wait worker [{}] to be {}", name, state);
+        if !WAIT_STATES.contains(&state) {
+            return Err(err_msg(format!("unknown state {}", state)));
+        }
+        let upgrades = if state == "upgraded" {
+            let stats: WorkerStats =
+                self.send_query(sock_path, name, Command::Metrics, None, None)?;
+            Some(stats.upgrades)
+        } else {
+            None
+        };
+        let started_at = time::Instant::now();
+        loop {
+            let left = timeout.saturating_sub(started_at.elapsed().as_secs());
+            let last = match self.wait_monitor(sock_path, name, state, upgrades, left) {
+                Ok(res) => match res.status {
+                    Status::Ok => return self.print_reached(name, state, &res.message),
+                    _ => return Err(err_msg(res.message)),
+                },
+                // the monitor is not up, stopped or exited, or the daemon is re-executing
+                Err(e) => match self.worker_status(sock_path, name) {
+                    Ok(ref status) if state != "upgraded" && status.reached(state) => {
+                        return self.print_reached(name, state, &serde_json::to_string(status)?)
+                    }
+                    Ok(status) => match status.unreachable(state) {
+                        Some(msg) => return Err(err_msg(msg)),
+                        None => format!("state {}", status.state),
+                    },
+                    Err(_) => e.to_string(),
+                },
+            };
+            if started_at.elapsed() >= time::Duration::from_secs(timeout) {
+                return Err(err_msg(format!(
+                    "timed out waiting [{}] to be {}. {}",
+                    name, state, last
+                )));
+            }
+            thread::sleep(time::Duration::from_millis(WAIT_INTERVAL_MILLIS));
+        }
+    }
+
+    /// Sends the wait command to the monitor and reads the result that follows the response.
+    fn wait_monitor(
+        &self,
+        sock_path: &str,
+        name: &str,
+        state: &str,
+        upgrades: Option<u64>,
+        timeout: u64,
+    ) -> Result<CommandResponse, Error> {
+        let (mut stream, _res) = self.connect_stream(
+            sock_path,
+            name,
+            CtrlCommand {
+                args: Some(vec![state.to_owned()]),
+                since: upgrades,
+                timeout: Some(timeout),
+                ..CtrlCommand::new(Command::Wait, pid_t::from(getpid()) as u32)
+            },
+        )?;
+        // the monitor answers by the timeout
+        stream.set_read_timeout(Some(time::Duration::from_secs(timeout + WAIT_GRACE_SECS)))?;
+        let payload = read_response(&mut stream)?;
+        Ok(serde_json::from_slice(&payload)?)
+    }
+
+    fn print_reached(&self, name: &str, state: &str, message: &str) -> Result<(), Error> {
+        if self.json {
+            println!("{}", message);
+        } else {
+            println!("[{}] {}", name, state);
+        }
+        Ok(())
+    }
+
+    fn worker_status(&self, sock_path: &str, name: &str) -> Result<WorkerStatus, Error> {
+        let payload = send_daemon_request(sock_path, &worker_status_command(name))?;
+        let res: CommandResponse = serde_json::from_slice(&payload)?;
+        if let Status::Error = res.status {
+            return Err(err_msg(res.message));
+        }
+        Ok(serde_json::from_str(&res.message)?)
+    }
+
+    /// Bridges the terminal to the pty of the oldest process of a worker until the detach key
+    /// is pressed or the process exits.
+    pub fn attach(&mut self, sock_path: &str, name: &str) -> Result<(), Error> {
+        info!("attach to worker [{}]", name);
+        let (mut stream, res) = self.open_stream(sock_path, name, Command::Attach, None)?;
+        if self.json {
+            println!("{}", serde_json::to_string(&res)?);
+        } else {
+            eprintln!("{}. detach with Ctrl-]", res.message);
+        }
+
+        let raw_mode = RawMode::enable(libc::STDIN_FILENO)?;
+        let mut input = stream.try_clone()?;
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            let mut buf = [0u8; 1024];
+            loop {
+                let size = match stdin.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => size,
+                };
+                if let Some(pos) = buf[..size].iter().position(|b| *b == DETACH_KEY) {
+                    let _ = input.write_all(&buf[..pos]);
+                    break;
+                }
+                if input.write_all(&buf[..size]).is_err() {
+                    break;
+                }
+            }
+            let _ = input.shutdown(Shutdown::Both);
+        });
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let mut buf = [0u8; 4096];
+        loop {
+            let size = stream.read(&mut buf)?;
+            if size == 0 {
+                break;
+            }
+            stdout.write_all(&buf[..size])?;
+            stdout.flush()?;
+        }
+        drop(raw_mode);
+        if !self.json {
+            eprintln!("detached from [{}] worker", name);
+        }
+        Ok(())
+    }
+
+    /// Runs a one-off command in the context of a worker and prints its output.
+    /// Returns the exit code of the command.
+    pub fn exec(&mut self, sock_path: &str, name: &str, args: &[String]) -> Result<i32, Error> {
+        info!("exec {:?} in worker [{}]", args, name);
+        let (mut stream, res) =
+            self.open_stream(sock_path, name, Command::Exec, Some(args.to_vec()))?;
+        if self.json {
+            println!("{}", serde_json::to_string(&res)?);
+        }
+        loop {
+            let payload = read_response(&mut stream)?;
+            if self.json {
+                println!("{}", String::from_utf8_lossy(&payload));
+            }
+            match serde_json::from_slice(&payload)? {
+                ExecOutput::Exit(code) => {
+                    // the caller exits with the code without flushing
+                    io::stdout().flush()?;
+                    return Ok(code);
+                }
+                _ if self.json => {}
+                ExecOutput::Stdout(line) => print!("{}", line),
+                ExecOutput::Stderr(line) => eprint!("{}", line),
+            }
+        }
+    }
+
+    /// Sends a command that streams after the response, attach and exec.
+    /// Returns the connection when the command is accepted.
+    fn open_stream(
+        &self,
+        sock_path: &str,
+        name: &str,
+        command: Command,
+        args: Option<Vec<String>>,
+    ) -> Result<(CtrlStream, CommandResponse), Error> {
+        let pid = pid_t::from(getpid());
+        let ctrl_cmd = CtrlCommand {
+            args,
+            ..CtrlCommand::new(command, pid as u32)
+        };
+        let res = self.connect_stream(sock_path, name, ctrl_cmd);
+        if let Err(ref e) = res {
+            if self.json {
+                println!("{}", json_error(pid as u32, e.to_string())?);
+            }
+        }
+        res
+    }
+
+    /// Sends the command to the worker and returns the connection after a successful response.
+    fn connect_stream(
+        &self,
+        sock_path: &str,
+        name: &str,
+        ctrl_cmd: CtrlCommand,
+    ) -> Result<(CtrlStream, CommandResponse), Error> {
+        let dcmd = DaemonCommand {
+            worker: Some(name.to_owned()),
+            command: Some(ctrl_cmd),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::CtrlWorker, pid_t::from(getpid()) as u32)
+        };
+        CtrlStream::connect(sock_path)
+            .and_then(|mut stream| {
+                write_frame(&mut stream, MessageType::DaemonCommand, &dcmd)?;
+                let payload = read_response(&mut stream)?;
+                Ok((stream, payload))
+            })
+            .map_err(Error::from)
+            .and_then(|(stream, payload)| {
+                let res: CommandResponse = serde_json::from_slice(&payload)?;
+                match res.status {
+                    Status::Ok => Ok((stream, res)),
+                    _ => Err(err_msg(res.message)),
+                }
+            })
+    }
+
+    fn send_logs(
+        &self,
+        sock_path: &str,
+        name: &str,
+        lines: u64,
+        since: Option<u64>,
+    ) -> Result<LogChunk, Error> {
+        self.send_query(sock_path, name, Command::Logs, Some(lines), since)
+    }
+
+    /// Sends a ctrl command answered with a JSON message, such as logs and crashes.
+    fn send_query<T: DeserializeOwned>(
+        &self,
+        sock_path: &str,
+        name: &str,
+        command: Command,
+        count: Option<u64>,
+        since: Option<u64>,
+    ) -> Result<T, Error> {
+        let pid = pid_t::from(getpid());
+        let ctrl_cmd = CtrlCommand {
+            count,
+            since,
+            ..CtrlCommand::new(command, pid as u32)
+        };
+        let dcmd = DaemonCommand {
+            worker: Some(name.to_owned()),
+            command: Some(ctrl_cmd),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::CtrlWorker, pid as u32)
+        };
+        let res = send_daemon_request(sock_path, &dcmd)
+            .map_err(Error::from)
+            .and_then(|payload| {
+                let res: CommandResponse = serde_json::from_slice(&payload)?;
+                match res.status {
+                    Status::Ok => Ok(serde_json::from_str(&res.message)?),
+                    _ => Err(err_msg(res.message)),
+                }
+            });
+        if let Err(ref e) = res {
+            if self.json {
+                println!("{}", json_error(pid as u32, e.to_string())?);
+            }
+        }
+        res
     }
 
     fn send_ctrl_command(
@@ -39,58 +679,276 @@ impl Client {
         name: &str,
         command: &str,
         signal: Option<&str>,
+        count: Option<u64>,
     ) -> Result<(), Error> {
-        let signal: Option<Signal> = signal.map(|signal| signal.parse().unwrap());
+        let signal: Option<Signal> = match signal {
+            Some(signal) => Some(signal.parse()?),
+            None => None,
+        };
         let cmd: Command = command.parse().unwrap();
         let pid = pid_t::from(getpid());
         let ctrl_cmd = CtrlCommand {
-            command: cmd,
-            pid: pid as u32,
             signal,
+            // join the trace of a deploy tool
+            traceparent: env::var("TRACEPARENT").ok(),
+            count,
+            ..CtrlCommand::new(cmd, pid as u32)
         };
         let dcmd = DaemonCommand {
-            command_type: CommandType::CtrlWorker,
             worker: Some(name.to_owned()),
             command: Some(ctrl_cmd),
-            pid: pid as u32,
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::CtrlWorker, pid as u32)
         };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
         let res = send_daemon_command(sock_path, &dcmd)?;
         println!("send ctrl command [{}] to [{}] worker", command, name);
         println!("{}", res.to_string());
         Ok(())
     }
 
-    fn send_list(&self, sock_path: &str) -> Result<(), Error> {
+    fn send_worker_command(
+        &self,
+        sock_path: &str,
+        command_type: CommandType,
+        name: &str,
+        timeout: Option<u64>,
+    ) -> Result<(), Error> {
+        let pid = pid_t::from(getpid());
+        let dcmd = DaemonCommand {
+            worker: Some(name.to_owned()),
+            timeout,
+            token: token_from_env(),
+            ..DaemonCommand::new(command_type, pid as u32)
+        };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    fn send_list(&self, sock_path: &str, quiet: bool) -> Result<(), Error> {
         let pid = pid_t::from(getpid());
         let dcmd = DaemonCommand {
-            command_type: CommandType::List,
-            worker: None,
-            command: None,
-            pid: pid as u32,
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::List, pid as u32)
         };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        if quiet {
+            // for scripts and shell completion
+            let payload = send_daemon_request(sock_path, &dcmd)?;
+            let res: ListResponse = serde_json::from_slice(&payload)?;
+            for name in &res.workers {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
         let res = send_daemon_command(sock_path, &dcmd)?;
         println!("worker names:");
         println!("{}", res.to_string());
         Ok(())
     }
 
-    fn send_status(&self, sock_path: &str) -> Result<(), Error> {
+    fn send_reload(&self, sock_path: &str) -> Result<(), Error> {
         let pid = pid_t::from(getpid());
-        let ctrl_cmd = CtrlCommand {
-            command: Command::Status,
-            pid: pid as u32,
-            signal: None,
+        let dcmd = DaemonCommand {
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::Reload, pid as u32)
         };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    fn send_apply(&self, sock_path: &str, source: ConfigSource) -> Result<(), Error> {
+        let pid = pid_t::from(getpid());
         let dcmd = DaemonCommand {
-            command_type: CommandType::Status,
-            worker: None,
-            command: Some(ctrl_cmd),
-            pid: pid as u32,
+            config: Some(source),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::Apply, pid as u32)
+        };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    fn send_shutdown(&self, sock_path: &str, timeout: u64) -> Result<(), Error> {
+        let pid = pid_t::from(getpid());
+        let dcmd = DaemonCommand {
+            timeout: Some(timeout),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::Shutdown, pid as u32)
+        };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    fn send_upexec(&self, sock_path: &str, binary: Option<&str>) -> Result<(), Error> {
+        let pid = pid_t::from(getpid());
+        let dcmd = DaemonCommand {
+            binary: binary.map(|b| b.to_owned()),
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::Upexec, pid as u32)
         };
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
+        let res = send_daemon_command(sock_path, &dcmd)?;
+        println!("{}", res.to_string());
+        Ok(())
+    }
+
+    fn send_status(&self, sock_path: &str) -> Result<(), Error> {
+        let dcmd = status_command();
+        if self.json {
+            return self.print_json(sock_path, &dcmd);
+        }
         let res = send_daemon_list_command(sock_path, &dcmd)?;
         for r in res {
             println!("{}", r.to_string());
         }
         Ok(())
     }
+
+    /// Prints the status of the workers of `group` in the same form as the status of all.
+    fn send_group_status(&self, sock_path: &str, group: &str) -> Result<(), Error> {
+        let pid = pid_t::from(getpid());
+        let mut v = Vec::new();
+        for name in self.group_workers(sock_path, group)? {
+            let res = send_daemon_request(sock_path, &worker_status_command(&name))
+                .map_err(Error::from)
+                .and_then(|payload| Ok(serde_json::from_slice(&payload)?))
+                .unwrap_or_else(|e| CommandResponse {
+                    status: Status::Error,
+                    command: Command::Status,
+                    pid: pid as u32,
+                    message: e.to_string(),
+                });
+            v.push(res);
+        }
+        if self.json {
+            println!("{}", serde_json::to_string(&v)?);
+            return Ok(());
+        }
+        for r in v {
+            println!("{}", r.to_string());
+        }
+        Ok(())
+    }
+
+    /// The workers of `group` in the order of their names.
+    fn group_workers(&self, sock_path: &str, group: &str) -> Result<Vec<String>, Error> {
+        let pid = pid_t::from(getpid());
+        let dcmd = DaemonCommand {
+            token: token_from_env(),
+            ..DaemonCommand::new(CommandType::List, pid as u32)
+        };
+        let payload = send_daemon_request(sock_path, &dcmd)?;
+        let mut res: ListResponse = serde_json::from_slice(&payload)?;
+        res.groups
+            .remove(group)
+            .ok_or_else(|| err_msg(format!("unknown group [{}]", group)))
+    }
+
+    /// Prints the response payload. A failed command is printed as an error response,
+    /// so that a script always reads JSON.
+    fn print_json(&self, sock_path: &str, dcmd: &DaemonCommand) -> Result<(), Error> {
+        match send_daemon_request(sock_path, dcmd) {
+            Ok(payload) => {
+                println!("{}", String::from_utf8_lossy(&payload));
+                Ok(())
+            }
+            Err(e) => {
+                println!("{}", json_error(dcmd.pid, e.to_string())?);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+fn status_command() -> DaemonCommand {
+    let pid = pid_t::from(getpid());
+    let ctrl_cmd = CtrlCommand::new(Command::Status, pid as u32);
+    DaemonCommand {
+        command: Some(ctrl_cmd),
+        token: token_from_env(),
+        ..DaemonCommand::new(CommandType::Status, pid as u32)
+    }
+}
+
+fn worker_status_command(name: &str) -> DaemonCommand {
+    let pid = pid_t::from(getpid());
+    DaemonCommand {
+        worker: Some(name.to_owned()),
+        command: Some(CtrlCommand::new(Command::Status, pid as u32)),
+        token: token_from_env(),
+        ..DaemonCommand::new(CommandType::CtrlWorker, pid as u32)
+    }
+}
+
+/// Puts the terminal in raw mode, so that the keys reach the worker as is.
+/// The settings are restored on drop.
+struct RawMode {
+    fd: RawFd,
+    termios: Termios,
+}
+
+impl RawMode {
+    /// Returns `None` when the fd is not a terminal.
+    fn enable(fd: RawFd) -> Result<Option<RawMode>, Error> {
+        if unsafe { libc::isatty(fd) } != 1 {
+            return Ok(None);
+        }
+        let termios = tcgetattr(fd)?;
+        let mut raw = termios.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+        Ok(Some(RawMode { fd, termios }))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, SetArg::TCSANOW, &self.termios);
+    }
+}
+
+/// Sends a ping. A stalled daemon fails the ping after `timeout` seconds.
+fn send_ping(sock_path: &str, dcmd: &DaemonCommand, timeout: u64) -> io::Result<Vec<u8>> {
+    let mut stream = CtrlStream::connect(sock_path)?;
+    stream.set_read_timeout(Some(time::Duration::from_secs(timeout)))?;
+    write_frame(&mut stream, MessageType::DaemonCommand, dcmd)?;
+    read_response(&mut stream).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("daemon not answered in {} secs", timeout),
+        ),
+        _ => e,
+    })
+}
+
+fn json_error(pid: u32, message: String) -> Result<String, Error> {
+    let res = CommandResponse {
+        status: Status::Error,
+        command: Command::None,
+        pid,
+        message,
+    };
+    Ok(serde_json::to_string(&res)?)
 }
@@ -1,12 +1,23 @@
-use std::{env, path};
+use std::{env, path, process};
 
 use clap::{App, AppSettings, Arg, SubCommand};
-use failure::Error;
+use failure::{err_msg, Error};
+use nix::unistd::getpid;
 
 use app::APP_NAME;
+use audit;
+use check::check_config;
 use client::Client;
-use config::parse_config;
+use command::CommandType;
+use completion::print_completion;
+use config::Config;
 use daemon::Daemon;
+use detach::detach;
+use logs::set_foreground;
+use monitor::MonitorProcess;
+use sock::{adopt_listen_fds, inherit_fds};
+use standby::wait_primary;
+use upexec::Handover;
 
 lazy_static! {
     pub static ref SOCK_PATH: path::PathBuf = {
@@ -22,18 +33,626 @@ fn make_app() -> App<'static, 'static> {
         .version(env!("CARGO_PKG_VERSION"))
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .about("A process and shared socket manager")
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("print the result as JSON."),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run daemon")
+                .arg(
+                    Arg::with_name("config")
+                        .required(true)
+                        .multiple(false)
+                        .value_name("FILE")
+                        .short("c")
+                        .long("config")
+                        .help("set config file."),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .multiple(false)
+                        .value_name("PROFILE")
+                        .short("p")
+                        .long("profile")
+                        .help("set config profile."),
+                )
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("pidfile")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .long("pidfile")
+                        .help("set pid file path."),
+                )
+                .arg(
+                    Arg::with_name("state-file")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .long("state-file")
+                        .help("save runtime state to the file and restore it on start."),
+                )
+                .arg(
+                    Arg::with_name("audit-log")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .long("audit-log")
+                        .help("append control commands to the audit log."),
+                )
+                .arg(
+                    Arg::with_name("http-address")
+                        .multiple(false)
+                        .value_name("ADDR")
+                        .long("http-address")
+                        .help("serve the control api over http."),
+                )
+                .arg(
+                    Arg::with_name("metrics-address")
+                        .multiple(false)
+                        .value_name("ADDR")
+                        .long("metrics-address")
+                        .help("serve prometheus metrics over http."),
+                )
+                .arg(
+                    Arg::with_name("detach")
+                        .long("detach")
+                        .alias("daemon")
+                        .help("run in background. wait until the workers are up."),
+                )
+                .arg(
+                    Arg::with_name("foreground")
+                        .long("foreground")
+                        .conflicts_with("detach")
+                        .help("stream worker output without log file to stdout."),
+                )
+                .arg(
+                    Arg::with_name("standby")
+                        .long("standby")
+                        .help("wait until the daemon on the control socket dies and take over."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check config file")
+                .arg(
+                    Arg::with_name("config")
+                        .required(true)
+                        .multiple(false)
+                        .value_name("FILE")
+                        .short("c")
+                        .long("config")
+                        .help("set config file."),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .multiple(false)
+                        .value_name("PROFILE")
+                        .short("p")
+                        .long("profile")
+                        .help("set config profile."),
+                )
+                .arg(
+                    Arg::with_name("spawn")
+                        .long("spawn")
+                        .help("spawn each worker command as its user and exit before the exec."),
+                )
+                .arg(
+                    Arg::with_name("spawn-arg")
+                        .long("spawn-arg")
+                        .value_name("ARG")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .allow_hyphen_values(true)
+                        .requires("spawn")
+                        .help("run the worker command with ARG instead, and require exit 0."),
+                )
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Show worker names")
+                .arg(
+                    Arg::with_name("quiet")
+                        .short("q")
+                        .long("quiet")
+                        .help("print only the names, one per line."),
+                )
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show worker status")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .multiple(false)
+                        .value_name("GROUP")
+                        .short("g")
+                        .long("group")
+                        .help("show only the workers of the group."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("top")
+                .about("Show the processes of the workers and refresh them")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .multiple(false)
+                        .value_name("SECONDS")
+                        .short("i")
+                        .long("interval")
+                        .default_value("2")
+                        .help("set the refresh interval."),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .multiple(false)
+                        .value_name("N")
+                        .short("n")
+                        .long("iterations")
+                        .help("exit after N refreshes."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reload")
+                .about("Reload config file")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Apply a config file to the running daemon")
+                .arg(
+                    Arg::with_name("config")
+                        .required(true)
+                        .multiple(false)
+                        .value_name("FILE")
+                        .short("c")
+                        .long("config")
+                        .help("set config file."),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .multiple(false)
+                        .value_name("PROFILE")
+                        .short("p")
+                        .long("profile")
+                        .help("set config profile. defaults to the profile of the daemon."),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("show the changes without applying them."),
+                )
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about("Check that the daemon is alive")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .multiple(false)
+                        .value_name("SECS")
+                        .short("t")
+                        .long("timeout")
+                        .default_value("5")
+                        .help("fail when the daemon does not answer within the timeout."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("shutdown")
+                .about("Stop all workers and exit daemon")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .multiple(false)
+                        .value_name("SECS")
+                        .short("t")
+                        .long("timeout")
+                        .default_value("30")
+                        .help("kill workers not stopped within the timeout."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("upexec")
+                .about("Re-exec the daemon binary without stopping workers")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("binary")
+                        .multiple(false)
+                        .value_name("FILE")
+                        .short("b")
+                        .long("binary")
+                        .help("set new firestarter binary. default is the running binary path."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("start")
+                .about("Start a stopped worker")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .multiple(false)
+                        .value_name("GROUP")
+                        .short("g")
+                        .long("group")
+                        .conflicts_with("name")
+                        .help("start the workers of the group."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required_unless("group")
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stop")
+                .about("Stop the monitor and the processes of a worker")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .multiple(false)
+                        .value_name("SECS")
+                        .short("t")
+                        .long("timeout")
+                        .default_value("30")
+                        .help("kill the worker not stopped within the timeout."),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .multiple(false)
+                        .value_name("GROUP")
+                        .short("g")
+                        .long("group")
+                        .conflicts_with("name")
+                        .help("stop the workers of the group."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required_unless("group")
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("drain")
+                .about("Stop a worker after its connections are closed")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .multiple(false)
+                        .value_name("SECS")
+                        .short("t")
+                        .long("timeout")
+                        .default_value("30")
+                        .help("stop the worker with connections left after the timeout."),
+                )
+                .arg(
+                    Arg::with_name("signal")
+                        .multiple(false)
+                        .value_name("SIGNAL")
+                        .short("s")
+                        .long("signal")
+                        .help("set the signal to finish the work. default SIGTERM."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("wait")
+                .about("Wait until a worker reaches a state")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .multiple(false)
+                        .value_name("STATE")
+                        .short("s")
+                        .long("state")
+                        .possible_values(&["running", "stopped", "upgraded", "completed"])
+                        .default_value("running")
+                        .help("set the state to wait for."),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .multiple(false)
+                        .value_name("SECS")
+                        .short("t")
+                        .long("timeout")
+                        .default_value("60")
+                        .help("fail when the state is not reached within the timeout."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restart")
+                .about("Stop and start a worker")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .multiple(false)
+                        .value_name("SECS")
+                        .short("t")
+                        .long("timeout")
+                        .default_value("30")
+                        .help("kill the worker not stopped within the timeout."),
+                )
+                .arg(
+                    Arg::with_name("rolling")
+                        .long("rolling")
+                        .conflicts_with("group")
+                        .help("replace the processes in batches without stopping the monitor."),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .multiple(false)
+                        .value_name("GROUP")
+                        .short("g")
+                        .long("group")
+                        .conflicts_with("name")
+                        .help("restart the workers of the group."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required_unless("group")
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scale")
+                .about("Change the number of processes of a worker")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .required(true)
+                        .value_name("N")
+                        .help("set the number of processes."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Upgrade the processes of a worker")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("signal")
+                        .multiple(false)
+                        .value_name("SIGNAL")
+                        .short("s")
+                        .long("signal")
+                        .help("set the signal to the old processes."),
+                )
+                .arg(
+                    Arg::with_name("binary")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("b")
+                        .long("binary")
+                        .help("run the binary instead of the configured one."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .multiple(true)
+                        .last(true)
+                        .value_name("ARGS")
+                        .help("set the arguments instead of the configured ones after --."),
+                ),
+        )
         .subcommand(
-            SubCommand::with_name("run")
-                .about("Run daemon")
+            SubCommand::with_name("rollback")
+                .about("Run the binary of a worker before the last upgrade again")
                 .arg(
-                    Arg::with_name("config")
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("signal")
+                        .multiple(false)
+                        .value_name("SIGNAL")
+                        .short("s")
+                        .long("signal")
+                        .help("set the signal to the old processes."),
+                )
+                .arg(
+                    Arg::with_name("name")
                         .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("signal")
+                .about("Send a signal to the processes of a worker")
+                .arg(
+                    Arg::with_name("socket-path")
                         .multiple(false)
-                        .value_name("FILE")
-                        .short("c")
-                        .long("config")
-                        .help("set config file."),
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
                 )
+                .arg(
+                    Arg::with_name("signal")
+                        .required(true)
+                        .value_name("SIGNAL")
+                        .help("set signal name or number. e.g. SIGUSR1, usr1 or 10"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Show the output of a worker")
                 .arg(
                     Arg::with_name("socket-path")
                         .multiple(false)
@@ -42,11 +661,32 @@ fn make_app() -> App<'static, 'static> {
                         .long("socket-path")
                         .default_value(sock_path)
                         .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .multiple(false)
+                        .value_name("N")
+                        .short("n")
+                        .long("lines")
+                        .default_value("10")
+                        .help("set the number of lines."),
+                )
+                .arg(
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("keep printing new lines."),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("list")
-                .about("Show worker names")
+            SubCommand::with_name("crashes")
+                .about("Show the last crashes of a worker with the tail of stderr")
                 .arg(
                     Arg::with_name("socket-path")
                         .multiple(false)
@@ -55,11 +695,17 @@ fn make_app() -> App<'static, 'static> {
                         .long("socket-path")
                         .default_value(sock_path)
                         .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("status")
-                .about("Show worker status")
+            SubCommand::with_name("attach")
+                .about("Attach the terminal to a worker running on a pty")
                 .arg(
                     Arg::with_name("socket-path")
                         .multiple(false)
@@ -68,6 +714,89 @@ fn make_app() -> App<'static, 'static> {
                         .long("socket-path")
                         .default_value(sock_path)
                         .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("exec")
+                .about("Run a one-off command in the context of a worker")
+                .arg(
+                    Arg::with_name("socket-path")
+                        .multiple(false)
+                        .value_name("PATH")
+                        .short("d")
+                        .long("socket-path")
+                        .default_value(sock_path)
+                        .help("set ctrl socket path."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .required(true)
+                        .multiple(true)
+                        .last(true)
+                        .value_name("COMMAND")
+                        .help("set the command and the arguments after --."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("audit")
+                .about("Show the audit log of control commands")
+                .arg(
+                    Arg::with_name("file")
+                        .multiple(false)
+                        .value_name("FILE")
+                        .short("f")
+                        .long("file")
+                        .required_unless("config")
+                        .conflicts_with("config")
+                        .help("set audit log file."),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .multiple(false)
+                        .value_name("FILE")
+                        .short("c")
+                        .long("config")
+                        .help("read the audit log path from the config file."),
+                )
+                .arg(
+                    Arg::with_name("worker")
+                        .multiple(false)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .short("w")
+                        .long("worker")
+                        .help("show only the commands of the worker."),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .multiple(false)
+                        .value_name("N")
+                        .short("n")
+                        .long("lines")
+                        .default_value("20")
+                        .help("set the number of records."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completion")
+                .about("Print the shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"])
+                        .value_name("SHELL")
+                        .help("set the shell."),
                 ),
         )
         .subcommand(
@@ -102,39 +831,351 @@ fn make_app() -> App<'static, 'static> {
                     Arg::with_name("command")
                         .required(true)
                         .possible_values(&[
-                            "start", "stop", "inc", "dec", "upgrade", "killall", "status",
+                            "start", "stop", "inc", "dec", "upgrade", "killall", "status", "pause",
+                            "resume",
                         ])
                         .value_name("COMMAND")
                         .help("set send command."),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("monitor")
+                .setting(AppSettings::Hidden)
+                .about("Run the monitor of a worker. It is started by the daemon")
+                .arg(
+                    Arg::with_name("spec-fd")
+                        .required(true)
+                        .value_name("FD")
+                        .long("spec-fd")
+                        .help("set the pipe to read the spec from."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .value_name("WORKER_CONFIG_NAME")
+                        .help("set worker name."),
+                ),
+        )
 }
 
 pub fn execute() -> Result<(), Error> {
     let app = make_app();
     let matches = app.get_matches();
+    let json = matches
+        .subcommand()
+        .1
+        .map_or(false, |m| m.is_present("json"));
 
     match matches.subcommand() {
         ("run", Some(m)) => {
+            let fds = adopt_listen_fds();
+            if !fds.is_empty() {
+                info!("adopt LISTEN_FDS {:?}", fds);
+            }
+            let handover = Handover::take()?;
+            if let Some(ref handover) = handover {
+                info!(
+                    "adopt upexec fds {:?} monitors {:?}",
+                    handover.fds, handover.monitors
+                );
+                inherit_fds(&handover.fds);
+            }
+            let path = m.value_of("config").expect("require config path");
+            let mut config = Config::from_file(path, m.value_of("profile"))?;
+            // the option overrides control_sock of the config
+            if m.occurrences_of("socket-path") > 0 {
+                let sock_path = m
+                    .value_of("socket-path")
+                    .expect("require control socket path");
+                config.control_sock = sock_path.to_owned();
+            }
+            if let Some(pidfile) = m.value_of("pidfile") {
+                config.pidfile = Some(pidfile.to_owned());
+            }
+            if let Some(path) = m.value_of("state-file") {
+                config.state_file = Some(path.to_owned());
+            }
+            if let Some(path) = m.value_of("audit-log") {
+                config.audit_log = Some(path.to_owned());
+            }
+            if let Some(addr) = m.value_of("http-address") {
+                config.http_address = Some(addr.to_owned());
+            }
+            if let Some(addr) = m.value_of("metrics-address") {
+                config.metrics_address = Some(addr.to_owned());
+            }
+            set_foreground(m.is_present("foreground"));
+            // the re-executed daemon is already detached
+            let mut launcher = if m.is_present("detach") && handover.is_none() {
+                Some(detach()?)
+            } else {
+                None
+            };
+            if m.is_present("standby") && handover.is_none() {
+                // the launcher does not wait for the primary to die
+                if let Some(launcher) = launcher.take() {
+                    launcher.standby(getpid());
+                }
+                wait_primary(&config)?;
+            }
+            let mut daemon = Daemon::new(config);
+            if let Some(launcher) = launcher {
+                daemon.set_launcher(launcher);
+            }
+            if let Some(handover) = handover {
+                daemon.set_handover(handover);
+            }
+            daemon.run()
+        }
+        ("check", Some(m)) => {
+            let sock_path = if m.occurrences_of("socket-path") > 0 {
+                m.value_of("socket-path")
+            } else {
+                None
+            };
+            let path = m.value_of("config").expect("require config path");
+            let spawn_args: Vec<String> = m
+                .values_of("spawn-arg")
+                .map(|args| args.map(|a| a.to_owned()).collect())
+                .unwrap_or_default();
+            check_config(
+                path,
+                m.value_of("profile"),
+                sock_path,
+                m.is_present("spawn"),
+                &spawn_args,
+                json,
+            )
+        }
+        ("list", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            Client::new(json).list(sock_path, m.is_present("quiet"))
+        }
+        ("completion", Some(m)) => {
+            let shell = m.value_of("shell").expect("require shell");
+            print_completion(make_app(), shell)
+        }
+        ("status", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            Client::new(json).status(sock_path, m.value_of("group"))
+        }
+        ("top", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let interval: u64 = m.value_of("interval").expect("require interval").parse()?;
+            let iterations: Option<u64> = match m.value_of("iterations") {
+                Some(n) => Some(n.parse()?),
+                None => None,
+            };
+            Client::new(json).top(sock_path, interval, iterations)
+        }
+        ("reload", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            Client::new(json).reload(sock_path)
+        }
+        ("apply", Some(m)) => {
             let sock_path = m
                 .value_of("socket-path")
                 .expect("require control socket path");
             let path = m.value_of("config").expect("require config path");
-            let mut config = { parse_config(path)? };
-            config.control_sock = sock_path.to_owned();
-            Daemon::new(config).run()
+            Client::new(json).apply(
+                sock_path,
+                path,
+                m.value_of("profile"),
+                m.is_present("dry-run"),
+            )
         }
-        ("list", Some(m)) => {
+        ("ping", Some(m)) => {
             let sock_path = m
                 .value_of("socket-path")
                 .expect("require control socket path");
-            Client::new().list(sock_path)
+            let timeout: u64 = m.value_of("timeout").expect("require timeout").parse()?;
+            Client::new(json).ping(sock_path, timeout)
         }
-        ("status", Some(m)) => {
+        ("shutdown", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let timeout: u64 = m.value_of("timeout").expect("require timeout").parse()?;
+            Client::new(json).shutdown(sock_path, timeout)
+        }
+        ("upexec", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            Client::new(json).upexec(sock_path, m.value_of("binary"))
+        }
+        ("start", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            match m.value_of("group") {
+                Some(group) => Client::new(json).group_command(
+                    sock_path,
+                    CommandType::StartWorker,
+                    group,
+                    None,
+                ),
+                None => {
+                    let name = m.value_of("name").expect("require worker name");
+                    Client::new(json).start_worker(sock_path, name)
+                }
+            }
+        }
+        ("stop", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let timeout: u64 = m.value_of("timeout").expect("require timeout").parse()?;
+            match m.value_of("group") {
+                Some(group) => Client::new(json).group_command(
+                    sock_path,
+                    CommandType::StopWorker,
+                    group,
+                    Some(timeout),
+                ),
+                None => {
+                    let name = m.value_of("name").expect("require worker name");
+                    Client::new(json).stop_worker(sock_path, name, timeout)
+                }
+            }
+        }
+        ("wait", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let state = m.value_of("state").expect("require state");
+            let timeout: u64 = m.value_of("timeout").expect("require timeout").parse()?;
+            Client::new(json).wait(sock_path, name, state, timeout)
+        }
+        ("drain", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let timeout: u64 = m.value_of("timeout").expect("require timeout").parse()?;
+            Client::new(json).drain_worker(sock_path, name, timeout, m.value_of("signal"))
+        }
+        ("restart", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let timeout: u64 = m.value_of("timeout").expect("require timeout").parse()?;
+            match m.value_of("group") {
+                Some(group) => Client::new(json).group_command(
+                    sock_path,
+                    CommandType::RestartWorker,
+                    group,
+                    Some(timeout),
+                ),
+                None => {
+                    let name = m.value_of("name").expect("require worker name");
+                    if m.is_present("rolling") {
+                        Client::new(json).rolling_restart(sock_path, name)
+                    } else {
+                        Client::new(json).restart_worker(sock_path, name, timeout)
+                    }
+                }
+            }
+        }
+        ("scale", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let count: u64 = m.value_of("count").expect("require count").parse()?;
+            Client::new(json).scale(sock_path, name, count)
+        }
+        ("signal", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let signal = m.value_of("signal").expect("require signal");
+            Client::new(json).signal(sock_path, name, signal)
+        }
+        ("upgrade", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let args: Option<Vec<String>> = m
+                .values_of("args")
+                .map(|args| args.map(|s| s.to_owned()).collect());
+            Client::new(json).upgrade(
+                sock_path,
+                name,
+                m.value_of("signal"),
+                m.value_of("binary"),
+                args,
+            )
+        }
+        ("rollback", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            Client::new(json).rollback_worker(sock_path, name, m.value_of("signal"))
+        }
+        ("crashes", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            Client::new(json).crashes(sock_path, name)
+        }
+        ("logs", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let lines: u64 = m.value_of("lines").expect("require lines").parse()?;
+            Client::new(json).logs(sock_path, name, lines, m.is_present("follow"))
+        }
+        ("attach", Some(m)) => {
             let sock_path = m
                 .value_of("socket-path")
                 .expect("require control socket path");
-            Client::new().status(sock_path)
+            let name = m.value_of("name").expect("require worker name");
+            Client::new(json).attach(sock_path, name)
+        }
+        ("exec", Some(m)) => {
+            let sock_path = m
+                .value_of("socket-path")
+                .expect("require control socket path");
+            let name = m.value_of("name").expect("require worker name");
+            let args: Vec<String> = m
+                .values_of("command")
+                .expect("require command")
+                .map(|s| s.to_owned())
+                .collect();
+            let code = Client::new(json).exec(sock_path, name, &args)?;
+            if code != 0 {
+                process::exit(code);
+            }
+            Ok(())
+        }
+        ("audit", Some(m)) => {
+            let path = match m.value_of("file") {
+                Some(path) => path.to_owned(),
+                None => {
+                    let path = m.value_of("config").expect("require config path");
+                    let config = Config::from_file(path, None)?;
+                    config
+                        .audit_log
+                        .ok_or_else(|| err_msg(format!("{} has no audit_log", path)))?
+                }
+            };
+            let lines: usize = m.value_of("lines").expect("require lines").parse()?;
+            audit::show(path::Path::new(&path), m.value_of("worker"), lines, json)
         }
         ("ctrl", Some(m)) => {
             let sock_path = m
@@ -143,7 +1184,11 @@ pub fn execute() -> Result<(), Error> {
             let name = m.value_of("name").expect("require worker name");
             let command = m.value_of("command").expect("require command");
             let signal = m.value_of("signal");
-            Client::new().run(sock_path, name, command, signal)
+            Client::new(json).run(sock_path, name, command, signal)
+        }
+        ("monitor", Some(m)) => {
+            let spec_fd = m.value_of("spec-fd").expect("require spec fd").parse()?;
+            MonitorProcess::run(spec_fd)
         }
         _ => Ok(()),
     }
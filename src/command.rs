@@ -1,15 +1,21 @@
-use std::io::{BufRead, BufReader, Write};
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::ops::Add;
-use std::os::unix::net::UnixStream;
 use std::str::FromStr;
 use std::string::ToString;
-use std::{io, path};
+use std::{fmt, io, path, time};
 
+use chrono::{Duration, LocalResult, TimeZone, Utc};
 use failure::{err_msg, Error};
+use libc;
 use nix::unistd::getpid;
+use serde::Serialize;
 use serde_json;
 
+use auth::Secret;
 use signal::Signal;
+use sock::{connect_unix, unix_sock_exists, CtrlStream};
+use utils::format_duration;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum Command {
@@ -25,12 +31,50 @@ pub enum Command {
     Upgrade, // upgrade swap process
     #[serde(rename = "worker:ack")]
     Ack,
+    #[serde(rename = "worker:heartbeat")]
+    Heartbeat,
     #[serde(rename = "worker:inc")]
     Inc,
     #[serde(rename = "worker:dec")]
     Dec,
+    #[serde(rename = "worker:scale")]
+    Scale,
+    #[serde(rename = "worker:signal")]
+    Signal,
+    #[serde(rename = "worker:logs")]
+    Logs,
+    #[serde(rename = "worker:crashes")]
+    Crashes,
+    #[serde(rename = "worker:attach")]
+    Attach,
+    #[serde(rename = "worker:exec")]
+    Exec,
+    #[serde(rename = "worker:wait")]
+    Wait,
+    #[serde(rename = "worker:drain")]
+    Drain,
+    #[serde(rename = "worker:rollback")]
+    Rollback,
+    #[serde(rename = "worker:rolling_restart")]
+    RollingRestart,
+    #[serde(rename = "worker:pause")]
+    Pause,
+    #[serde(rename = "worker:resume")]
+    Resume,
     #[serde(rename = "worker:status")]
     Status,
+    #[serde(rename = "worker:reopen")]
+    ReopenLogs,
+    #[serde(rename = "worker:metrics")]
+    Metrics,
+    #[serde(rename = "daemon:reload")]
+    Reload,
+    #[serde(rename = "daemon:apply")]
+    Apply,
+    #[serde(rename = "daemon:shutdown")]
+    Shutdown,
+    #[serde(rename = "daemon:upexec")]
+    Upexec,
 }
 
 // Use from client
@@ -45,7 +89,17 @@ impl FromStr for Command {
             "stop" => Ok(Command::Stop),
             "inc" => Ok(Command::Inc),
             "dec" => Ok(Command::Dec),
+            "scale" => Ok(Command::Scale),
+            "signal" => Ok(Command::Signal),
+            "logs" => Ok(Command::Logs),
+            "crashes" => Ok(Command::Crashes),
+            "attach" => Ok(Command::Attach),
+            "exec" => Ok(Command::Exec),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
             "status" => Ok(Command::Status),
+            "reopen" => Ok(Command::ReopenLogs),
+            "rolling-restart" => Ok(Command::RollingRestart),
             _ => Err(err_msg(format!("{} not support.", s))),
         }
     }
@@ -57,6 +111,62 @@ pub struct DaemonCommand {
     pub worker: Option<String>,
     pub command: Option<CtrlCommand>,
     pub pid: u32,
+    // seconds to wait for the workers on shutdown, stop_worker or restart_worker
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    // the firestarter binary to re-exec on upexec
+    #[serde(default)]
+    pub binary: Option<String>,
+    // the config to apply on apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigSource>,
+    // required over tcp when the daemon has a token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret>,
+}
+
+impl DaemonCommand {
+    /// A command without a worker. The other fields are set with the struct update syntax.
+    pub fn new(command_type: CommandType, pid: u32) -> Self {
+        DaemonCommand {
+            command_type,
+            worker: None,
+            command: None,
+            pid,
+            timeout: None,
+            binary: None,
+            config: None,
+            token: None,
+        }
+    }
+}
+
+/// A whole config sent by `apply` in place of the config file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConfigSource {
+    // toml or yaml
+    pub format: String,
+    pub content: String,
+    // the profile of the running config when not set
+    #[serde(default)]
+    pub profile: Option<String>,
+    // reports the changes without applying them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// the content is not logged, it may hold the secrets of the workers
+impl fmt::Debug for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ConfigSource {{ format: {:?}, content: ({} bytes), profile: {:?}, dry_run: {} }}",
+            self.format,
+            self.content.len(),
+            self.profile,
+            self.dry_run
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -67,6 +177,26 @@ pub enum CommandType {
     Status,
     #[serde(rename = "ctrl_worker")]
     CtrlWorker,
+    #[serde(rename = "reload")]
+    Reload,
+    #[serde(rename = "apply")]
+    Apply,
+    #[serde(rename = "ping")]
+    Ping,
+    #[serde(rename = "shutdown")]
+    Shutdown,
+    #[serde(rename = "upexec")]
+    Upexec,
+    #[serde(rename = "start_worker")]
+    StartWorker,
+    #[serde(rename = "stop_worker")]
+    StopWorker,
+    #[serde(rename = "restart_worker")]
+    RestartWorker,
+    #[serde(rename = "drain_worker")]
+    DrainWorker,
+    #[serde(rename = "rollback_worker")]
+    RollbackWorker,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -74,6 +204,42 @@ pub struct CtrlCommand {
     pub command: Command,
     pub pid: u32,
     pub signal: Option<Signal>,
+    // W3C trace context of the client
+    #[serde(default)]
+    pub traceparent: Option<String>,
+    // the number of processes for worker:scale, the number of lines for worker:logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    // worker:logs returns the lines after this seq. set by the polls of `logs -f`.
+    // worker:wait upgraded waits for an upgrade after this count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    // the command line of worker:exec, or the arguments of worker:upgrade
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<String>>,
+    // the program of worker:upgrade in place of the configured one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+    // seconds worker:wait holds the connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+impl CtrlCommand {
+    /// A command without options. The other fields are set with the struct update syntax.
+    pub fn new(command: Command, pid: u32) -> Self {
+        CtrlCommand {
+            command,
+            pid,
+            signal: None,
+            traceparent: None,
+            count: None,
+            since: None,
+            args: None,
+            binary: None,
+            timeout: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -82,6 +248,9 @@ pub enum Status {
     Ok,
     #[serde(rename = "error")]
     Error,
+    // rejected by the connection cap or the rate limit. retry later
+    #[serde(rename = "busy")]
+    Busy,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -94,6 +263,11 @@ pub struct CommandResponse {
 
 impl ToString for CommandResponse {
     fn to_string(&self) -> String {
+        if let Command::Status = self.command {
+            if let Ok(status) = serde_json::from_str::<WorkerStatus>(&self.message) {
+                return status.to_string();
+            }
+        }
         let buf = String::new();
         // buf = buf.add(&format!("status  {:?}\n", self.status));
         // buf.add(&format!("{}", self.message))
@@ -106,26 +280,445 @@ impl ToString for CommandResponse {
 pub struct ListResponse {
     pub pid: u32,
     pub workers: Vec<String>,
+    // workers stopped by the pause command
+    #[serde(default)]
+    pub paused: Vec<String>,
+    // the workers of each group
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
 }
 
 impl ToString for ListResponse {
     fn to_string(&self) -> String {
         let mut buf = String::new();
         for w in &self.workers {
-            buf = buf.add(&format!("{}\n", w));
+            if self.paused.contains(w) {
+                buf = buf.add(&format!("{} (paused)\n", w));
+            } else {
+                buf = buf.add(&format!("{}\n", w));
+            }
         }
         buf
         // buf.add(&format!("\nresponse from pid [{}]\n", self.pid))
     }
 }
 
-pub fn read_daemon_command(stream: &mut UnixStream) -> io::Result<DaemonCommand> {
+/// The health of the daemon. The main loop answers it, so a stalled loop does not answer.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PingResponse {
+    pub pid: u32,
+    pub version: String,
+    pub protocol_version: u8,
+    // seconds since the daemon started or re-executed
+    pub uptime: u64,
+    pub monitors: usize,
+    pub workers: usize,
+    // microseconds from the receipt of the ping to the main loop
+    pub latency: u64,
+}
+
+impl fmt::Display for PingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pong. pid [{}] version {} protocol {} uptime {} monitors {}/{} latency {}us",
+            self.pid,
+            self.version,
+            self.protocol_version,
+            format_duration(&Duration::seconds(self.uptime as i64)),
+            self.monitors,
+            self.workers,
+            self.latency
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RunState {
+    #[serde(rename = "running")]
+    Running,
+    // fewer processes than the number to run. they are being respawned
+    #[serde(rename = "restarting")]
+    Restarting,
+    // the processes are up, but some have not sent the ack yet
+    #[serde(rename = "starting")]
+    Starting,
+    #[serde(rename = "paused")]
+    Paused,
+    // waiting for the connections to close before the stop
+    #[serde(rename = "draining")]
+    Draining,
+    #[serde(rename = "stopped")]
+    Stopped,
+    // the processes exited and the restart policy does not respawn them
+    #[serde(rename = "exited")]
+    Exited,
+    // the processes of a oneshot worker exited successfully
+    #[serde(rename = "completed")]
+    Completed,
+    // a process of a oneshot worker exited with an error and is not restarted
+    #[serde(rename = "failed")]
+    Failed,
+    // waiting for the next run of the schedule
+    #[serde(rename = "scheduled")]
+    Scheduled,
+    // the monitor gave up respawning the processes and exited
+    #[serde(rename = "gave-up")]
+    GaveUp,
+}
+
+impl fmt::Display for RunState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| s.to_owned()))
+            .unwrap_or_default();
+        f.pad(&name)
+    }
+}
+
+/// The message of `worker:drain`. The daemon polls it until the connections are closed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DrainStatus {
+    pub connections: usize,
+    pub processes: usize,
+}
+
+/// Resource usage of a process. `top` computes the CPU usage from two samples.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessUsage {
+    pub pid: u32,
+    // user and system time in milliseconds
+    pub cpu_time: u64,
+    // resident set size in bytes
+    pub rss: u64,
+    // open file descriptors
+    #[serde(default)]
+    pub fds: u64,
+}
+
+/// A process exited abnormally, with the last lines of its stderr. The message of
+/// `worker:crashes` is the list of the recent ones, the oldest first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CrashReport {
+    pub pid: u32,
+    // unix time in seconds of the exit
+    pub time: i64,
+    pub exit_code: i32,
+    // the signal that killed the process
+    pub signal: Option<i32>,
+    pub stderr: Vec<String>,
+    // the core file dumped with core_dump
+    #[serde(default)]
+    pub core: Option<String>,
+}
+
+impl ToString for CrashReport {
+    fn to_string(&self) -> String {
+        let mut buf = match self.signal {
+            Some(signal) => format!(
+                "{} pid [{}] killed by signal {}",
+                format_time(Some(self.time)),
+                self.pid,
+                signal
+            ),
+            None => format!(
+                "{} pid [{}] exited {}",
+                format_time(Some(self.time)),
+                self.pid,
+                self.exit_code
+            ),
+        };
+        if let Some(ref core) = self.core {
+            buf = buf.add(&format!(" (core dumped to {})", core));
+        }
+        for line in &self.stderr {
+            buf = buf.add(&format!("\n  {}", line));
+        }
+        buf
+    }
+}
+
+/// The message of `worker:status`. Workers without a monitor are answered by the daemon.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub state: RunState,
+    pub pids: Vec<u32>,
+    pub num_processes: u64,
+    // unix time in seconds
+    pub start_time: Option<i64>,
+    pub uptime: i64,
+    pub restarts: u64,
+    pub last_exit_code: Option<i32>,
+    pub binary: Option<String>,
+    // unix time in seconds of the binary
+    pub binary_mtime: Option<i64>,
+    // the binary before the last upgrade. rollback runs it again
+    #[serde(default)]
+    pub previous_binary: Option<String>,
+    // the binary was replaced after the processes were spawned. an upgrade runs the new one
+    #[serde(default)]
+    pub outdated: bool,
+    #[serde(default)]
+    pub usage: Vec<ProcessUsage>,
+    // unix time in seconds of the last and the next run of the schedule
+    #[serde(default)]
+    pub last_run: Option<i64>,
+    #[serde(default)]
+    pub next_run: Option<i64>,
+}
+
+impl WorkerStatus {
+    pub fn down(name: &str, state: RunState) -> WorkerStatus {
+        WorkerStatus {
+            name: name.to_owned(),
+            state,
+            pids: Vec::new(),
+            num_processes: 0,
+            start_time: None,
+            uptime: 0,
+            restarts: 0,
+            last_exit_code: None,
+            binary: None,
+            binary_mtime: None,
+            previous_binary: None,
+            outdated: false,
+            usage: Vec::new(),
+            last_run: None,
+            next_run: None,
+        }
+    }
+
+    /// Whether the worker is in a state of `wait`. `upgraded` is a running worker here,
+    /// and the monitor checks that an upgrade has completed.
+    pub fn reached(&self, state: &str) -> bool {
+        match state {
+            "running" | "upgraded" => self.state == RunState::Running,
+            "stopped" => match self.state {
+                RunState::Stopped | RunState::Exited | RunState::Completed | RunState::Failed => {
+                    true
+                }
+                _ => false,
+            },
+            "completed" => self.state == RunState::Completed,
+            _ => false,
+        }
+    }
+
+    /// The reason the worker does not reach a state of `wait` any more.
+    pub fn unreachable(&self, state: &str) -> Option<String> {
+        match self.state {
+            RunState::GaveUp => Some(format!("[{}] gave up", self.name)),
+            RunState::Failed if state == "completed" => Some(format!("[{}] failed", self.name)),
+            _ => None,
+        }
+    }
+}
+
+/// The states `wait` waits for.
+pub const WAIT_STATES: [&str; 4] = ["running", "stopped", "completed", "upgraded"];
+
+fn format_time(secs: Option<i64>) -> String {
+    match secs.map(|secs| Utc.timestamp_opt(secs, 0)) {
+        Some(LocalResult::Single(t)) => t.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        _ => "-".to_owned(),
+    }
+}
+
+impl ToString for WorkerStatus {
+    fn to_string(&self) -> String {
+        let mut buf = format!("[{}] {}", self.name, self.state);
+        // answered by the daemon. there is no monitor to tell more
+        if self.binary.is_none() {
+            return buf;
+        }
+        buf = buf.add(&format!(
+            "\n  pids       {:?} of {}",
+            self.pids, self.num_processes
+        ));
+        buf = buf.add(&format!(
+            "\n  started    {} (up {})",
+            format_time(self.start_time),
+            format_duration(&Duration::seconds(self.uptime))
+        ));
+        buf = buf.add(&format!("\n  restarts   {}", self.restarts));
+        if !self.usage.is_empty() {
+            let fds: u64 = self.usage.iter().map(|u| u.fds).sum();
+            buf = buf.add(&format!("\n  open fds   {}", fds));
+        }
+        if self.next_run.is_some() {
+            buf = buf.add(&format!(
+                "\n  last run   {}\n  next run   {}",
+                format_time(self.last_run),
+                format_time(self.next_run)
+            ));
+        }
+        buf = buf.add(&format!(
+            "\n  last exit  {}",
+            self.last_exit_code
+                .map_or_else(|| "-".to_owned(), |code| code.to_string())
+        ));
+        buf = buf.add(&format!(
+            "\n  binary     {} (modified {}{})",
+            self.binary.as_ref().map(|b| b.as_str()).unwrap_or("-"),
+            format_time(self.binary_mtime),
+            if self.outdated { ", not upgraded" } else { "" }
+        ));
+        match self.previous_binary {
+            Some(ref previous) => buf.add(&format!("\n  previous   {}", previous)),
+            None => buf,
+        }
+    }
+}
+
+/// Version of the control protocol. A frame of another version is rejected.
+pub const PROTOCOL_VERSION: u8 = 1;
+const FRAME_MAGIC: [u8; 2] = *b"FS";
+const FRAME_HEADER_SIZE: usize = 8;
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// The type of the JSON payload of a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    DaemonCommand = 1,
+    CtrlCommand = 2,
+    Response = 3,
+    Error = 4,
+}
+
+impl MessageType {
+    fn from_u8(ty: u8) -> Option<MessageType> {
+        match ty {
+            1 => Some(MessageType::DaemonCommand),
+            2 => Some(MessageType::CtrlCommand),
+            3 => Some(MessageType::Response),
+            4 => Some(MessageType::Error),
+            _ => None,
+        }
+    }
+}
+
+enum Frame {
+    Framed(MessageType, Vec<u8>),
+    // a line of JSON sent by a client of the old protocol
+    Legacy(Vec<u8>),
+}
+
+fn invalid_data<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Writes a frame. The header is `FS`, the protocol version, the message type and
+/// the big endian u32 length of the JSON payload.
+pub fn write_frame<W: Write, T: Serialize>(
+    stream: &mut W,
+    ty: MessageType,
+    msg: &T,
+) -> io::Result<()> {
+    let payload = serde_json::to_vec(msg)?;
+    let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&FRAME_MAGIC);
+    buf.push(PROTOCOL_VERSION);
+    buf.push(ty as u8);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&payload);
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+fn read_frame<R: Read>(stream: &mut R) -> io::Result<Frame> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    stream.read_exact(&mut header[..1])?;
+    // a line of JSON, or `ready` from a worker
+    if header[0] == b'{' || header[0].is_ascii_lowercase() {
+        let mut line = vec![header[0]];
+        let mut byte = [0u8; 1];
+        while stream.read(&mut byte)? > 0 && byte[0] != b'\n' {
+            if line.len() >= MAX_FRAME_SIZE {
+                return Err(invalid_data("message too large"));
+            }
+            line.push(byte[0]);
+        }
+        return Ok(Frame::Legacy(line));
+    }
+    stream.read_exact(&mut header[1..])?;
+    if header[..2] != FRAME_MAGIC {
+        return Err(invalid_data("not a control protocol frame"));
+    }
+    if header[2] != PROTOCOL_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported protocol version {}. expected {}",
+            header[2], PROTOCOL_VERSION
+        )));
+    }
+    let ty = MessageType::from_u8(header[3])
+        .ok_or_else(|| invalid_data(format!("unknown message type {}", header[3])))?;
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data("message too large"));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(Frame::Framed(ty, payload))
+}
+
+/// Reads a frame of the expected type. A protocol error is sent back to the peer.
+fn read_request<S: Read + Write>(stream: &mut S, expected: MessageType) -> io::Result<Frame> {
+    let res = match read_frame(stream) {
+        Ok(Frame::Framed(ty, _)) if ty != expected => {
+            Err(invalid_data(format!("unexpected message {:?}", ty)))
+        }
+        res => res,
+    };
+    if let Err(ref e) = res {
+        if e.kind() == io::ErrorKind::InvalidData {
+            let _ = write_frame(stream, MessageType::Error, &e.to_string());
+        }
+    }
+    res
+}
+
+/// Reads a response. An error frame is returned as an error.
+pub fn read_response<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    match read_frame(stream)? {
+        Frame::Framed(MessageType::Response, payload) => Ok(payload),
+        Frame::Framed(MessageType::Error, payload) => {
+            let msg: String = serde_json::from_slice(&payload)?;
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+        _ => Err(invalid_data("unexpected response")),
+    }
+}
+
+pub fn read_daemon_command<S: Read + Write>(stream: &mut S) -> io::Result<DaemonCommand> {
     let pid = getpid();
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
-    let _len = reader.read_line(&mut line)?;
-    debug!("receive daemon command {:?}. pid [{}]", line, pid);
-    match serde_json::from_str(&line) {
+    let payload = match read_request(stream, MessageType::DaemonCommand)? {
+        Frame::Framed(_, payload) => payload,
+        Frame::Legacy(_) => {
+            // a client of the old protocol reads a line of CommandResponse
+            let res = CommandResponse {
+                status: Status::Error,
+                command: Command::None,
+                pid: libc::pid_t::from(pid) as u32,
+                message: format!(
+                    "unsupported protocol. the daemon requires protocol version {}",
+                    PROTOCOL_VERSION
+                ),
+            };
+            let buf = serde_json::to_string(&res)?;
+            stream.write_all(buf.as_bytes())?;
+            stream.write_all(b"\n")?;
+            stream.flush()?;
+            return Err(invalid_data("unsupported protocol"));
+        }
+    };
+    debug!(
+        "receive daemon command {:?}. pid [{}]",
+        String::from_utf8_lossy(&payload),
+        pid
+    );
+    match serde_json::from_slice(&payload) {
         Err(e) => {
             warn!("ctrl socket error. caused by: {}. pid [{}]", e, pid);
             Err(io::Error::new(io::ErrorKind::InvalidInput, e))
@@ -137,13 +730,30 @@ pub fn read_daemon_command(stream: &mut UnixStream) -> io::Result<DaemonCommand>
     }
 }
 
-pub fn read_command(stream: &UnixStream) -> io::Result<CtrlCommand> {
+/// Reads a command sent to the monitor. Line delimited JSON is also accepted,
+/// since worker processes send `worker:ack` in that form.
+pub fn read_command<S: Read + Write>(stream: &mut S) -> io::Result<CtrlCommand> {
     let pid = getpid();
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
-    let _len = reader.read_line(&mut line)?;
-    debug!("receive command {:?}. pid [{}]", line, pid);
-    match serde_json::from_str(&line) {
+    let payload = match read_request(stream, MessageType::CtrlCommand)? {
+        Frame::Framed(_, payload) => payload,
+        Frame::Legacy(line) => line,
+    };
+    debug!(
+        "receive command {:?}. pid [{}]",
+        String::from_utf8_lossy(&payload),
+        pid
+    );
+    // the ack and the heartbeat of a worker that does not speak JSON.
+    // the monitor takes the pid of the peer
+    let plain = match String::from_utf8_lossy(&payload).trim() {
+        "ready" => Some(Command::Ack),
+        "heartbeat" => Some(Command::Heartbeat),
+        _ => None,
+    };
+    if let Some(command) = plain {
+        return Ok(CtrlCommand::new(command, 0));
+    }
+    match serde_json::from_slice(&payload) {
         Err(e) => {
             warn!("fail deserialize command. caused by: {}. pid [{}]", e, pid);
             Err(io::Error::new(io::ErrorKind::InvalidInput, e))
@@ -156,21 +766,31 @@ pub fn read_command(stream: &UnixStream) -> io::Result<CtrlCommand> {
 }
 
 pub fn send_ctrl_command(sock_path: &str, cmd: &CtrlCommand) -> io::Result<CommandResponse> {
-    if path::Path::new(sock_path).exists() {
+    send_ctrl_command_timeout(sock_path, cmd, None)
+}
+
+/// Fails with a timeout when the monitor does not answer within `timeout`.
+pub fn send_ctrl_command_timeout(
+    sock_path: &str,
+    cmd: &CtrlCommand,
+    timeout: Option<time::Duration>,
+) -> io::Result<CommandResponse> {
+    if unix_sock_exists(path::Path::new(sock_path)) {
         let pid = getpid();
         debug!("send command to {}. pid [{}]", sock_path, pid);
-        let buf = serde_json::to_string(cmd)?;
-        let mut stream = UnixStream::connect(sock_path)?;
-        stream.write_all(buf.as_bytes())?;
-        stream.write_all(b"\n")?;
-        stream.flush()?;
+        let mut stream = connect_unix(sock_path)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        write_frame(&mut stream, MessageType::CtrlCommand, cmd)?;
         debug!("sended ctrl command {:?}. pid [{}]", cmd, pid);
-        let mut reader = BufReader::new(&stream);
-        let mut line = String::new();
         debug!("wait receive command response. pid [{}]", pid);
-        let _len = reader.read_line(&mut line)?;
-        debug!("received response {}. pid [{}]", line, pid);
-        let res = serde_json::from_str(&line)?;
+        let payload = read_response(&mut stream)?;
+        debug!(
+            "received response {}. pid [{}]",
+            String::from_utf8_lossy(&payload),
+            pid
+        );
+        let res = serde_json::from_slice(&payload)?;
         Ok(res)
     } else {
         Err(io::Error::new(
@@ -180,33 +800,49 @@ pub fn send_ctrl_command(sock_path: &str, cmd: &CtrlCommand) -> io::Result<Comma
     }
 }
 
-pub fn send_daemon_command(sock_path: &str, cmd: &DaemonCommand) -> io::Result<Box<ToString>> {
-    if !path::Path::new(sock_path).exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "require sock path",
-        ));
-    }
+/// Returns the JSON payload of the response.
+pub fn send_daemon_request(sock_path: &str, cmd: &DaemonCommand) -> io::Result<Vec<u8>> {
     let pid = getpid();
     debug!("send command to {}. cmd {:?} pid [{}]", sock_path, cmd, pid);
-    let buf = serde_json::to_string(cmd)?;
-    let mut stream = UnixStream::connect(sock_path)?;
-    stream.write_all(buf.as_bytes())?;
-    stream.write_all(b"\n")?;
-    stream.flush()?;
+    let mut stream = CtrlStream::connect(sock_path)?;
+    let payload = match write_frame(&mut stream, MessageType::DaemonCommand, cmd) {
+        Ok(_) => {
+            debug!(
+                "sended command to {}. cmd {:?} pid [{}]",
+                sock_path, cmd, pid
+            );
+            debug!("wait receive command response. pid [{}]", pid);
+            read_response(&mut stream)?
+        }
+        // the daemon answers busy and closes a connection over the cap without
+        // reading the command
+        Err(e) => read_response(&mut stream).map_err(|_| e)?,
+    };
     debug!(
-        "sended command to {}. cmd {:?} pid [{}]",
-        sock_path, cmd, pid
+        "received response {}. pid [{}]",
+        String::from_utf8_lossy(&payload),
+        pid
     );
-    let mut reader = BufReader::new(&stream);
-    let mut line = String::new();
-    debug!("wait receive command response. pid [{}]", pid);
-    let _len = reader.read_line(&mut line)?;
-    debug!("received response {}. pid [{}]", line, pid);
-    if let Ok(res @ ListResponse { .. }) = serde_json::from_str(&line) {
+    if let Ok(CommandResponse {
+        status: Status::Busy,
+        message,
+        ..
+    }) = serde_json::from_slice(&payload)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("daemon busy. {}", message),
+        ));
+    }
+    Ok(payload)
+}
+
+pub fn send_daemon_command(sock_path: &str, cmd: &DaemonCommand) -> io::Result<Box<ToString>> {
+    let payload = send_daemon_request(sock_path, cmd)?;
+    if let Ok(res @ ListResponse { .. }) = serde_json::from_slice(&payload) {
         Ok(Box::new(res))
     } else {
-        match serde_json::from_str(&line)? {
+        match serde_json::from_slice(&payload)? {
             res @ CommandResponse { .. } => Ok(Box::new(res)),
         }
     }
@@ -216,30 +852,8 @@ pub fn send_daemon_list_command(
     sock_path: &str,
     cmd: &DaemonCommand,
 ) -> io::Result<Vec<Box<ToString>>> {
-    if !path::Path::new(sock_path).exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "require sock path",
-        ));
-    }
-    let pid = getpid();
-    debug!("send command to {}. cmd {:?} pid [{}]", sock_path, cmd, pid);
-    let buf = serde_json::to_string(cmd)?;
-    let mut stream = UnixStream::connect(sock_path)?;
-    stream.write_all(buf.as_bytes())?;
-    stream.write_all(b"\n")?;
-    stream.flush()?;
-    debug!(
-        "sended command to {}. cmd {:?} pid [{}]",
-        sock_path, cmd, pid
-    );
-
-    let mut reader = BufReader::new(&stream);
-    let mut line = String::new();
-    debug!("wait receive command response. pid [{}]", pid);
-    let _len = reader.read_line(&mut line)?;
-    debug!("received response {}. pid [{}]", line, pid);
-    let response: Vec<CommandResponse> = serde_json::from_str(&line)?;
+    let payload = send_daemon_request(sock_path, cmd)?;
+    let response: Vec<CommandResponse> = serde_json::from_slice(&payload)?;
     let mut result: Vec<Box<ToString>> = Vec::new();
     for res in response {
         result.push(Box::new(res));
@@ -247,10 +861,6 @@ pub fn send_daemon_list_command(
     Ok(result)
 }
 
-pub fn send_response(stream: &mut UnixStream, res: &CommandResponse) -> io::Result<()> {
-    let buf = serde_json::to_string(res)?;
-    stream.write_all(buf.as_bytes())?;
-    stream.write_all(b"\n")?;
-    stream.flush()?;
-    Ok(())
+pub fn send_response<W: Write, T: Serialize>(stream: &mut W, res: &T) -> io::Result<()> {
+    write_frame(stream, MessageType::Response, res)
 }
@@ -0,0 +1,121 @@
+use std::io::{self, Write};
+
+use clap::{App, Shell};
+use failure::{err_msg, Error};
+
+use app::APP_NAME;
+
+// completes the names of the workers of the daemon at `-d`, or at the default socket path
+const BASH_WORKERS: &str = r#"
+_firestarter_workers() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    local i word cmd="" positional=0 sock=()
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${COMP_WORDS[i]}"
+        case "${word}" in
+            -d|--socket-path)
+                sock=(--socket-path "${COMP_WORDS[i+1]}")
+                i=$((i + 1))
+                ;;
+            -t|--timeout|-s|--signal|-n|--lines|-b|--binary|-w|--worker|-c|--config|-g|--group)
+                i=$((i + 1))
+                ;;
+            -*)
+                ;;
+            *)
+                if [[ -z "${cmd}" ]]; then
+                    cmd="${word}"
+                else
+                    positional=$((positional + 1))
+                fi
+                ;;
+        esac
+    done
+    local names=0
+    case "${cmd}" in
+        start|stop|drain|wait|restart|scale|signal|logs|crashes|attach|exec|upgrade|rollback|ctrl)
+            case "${prev}" in
+                -d|--socket-path|-t|--timeout|-s|--signal|-n|--lines|-b|--binary|-g|--group)
+                    ;;
+                *)
+                    [[ ${positional} -eq 0 && ${cur} != -* ]] && names=1
+                    ;;
+            esac
+            ;;
+        audit)
+            [[ ${prev} == -w || ${prev} == --worker ]] && names=1
+            ;;
+    esac
+    if [[ ${names} -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "$("${COMP_WORDS[0]}" list --quiet "${sock[@]}" 2>/dev/null)" -- "${cur}"))
+        return 0
+    fi
+    _firestarter "$@"
+}
+
+complete -F _firestarter_workers -o bashdefault -o default firestarter
+"#;
+
+const ZSH_WORKERS: &str = r#"_firestarter_workers() {
+    local i=${words[(I)(-d|--socket-path)]}
+    local -a sock workers
+    (( i )) && sock=(--socket-path "${words[i+1]}")
+    workers=(${(f)"$(${words[1]} list --quiet $sock 2>/dev/null)"})
+    _wanted workers expl 'worker' compadd -a workers
+}
+
+"#;
+
+const FISH_WORKERS: &str = r#"
+function __firestarter_workers
+    set -l tokens (commandline -opc)
+    set -l i (contains -i -- -d $tokens; or contains -i -- --socket-path $tokens)
+    set -l sock
+    if test -n "$i"
+        set sock --socket-path $tokens[(math $i + 1)]
+    end
+    $tokens[1] list --quiet $sock 2>/dev/null
+end
+
+complete -c firestarter -n "__fish_seen_subcommand_from start stop drain wait restart scale signal logs crashes attach exec upgrade rollback ctrl; and not __fish_seen_subcommand_from (__firestarter_workers)" -f -a "(__firestarter_workers)"
+complete -c firestarter -n "__fish_seen_subcommand_from audit" -s w -l worker -x -a "(__firestarter_workers)"
+"#;
+
+/// Prints the completion script generated by clap, with the names of the workers completed
+/// by `list --quiet` of the running daemon.
+pub fn print_completion(mut app: App, shell: &str) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut buf = Vec::new();
+    app.gen_completions_to(APP_NAME, shell.parse::<Shell>().map_err(err_msg)?, &mut buf);
+    let script = String::from_utf8_lossy(&buf);
+    match shell {
+        "bash" => {
+            out.write_all(script.as_bytes())?;
+            out.write_all(BASH_WORKERS.as_bytes())?;
+        }
+        "zsh" => {
+            let script = script
+                .replace(
+                    ":name -- set worker name.:_files",
+                    ":name -- set worker name.:_firestarter_workers",
+                )
+                .replace(
+                    "[show only the commands of the worker.]'",
+                    "[show only the commands of the worker.]:WORKER_CONFIG_NAME:_firestarter_workers'",
+                );
+            // define the function before the last line calls _firestarter
+            let last = script.trim_end().rfind('\n').map_or(0, |i| i + 1);
+            out.write_all(script[..last].as_bytes())?;
+            out.write_all(ZSH_WORKERS.as_bytes())?;
+            out.write_all(script[last..].as_bytes())?;
+        }
+        "fish" => {
+            out.write_all(script.as_bytes())?;
+            out.write_all(FISH_WORKERS.as_bytes())?;
+        }
+        _ => return Err(err_msg(format!("unsupported shell {}", shell))),
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -1,39 +1,349 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::{env, io};
+use std::{env, fmt, fs, path, time};
 
-use toml::from_str;
+use failure::{err_msg, Error, Fail};
+use glob::glob;
+use libc;
+use regex::{Captures, Regex};
+use serde_json::{self, Map, Value};
+use serde_path_to_error;
+use serde_yaml;
+use strsim::levenshtein;
+use toml;
 
 use app::{APP_NAME, APP_NAME_UPPER};
+use auth::Secret;
+use cgroup::{check_cpu_max, check_memory_max, DEFAULT_CGROUP_ROOT};
+use cron::Schedule;
+use http::parse_url;
 use logs::RollingLogFile;
+use process::read_env_file;
+use signal::Signal;
+use sock::{ListenFd, SockOptions, SockPermissions};
+use utils::{group_id, parse_size, rlimit_resource, Resource};
+
+lazy_static! {
+    static ref ENV_VAR: Regex =
+        Regex::new(r"\$\{([a-zA-Z_][a-zA-Z0-9_]*)(?::-([^}]*))?\}").unwrap();
+    static ref UNKNOWN_FIELD: Regex = Regex::new(r"unknown field `([^`]*)`, expected (.*)").unwrap();
+    static ref FIELD_NAME: Regex = Regex::new(r"`([^`]*)`").unwrap();
+}
+
+/// A worker config error located in the config file.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub worker: String,
+    pub key: Option<String>,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl ConfigError {
+    fn new(worker: &str, key: Option<&str>, message: String) -> Self {
+        ConfigError {
+            file: None,
+            line: None,
+            worker: worker.to_owned(),
+            key: key.map(|k| k.to_owned()),
+            message,
+            suggestion: None,
+        }
+    }
+
+    fn from_serde(worker: &str, err: &serde_path_to_error::Error<serde_json::Error>) -> Self {
+        let message = err.inner().to_string();
+        if let Some(caps) = UNKNOWN_FIELD.captures(&message) {
+            let key = &caps[1];
+            let suggestion = FIELD_NAME
+                .captures_iter(&caps[2])
+                .map(|c| c[1].to_owned())
+                .map(|c| (levenshtein(key, &c), c))
+                .filter(|&(distance, _)| distance <= 3)
+                .min()
+                .map(|(_, c)| c);
+            let mut e = ConfigError::new(worker, Some(key), "unknown key".to_owned());
+            e.suggestion = suggestion;
+            return e;
+        }
+        let path = err.path().to_string();
+        let key = if path == "." { None } else { Some(path.as_str()) };
+        ConfigError::new(worker, key, message)
+    }
+
+    /// Sets the file and the line of the worker section or the key.
+    fn locate(mut self, file: &path::Path) -> Self {
+        if let Ok(source) = fs::read_to_string(file) {
+            let key = self.key.as_ref().map(|k| k.split('.').next().unwrap_or(k));
+            self.line = find_line(&source, &self.worker, key, is_yaml(file));
+        }
+        self.file = Some(file.display().to_string());
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "[{}]", self.worker)?;
+        if let Some(ref key) = self.key {
+            write!(f, " {}", key)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(ref suggestion) = self.suggestion {
+            write!(f, ". did you mean `{}`?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for ConfigError {}
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    pub path: String,
+    pub profile: Option<String>,
     pub control_sock: String,
+    // control_sock_mode and control_sock_group
+    pub control_sock_permissions: SockPermissions,
+    // concurrent connections to the control socket, http and metrics. 0 is unlimited
+    pub control_max_connections: u64,
+    // commands per second of each peer. 0 is unlimited
+    pub control_rate_limit: u64,
+    pub pidfile: Option<String>,
+    pub state_file: Option<String>,
+    pub audit_log: Option<String>,
+    pub http_address: Option<String>,
+    pub metrics_address: Option<String>,
+    pub statsd: Option<StatsdConfig>,
+    pub otlp: Option<OtlpConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub auth: Option<AuthConfig>,
     pub workers: HashMap<String, WorkerConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// The `[statsd]` table. The monitors send worker lifecycle metrics to it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    pub address: String,
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    // send the worker name as a dogstatsd tag instead of a part of the metric name
+    #[serde(default = "default_bool")]
+    pub tags: bool,
+}
+
+/// The `[otlp]` table. Spans of the supervisor activity are exported to the collector.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    pub endpoint: String,
+    #[serde(default = "default_otlp_service_name")]
+    pub service_name: String,
+}
+
+/// The `[webhook]` table. Lifecycle events are POSTed to the urls.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<WebhookEvent>,
+    // restart_storm is sent when a worker restarts this many times within restart_storm_sec
+    #[serde(default = "default_restart_storm")]
+    pub restart_storm: u64,
+    #[serde(default = "default_restart_storm_sec")]
+    pub restart_storm_sec: u64,
+}
+
+/// The `[auth]` table. Commands on unix control sockets are allowed only from these users,
+/// and commands over tcp and http require the token.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    // matched against the primary gid of the peer
+    #[serde(default)]
+    pub gids: Vec<u32>,
+    pub token: Option<Secret>,
+    // read on start. the file is kept out of the config
+    pub token_file: Option<String>,
+}
+
+/// The `health_check` table of a worker. The monitor runs `cmd`, GETs `url` or connects to
+/// `address` every `interval` seconds, and takes the action after `failure_threshold`
+/// failures in a row.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_vec_str")]
+    pub cmd: Vec<String>,
+    // http:// only
+    pub url: Option<String>,
+    #[serde(default = "default_expected_status")]
+    pub expected_status: u16,
+    // connect to address, the first socket_address by default
+    #[serde(default)]
+    pub tcp: bool,
+    pub address: Option<String>,
+    #[serde(default = "default_health_check_interval")]
+    pub interval: u64,
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u64,
+    #[serde(default = "default_health_action")]
+    pub action: HealthAction,
+    // sent by the signal action
+    pub signal: Option<Signal>,
+}
+
+impl HealthCheckConfig {
+    fn setup(&mut self, first_address: Option<String>) -> Result<(), String> {
+        let kinds = [!self.cmd.is_empty(), self.url.is_some(), self.tcp];
+        match kinds.iter().filter(|kind| **kind).count() {
+            0 => return Err("require cmd, url or tcp".to_owned()),
+            1 => {}
+            _ => return Err("cmd, url and tcp are exclusive".to_owned()),
+        }
+        if let Some(ref url) = self.url {
+            parse_url(url).map_err(|e| e.to_string())?;
+        }
+        if self.tcp {
+            if self.address.is_none() {
+                self.address = first_address;
+            }
+            match self.address.as_ref().map(|address| address.parse()) {
+                Some(Ok(ListenFd::TcpListener(_))) | Some(Ok(ListenFd::UnixListener(_))) => {}
+                Some(Ok(ListenFd::UdpSocket(_))) => {
+                    return Err("udp address is not supported".to_owned())
+                }
+                Some(Err(e)) => return Err(e.to_string()),
+                None => return Err("require address or socket_address".to_owned()),
+            }
+        } else if self.address.is_some() {
+            return Err("address requires tcp".to_owned());
+        }
+        if self.interval == 0 || self.timeout == 0 || self.failure_threshold == 0 {
+            return Err(
+                "interval, timeout and failure_threshold must be greater than 0".to_owned(),
+            );
+        }
+        if self.action == HealthAction::Signal && self.signal.is_none() {
+            return Err("the signal action requires signal".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum HealthAction {
+    // terminate the processes. they are respawned by the restart policy
+    #[serde(rename = "restart")]
+    Restart,
+    #[serde(rename = "signal")]
+    Signal,
+    // only send the unhealthy event
+    #[serde(rename = "alert")]
+    Alert,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LimitAction {
+    // terminate the processes. they are respawned whatever the restart policy
+    #[serde(rename = "restart")]
+    Restart,
+    // only send the event
+    #[serde(rename = "alert")]
+    Alert,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    #[serde(rename = "crash")]
+    Crash,
+    #[serde(rename = "restart_storm")]
+    RestartStorm,
+    #[serde(rename = "giveup")]
+    Giveup,
+    #[serde(rename = "upgrade")]
+    Upgrade,
+    #[serde(rename = "upgrade_failed")]
+    UpgradeFailed,
+    #[serde(rename = "shutdown")]
+    Shutdown,
+    #[serde(rename = "unhealthy")]
+    Unhealthy,
+    #[serde(rename = "memory_limit")]
+    MemoryLimit,
+    #[serde(rename = "cpu_high")]
+    CpuHigh,
+    #[serde(rename = "fd_limit")]
+    FdLimit,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct WorkerConfig {
     #[serde(default = "default_vec_str")]
     pub cmd: Vec<String>,
     #[serde(default = "default_num")]
     pub numprocesses: u64,
+    #[serde(default = "default_kind")]
+    pub kind: WorkerKind,
     #[serde(default = "default_bool")]
     pub start_immediate: bool,
     #[serde(default = "default_vec_str")]
     pub socket_address: Vec<String>,
+    // the options of each socket_address, keyed by the address
+    #[serde(default)]
+    pub socket_options: HashMap<String, SockOptions>,
     #[serde(default = "default_vec_str")]
     pub environments: Vec<String>,
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+    pub env_file: Option<String>,
     #[serde(default = "default_directory")]
     pub working_directory: String,
     #[serde(default = "default_restart")]
     pub restart: RestartStrategy,
+    // the delay before respawning a process exited soon after the spawn. 0 respawns at once
+    #[serde(default = "default_num")]
+    pub restart_backoff_sec: u64,
+    // the delay doubles up to this, and a process running this long resets it
+    #[serde(default = "default_restart_backoff_max_sec")]
+    pub restart_backoff_max_sec: u64,
+    // the monitor gives up after this many respawns within restart_window_sec. 0 is unlimited
+    #[serde(default = "default_zero")]
+    pub max_restarts: u64,
+    #[serde(default = "default_restart_window_sec")]
+    pub restart_window_sec: u64,
+    // the delay before the daemon respawns an exited monitor
+    #[serde(default = "default_restart_delay_sec")]
+    pub restart_delay_sec: f64,
     #[serde(default = "default_ack")]
     pub ack: AckKind,
     #[serde(default = "default_num")]
     pub ack_timeout: u64,
+    // kill the processes not ready in time. 0 disables
+    #[serde(default = "default_zero")]
+    pub start_timeout_sec: u64,
+    #[serde(default = "default_stop_signal")]
+    pub stop_signal: Signal,
+    // SIGKILL the processes alive after the stop signal. 0 disables
+    #[serde(default = "default_kill_after_sec")]
+    pub kill_after_sec: u64,
     #[serde(default = "default_base_name")]
     pub environment_base_name: String,
     #[serde(default = "default_zero")]
@@ -42,13 +352,89 @@ pub struct WorkerConfig {
     pub warmup_delay: u64,
     pub stdout_log: Option<String>,
     pub stderr_log: Option<String>,
+    // the last lines of stdout and stderr kept for the logs command. 0 disables
+    #[serde(default = "default_log_buffer_lines")]
+    pub log_buffer_lines: u64,
+    // the last lines of stderr kept in the report of a crash. 0 keeps none
+    #[serde(default = "default_crash_stderr_lines")]
+    pub crash_stderr_lines: u64,
+    // run the processes on a pseudo terminal for the attach command
+    #[serde(default = "default_bool")]
+    pub pty: bool,
     pub control_socket: Option<String>,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
+    pub chroot: Option<String>,
+    pub umask: Option<String>,
+    #[serde(default)]
+    pub limits: HashMap<String, Limit>,
+    // lift RLIMIT_CORE unless limits has core, and move the cores of crashes to core_dump_dir
+    #[serde(default = "default_bool")]
+    pub core_dump: bool,
+    pub core_dump_dir: Option<String>,
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+    pub nice: Option<i32>,
+    pub ionice_class: Option<IoniceClass>,
+    pub ionice_level: Option<u32>,
+    pub oom_score_adj: Option<i32>,
+    pub cgroup_root: Option<String>,
+    pub memory_max: Option<String>,
+    pub cpu_max: Option<String>,
     #[serde(default = "default_bool")]
     pub auto_upgrade: bool,
     #[serde(default = "default_zero")]
     pub live_check_timeout: u64,
+    #[serde(default = "default_zero")]
+    pub heartbeat_timeout_sec: u64,
+    // restart a process whose resident set with its descendants grows over this size, e.g. 1.5G
+    pub max_memory: Option<String>,
+    // alert when the processes use more cpu than this, 100 being a core, for
+    // cpu_alert_intervals samples in a row. nothing is restarted
+    pub cpu_alert_percent: Option<f64>,
+    #[serde(default = "default_cpu_alert_intervals")]
+    pub cpu_alert_intervals: u64,
+    #[serde(default = "default_cpu_sample_sec")]
+    pub cpu_sample_sec: u64,
+    // the open fds of a process with its descendants over this are alerted, or restarted
+    pub max_fds: Option<u64>,
+    #[serde(default = "default_max_fds_action")]
+    pub max_fds_action: LimitAction,
+    pub health_check: Option<HealthCheckConfig>,
+    // gates the cutover of an upgrade. action and failure_threshold are not used
+    pub readiness_check: Option<HealthCheckConfig>,
+    #[serde(default = "default_readiness_timeout_sec")]
+    pub readiness_timeout_sec: u64,
+    // replace the processes of an upgrade this many at a time. 0 replaces all at once
+    #[serde(default = "default_zero")]
+    pub max_parallel: u64,
+    // replace one process first and watch it this long before the rest. 0 disables
+    #[serde(default = "default_zero")]
+    pub canary_bake_sec: u64,
+    // run the new processes of an upgrade alongside the old ones this long. 0 disables
+    #[serde(default = "default_zero")]
+    pub overlap_sec: u64,
+    // the workers started before this one
+    #[serde(default = "default_vec_str")]
+    pub depends_on: Vec<String>,
+    // wait the workers of depends_on to be running before the start. 0 does not wait
+    #[serde(default = "default_zero")]
+    pub depends_on_timeout_sec: u64,
+    // the groups to target the worker with --group
+    #[serde(default = "default_vec_str")]
+    pub groups: Vec<String>,
+    // run the processes as a oneshot job on the cron expression
+    pub schedule: Option<String>,
+    // a run due while the previous one is still running
+    #[serde(default = "default_schedule_overlap")]
+    pub schedule_overlap: ScheduleOverlap,
+    // the commands run by the monitor around the spawn and the stop of each process
+    pub pre_start: Option<Vec<String>>,
+    pub post_start: Option<Vec<String>>,
+    pub pre_stop: Option<Vec<String>>,
+    pub post_stop: Option<Vec<String>>,
+    #[serde(default = "default_hook_timeout_sec")]
+    pub hook_timeout_sec: u64,
 
     pub upgrader: Option<Vec<String>>,
     #[serde(skip, default = "default_run_upgrader")]
@@ -82,24 +468,133 @@ fn default_ack() -> AckKind {
 fn default_restart() -> RestartStrategy {
     RestartStrategy::None
 }
+fn default_kind() -> WorkerKind {
+    WorkerKind::Service
+}
+fn default_schedule_overlap() -> ScheduleOverlap {
+    ScheduleOverlap::Skip
+}
+fn default_restart_backoff_max_sec() -> u64 {
+    60
+}
+fn default_restart_window_sec() -> u64 {
+    60
+}
+fn default_restart_delay_sec() -> f64 {
+    0.5
+}
+fn default_health_check_interval() -> u64 {
+    10
+}
+fn default_health_check_timeout() -> u64 {
+    5
+}
+fn default_failure_threshold() -> u64 {
+    3
+}
+fn default_stop_signal() -> Signal {
+    Signal::SIGTERM
+}
+fn default_kill_after_sec() -> u64 {
+    10
+}
+fn default_readiness_timeout_sec() -> u64 {
+    60
+}
+fn default_expected_status() -> u16 {
+    200
+}
+fn default_health_action() -> HealthAction {
+    HealthAction::Restart
+}
+fn default_log_buffer_lines() -> u64 {
+    1000
+}
+fn default_crash_stderr_lines() -> u64 {
+    20
+}
 fn default_run_upgrader() -> RunUpgrader {
     RunUpgrader::None
 }
 fn default_upgrader_timeout() -> u64 {
     300
 }
+fn default_hook_timeout_sec() -> u64 {
+    30
+}
+fn default_cpu_alert_intervals() -> u64 {
+    3
+}
+fn default_cpu_sample_sec() -> u64 {
+    10
+}
+fn default_max_fds_action() -> LimitAction {
+    LimitAction::Alert
+}
+fn default_statsd_prefix() -> String {
+    APP_NAME.to_owned()
+}
+fn default_otlp_service_name() -> String {
+    APP_NAME.to_owned()
+}
+fn default_webhook_events() -> Vec<WebhookEvent> {
+    vec![
+        WebhookEvent::Crash,
+        WebhookEvent::RestartStorm,
+        WebhookEvent::Giveup,
+        WebhookEvent::Upgrade,
+        WebhookEvent::UpgradeFailed,
+        WebhookEvent::Shutdown,
+        WebhookEvent::Unhealthy,
+        WebhookEvent::MemoryLimit,
+        WebhookEvent::CpuHigh,
+        WebhookEvent::FdLimit,
+    ]
+}
+fn default_restart_storm() -> u64 {
+    5
+}
+fn default_restart_storm_sec() -> u64 {
+    60
+}
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum RestartStrategy {
+    // the same as never
     #[serde(rename = "none")]
     None,
+    #[serde(rename = "never")]
+    Never,
     #[serde(rename = "always")]
     Always,
     #[serde(rename = "on-failure")]
     OnFailure,
+    // always, except the processes stopped by the stop command
+    #[serde(rename = "unless-stopped")]
+    UnlessStopped,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum WorkerKind {
+    #[serde(rename = "service")]
+    Service,
+    // runs once to the end, such as a migration. start runs it again
+    #[serde(rename = "oneshot")]
+    Oneshot,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ScheduleOverlap {
+    #[serde(rename = "skip")]
+    Skip,
+    // run once more when the previous run exits
+    #[serde(rename = "queue")]
+    Queue,
+    #[serde(rename = "kill-previous")]
+    KillPrevious,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum AckKind {
     #[serde(rename = "timer")]
     Timer,
@@ -109,7 +604,24 @@ pub enum AckKind {
     None,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum IoniceClass {
+    #[serde(rename = "realtime")]
+    Realtime = 1,
+    #[serde(rename = "best-effort")]
+    BestEffort = 2,
+    #[serde(rename = "idle")]
+    Idle = 3,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Limit {
+    Value(u64),
+    Name(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum RunUpgrader {
     #[serde(rename = "none")]
     None,
@@ -123,18 +635,807 @@ pub enum RunUpgrader {
 }
 
 impl RestartStrategy {
-    pub fn need_respawn(self, code: i32) -> bool {
+    /// `stopped` is a process signaled to stop by the monitor.
+    pub fn need_respawn(self, code: i32, stopped: bool) -> bool {
         match self {
-            RestartStrategy::None => false,
+            RestartStrategy::None | RestartStrategy::Never => false,
             RestartStrategy::Always => true,
             RestartStrategy::OnFailure => code != 0,
+            RestartStrategy::UnlessStopped => !stopped,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str, profile: Option<&str>) -> Result<Config, Error> {
+        let config_path = path::Path::new(path);
+        let workers = read_table(config_path)?;
+        Config::from_table(path, workers, Some(config_path), profile)
+    }
+
+    /// Parses a config sent over the control socket instead of reading the config file at `path`.
+    /// `include` is relative to the directory of `path`.
+    pub fn from_source(
+        path: &str,
+        source: &str,
+        format: &str,
+        profile: Option<&str>,
+    ) -> Result<Config, Error> {
+        let workers = parse_table(source, format).map_err(|e| err_msg(format!("config: {}", e)))?;
+        Config::from_table(path, workers, None, profile)
+    }
+
+    /// `source` is the file of the table to locate the errors in.
+    fn from_table(
+        path: &str,
+        mut workers: Map<String, Value>,
+        source: Option<&path::Path>,
+        profile: Option<&str>,
+    ) -> Result<Config, Error> {
+        let config_path = path::Path::new(path);
+        let profiles = workers.remove("profile");
+        let mut defaults = match workers.remove("defaults") {
+            Some(Value::Object(defaults)) => defaults,
+            Some(_) => return Err(err_msg("defaults must be a table")),
+            None => Map::new(),
+        };
+        let templates = workers.remove("templates");
+        let pidfile = match workers.remove("pidfile") {
+            Some(Value::String(pidfile)) => Some(interpolate_env(&pidfile)?),
+            Some(_) => return Err(err_msg("pidfile must be a path string")),
+            None => None,
+        };
+        let state_file = match workers.remove("state_file") {
+            Some(Value::String(path)) => Some(interpolate_env(&path)?),
+            Some(_) => return Err(err_msg("state_file must be a path string")),
+            None => None,
+        };
+        let audit_log = match workers.remove("audit_log") {
+            Some(Value::String(path)) => Some(interpolate_env(&path)?),
+            Some(_) => return Err(err_msg("audit_log must be a path string")),
+            None => None,
+        };
+        let control_sock = match workers.remove("control_sock") {
+            Some(Value::String(addr)) => {
+                let addr = interpolate_env(&addr)?;
+                match addr.parse::<ListenFd>() {
+                    Ok(ListenFd::TcpListener(_)) | Ok(ListenFd::UnixListener(_)) => addr,
+                    Ok(ListenFd::UdpSocket(_)) => {
+                        return Err(err_msg(format!(
+                            "control_sock {}: udp address is not supported",
+                            addr
+                        )))
+                    }
+                    Err(e) => return Err(err_msg(format!("control_sock {}: {}", addr, e))),
+                }
+            }
+            Some(_) => return Err(err_msg("control_sock must be an address string")),
+            None => default_control_sock(),
+        };
+        let mode = match workers.remove("control_sock_mode") {
+            Some(Value::String(mode)) => Some(
+                u32::from_str_radix(&mode, 8)
+                    .map_err(|e| err_msg(format!("control_sock_mode {}: {}", mode, e)))?,
+            ),
+            Some(_) => return Err(err_msg("control_sock_mode must be an octal string")),
+            None => None,
+        };
+        let gid = match workers.remove("control_sock_group") {
+            Some(Value::String(group)) => Some(
+                group_id(&group)
+                    .map_err(|e| err_msg(format!("control_sock_group {}: {}", group, e)))?,
+            ),
+            Some(Value::Number(ref gid)) if gid.is_u64() => gid.as_u64().map(|gid| gid as u32),
+            Some(_) => return Err(err_msg("control_sock_group must be a group name or gid")),
+            None => None,
+        };
+        let control_max_connections = match workers.remove("control_max_connections") {
+            Some(Value::Number(ref n)) if n.is_u64() => n.as_u64().unwrap(),
+            Some(_) => return Err(err_msg("control_max_connections must be a number")),
+            None => default_control_max_connections(),
+        };
+        let control_rate_limit = match workers.remove("control_rate_limit") {
+            Some(Value::Number(ref n)) if n.is_u64() => n.as_u64().unwrap(),
+            Some(_) => return Err(err_msg("control_rate_limit must be a number")),
+            None => 0,
+        };
+        let http_address = match workers.remove("http_address") {
+            Some(Value::String(addr)) => Some(interpolate_env(&addr)?),
+            Some(_) => return Err(err_msg("http_address must be an address string")),
+            None => None,
+        };
+        let metrics_address = match workers.remove("metrics_address") {
+            Some(Value::String(addr)) => Some(interpolate_env(&addr)?),
+            Some(_) => return Err(err_msg("metrics_address must be an address string")),
+            None => None,
+        };
+        let statsd = match workers.remove("statsd") {
+            Some(Value::Object(mut statsd)) => {
+                if let Some(Value::String(addr)) = statsd.remove("address") {
+                    statsd.insert("address".to_owned(), Value::String(interpolate_env(&addr)?));
+                }
+                let statsd: StatsdConfig = serde_json::from_value(Value::Object(statsd))
+                    .map_err(|e| err_msg(format!("statsd {}", e)))?;
+                Some(statsd)
+            }
+            Some(_) => return Err(err_msg("statsd must be a table")),
+            None => None,
+        };
+        let otlp = match workers.remove("otlp") {
+            Some(Value::Object(mut otlp)) => {
+                if let Some(Value::String(endpoint)) = otlp.remove("endpoint") {
+                    otlp.insert(
+                        "endpoint".to_owned(),
+                        Value::String(interpolate_env(&endpoint)?),
+                    );
+                }
+                let otlp: OtlpConfig = serde_json::from_value(Value::Object(otlp))
+                    .map_err(|e| err_msg(format!("otlp {}", e)))?;
+                Some(otlp)
+            }
+            Some(_) => return Err(err_msg("otlp must be a table")),
+            None => None,
+        };
+        let webhook = match workers.remove("webhook") {
+            Some(Value::Object(mut webhook)) => {
+                if let Some(Value::Array(urls)) = webhook.remove("urls") {
+                    let mut v = Vec::new();
+                    for url in urls {
+                        v.push(match url {
+                            Value::String(url) => Value::String(interpolate_env(&url)?),
+                            url => url,
+                        });
+                    }
+                    webhook.insert("urls".to_owned(), Value::Array(v));
+                }
+                let webhook: WebhookConfig = serde_json::from_value(Value::Object(webhook))
+                    .map_err(|e| err_msg(format!("webhook {}", e)))?;
+                Some(webhook)
+            }
+            Some(_) => return Err(err_msg("webhook must be a table")),
+            None => None,
+        };
+        let auth = match workers.remove("auth") {
+            Some(Value::Object(mut auth)) => {
+                for key in &["token", "token_file"] {
+                    if let Some(Value::String(s)) = auth.remove(*key) {
+                        auth.insert(key.to_string(), Value::String(interpolate_env(&s)?));
+                    }
+                }
+                let auth: AuthConfig = serde_json::from_value(Value::Object(auth))
+                    .map_err(|e| err_msg(format!("auth {}", e)))?;
+                Some(auth)
+            }
+            Some(_) => return Err(err_msg("auth must be a table")),
+            None => None,
+        };
+        let include = workers.remove("include");
+        // the file of each worker for error reporting
+        let mut sources: HashMap<String, path::PathBuf> = match source {
+            Some(source) => workers
+                .keys()
+                .map(|name| (name.clone(), source.to_owned()))
+                .collect(),
+            None => HashMap::new(),
+        };
+        if let Some(include) = include {
+            let pattern = include
+                .as_str()
+                .ok_or_else(|| err_msg("include must be a glob pattern string"))?;
+            let base = config_path.parent().unwrap_or_else(|| path::Path::new("."));
+            let pattern = base.join(pattern);
+            let pattern = pattern.to_str().unwrap();
+            for entry in glob(pattern)? {
+                let include_path = entry?;
+                debug!("include config {:?}", include_path);
+                let table = read_table(&include_path)?;
+                if table.contains_key("include") {
+                    return Err(err_msg(format!(
+                        "{:?} nested include not support",
+                        include_path
+                    )));
+                }
+                for key in &[
+                    "defaults",
+                    "templates",
+                    "profile",
+                    "pidfile",
+                    "state_file",
+                    "audit_log",
+                    "control_sock",
+                    "control_sock_mode",
+                    "control_sock_group",
+                    "control_max_connections",
+                    "control_rate_limit",
+                    "http_address",
+                    "metrics_address",
+                    "statsd",
+                    "otlp",
+                    "webhook",
+                    "auth",
+                ] {
+                    if table.contains_key(*key) {
+                        return Err(err_msg(format!(
+                            "{:?} {} must be in the main config",
+                            include_path, key
+                        )));
+                    }
+                }
+                for (name, wrk_config) in table {
+                    if workers.contains_key(&name) {
+                        return Err(err_msg(format!(
+                            "duplicate worker [{}] in {:?}",
+                            name, include_path
+                        )));
+                    }
+                    sources.insert(name.clone(), include_path.clone());
+                    workers.insert(name, wrk_config);
+                }
+            }
+        }
+        if let Some(templates) = templates {
+            for (name, wrk_config) in expand_templates(templates)? {
+                if workers.contains_key(&name) {
+                    return Err(err_msg(format!("duplicate worker [{}] in templates", name)));
+                }
+                if let Some(source) = source {
+                    sources.insert(name.clone(), source.to_owned());
+                }
+                workers.insert(name, wrk_config);
+            }
+        }
+        if let Some(profile) = profile {
+            let overrides = profiles
+                .as_ref()
+                .and_then(|profiles| profiles.get(profile))
+                .and_then(|overrides| overrides.as_object())
+                .ok_or_else(|| err_msg(format!("unknown profile {}", profile)))?;
+            for (name, over) in overrides {
+                let table = if name == "defaults" {
+                    &mut defaults
+                } else {
+                    workers
+                        .get_mut(name)
+                        .and_then(|v| v.as_object_mut())
+                        .ok_or_else(|| {
+                            err_msg(format!("profile {} unknown worker [{}]", profile, name))
+                        })?
+                };
+                let over = over.as_object().ok_or_else(|| {
+                    err_msg(format!("profile {} [{}] must be a table", profile, name))
+                })?;
+                with_overrides(table, over);
+            }
+        }
+        let mut config = Config::from_workers(path, workers, &defaults, &sources)?;
+        config.profile = profile.map(|p| p.to_owned());
+        config.pidfile = pidfile;
+        config.state_file = state_file;
+        config.audit_log = audit_log;
+        config.control_sock = control_sock;
+        config.control_sock_permissions = SockPermissions { mode, gid };
+        config.control_max_connections = control_max_connections;
+        config.control_rate_limit = control_rate_limit;
+        config.http_address = http_address;
+        config.metrics_address = metrics_address;
+        config.statsd = statsd;
+        config.otlp = otlp;
+        config.webhook = webhook;
+        config.auth = auth;
+        Ok(config)
+    }
+
+    fn from_workers(
+        path: &str,
+        table: Map<String, Value>,
+        defaults: &Map<String, Value>,
+        sources: &HashMap<String, path::PathBuf>,
+    ) -> Result<Config, Error> {
+        let mut workers = HashMap::new();
+        for (name, value) in table {
+            let locate = |e: ConfigError| match sources.get(&name) {
+                Some(file) => e.locate(file),
+                None => e,
+            };
+            let value = with_defaults(value, defaults);
+            let mut wrk_config: WorkerConfig = serde_path_to_error::deserialize(value)
+                .map_err(|e| locate(ConfigError::from_serde(&name, &e)))?;
+            wrk_config.setup(&name).map_err(locate)?;
+            debug!("{:?}", wrk_config);
+            workers.insert(name, wrk_config);
+        }
+        let mut names: Vec<&String> = workers.keys().collect();
+        names.sort();
+        for name in names {
+            let invalid = |message: String| {
+                let e = ConfigError::new(name, Some("depends_on"), message);
+                match sources.get(name) {
+                    Some(file) => e.locate(file),
+                    None => e,
+                }
+            };
+            if let Some(dep) = workers[name]
+                .depends_on
+                .iter()
+                .find(|dep| !workers.contains_key(*dep))
+            {
+                return Err(invalid(format!("unknown worker [{}]", dep)).into());
+            }
+            if let Err(cycle) = visit_dependencies(name, &workers, &mut Vec::new(), &mut Vec::new())
+            {
+                return Err(invalid(format!("dependency cycle {}", cycle.join(" -> "))).into());
+            }
+        }
+        Ok(Config {
+            path: path.to_owned(),
+            profile: None,
+            control_sock: default_control_sock(),
+            control_sock_permissions: SockPermissions::default(),
+            control_max_connections: default_control_max_connections(),
+            control_rate_limit: 0,
+            pidfile: None,
+            state_file: None,
+            audit_log: None,
+            http_address: None,
+            metrics_address: None,
+            statsd: None,
+            otlp: None,
+            webhook: None,
+            auth: None,
+            workers,
+        })
+    }
+
+    /// The names of the workers in the order to start, each after the workers of its
+    /// depends_on, and in the order of the names otherwise.
+    pub fn start_order(&self) -> Vec<String> {
+        let mut names: Vec<&String> = self.workers.keys().collect();
+        names.sort();
+        let mut order = Vec::new();
+        for name in names {
+            // cycles are rejected at the load
+            let _ = visit_dependencies(name, &self.workers, &mut Vec::new(), &mut order);
+        }
+        order
+    }
+}
+
+/// Appends the dependencies of `name` and then `name` to `order` depth first.
+/// Returns the cycle when `name` depends on itself through `path`.
+fn visit_dependencies(
+    name: &str,
+    workers: &HashMap<String, WorkerConfig>,
+    path: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<(), Vec<String>> {
+    if order.iter().any(|n| n == name) {
+        return Ok(());
+    }
+    if let Some(i) = path.iter().position(|n| n == name) {
+        let mut cycle = path[i..].to_vec();
+        cycle.push(name.to_owned());
+        return Err(cycle);
+    }
+    path.push(name.to_owned());
+    if let Some(wrk_config) = workers.get(name) {
+        let mut deps: Vec<&String> = wrk_config.depends_on.iter().collect();
+        deps.sort();
+        for dep in deps {
+            visit_dependencies(dep, workers, path, order)?;
+        }
+    }
+    path.pop();
+    order.push(name.to_owned());
+    Ok(())
+}
+
+/// Instantiates each template `instances` times as workers named `<template>-<instance>`.
+/// `{name}`, `{instance}` and `{port}` (`base_port` + instance) are substituted in every string.
+fn expand_templates(templates: Value) -> Result<Vec<(String, Value)>, Error> {
+    let templates = match templates {
+        Value::Object(templates) => templates,
+        _ => return Err(err_msg("templates must be a table")),
+    };
+    let mut workers = Vec::new();
+    for (tmpl_name, template) in templates {
+        let mut template = match template {
+            Value::Object(template) => template,
+            _ => return Err(err_msg(format!("template [{}] must be a table", tmpl_name))),
+        };
+        let instances = template
+            .remove("instances")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| err_msg(format!("template [{}] require instances", tmpl_name)))?;
+        let base_port = match template.remove("base_port") {
+            Some(v) => Some(v.as_u64().ok_or_else(|| {
+                err_msg(format!("template [{}] invalid base_port", tmpl_name))
+            })?),
+            None => None,
+        };
+        let template = Value::Object(template);
+        for instance in 0..instances {
+            let name = format!("{}-{}", tmpl_name, instance);
+            let mut vars = vec![("{name}", name.clone()), ("{instance}", instance.to_string())];
+            if let Some(base_port) = base_port {
+                vars.push(("{port}", (base_port + instance).to_string()));
+            }
+            workers.push((name, substitute(&template, &vars)));
+        }
+    }
+    Ok(workers)
+}
+
+fn substitute_str(s: &str, vars: &[(&str, String)]) -> String {
+    let mut s = s.to_owned();
+    for &(var, ref val) in vars {
+        s = s.replace(var, val);
+    }
+    s
+}
+
+fn substitute(value: &Value, vars: &[(&str, String)]) -> Value {
+    match *value {
+        Value::String(ref s) => Value::String(substitute_str(s, vars)),
+        Value::Array(ref values) => {
+            Value::Array(values.iter().map(|v| substitute(v, vars)).collect())
         }
+        Value::Object(ref table) => Value::Object(
+            table
+                .iter()
+                .map(|(k, v)| (substitute_str(k, vars), substitute(v, vars)))
+                .collect(),
+        ),
+        ref v => v.clone(),
     }
 }
 
-impl Config {}
+/// Replaces keys of `table` with `overrides`. Tables such as environment are merged per key.
+fn with_overrides(table: &mut Map<String, Value>, overrides: &Map<String, Value>) {
+    for (key, over) in overrides {
+        if let (Some(&mut Value::Object(ref mut inner)), &Value::Object(ref over)) =
+            (table.get_mut(key), over)
+        {
+            for (k, v) in over {
+                inner.insert(k.clone(), v.clone());
+            }
+            continue;
+        }
+        table.insert(key.clone(), over.clone());
+    }
+}
+
+/// Fills keys missing in the worker with `defaults`. Tables such as environment are merged per key.
+fn with_defaults(value: Value, defaults: &Map<String, Value>) -> Value {
+    let mut table = match value {
+        Value::Object(table) => table,
+        value => return value,
+    };
+    for (key, default) in defaults {
+        match (table.get_mut(key), default) {
+            (Some(&mut Value::Object(ref mut inner)), &Value::Object(ref default)) => {
+                for (k, v) in default {
+                    inner.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+            (Some(_), _) => {}
+            (None, _) => {
+                table.insert(key.clone(), default.clone());
+            }
+        }
+    }
+    Value::Object(table)
+}
 
 impl WorkerConfig {
+    fn setup(&mut self, name: &str) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: String| ConfigError::new(name, Some(key), message);
+        self.interpolate_env(name)?;
+        // validate config
+        if self.cmd.is_empty() {
+            return Err(invalid("cmd", "require cmd".to_owned()));
+        }
+        if let Some(ref root) = self.chroot {
+            if !path::Path::new(root).is_dir() {
+                return Err(invalid("chroot", format!("{} is not a directory", root)));
+            }
+        }
+        if !self.root_path(&self.working_directory).is_dir() {
+            return Err(invalid(
+                "working_directory",
+                format!("{} is not a directory", self.working_directory),
+            ));
+        }
+        if let Some(ref umask) = self.umask {
+            u32::from_str_radix(umask, 8)
+                .map_err(|e| invalid("umask", format!("{}: {}", umask, e)))?;
+        }
+        let max_cpu = libc::CPU_SETSIZE as usize;
+        if let Some(cpu) = self.cpu_affinity.iter().find(|cpu| **cpu >= max_cpu) {
+            return Err(invalid("cpu_affinity", format!("invalid cpu {}", cpu)));
+        }
+        if let Some(nice) = self.nice {
+            if nice < -20 || nice > 19 {
+                return Err(invalid("nice", format!("{} is not in -20 to 19", nice)));
+            }
+        }
+        if let Some(level) = self.ionice_level {
+            if level > 7 {
+                return Err(invalid("ionice_level", format!("{} is not in 0 to 7", level)));
+            }
+        }
+        if let Some(adj) = self.oom_score_adj {
+            if adj < -1000 || adj > 1000 {
+                return Err(invalid(
+                    "oom_score_adj",
+                    format!("{} is not in -1000 to 1000", adj),
+                ));
+            }
+        }
+        if let Some(ref memory_max) = self.memory_max {
+            check_memory_max(memory_max).map_err(|e| invalid("memory_max", e.to_string()))?;
+        }
+        if let Some(ref cpu_max) = self.cpu_max {
+            check_cpu_max(cpu_max).map_err(|e| invalid("cpu_max", e.to_string()))?;
+        }
+        if let Some(ref max_memory) = self.max_memory {
+            parse_size(max_memory).map_err(|e| invalid("max_memory", e.to_string()))?;
+        }
+        if let Some(percent) = self.cpu_alert_percent {
+            if percent.is_nan() || percent <= 0.0 {
+                return Err(invalid(
+                    "cpu_alert_percent",
+                    format!("{} is not positive", percent),
+                ));
+            }
+        }
+        for (key, value) in vec![
+            ("cpu_alert_intervals", self.cpu_alert_intervals),
+            ("cpu_sample_sec", self.cpu_sample_sec),
+        ] {
+            if value == 0 {
+                return Err(invalid(key, "require 1 or more".to_owned()));
+            }
+        }
+        if let Some(ref schedule) = self.schedule {
+            schedule
+                .parse::<Schedule>()
+                .map_err(|e| invalid("schedule", e.to_string()))?;
+            // each run is a oneshot job
+            self.kind = WorkerKind::Oneshot;
+        }
+        if self.kind == WorkerKind::Oneshot
+            && (self.restart == RestartStrategy::Always
+                || self.restart == RestartStrategy::UnlessStopped)
+        {
+            return Err(invalid(
+                "restart",
+                "a oneshot worker is not restarted on success".to_owned(),
+            ));
+        }
+        for (key, hook) in vec![
+            ("pre_start", &self.pre_start),
+            ("post_start", &self.post_start),
+            ("pre_stop", &self.pre_stop),
+            ("post_stop", &self.post_stop),
+        ] {
+            if hook.as_ref().map_or(false, |cmd| cmd.is_empty()) {
+                return Err(invalid(key, "require a command".to_owned()));
+            }
+        }
+        if self.overlap_sec > 0 && (self.max_parallel > 0 || self.canary_bake_sec > 0) {
+            return Err(invalid(
+                "overlap_sec",
+                "conflicts with max_parallel and canary_bake_sec".to_owned(),
+            ));
+        }
+        for (addr, options) in &self.socket_options {
+            if !self.socket_address.contains(addr) {
+                return Err(invalid(
+                    "socket_options",
+                    format!("{} is not in socket_address", addr),
+                ));
+            }
+            let listen_fd = addr.parse::<ListenFd>().ok();
+            if options.backlog == Some(0) {
+                return Err(invalid(
+                    "socket_options",
+                    format!("{} backlog must be 1 or more", addr),
+                ));
+            }
+            if let (Some(ListenFd::UdpSocket(_)), Some(_)) = (&listen_fd, options.backlog) {
+                return Err(invalid(
+                    "socket_options",
+                    format!("{} backlog is not for udp", addr),
+                ));
+            }
+            if let Some(key) = options.keepalive_option() {
+                return Err(invalid(
+                    "socket_options",
+                    format!("{} {} requires keepalive = true", addr, key),
+                ));
+            }
+            if let Some(key) = options.tcp_option() {
+                match listen_fd {
+                    Some(ListenFd::TcpListener(_)) | None => {}
+                    Some(_) => {
+                        return Err(invalid(
+                            "socket_options",
+                            format!("{} {} is only for tcp", addr, key),
+                        ))
+                    }
+                }
+            }
+        }
+        if self.core_dump_dir.is_some() && !self.core_dump {
+            return Err(invalid(
+                "core_dump_dir",
+                "requires core_dump = true".to_owned(),
+            ));
+        }
+        self.rlimits()
+            .map_err(|e| invalid("limits", e.to_string()))?;
+        if let Some(ref env_file) = self.env_file {
+            read_env_file(env_file)
+                .map_err(|e| invalid("env_file", format!("{}: {}", env_file, e)))?;
+        }
+        let first_address = self.socket_address.first().cloned();
+        for (key, check) in vec![
+            ("health_check", &mut self.health_check),
+            ("readiness_check", &mut self.readiness_check),
+        ] {
+            if let Some(ref mut check) = *check {
+                check
+                    .setup(first_address.clone())
+                    .map_err(|e| invalid(key, e))?;
+            }
+        }
+        if let Some(ref stdout) = self.stdout_log {
+            let _stdout_log: RollingLogFile = stdout
+                .parse()
+                .map_err(|e: Error| invalid("stdout_log", e.to_string()))?;
+        }
+        if let Some(ref stderr) = self.stderr_log {
+            let _stderr_log: RollingLogFile = stderr
+                .parse()
+                .map_err(|e: Error| invalid("stderr_log", e.to_string()))?;
+        }
+
+        self.set_run_upgrader();
+        Ok(())
+    }
+
+    /// `run_upgrader` is not read from the config. It follows `upgrader` and `upgrader_active_sec`.
+    pub fn set_run_upgrader(&mut self) {
+        if let Some(ref _upgrader) = self.upgrader {
+            if self.upgrader_active_sec.is_some() {
+                self.run_upgrader = RunUpgrader::OnActiveSec;
+            } else {
+                self.run_upgrader = RunUpgrader::OnUpgrade;
+            }
+        }
+    }
+
+    fn interpolate_env(&mut self, name: &str) -> Result<(), ConfigError> {
+        let expand = |key: &str, s: &str| {
+            interpolate_env(s).map_err(|e| ConfigError::new(name, Some(key), e.to_string()))
+        };
+        for v in &mut self.cmd {
+            *v = expand("cmd", v)?;
+        }
+        for v in &mut self.socket_address {
+            *v = expand("socket_address", v)?;
+        }
+        let mut socket_options = HashMap::new();
+        for (addr, options) in self.socket_options.drain() {
+            socket_options.insert(expand("socket_options", &addr)?, options);
+        }
+        self.socket_options = socket_options;
+        if let Some(ref mut upgrader) = self.upgrader {
+            for v in upgrader {
+                *v = expand("upgrader", v)?;
+            }
+        }
+        for (key, hook) in vec![
+            ("pre_start", &mut self.pre_start),
+            ("post_start", &mut self.post_start),
+            ("pre_stop", &mut self.pre_stop),
+            ("post_stop", &mut self.post_stop),
+        ] {
+            if let Some(ref mut hook) = *hook {
+                for v in hook {
+                    *v = expand(key, v)?;
+                }
+            }
+        }
+        self.working_directory = expand("working_directory", &self.working_directory)?;
+        for (key, path) in vec![
+            ("env_file", &mut self.env_file),
+            ("chroot", &mut self.chroot),
+            ("cgroup_root", &mut self.cgroup_root),
+            ("core_dump_dir", &mut self.core_dump_dir),
+            ("stdout_log", &mut self.stdout_log),
+            ("stderr_log", &mut self.stderr_log),
+            ("control_socket", &mut self.control_socket),
+        ] {
+            if let Some(ref mut path) = *path {
+                *path = expand(key, path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The max_memory in bytes.
+    pub fn max_memory_bytes(&self) -> Option<u64> {
+        self.max_memory.as_ref().and_then(|s| parse_size(s).ok())
+    }
+
+    /// The options of the socket at `addr`.
+    pub fn socket_options(&self, addr: &str) -> SockOptions {
+        self.socket_options.get(addr).cloned().unwrap_or_default()
+    }
+
+    /// Maps a path seen by the processes to the path outside of `chroot`.
+    pub fn root_path(&self, path: &str) -> path::PathBuf {
+        let inner = path::Path::new(path);
+        match self.chroot {
+            Some(ref root) => path::Path::new(root).join(inner.strip_prefix("/").unwrap_or(inner)),
+            None => inner.to_owned(),
+        }
+    }
+
+    pub fn restart_delay(&self) -> time::Duration {
+        time::Duration::from_millis((self.restart_delay_sec.max(0.0) * 1000.0) as u64)
+    }
+
+    pub fn umask(&self) -> Option<u32> {
+        self.umask
+            .as_ref()
+            .and_then(|umask| u32::from_str_radix(umask, 8).ok())
+    }
+
+    pub fn rlimits(&self) -> Result<Vec<(Resource, libc::rlim_t)>, Error> {
+        let mut res = Vec::new();
+        for (key, limit) in &self.limits {
+            let resource = rlimit_resource(key)
+                .ok_or_else(|| err_msg(format!("unknown limit {}", key)))?;
+            let value = match limit {
+                Limit::Value(v) => *v as libc::rlim_t,
+                Limit::Name(ref v) if v == "unlimited" => libc::RLIM_INFINITY,
+                Limit::Name(ref v) => {
+                    return Err(err_msg(format!("invalid limit {} = {}", key, v)));
+                }
+            };
+            res.push((resource, value));
+        }
+        if self.core_dump && !self.limits.contains_key("core") {
+            res.push((libc::RLIMIT_CORE, libc::RLIM_INFINITY));
+        }
+        Ok(res)
+    }
+
+    pub fn ioprio(&self) -> Option<(u32, u32)> {
+        match (self.ionice_class, self.ionice_level) {
+            (None, None) => None,
+            (Some(IoniceClass::Idle), _) => Some((IoniceClass::Idle as u32, 0)),
+            (class, level) => Some((
+                class.unwrap_or(IoniceClass::BestEffort) as u32,
+                level.unwrap_or(4),
+            )),
+        }
+    }
+
+    pub fn cgroup_path(&self, name: &str) -> Option<path::PathBuf> {
+        if self.memory_max.is_none() && self.cpu_max.is_none() {
+            return None;
+        }
+        let root = self
+            .cgroup_root
+            .as_ref()
+            .map(|root| root.as_str())
+            .unwrap_or(DEFAULT_CGROUP_ROOT);
+        Some(path::Path::new(root).join(name))
+    }
+
     pub fn control_sock(&self, name: &str) -> String {
         if self.control_socket.is_some() {
             self.control_socket.clone().unwrap()
@@ -147,43 +1448,399 @@ impl WorkerConfig {
     }
 }
 
-pub fn parse_config(path: &str) -> io::Result<Config> {
-    let mut config_toml = String::new();
+fn read_table(path: &path::Path) -> Result<Map<String, Value>, Error> {
+    let mut buf = String::new();
     let mut file = File::open(path)?;
-    file.read_to_string(&mut config_toml)?;
+    file.read_to_string(&mut buf)?;
+
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("toml");
+    parse_table(&buf, ext).map_err(|e| err_msg(format!("{}: {}", path.display(), e)))
+}
+
+/// Parses a config of the format named by the file extension.
+fn parse_table(source: &str, format: &str) -> Result<Map<String, Value>, Error> {
+    let value: Value = match format {
+        "toml" => toml::from_str(source)?,
+        "yaml" | "yml" => serde_yaml::from_str(source)?,
+        _ => return Err(err_msg(format!("{} not support config format", format))),
+    };
+    match value {
+        Value::Object(table) => Ok(table),
+        Value::Null => Ok(Map::new()),
+        _ => Err(err_msg("not a table")),
+    }
+}
+
+fn is_yaml(path: &path::Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => true,
+        _ => false,
+    }
+}
+
+/// Finds the 1-based line of `key` in the `worker` section, or of the section itself.
+fn find_line(source: &str, worker: &str, key: Option<&str>, yaml: bool) -> Option<usize> {
+    let mut section = None;
+    for (i, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let header = if yaml {
+            if line.starts_with(char::is_whitespace) {
+                None
+            } else {
+                Some(trimmed.trim_end_matches(':').trim_matches(|c| c == '"' || c == '\''))
+            }
+        } else if trimmed.starts_with('[') {
+            let end = trimmed.find(']').unwrap_or_else(|| trimmed.len());
+            Some(trimmed[1..end].trim().trim_matches('"'))
+        } else {
+            None
+        };
+        if let Some(header) = header {
+            if section.is_some() {
+                break;
+            }
+            if header == worker {
+                section = Some(i + 1);
+                if key.is_none() {
+                    break;
+                }
+            }
+            continue;
+        }
+        if let (Some(_), Some(key)) = (section, key) {
+            let sep = if yaml { ':' } else { '=' };
+            if trimmed.starts_with(key) && trimmed[key.len()..].trim_start().starts_with(sep) {
+                return Some(i + 1);
+            }
+        }
+    }
+    section
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` using the daemon environment.
+pub fn interpolate_env(s: &str) -> Result<String, Error> {
+    let mut missing = None;
+    let res = ENV_VAR.replace_all(s, |caps: &Captures| {
+        let key = &caps[1];
+        match (env::var(key), caps.get(2)) {
+            (Ok(ref val), _) if !val.is_empty() => val.to_owned(),
+            (_, Some(default)) => default.as_str().to_owned(),
+            (Ok(val), None) => val,
+            (Err(_), None) => {
+                missing = Some(key.to_owned());
+                String::new()
+            }
+        }
+    });
+    if let Some(key) = missing {
+        return Err(err_msg(format!("environment variable {} not found", key)));
+    }
+    Ok(res.into_owned())
+}
+
+fn default_control_max_connections() -> u64 {
+    64
+}
 
+fn default_control_sock() -> String {
     let mut dir = env::temp_dir();
     dir.push(format!("{}-control.socket", APP_NAME));
     let path = dir.to_str().unwrap();
-    let sock = String::from(path);
+    String::from(path)
+}
 
-    let mut config = Config {
-        control_sock: sock,
-        workers: HashMap::new(),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut wrkrs: HashMap<String, WorkerConfig> =
-        from_str(&config_toml).expect("toml parse error");
+    fn parse(source: &str) -> Result<Config, Error> {
+        Config::from_source("/tmp/firestarter.toml", source, "toml", None)
+    }
 
-    for wrk_config in &mut wrkrs.values_mut() {
-        // validate config
-        if let Some(ref stdout) = wrk_config.stdout_log {
-            let _stdout_log: RollingLogFile = stdout.parse().unwrap();
+    fn parse_err(source: &str) -> String {
+        parse(source).err().expect("require an error").to_string()
+    }
+
+    /// A directory of its own for each test, removed on drop.
+    struct TestDir(path::PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let mut dir = env::temp_dir();
+            dir.push(format!("{}-test-{}-{}", APP_NAME, name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("conf.d")).unwrap();
+            TestDir(dir)
         }
-        if let Some(ref stderr) = wrk_config.stderr_log {
-            let _stderr_log: RollingLogFile = stderr.parse().unwrap();
+
+        fn write(&self, name: &str, source: &str) {
+            fs::write(self.0.join(name), source).unwrap();
         }
 
-        if let Some(ref _upgrader) = wrk_config.upgrader {
-            if wrk_config.upgrader_active_sec.is_some() {
-                wrk_config.run_upgrader = RunUpgrader::OnActiveSec;
-            } else {
-                wrk_config.run_upgrader = RunUpgrader::OnUpgrade;
-            }
+        fn config_path(&self) -> String {
+            self.0.join("main.toml").to_str().unwrap().to_owned()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
         }
+    }
+
+    #[test]
+    fn interpolate_set_var() {
+        env::set_var("FIRESTARTER_TEST_SET", "/srv/app");
+        assert_eq!(
+            interpolate_env("${FIRESTARTER_TEST_SET}/bin").unwrap(),
+            "/srv/app/bin"
+        );
+        assert_eq!(
+            interpolate_env("${FIRESTARTER_TEST_SET:-/opt}/bin").unwrap(),
+            "/srv/app/bin"
+        );
+    }
+
+    #[test]
+    fn interpolate_default() {
+        env::remove_var("FIRESTARTER_TEST_UNSET");
+        assert_eq!(
+            interpolate_env("${FIRESTARTER_TEST_UNSET:-127.0.0.1:8080}").unwrap(),
+            "127.0.0.1:8080"
+        );
+        assert_eq!(interpolate_env("${FIRESTARTER_TEST_UNSET:-}").unwrap(), "");
+        // an empty variable takes the default as well
+        env::set_var("FIRESTARTER_TEST_EMPTY", "");
+        assert_eq!(
+            interpolate_env("${FIRESTARTER_TEST_EMPTY:-info}").unwrap(),
+            "info"
+        );
+        assert_eq!(interpolate_env("${FIRESTARTER_TEST_EMPTY}").unwrap(), "");
+    }
+
+    #[test]
+    fn interpolate_missing() {
+        env::remove_var("FIRESTARTER_TEST_MISSING");
+        let e = interpolate_env("${FIRESTARTER_TEST_MISSING}/bin").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "environment variable FIRESTARTER_TEST_MISSING not found"
+        );
+        // not a variable
+        assert_eq!(interpolate_env("$HOME ${1X}").unwrap(), "$HOME ${1X}");
+    }
+
+    #[test]
+    fn interpolate_config() {
+        env::set_var("FIRESTARTER_TEST_BIN", "/srv/bin/app");
+        env::remove_var("FIRESTARTER_TEST_PIDFILE");
+        let config = parse(
+            r#"
+            pidfile = "${FIRESTARTER_TEST_PIDFILE:-/run/fs.pid}"
+            [a]
+            cmd = ["${FIRESTARTER_TEST_BIN}", "--port", "${FIRESTARTER_TEST_PORT:-8000}"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.pidfile, Some("/run/fs.pid".to_owned()));
+        assert_eq!(
+            config.workers["a"].cmd,
+            vec!["/srv/bin/app", "--port", "8000"]
+        );
+    }
+
+    #[test]
+    fn interpolate_config_missing() {
+        env::remove_var("FIRESTARTER_TEST_NOBIN");
+        let e = parse_err("[a]\ncmd = [\"${FIRESTARTER_TEST_NOBIN}\"]\n");
+        assert_eq!(
+            e,
+            "[a] cmd: environment variable FIRESTARTER_TEST_NOBIN not found"
+        );
+    }
+
+    #[test]
+    fn expand_template_instances() {
+        let config = parse(
+            r#"
+            [templates.web]
+            instances = 2
+            base_port = 8000
+            cmd = ["/bin/app", "--name", "{name}", "--port", "{port}"]
+            environment = { INSTANCE = "{instance}" }
+            "#,
+        )
+        .unwrap();
+        let mut names: Vec<&String> = config.workers.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["web-0", "web-1"]);
+        let web1 = &config.workers["web-1"];
+        assert_eq!(
+            web1.cmd,
+            vec!["/bin/app", "--name", "web-1", "--port", "8001"]
+        );
+        assert_eq!(web1.environment["INSTANCE"], "1");
+    }
+
+    #[test]
+    fn expand_template_errors() {
+        let e = parse_err("[templates.web]\ncmd = [\"/bin/app\"]\n");
+        assert_eq!(e, "template [web] require instances");
+        let e = parse_err(
+            "[templates.web]\ninstances = 1\nbase_port = \"8000\"\ncmd = [\"/bin/app\"]\n",
+        );
+        assert_eq!(e, "template [web] invalid base_port");
+        let e = parse_err("templates = 1\n");
+        assert_eq!(e, "templates must be a table");
+        let e = parse_err(
+            "[web-0]\ncmd = [\"/bin/app\"]\n[templates.web]\ninstances = 1\ncmd = [\"/bin/app\"]\n",
+        );
+        assert_eq!(e, "duplicate worker [web-0] in templates");
+    }
+
+    #[test]
+    fn include_workers() {
+        let dir = TestDir::new("include");
+        dir.write("conf.d/b.toml", "[b]\ncmd = [\"/bin/b\"]\n");
+        dir.write("conf.d/c.toml", "[c]\ncmd = [\"/bin/c\"]\n");
+        dir.write(
+            "main.toml",
+            "include = \"conf.d/*.toml\"\n[a]\ncmd = [\"/bin/a\"]\n",
+        );
+        let config = Config::from_file(&dir.config_path(), None).unwrap();
+        let mut names: Vec<&String> = config.workers.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert_eq!(config.workers["c"].cmd, vec!["/bin/c"]);
+    }
+
+    #[test]
+    fn include_errors() {
+        let dir = TestDir::new("include-errors");
+        dir.write(
+            "main.toml",
+            "include = \"conf.d/*.toml\"\n[a]\ncmd = [\"/bin/a\"]\n",
+        );
+        dir.write("conf.d/a.toml", "[a]\ncmd = [\"/bin/other\"]\n");
+        let e = Config::from_file(&dir.config_path(), None).unwrap_err();
+        assert!(
+            e.to_string().starts_with("duplicate worker [a] in "),
+            "{}",
+            e
+        );
+
+        dir.write("conf.d/a.toml", "pidfile = \"/run/fs.pid\"\n");
+        let e = Config::from_file(&dir.config_path(), None).unwrap_err();
+        assert!(
+            e.to_string()
+                .ends_with(" pidfile must be in the main config"),
+            "{}",
+            e
+        );
+
+        dir.write("conf.d/a.toml", "include = \"*.toml\"\n");
+        let e = Config::from_file(&dir.config_path(), None).unwrap_err();
+        assert!(
+            e.to_string().ends_with(" nested include not support"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn defaults_and_profile() {
+        let source = r#"
+            [defaults]
+            numprocesses = 2
+            [a]
+            cmd = ["/bin/a"]
+            [b]
+            cmd = ["/bin/b"]
+            numprocesses = 3
+            [profile.prod.a]
+            numprocesses = 8
+        "#;
+        let config = parse(source).unwrap();
+        assert_eq!(config.workers["a"].numprocesses, 2);
+        assert_eq!(config.workers["b"].numprocesses, 3);
+        let config =
+            Config::from_source("/tmp/firestarter.toml", source, "toml", Some("prod")).unwrap();
+        assert_eq!(config.workers["a"].numprocesses, 8);
+        let e =
+            Config::from_source("/tmp/firestarter.toml", source, "toml", Some("dev")).unwrap_err();
+        assert_eq!(e.to_string(), "unknown profile dev");
+    }
+
+    #[test]
+    fn control_sock() {
+        let config = parse("control_sock = \"tcp://127.0.0.1:7000\"\n").unwrap();
+        assert_eq!(config.control_sock, "tcp://127.0.0.1:7000");
+        assert_eq!(parse("").unwrap().control_sock, default_control_sock());
+        let e = parse_err("control_sock = \"udp://127.0.0.1:7000\"\n");
+        assert_eq!(
+            e,
+            "control_sock udp://127.0.0.1:7000: udp address is not supported"
+        );
+        let e = parse_err("control_sock = 7000\n");
+        assert_eq!(e, "control_sock must be an address string");
+    }
+
+    #[test]
+    fn worker_errors() {
+        for &(source, message) in &[
+            ("[a]\ncmd = []\n", "[a] cmd: require cmd"),
+            (
+                "[a]\ncmd = [\"/bin/a\"]\nnice = 20\n",
+                "[a] nice: 20 is not in -20 to 19",
+            ),
+            (
+                "[a]\ncmd = [\"/bin/a\"]\nionice_level = 8\n",
+                "[a] ionice_level: 8 is not in 0 to 7",
+            ),
+            (
+                "[a]\ncmd = [\"/bin/a\"]\noom_score_adj = 1001\n",
+                "[a] oom_score_adj: 1001 is not in -1000 to 1000",
+            ),
+            (
+                "[a]\ncmd = [\"/bin/a\"]\nschedule = \"0 0 * *\"\n",
+                "[a] schedule: 0 0 * * is not 5 fields",
+            ),
+            (
+                "[a]\ncmd = [\"/bin/a\"]\ndepends_on = [\"b\"]\n",
+                "[a] depends_on: unknown worker [b]",
+            ),
+        ] {
+            assert_eq!(parse_err(source), message);
+        }
+    }
+
+    #[test]
+    fn worker_dependency_cycle() {
+        let e = parse_err(
+            "[a]\ncmd = [\"/bin/a\"]\ndepends_on = [\"b\"]\n[b]\ncmd = [\"/bin/b\"]\ndepends_on = [\"a\"]\n",
+        );
+        assert_eq!(e, "[a] depends_on: dependency cycle a -> b -> a");
+    }
+
+    #[test]
+    fn worker_unknown_key() {
+        let e = parse("[a]\ncmd = [\"/bin/a\"]\nnumprocess = 2\n").unwrap_err();
+        let e = e.downcast::<ConfigError>().unwrap();
+        assert_eq!(e.worker, "a");
+        assert_eq!(e.key, Some("numprocess".to_owned()));
+        assert_eq!(e.suggestion, Some("numprocesses".to_owned()));
+    }
 
-        debug!("{:?}", wrk_config);
+    #[test]
+    fn worker_wrong_type() {
+        let e = parse("[a]\ncmd = [\"/bin/a\"]\nnumprocesses = \"2\"\n").unwrap_err();
+        let e = e.downcast::<ConfigError>().unwrap();
+        assert_eq!(e.key, Some("numprocesses".to_owned()));
     }
-    config.workers = wrkrs;
-    Ok(config)
 }
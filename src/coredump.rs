@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glob::{glob, Pattern};
+
+// the kernel truncates the executable name of %e to this length
+const COMM_LEN: usize = 15;
+
+/// Finds the core file dumped by the exited process, following `core_pattern` of the kernel.
+/// A relative pattern is relative to the working directory of the process. A pattern piped
+/// to a program, e.g. systemd-coredump, leaves no file to find.
+pub fn find_core(pid: u32, exe: &str, working_directory: &Path) -> Option<PathBuf> {
+    let pattern = fs::read_to_string("/proc/sys/kernel/core_pattern").ok()?;
+    let pattern = pattern.trim_end();
+    if pattern.is_empty() || pattern.starts_with('|') {
+        return None;
+    }
+    let exe: String = Path::new(exe)
+        .file_name()
+        .map(|name| name.to_string_lossy().chars().take(COMM_LEN).collect())
+        .unwrap_or_default();
+    let mut expanded = String::new();
+    let mut has_pid = false;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push_str(&Pattern::escape(&c.to_string()));
+            continue;
+        }
+        match chars.next() {
+            Some('p') | Some('P') | Some('i') | Some('I') => {
+                has_pid = true;
+                expanded.push_str(&pid.to_string());
+            }
+            Some('e') => expanded.push_str(&Pattern::escape(&exe)),
+            Some('%') => expanded.push('%'),
+            // the time, the host name, the signal and so on are matched by any
+            Some(_) => expanded.push('*'),
+            None => {}
+        }
+    }
+    let uses_pid = fs::read_to_string("/proc/sys/kernel/core_uses_pid")
+        .map(|s| s.trim() == "1")
+        .unwrap_or(false);
+    if uses_pid && !has_pid {
+        expanded.push_str(&format!(".{}", pid));
+    }
+    let path = if expanded.starts_with('/') {
+        expanded
+    } else {
+        let dir = Pattern::escape(&working_directory.to_string_lossy());
+        format!("{}/{}", dir, expanded)
+    };
+    // the newest one, when the pattern does not tell the processes apart
+    glob(&path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|path| {
+            let mtime = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((mtime, path))
+        })
+        .max()
+        .map(|(_, path)| path)
+}
+
+/// Moves the core file into the directory as `core.<worker>.<pid>`.
+pub fn move_core(core: &Path, dir: &Path, worker: &str, pid: u32) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let dest = dir.join(format!("core.{}.{}", worker, pid));
+    if fs::rename(core, &dest).is_err() {
+        // another file system
+        fs::copy(core, &dest)?;
+        fs::remove_file(core)?;
+    }
+    Ok(dest)
+}
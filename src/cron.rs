@@ -0,0 +1,269 @@
+use std::str::FromStr;
+
+use chrono::{
+    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc,
+};
+use failure::{err_msg, Error};
+
+// a schedule never due within this many years is given up
+const MAX_YEARS: i32 = 5;
+
+/// A cron expression of five fields, minute hour day-of-month month day-of-week, in the local
+/// time. Each field takes `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`, and a list of
+/// them separated by `,`. Day-of-week is 0 to 7, where both 0 and 7 are Sunday.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // a restricted day-of-month or day-of-week matches either of them, as cron does
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = match s.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(err_msg(format!("{} is not 5 fields", s)));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // 7 is Sunday as well as 0
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Schedule {
+    /// Returns the first time after `after` at which the schedule is due.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = after.with_timezone(&Local).naive_local();
+        let mut t =
+            local.date().and_hms_opt(local.hour(), local.minute(), 0)? + Duration::minutes(1);
+        let last_year = t.year() + MAX_YEARS;
+        while t.year() <= last_year {
+            if !has(self.months, t.month()) {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.day_matches(&t) {
+                t = (t.date() + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !has(self.hours, t.hour()) {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+                continue;
+            }
+            if !has(self.minutes, t.minute()) {
+                t = t + Duration::minutes(1);
+                continue;
+            }
+            // a time skipped by the daylight saving time does not exist
+            match Local.from_local_datetime(&t).earliest() {
+                Some(at) => return Some(at.with_timezone(&Utc)),
+                None => t = t + Duration::minutes(1),
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let day = has(self.days, t.day());
+        let weekday = has(self.weekdays, t.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn has(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+/// Parses a field into the bits of the values from `min` to `max`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, Error> {
+    let invalid = || err_msg(format!("invalid field {}", field));
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (
+                &part[..i],
+                part[i + 1..].parse::<u32>().map_err(|_| invalid())?,
+            ),
+            None => (part, 1),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            let first = range[..i].parse::<u32>().map_err(|_| invalid())?;
+            let last = range[i + 1..].parse::<u32>().map_err(|_| invalid())?;
+            (first, last)
+        } else {
+            // 5/15 is from 5 to the last every 15
+            let value = range.parse::<u32>().map_err(|_| invalid())?;
+            (value, if part.contains('/') { max } else { value })
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(err_msg(format!("{} is not in {} to {}", part, min, max)));
+        }
+        let mut value = first;
+        while value <= last {
+            bits |= 1 << value;
+            value += step;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(s: &str) -> DateTime<Utc> {
+        let t = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&t).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(expr: &str, after: &str) -> Option<String> {
+        let schedule: Schedule = expr.parse().unwrap();
+        schedule
+            .next_after(local(after))
+            .map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+    }
+
+    #[test]
+    fn parse_fields() {
+        let schedule: Schedule = "*/15 0-6/3 1,15 * 1-5".parse().unwrap();
+        assert_eq!(schedule.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(schedule.hours, 1 | 1 << 3 | 1 << 6);
+        assert_eq!(schedule.days, 1 << 1 | 1 << 15);
+        assert_eq!(schedule.months, 0b1_1111_1111_1110);
+        assert_eq!(schedule.weekdays, 0b11_1110);
+        assert!(!schedule.any_day);
+        assert!(!schedule.any_weekday);
+    }
+
+    #[test]
+    fn parse_step_from_value() {
+        let schedule: Schedule = "50/5 * * * *".parse().unwrap();
+        assert_eq!(schedule.minutes, 1 << 50 | 1 << 55);
+    }
+
+    #[test]
+    fn parse_nicknames() {
+        let daily: Schedule = "@daily".parse().unwrap();
+        assert_eq!(daily, "0 0 * * *".parse().unwrap());
+        let yearly: Schedule = "@annually".parse().unwrap();
+        assert_eq!(yearly, "0 0 1 1 *".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_sunday_as_7() {
+        let schedule: Schedule = "0 0 * * 7".parse().unwrap();
+        assert_eq!(schedule, "0 0 * * 0".parse().unwrap());
+        let schedule: Schedule = "0 0 * * 5-7".parse().unwrap();
+        assert_eq!(schedule.weekdays, 1 | 1 << 5 | 1 << 6);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for expr in &[
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "1- * * * *",
+            "1,,2 * * * *",
+            "@every 5m",
+        ] {
+            assert!(expr.parse::<Schedule>().is_err(), "{}", expr);
+        }
+        let e = "60 * * * *".parse::<Schedule>().unwrap_err();
+        assert_eq!(e.to_string(), "60 is not in 0 to 59");
+        let e = "* * * *".parse::<Schedule>().unwrap_err();
+        assert_eq!(e.to_string(), "* * * * is not 5 fields");
+    }
+
+    #[test]
+    fn next_after_minute() {
+        assert_eq!(
+            next("* * * * *", "2026-01-10 03:00"),
+            Some("2026-01-10 03:01".to_owned())
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2026-01-10 03:59"),
+            Some("2026-01-10 04:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn next_after_next_day() {
+        assert_eq!(
+            next("30 2 * * *", "2026-01-10 03:00"),
+            Some("2026-01-11 02:30".to_owned())
+        );
+        assert_eq!(
+            next("0 0 1 * *", "2026-01-31 12:00"),
+            Some("2026-02-01 00:00".to_owned())
+        );
+        assert_eq!(
+            next("0 0 1 1 *", "2026-12-31 23:59"),
+            Some("2027-01-01 00:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn next_after_day_or_weekday() {
+        // 2026-01-01 is a Thursday. the Friday comes before the 13th
+        assert_eq!(
+            next("0 0 13 * 5", "2026-01-01 12:00"),
+            Some("2026-01-02 00:00".to_owned())
+        );
+        assert_eq!(
+            next("0 0 * * 0", "2026-01-01 12:00"),
+            Some("2026-01-04 00:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn next_after_never() {
+        assert_eq!(next("0 0 31 2 *", "2026-01-01 00:00"), None);
+    }
+}
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::{io, thread, time};
+
+use libc::pid_t;
+use mio::{Ready, SetReadiness};
+use nix::unistd::getpid;
+use serde_json;
+
+use audit::{command_name, AuditLog};
+use auth::Auth;
+use command::*;
+use config::Config;
+use limit::{Limiter, Slot};
+use metrics::{self, WorkerStats};
+use pool::Pool;
+use sock::{self, connect_unix, CtrlStream, Peer};
+
+// a client must send the command within this time
+const READ_TIMEOUT_SECS: u64 = 30;
+// the same as the timeout of stop_worker
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+const DRAIN_POLL_INTERVAL_MILLIS: u64 = 500;
+
+/// Control socket paths of the workers, shared with the connection threads.
+#[derive(Clone, Default)]
+pub struct CtrlSocks {
+    inner: Arc<RwLock<HashMap<String, String>>>,
+    // workers without a monitor. the daemon answers their status
+    down: Arc<RwLock<HashMap<String, RunState>>>,
+    // the workers of each group in the order of their names
+    groups: Arc<RwLock<HashMap<String, Vec<String>>>>,
+}
+
+impl CtrlSocks {
+    pub fn update(&self, config: &Config) {
+        let socks = config
+            .workers
+            .iter()
+            .map(|(name, config)| (name.to_owned(), config.control_sock(name)))
+            .collect();
+        *self.inner.write().unwrap() = socks;
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, config) in &config.workers {
+            for group in &config.groups {
+                groups
+                    .entry(group.to_owned())
+                    .or_insert_with(Vec::new)
+                    .push(name.to_owned());
+            }
+        }
+        for names in groups.values_mut() {
+            names.sort();
+        }
+        *self.groups.write().unwrap() = groups;
+    }
+
+    pub fn update_down(&self, down: HashMap<String, RunState>) {
+        *self.down.write().unwrap() = down;
+    }
+
+    fn down_status(&self, name: &str, cmd: &CtrlCommand) -> Option<CommandResponse> {
+        if let Command::Status = cmd.command {
+            let state = self.down.read().unwrap().get(name).cloned()?;
+            return Some(CommandResponse {
+                status: Status::Ok,
+                command: Command::Status,
+                pid: pid_t::from(getpid()) as u32,
+                message: serde_json::to_string(&WorkerStatus::down(name, state)).ok()?,
+            });
+        }
+        None
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.inner.read().unwrap().get(name).cloned()
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        let mut socks: Vec<(String, String)> = self
+            .inner
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, path)| (name.to_owned(), path.to_owned()))
+            .collect();
+        socks.sort();
+        socks
+    }
+}
+
+/// A command that changes the daemon state. It is handled by the main loop.
+pub struct CtrlRequest {
+    pub cmd: DaemonCommand,
+    pub stream: CtrlStream,
+    pub peer: Peer,
+    // "control" or "http"
+    pub socket: &'static str,
+    pub received_at: time::Instant,
+}
+
+impl CtrlRequest {
+    /// Sends the result and records it in the audit log.
+    pub fn respond(&mut self, audit: Option<&AuditLog>, res: &CommandResponse) -> io::Result<()> {
+        if let Some(audit) = audit {
+            let command = command_name(&self.cmd.command_type);
+            let worker = self.cmd.worker.as_ref().map(|w| w.as_str());
+            audit.record(
+                self.socket,
+                &self.peer,
+                command,
+                worker,
+                &res.status,
+                &res.message,
+            );
+        }
+        send_response(&mut self.stream, res)
+    }
+}
+
+/// Handles control connections on a pool of threads, so that a slow client
+/// does not stall the main loop.
+#[derive(Clone)]
+pub struct CtrlHandler {
+    socks: CtrlSocks,
+    requests: Sender<CtrlRequest>,
+    waker: SetReadiness,
+    audit: Option<AuditLog>,
+    auth: Option<Auth>,
+    limiter: Limiter,
+    pool: Pool,
+    started_at: time::SystemTime,
+}
+
+impl CtrlHandler {
+    pub fn new(
+        socks: CtrlSocks,
+        requests: Sender<CtrlRequest>,
+        waker: SetReadiness,
+        audit: Option<AuditLog>,
+        auth: Option<Auth>,
+        limiter: Limiter,
+        pool: Pool,
+    ) -> Self {
+        CtrlHandler {
+            socks,
+            requests,
+            waker,
+            audit,
+            auth,
+            limiter,
+            pool,
+            started_at: time::SystemTime::now(),
+        }
+    }
+
+    /// Handles the connection on a thread of the pool. A connection over the cap is
+    /// rejected here without a thread.
+    pub fn spawn(&self, stream: CtrlStream) {
+        let slot = match self.limiter.acquire() {
+            Some(slot) => slot,
+            None => {
+                reject(stream, "too many control connections");
+                return;
+            }
+        };
+        let handler = self.clone();
+        self.pool.execute(move || {
+            let _slot = slot;
+            match handler.handle(stream) {
+                // a liveness probe of a standby daemon closes without a command
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    debug!(
+                        "closed control connection without command. pid [{}]",
+                        getpid()
+                    );
+                }
+                Err(e) => warn!(
+                    "fail handle control connection. caused by: {} pid [{}]",
+                    e,
+                    getpid()
+                ),
+                Ok(_) => {}
+            }
+        });
+    }
+
+    /// Runs a job of another listener, http or metrics, on the same pool.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.pool.execute(job)
+    }
+
+    pub fn handle(&self, mut stream: CtrlStream) -> io::Result<()> {
+        let peer = stream.peer();
+        let cmd = read_command(&mut stream, READ_TIMEOUT_SECS)?;
+        if let Err(msg) = self.check_rate(&peer) {
+            return send_response(&mut stream, &busy_response(&msg));
+        }
+        self.dispatch(stream, cmd, peer, "control")
+    }
+
+    /// Handles a command relayed from another protocol. `peer` is the original sender.
+    /// The rate of the peer is checked by the caller.
+    pub fn handle_from(
+        &self,
+        mut stream: CtrlStream,
+        peer: Peer,
+        socket: &'static str,
+    ) -> io::Result<()> {
+        let cmd = read_command(&mut stream, READ_TIMEOUT_SECS)?;
+        self.dispatch(stream, cmd, peer, socket)
+    }
+
+    /// Returns `None` when the connections are at the cap.
+    pub fn acquire(&self) -> Option<Slot> {
+        self.limiter.acquire()
+    }
+
+    pub fn check_rate(&self, peer: &Peer) -> Result<(), String> {
+        self.limiter.check_rate(peer).map_err(|wait| {
+            debug!("rate limit [{}]. pid [{}]", peer, getpid());
+            format!("rate limit exceeded. retry after {} ms", wait)
+        })
+    }
+
+    fn dispatch(
+        &self,
+        mut stream: CtrlStream,
+        cmd: DaemonCommand,
+        peer: Peer,
+        socket: &'static str,
+    ) -> io::Result<()> {
+        if !self.authorize(socket, &peer, &cmd) {
+            write_frame(&mut stream, MessageType::Error, &"permission denied")?;
+            return Ok(());
+        }
+        match cmd.command_type {
+            CommandType::CtrlWorker if is_stream(&cmd) => {
+                self.bridge_worker(stream, &cmd, &peer, socket)
+            }
+            CommandType::CtrlWorker => {
+                let (status, message) = self.send_command_worker(&cmd, &mut stream)?;
+                // the polls of `logs -f` are not audited
+                if cmd.command.as_ref().map_or(true, |c| c.since.is_none()) {
+                    self.audit(socket, &peer, &cmd, &status, &message);
+                }
+                Ok(())
+            }
+            CommandType::List => {
+                self.send_list(&mut stream)?;
+                self.audit(socket, &peer, &cmd, &Status::Ok, "");
+                Ok(())
+            }
+            CommandType::Status => {
+                self.send_command_workers(&cmd, &mut stream)?;
+                self.audit(socket, &peer, &cmd, &Status::Ok, "");
+                Ok(())
+            }
+            // the worker is stopped by the main loop after the drain
+            CommandType::DrainWorker => match self.drain_worker(&cmd) {
+                Ok(()) => self.send_request(stream, cmd, peer, socket),
+                Err(msg) => {
+                    write_frame(&mut stream, MessageType::Error, &msg)?;
+                    self.audit(socket, &peer, &cmd, &Status::Error, &msg);
+                    Ok(())
+                }
+            },
+            CommandType::Reload
+            | CommandType::Apply
+            | CommandType::Shutdown
+            | CommandType::Upexec
+            | CommandType::StartWorker
+            | CommandType::StopWorker
+            | CommandType::RestartWorker
+            | CommandType::RollbackWorker
+            | CommandType::Ping => self.send_request(stream, cmd, peer, socket),
+        }
+    }
+
+    /// Hands the command to the main loop. It answers on the stream.
+    fn send_request(
+        &self,
+        stream: CtrlStream,
+        cmd: DaemonCommand,
+        peer: Peer,
+        socket: &'static str,
+    ) -> io::Result<()> {
+        let req = CtrlRequest {
+            cmd,
+            stream,
+            peer,
+            socket,
+            received_at: time::Instant::now(),
+        };
+        if self.requests.send(req).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "main loop exited",
+            ));
+        }
+        self.waker.set_readiness(Ready::readable())
+    }
+
+    /// Makes the worker stop accepting and waits until its connections are closed,
+    /// its processes exited or the timeout passed.
+    fn drain_worker(&self, cmd: &DaemonCommand) -> Result<(), String> {
+        let name = cmd.worker.as_ref().map(|w| w.as_str()).unwrap_or("");
+        let sock_path = self
+            .socks
+            .get(name)
+            .ok_or_else(|| format!("unknown worker [{}]", name))?;
+        let timeout = time::Duration::from_secs(cmd.timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS));
+        let ctrl_cmd = CtrlCommand {
+            signal: cmd.command.as_ref().and_then(|c| c.signal),
+            ..CtrlCommand::new(Command::Drain, pid_t::from(getpid()) as u32)
+        };
+        let started_at = time::Instant::now();
+        loop {
+            let res = send_ctrl_command(&sock_path, &ctrl_cmd)
+                .map_err(|e| format!("[{}] not running. caused by: {}", name, e))?;
+            if let Status::Error = res.status {
+                return Err(res.message);
+            }
+            let status: DrainStatus =
+                serde_json::from_str(&res.message).map_err(|e| e.to_string())?;
+            if status.connections == 0 || status.processes == 0 {
+                info!(
+                    "drained [{}] in {} secs. pid [{}]",
+                    name,
+                    started_at.elapsed().as_secs(),
+                    getpid()
+                );
+                return Ok(());
+            }
+            if started_at.elapsed() >= timeout {
+                warn!(
+                    "drain [{}] timed out. {} connections left. pid [{}]",
+                    name,
+                    status.connections,
+                    getpid()
+                );
+                return Ok(());
+            }
+            thread::sleep(time::Duration::from_millis(DRAIN_POLL_INTERVAL_MILLIS));
+        }
+    }
+
+    /// Collects the stats of all workers in the prometheus text format.
+    pub fn metrics(&self) -> String {
+        let pid = pid_t::from(getpid()) as u32;
+        let cmd = CtrlCommand::new(Command::Metrics, pid);
+        let workers: Vec<(String, Option<WorkerStats>)> = self
+            .socks
+            .all()
+            .into_iter()
+            .map(|(name, sock_path)| {
+                let stats = send_ctrl_command(&sock_path, &cmd)
+                    .ok()
+                    .and_then(|res| serde_json::from_str(&res.message).ok());
+                (name, stats)
+            })
+            .collect();
+        let uptime = self
+            .started_at
+            .elapsed()
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        metrics::render(uptime, &workers)
+    }
+
+    /// Checks the sender against the `[auth]` table. A denied command is logged and audited.
+    pub fn authorize(&self, socket: &str, peer: &Peer, cmd: &DaemonCommand) -> bool {
+        let auth = match self.auth {
+            Some(ref auth) => auth,
+            None => return true,
+        };
+        if auth.allow(peer, cmd.token.as_ref()) {
+            return true;
+        }
+        warn!(
+            "deny {:?} from [{}] on {}. pid [{}]",
+            cmd.command_type,
+            peer,
+            socket,
+            getpid()
+        );
+        self.audit(socket, peer, cmd, &Status::Error, "permission denied");
+        false
+    }
+
+    fn audit(
+        &self,
+        socket: &str,
+        peer: &Peer,
+        cmd: &DaemonCommand,
+        status: &Status,
+        message: &str,
+    ) {
+        if let Some(ref audit) = self.audit {
+            let command = match cmd.command {
+                Some(ref ctrl) if cmd.worker.is_some() => command_name(&ctrl.command),
+                _ => command_name(&cmd.command_type),
+            };
+            let worker = cmd.worker.as_ref().map(|w| w.as_str());
+            audit.record(socket, peer, command, worker, status, message);
+        }
+    }
+
+    /// Returns the status and the message of the response for the audit log.
+    fn send_command_worker(
+        &self,
+        cmd: &DaemonCommand,
+        stream: &mut CtrlStream,
+    ) -> io::Result<(Status, String)> {
+        if let Some(ref name) = cmd.worker {
+            if let Some(sock_path) = self.socks.get(name) {
+                let ctrl_cmd = cmd.command.as_ref().unwrap();
+                let res = match send_ctrl_command(&sock_path, ctrl_cmd) {
+                    Ok(res) => res,
+                    Err(e) => match self.socks.down_status(name, ctrl_cmd) {
+                        Some(res) => res,
+                        None => {
+                            let msg = format!("[{}] not running. caused by: {}", name, e);
+                            write_frame(stream, MessageType::Error, &msg)?;
+                            return Ok((Status::Error, msg));
+                        }
+                    },
+                };
+                send_response(stream, &res)?;
+                return Ok((res.status, res.message));
+            } else {
+                let msg = format!("unknown worker [{}]", name);
+                write_frame(stream, MessageType::Error, &msg)?;
+                return Ok((Status::Error, msg));
+            }
+        }
+        Ok((Status::Error, "no worker".to_owned()))
+    }
+
+    /// Relays a command that streams after the response, attach, exec and wait.
+    /// On success, the connection is bridged to the monitor until either side closes it.
+    fn bridge_worker(
+        &self,
+        mut stream: CtrlStream,
+        cmd: &DaemonCommand,
+        peer: &Peer,
+        socket: &str,
+    ) -> io::Result<()> {
+        let name = cmd.worker.as_ref().map(|w| w.as_str()).unwrap_or("");
+        let sock_path = match self.socks.get(name) {
+            Some(sock_path) => sock_path,
+            None => {
+                let msg = format!("unknown worker [{}]", name);
+                write_frame(&mut stream, MessageType::Error, &msg)?;
+                self.audit(socket, peer, cmd, &Status::Error, &msg);
+                return Ok(());
+            }
+        };
+        let res = connect_unix(&sock_path).and_then(|mut monitor| {
+            write_frame(
+                &mut monitor,
+                MessageType::CtrlCommand,
+                cmd.command.as_ref().unwrap(),
+            )?;
+            let payload = read_response(&mut monitor)?;
+            let res: CommandResponse = serde_json::from_slice(&payload)?;
+            Ok((monitor, res))
+        });
+        let (monitor, res) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                let msg = format!("[{}] not running. caused by: {}", name, e);
+                write_frame(&mut stream, MessageType::Error, &msg)?;
+                self.audit(socket, peer, cmd, &Status::Error, &msg);
+                return Ok(());
+            }
+        };
+        send_response(&mut stream, &res)?;
+        self.audit(socket, peer, cmd, &res.status, &res.message);
+        if let Status::Ok = res.status {
+            bridge(stream, monitor)?;
+        }
+        Ok(())
+    }
+
+    fn send_command_workers(&self, cmd: &DaemonCommand, stream: &mut CtrlStream) -> io::Result<()> {
+        let cmd = cmd.command.as_ref().unwrap();
+        let mut v = Vec::new();
+        for (name, sock_path) in self.socks.all() {
+            // a stopped worker has no monitor to answer
+            let res = send_ctrl_command(&sock_path, cmd).unwrap_or_else(|_| {
+                self.socks
+                    .down_status(&name, cmd)
+                    .unwrap_or_else(|| CommandResponse {
+                        status: Status::Error,
+                        command: cmd.command.clone(),
+                        pid: pid_t::from(getpid()) as u32,
+                        message: format!("[{}] not running", name),
+                    })
+            });
+            v.push(res);
+        }
+        send_response(stream, &v)
+    }
+
+    fn send_list(&self, stream: &mut CtrlStream) -> io::Result<()> {
+        let pid = pid_t::from(getpid());
+        let cmd = CtrlCommand::new(Command::Metrics, pid as u32);
+        let mut workers = Vec::new();
+        let mut paused = Vec::new();
+        for (name, sock_path) in self.socks.all() {
+            let stats = send_ctrl_command(&sock_path, &cmd)
+                .ok()
+                .and_then(|res| serde_json::from_str::<WorkerStats>(&res.message).ok());
+            if stats.map_or(false, |s| s.paused) {
+                paused.push(name.to_owned());
+            }
+            workers.push(name);
+        }
+        let res = ListResponse {
+            pid: pid as u32,
+            workers,
+            paused,
+            groups: self.socks.groups.read().unwrap().clone(),
+        };
+        send_response(stream, &res)
+    }
+}
+
+fn read_command(stream: &mut CtrlStream, timeout: u64) -> io::Result<DaemonCommand> {
+    stream.set_read_timeout(Some(time::Duration::from_secs(timeout)))?;
+    let cmd = read_daemon_command(stream)?;
+    stream.set_read_timeout(None)?;
+    Ok(cmd)
+}
+
+fn is_stream(cmd: &DaemonCommand) -> bool {
+    match cmd.command {
+        Some(CtrlCommand {
+            command: Command::Attach,
+            ..
+        })
+        | Some(CtrlCommand {
+            command: Command::Exec,
+            ..
+        })
+        | Some(CtrlCommand {
+            command: Command::Wait,
+            ..
+        }) => true,
+        _ => false,
+    }
+}
+
+/// Copies the bytes in both ways. The monitor detaches the client on EOF.
+fn bridge(mut client: CtrlStream, mut monitor: UnixStream) -> io::Result<()> {
+    let mut input = client.try_clone()?;
+    let mut monitor_input = monitor.try_clone()?;
+    let handle = thread::spawn(move || {
+        let _ = io::copy(&mut input, &mut monitor_input);
+        let _ = monitor_input.shutdown(Shutdown::Write);
+    });
+    let res = io::copy(&mut monitor, &mut client);
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = handle.join();
+    res.map(|_| ())
+}
+
+/// Answers busy to a connection over the cap without waiting for its command.
+/// The write does not block, so a client that does not read is just closed.
+/// The command is read and discarded afterwards, so the answer is not reset.
+fn reject(mut stream: CtrlStream, msg: &str) {
+    debug!("reject control connection. {} pid [{}]", msg, getpid());
+    let res = stream
+        .set_nonblocking(true)
+        .and_then(|_| send_response(&mut stream, &busy_response(msg)))
+        .and_then(|_| stream.shutdown(Shutdown::Write));
+    match res {
+        Ok(()) => sock::linger(stream),
+        Err(e) => debug!("fail answer busy. caused by: {} pid [{}]", e, getpid()),
+    }
+}
+
+fn busy_response(msg: &str) -> CommandResponse {
+    CommandResponse {
+        status: Status::Busy,
+        command: Command::None,
+        pid: pid_t::from(getpid()) as u32,
+        message: msg.to_owned(),
+    }
+}
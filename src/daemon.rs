@@ -1,32 +1,173 @@
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::net::TcpListener;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::{fs, io, path, thread, time};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::{fmt, fs, io, path, thread, time};
 
 use failure::{err_msg, Error};
 use libc::pid_t;
 use mio::unix::EventedFd;
-use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::{Events, Poll, PollOpt, Ready, Registration, Token};
 use nix::sys::signal;
 use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
-use nix::unistd::{getpid, Pid};
+use nix::fcntl::OFlag;
+use nix::sys::wait::waitpid;
+use nix::unistd::{close, getpid, pipe2, read, Pid};
 use serde_json;
 
+use audit::{command_name, AuditLog};
+use auth::Auth;
 use command::*;
-use config::Config;
-use monitor::{ExitStatus, MonitorProcess};
+use config::{Config, WebhookEvent, WorkerKind};
+use ctrl::{CtrlHandler, CtrlRequest, CtrlSocks};
+use detach::Launcher;
+use http;
+use limit::Limiter;
+use pidfile::PidFile;
+use pool::Pool;
+use metrics::WorkerStats;
+use monitor::{ExitStatus, MonitorProcess, Reporters};
+use notify::Notifier;
+use otlp::Tracer;
 use process::{process_normally_exited, process_output, run_upgrader};
 use reloader;
-use sock::ListenFd;
+use signal::Signal;
+use sock::{
+    ctrl_sock_path, inherited_fds, is_abstract, unix_sock_exists, CtrlListener, ListenFd,
+    SockOptions, SockPermissions,
+};
+use state::{State, WorkerState};
+use upexec::{self, Handover};
+use webhook::Webhook;
+
+// seconds between saves of the state file
+const STATE_SAVE_INTERVAL_SECS: u64 = 10;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+// seconds to wait for the processes of a restarted worker
+const RESTART_WAIT_SECS: u64 = 10;
+// seconds a monitor stopped by a config change gets after kill_after_sec of its processes
+const STOP_MARGIN_SECS: u64 = 5;
+// the main loop wakes up this often while monitors are stopping or restarts are not answered
+const PENDING_POLL_MILLIS: u64 = 100;
+// threads of the control connections when control_max_connections is 0
+const UNLIMITED_CONNECTION_THREADS: usize = 64;
+// threads that send the commands of the main loop to the monitors
+const QUERY_THREADS: usize = 4;
+// seconds a hung monitor holds a query thread
+const QUERY_TIMEOUT_SECS: u64 = 5;
+
+// set by SIGHUP and handled in the main loop
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+// set by SIGUSR1 and handled in the main loop
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// write end of the self-pipe that wakes up the main loop on SIGCHLD
+static CHILD_PIPE: AtomicI32 = AtomicI32::new(-1);
+// set by SIGCHLD. the pipe is not readable when poll is interrupted
+static CHILD_EXITED: AtomicBool = AtomicBool::new(false);
 
 extern "C" fn handle_signal(_signum: i32) {}
 
+extern "C" fn handle_child_signal(_signum: i32) {
+    CHILD_EXITED.store(true, Ordering::SeqCst);
+    let fd = CHILD_PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::write(fd, b"c".as_ptr() as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+extern "C" fn handle_reload_signal(_signum: i32) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_reopen_signal(_signum: i32) {
+    REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// The change of a worker by a reload or an apply.
+enum WorkerChange {
+    Removed,
+    Changed,
+    // changed, but kept down until started
+    ChangedStopped,
+    Unchanged,
+    Added,
+}
+
+impl fmt::Display for WorkerChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = match *self {
+            WorkerChange::Removed => "removed",
+            WorkerChange::Changed => "changed",
+            WorkerChange::ChangedStopped => "changed. stopped",
+            WorkerChange::Unchanged => "unchanged",
+            WorkerChange::Added => "added",
+        };
+        f.write_str(change)
+    }
+}
+
+/// A monitor stopped by stop, restart or a config change. The main loop reaps it, and
+/// kills it when it is alive after the deadline.
+struct Stopping {
+    monitor: MonitorProcess,
+    kill_at: time::Instant,
+    killed: bool,
+    // respawned after the exit, for restart and a changed config
+    respawn: bool,
+    // answered after the exit, or after the respawn for restart
+    req: Option<CtrlRequest>,
+}
+
+/// A restart answered when the new monitor has processes, or after the deadline.
+struct Restarting {
+    req: CtrlRequest,
+    deadline: time::Instant,
+    // the processes of the new monitor, when it has answered
+    pids: Option<Vec<u32>>,
+}
+
+/// The stats of a worker asked on the query pool, with the pid of the monitor that
+/// answered. None when the monitor did not answer.
+struct Answer {
+    name: String,
+    stats: Option<(u32, WorkerStats)>,
+}
+
 // #[derive(Debug)]
 pub struct Daemon {
     config: Config,
     monitors: HashMap<String, MonitorProcess>,
     pid: Pid,
+    launcher: Option<Launcher>,
+    pidfile: Option<PidFile>,
+    notifier: Option<Notifier>,
+    status: String,
+    inherited_ctrl_sock: bool,
+    child_pipe: (RawFd, RawFd),
+    ctrl_socks: CtrlSocks,
+    http: Option<TcpListener>,
+    metrics: Option<TcpListener>,
+    reporters: Reporters,
+    auth: Option<Auth>,
+    state: State,
+    handover: Option<Handover>,
+    started_at: time::Instant,
+    // the monitors to respawn, and when
+    respawns: HashMap<String, time::Instant>,
+    stopping: HashMap<String, Stopping>,
+    restarting: HashMap<String, Restarting>,
+    // the main loop does not wait for the monitors. it takes the answers later
+    queries: Pool,
+    answers: (Sender<Answer>, Receiver<Answer>),
+    // the workers asked and not answered yet
+    querying: HashSet<String>,
 }
 
 impl Daemon {
@@ -40,12 +181,84 @@ impl Daemon {
             sigaction(signal::SIGINT, &sa).unwrap();
             sigaction(signal::SIGQUIT, &sa).unwrap();
         }
+        let sa = SigAction::new(
+            SigHandler::Handler(handle_reload_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe {
+            sigaction(signal::SIGHUP, &sa).unwrap();
+        }
+        let sa = SigAction::new(
+            SigHandler::Handler(handle_reopen_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe {
+            sigaction(signal::SIGUSR1, &sa).unwrap();
+        }
+        let child_pipe = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC).unwrap();
+        CHILD_PIPE.store(child_pipe.1, Ordering::SeqCst);
+        let sa = SigAction::new(
+            SigHandler::Handler(handle_child_signal),
+            SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+            SigSet::empty(),
+        );
+        unsafe {
+            sigaction(signal::SIGCHLD, &sa).unwrap();
+        }
 
         let pid = getpid();
+        let notifier = Notifier::from_env().unwrap_or_else(|e| {
+            warn!("fail open notify socket. caused by: {}", e);
+            None
+        });
         Daemon {
             config,
             monitors: HashMap::new(),
             pid,
+            launcher: None,
+            pidfile: None,
+            notifier,
+            status: String::new(),
+            inherited_ctrl_sock: false,
+            child_pipe,
+            ctrl_socks: CtrlSocks::default(),
+            http: None,
+            metrics: None,
+            reporters: Reporters::default(),
+            auth: None,
+            state: State::default(),
+            handover: None,
+            started_at: time::Instant::now(),
+            respawns: HashMap::new(),
+            stopping: HashMap::new(),
+            restarting: HashMap::new(),
+            queries: Pool::new("query", QUERY_THREADS),
+            answers: channel(),
+            querying: HashSet::new(),
+        }
+    }
+
+    pub fn set_launcher(&mut self, launcher: Launcher) {
+        self.launcher = Some(launcher);
+    }
+
+    pub fn set_handover(&mut self, handover: Handover) {
+        self.handover = Some(handover);
+    }
+
+    fn notify_status(&mut self) {
+        let status = format!(
+            "{} of {} workers running",
+            self.monitors.len(),
+            self.config.workers.len()
+        );
+        if status != self.status {
+            if let Some(ref notifier) = self.notifier {
+                notifier.status(&status);
+            }
+            self.status = status;
         }
     }
 
@@ -53,59 +266,169 @@ impl Daemon {
         self.pid == getpid()
     }
 
-    fn listen_ctrl_sock(path: &str) -> Result<UnixListener, Error> {
-        let listen_fd: ListenFd = path.parse().unwrap();
+    fn listen_ctrl_sock(path: &str, permissions: &SockPermissions) -> Result<CtrlListener, Error> {
+        let listen_fd: ListenFd = path.parse()?;
         let pid = getpid();
         match listen_fd {
-            ListenFd::UnixListener(_) => {
-                let raw_fd = listen_fd.create_raw_fd(1)?;
+            ListenFd::UnixListener(_) | ListenFd::TcpListener(_) => {
+                let raw_fd = listen_fd.get_raw_fd(1, &SockOptions::default())?;
+                if let ListenFd::UnixListener(ref path) = listen_fd {
+                    // the socket unit of systemd sets the mode of an inherited socket
+                    if listen_fd.inherited_raw_fd().is_none() {
+                        permissions.apply(path)?;
+                    }
+                }
                 info!(
                     "listen control socket {}. pid [{}]",
                     listen_fd.describe_raw_fd(raw_fd)?,
                     pid,
                 );
-                let listener: UnixListener = unsafe { UnixListener::from_raw_fd(raw_fd) };
+                let listener = if let ListenFd::TcpListener(_) = listen_fd {
+                    CtrlListener::Tcp(unsafe { TcpListener::from_raw_fd(raw_fd) })
+                } else {
+                    CtrlListener::Unix(unsafe { UnixListener::from_raw_fd(raw_fd) })
+                };
                 Ok(listener)
             }
             _ => Err(err_msg(format!("{:?} not support", listen_fd))),
         }
     }
 
-    fn send_command_worker(
-        &mut self,
-        cmd: DaemonCommand,
-        stream: &mut UnixStream,
-    ) -> io::Result<()> {
-        if let Some(name) = cmd.worker {
-            if let Some(config) = self.config.workers.get(&name) {
-                let sock_path = config.control_sock(&name);
-                let res = send_ctrl_command(&sock_path, &cmd.command.unwrap())?;
-                let buf = serde_json::to_string(&res)?;
-                stream.write_all(buf.as_bytes())?;
-                stream.write_all(b"\n")?;
-                stream.flush()?;
-            }
+    /// Removes the control socket left by a crashed daemon. It fails when a daemon is running.
+    fn check_ctrl_sock(addr: &str) -> Result<(), Error> {
+        let listen_fd: ListenFd = addr.parse()?;
+        if listen_fd.inherited_raw_fd().is_some() {
+            return Ok(());
         }
+        let path = match listen_fd {
+            ListenFd::UnixListener(ref path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        if UnixStream::connect(path).is_ok() {
+            return Err(err_msg(format!(
+                "daemon already running. control socket {:?}",
+                path
+            )));
+        }
+        warn!("remove stale control socket {:?}. pid [{}]", path, getpid());
+        fs::remove_file(path)?;
         Ok(())
     }
 
-    fn send_command_workers(
-        &mut self,
-        cmd: DaemonCommand,
-        stream: &mut UnixStream,
-    ) -> io::Result<()> {
-        let cmd = &cmd.command.unwrap();
-        let mut v = Vec::new();
-        for (name, config) in &self.config.workers {
-            let sock_path = config.control_sock(name);
-            let res = send_ctrl_command(&sock_path, cmd)?;
-            v.push(res);
+    fn listen_http(addr: &str) -> Result<TcpListener, Error> {
+        let listen_fd: ListenFd = addr.parse()?;
+        match listen_fd {
+            ListenFd::TcpListener(_) => {
+                let raw_fd = listen_fd.get_raw_fd(128, &SockOptions::default())?;
+                info!(
+                    "listen http {}. pid [{}]",
+                    listen_fd.describe_raw_fd(raw_fd)?,
+                    getpid(),
+                );
+                Ok(unsafe { TcpListener::from_raw_fd(raw_fd) })
+            }
+            _ => Err(err_msg(format!("http {:?} not support", listen_fd))),
+        }
+    }
+
+    fn drain_child_pipe(&self) {
+        let mut buf = [0u8; 64];
+        while let Ok(size) = read(self.child_pipe.0, &mut buf) {
+            if size == 0 {
+                break;
+            }
+        }
+    }
+
+    fn reopen_logs(&mut self) {
+        let pid = pid_t::from(getpid());
+        for name in self.monitors.keys() {
+            let cmd = CtrlCommand::new(Command::ReopenLogs, pid as u32);
+            self.post_command(name, cmd, Some(QUERY_TIMEOUT_SECS));
+        }
+    }
+
+    /// Sends the command to the monitor on the query pool, and logs a failure.
+    /// The main loop goes on while the monitor works on it.
+    fn post_command(&self, name: &str, cmd: CtrlCommand, timeout: Option<u64>) {
+        let sock_path = match self.config.workers.get(name) {
+            Some(config) => config.control_sock(name),
+            None => return,
+        };
+        let name = name.to_owned();
+        let pid = self.pid;
+        self.queries.execute(move || {
+            let timeout = timeout.map(time::Duration::from_secs);
+            let message = match send_ctrl_command_timeout(&sock_path, &cmd, timeout) {
+                Ok(res) => match res.status {
+                    Status::Ok => return,
+                    _ => res.message,
+                },
+                Err(e) => e.to_string(),
+            };
+            warn!(
+                "fail {:?} [{}]. caused by: {} pid [{}]",
+                cmd.command, name, message, pid
+            );
+        });
+    }
+
+    /// Asks the monitor for the stats of its worker on the query pool, unless the last
+    /// query is not answered yet. The answer is taken by `check_answers`.
+    fn query_stats(&mut self, name: &str) {
+        let sock_path = match self.config.workers.get(name) {
+            Some(config) => config.control_sock(name),
+            None => return,
+        };
+        if !self.querying.insert(name.to_owned()) {
+            return;
+        }
+        let cmd = CtrlCommand::new(Command::Metrics, pid_t::from(self.pid) as u32);
+        let answers = self.answers.0.clone();
+        let name = name.to_owned();
+        self.queries.execute(move || {
+            let timeout = time::Duration::from_secs(QUERY_TIMEOUT_SECS);
+            let stats = send_ctrl_command_timeout(&sock_path, &cmd, Some(timeout))
+                .ok()
+                .and_then(|res| {
+                    let stats = serde_json::from_str::<WorkerStats>(&res.message).ok()?;
+                    Some((res.pid, stats))
+                });
+            // the daemon owns the receiver
+            let _ = answers.send(Answer { name, stats });
+        });
+    }
+
+    /// Takes the answers of `query_stats`. The answers of a monitor that is gone are dropped.
+    fn check_answers(&mut self) {
+        while let Ok(answer) = self.answers.1.try_recv() {
+            self.querying.remove(&answer.name);
+            let (pid, stats) = match answer.stats {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let current = self
+                .monitors
+                .get(&answer.name)
+                .and_then(|m| m.pid)
+                .map_or(false, |p| pid_t::from(p) as u32 == pid);
+            if !current {
+                continue;
+            }
+            if let Some(restarting) = self.restarting.get_mut(&answer.name) {
+                restarting.pids = Some(stats.pids.clone());
+            }
+            if self.config.state_file.is_some() {
+                let worker = self
+                    .state
+                    .workers
+                    .entry(answer.name)
+                    .or_insert_with(WorkerState::default);
+                worker.pids = stats.pids;
+                worker.generation = stats.upgrades;
+                worker.restarts = stats.restarts;
+            }
         }
-        let buf = serde_json::to_string(&v)?;
-        stream.write_all(buf.as_bytes())?;
-        stream.write_all(b"\n")?;
-        stream.flush()?;
-        Ok(())
     }
 
     fn check_upgrade(&mut self) -> io::Result<()> {
@@ -115,7 +438,7 @@ impl Daemon {
                 if monitor.is_upgrade_active_time(timeout) {
                     if let Some(ref upgrader) = config.upgrader {
                         if monitor.upgrade_process.is_none() {
-                            let mut proc = run_upgrader(upgrader)?;
+                            let mut proc = run_upgrader(upgrader, &config.working_directory)?;
                             monitor.upgrade_process = Some(proc);
                         }
                     }
@@ -129,6 +452,7 @@ impl Daemon {
 
     fn check_cmd_modified(&mut self) -> io::Result<()> {
         let pid = getpid();
+        let mut upgrades = Vec::new();
         for (name, monitor) in &mut self.monitors {
             let config = &self.config.workers[name];
             if config.auto_upgrade {
@@ -136,29 +460,22 @@ impl Daemon {
                     reloader::is_modified_cmd(&config, &monitor.cmd_path, &monitor.cmd_mtime)?;
                 if modified {
                     info!("program upgrade detected. start upgrade. pid [{}]", pid);
-                    // start upgrade
-                    let upgrade_cmd = CtrlCommand {
-                        command: Command::Upgrade,
-                        pid: pid_t::from(self.pid) as u32,
-                        signal: None,
-                    };
-                    let sock_path = config.control_sock(&name);
-                    let res = send_ctrl_command(&sock_path, &upgrade_cmd)?;
-                    let _buf = serde_json::to_string(&res)?;
-
-                    let cmd_path = reloader::cmd_path(config);
-                    let metadata = cmd_path.metadata()?;
-                    let cmd_mtime = metadata.modified()?;
+                    let (cmd_path, cmd_mtime) = reloader::cmd_stat(config)?;
                     monitor.cmd_path = cmd_path;
                     monitor.cmd_mtime = cmd_mtime;
+                    upgrades.push(name.to_owned());
                 }
             }
         }
+        // start upgrade. the monitor answers when the upgrade is done
+        for name in upgrades {
+            let upgrade_cmd = CtrlCommand::new(Command::Upgrade, pid_t::from(self.pid) as u32);
+            self.post_command(&name, upgrade_cmd, None);
+        }
         Ok(())
     }
 
-    pub fn wait(&mut self, listener: &UnixListener) -> io::Result<()> {
-        let timeout = time::Duration::from_secs(1);
+    pub fn wait(&mut self, listener: &CtrlListener) -> io::Result<()> {
         let poll = Poll::new().unwrap();
         let ctrl_fd: RawFd = listener.as_raw_fd();
         let listen_token = Token(1);
@@ -168,30 +485,202 @@ impl Daemon {
             Ready::readable(),
             PollOpt::edge(),
         )?;
+        let child_token = Token(2);
+        poll.register(
+            &EventedFd(&self.child_pipe.0),
+            child_token,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        // connections are handled on threads. state changes come back through the channel
+        listener.set_nonblocking(true)?;
+        let (tx, rx) = channel::<CtrlRequest>();
+        let (registration, waker) = Registration::new2();
+        let request_token = Token(3);
+        poll.register(
+            &registration,
+            request_token,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        let max_connections = self.config.control_max_connections as usize;
+        // unlimited connections wait for a thread
+        let threads = if max_connections > 0 {
+            max_connections
+        } else {
+            UNLIMITED_CONNECTION_THREADS
+        };
+        let handler = CtrlHandler::new(
+            self.ctrl_socks.clone(),
+            tx,
+            waker.clone(),
+            self.reporters.audit.clone(),
+            self.auth.clone(),
+            Limiter::new(max_connections, self.config.control_rate_limit),
+            Pool::new("control", threads),
+        );
+        let http_listener = self.http.take();
+        let http_token = Token(4);
+        if let Some(ref http) = http_listener {
+            http.set_nonblocking(true)?;
+            poll.register(
+                &EventedFd(&http.as_raw_fd()),
+                http_token,
+                Ready::readable(),
+                PollOpt::edge(),
+            )?;
+        }
+        let metrics_listener = self.metrics.take();
+        let metrics_token = Token(5);
+        if let Some(ref metrics) = metrics_listener {
+            metrics.set_nonblocking(true)?;
+            poll.register(
+                &EventedFd(&metrics.as_raw_fd()),
+                metrics_token,
+                Ready::readable(),
+                PollOpt::edge(),
+            )?;
+        }
 
         // start loop
         let mut now = time::SystemTime::now();
+        let mut saved_at = time::SystemTime::now();
         let mut events = Events::with_capacity(128);
-        while !self.monitors.is_empty() {
-            if let Err(e) = poll.poll_interruptible(&mut events, Some(timeout)) {
+        while self.has_workers() {
+            let timeout = if self.stopping.is_empty() && self.restarting.is_empty() {
+                time::Duration::from_secs(1)
+            } else {
+                time::Duration::from_millis(PENDING_POLL_MILLIS)
+            };
+            let res = poll.poll_interruptible(&mut events, Some(timeout));
+            let reload = RELOAD_REQUESTED.swap(false, Ordering::SeqCst);
+            if reload {
+                info!("catch SIGHUP. pid [{}]", self.pid);
+                // nobody is there to answer. the running config is kept
+                if let Err(e) = self.reload() {
+                    error!(
+                        "fail reload config. keep the running config. caused by: {} pid [{}]",
+                        e, self.pid
+                    );
+                }
+            }
+            let reopen = REOPEN_REQUESTED.swap(false, Ordering::SeqCst);
+            if reopen {
+                info!("catch SIGUSR1. pid [{}]", self.pid);
+                self.reopen_logs();
+            }
+            let child = CHILD_EXITED.swap(false, Ordering::SeqCst);
+            if (reload || reopen || child) && res.is_err() {
+                continue;
+            }
+            if let Err(e) = res {
                 // Interrupt
                 debug!("interrupt main loop. caused by: {} pid [{}]", e, self.pid);
                 self.clean_process();
                 return Ok(());
             }
+            if let Some(ref mut notifier) = self.notifier {
+                notifier.watchdog();
+            }
             for event in &events {
                 let token = event.token();
                 if listen_token == token {
-                    let (mut stream, _addr) = listener.accept()?;
-                    let cmd = read_daemon_command(&mut stream)?;
-                    match cmd.command_type {
-                        CommandType::CtrlWorker => self.send_command_worker(cmd, &mut stream)?,
-                        CommandType::List => self.send_list(&mut stream)?,
-                        CommandType::Status => self.send_command_workers(cmd, &mut stream)?,
+                    loop {
+                        match listener.accept() {
+                            Ok(stream) => handler.spawn(stream),
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                warn!("fail accept. caused by: {} pid [{}]", e, self.pid);
+                                break;
+                            }
+                        }
+                    }
+                } else if http_token == token {
+                    if let Some(ref http) = http_listener {
+                        loop {
+                            match http.accept() {
+                                Ok((stream, _addr)) => {
+                                    stream.set_nonblocking(false)?;
+                                    http::serve(&handler, stream);
+                                }
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) => {
+                                    warn!("fail accept. caused by: {} pid [{}]", e, self.pid);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                } else if metrics_token == token {
+                    if let Some(ref metrics) = metrics_listener {
+                        loop {
+                            match metrics.accept() {
+                                Ok((stream, _addr)) => {
+                                    stream.set_nonblocking(false)?;
+                                    http::serve_metrics(&handler, stream);
+                                }
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) => {
+                                    warn!("fail accept. caused by: {} pid [{}]", e, self.pid);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                } else if request_token == token {
+                    waker.set_readiness(Ready::empty())?;
+                    while let Ok(mut req) = rx.try_recv() {
+                        let res = match req.cmd.command_type {
+                            CommandType::Shutdown => {
+                                let timeout = req.cmd.timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+                                self.send_shutdown(timeout, &mut req);
+                                return Ok(());
+                            }
+                            CommandType::Upexec => {
+                                let mut fds = vec![ctrl_fd];
+                                fds.extend(http_listener.iter().map(|l| l.as_raw_fd()));
+                                fds.extend(metrics_listener.iter().map(|l| l.as_raw_fd()));
+                                let binary = req.cmd.binary.take();
+                                self.send_upexec(binary, &fds, &mut req)
+                            }
+                            CommandType::StartWorker => self.send_start_worker(&mut req),
+                            CommandType::StopWorker | CommandType::DrainWorker => {
+                                self.send_stop_worker(req)
+                            }
+                            CommandType::RestartWorker => self.send_restart_worker(req),
+                            CommandType::RollbackWorker => self.send_rollback_worker(&mut req),
+                            CommandType::Apply => self.send_apply(&mut req),
+                            CommandType::Ping => self.send_ping(&mut req),
+                            CommandType::Reload => self.send_reload(&mut req),
+                            // answered on the connection threads
+                            CommandType::List | CommandType::Status | CommandType::CtrlWorker => {
+                                let msg = format!(
+                                    "unexpected command {}",
+                                    command_name(&req.cmd.command_type)
+                                );
+                                write_frame(&mut req.stream, MessageType::Error, &msg)
+                            }
+                        };
+                        // the client hung up. the daemon goes on
+                        if let Err(e) = res {
+                            warn!("fail send response. caused by: {} pid [{}]", e, self.pid);
+                        }
                     }
+                } else if child_token == token {
+                    self.drain_child_pipe();
+                    self.check_stopping();
+                    self.check_monitor_processes();
+                    self.notify_status();
                 }
             }
 
+            self.check_answers();
+            if !self.stopping.is_empty() {
+                self.check_stopping();
+            }
+            if !self.restarting.is_empty() {
+                self.check_restarting();
+            }
             // check every 1sec
             if let Ok(elapsed) = now.elapsed() {
                 if elapsed.as_secs() >= 1 {
@@ -204,12 +693,22 @@ impl Daemon {
                     if let Err(e) = self.check_upgrader_process() {
                         warn!("fail check upgrader process. caused by: {}", e);
                     }
-                    if let Err(e) = self.check_monitor_processes() {
-                        warn!("fail check monitor process. caused by: {}", e);
+                    // the exit of an orphan monitor is not notified by SIGCHLD,
+                    // and the exited monitors are respawned after the delay
+                    if !self.respawns.is_empty() || self.monitors.values().any(|m| m.orphan) {
+                        self.check_monitor_processes();
                     }
+                    self.notify_status();
                     now = time::SystemTime::now();
                 }
             }
+            if let Ok(elapsed) = saved_at.elapsed() {
+                if elapsed.as_secs() >= STATE_SAVE_INTERVAL_SECS {
+                    self.refresh_state();
+                    self.save_state();
+                    saved_at = time::SystemTime::now();
+                }
+            }
         }
         self.clean_process();
         info!("exited daemon. pid [{}]", self.pid);
@@ -226,6 +725,7 @@ impl Daemon {
 
     fn check_upgrader_process(&mut self) -> io::Result<()> {
         let mut need_clean = Vec::new();
+        let mut upgrades = Vec::new();
         for (name, monitor) in &mut self.monitors {
             if let Some(ref mut p) = monitor.upgrade_process {
                 let config = &self.config.workers[name];
@@ -236,15 +736,8 @@ impl Daemon {
                             "upgrade process terminated successfully. start upgrade pid [{}]",
                             p.id()
                         );
-                        let upgrade_cmd = CtrlCommand {
-                            command: Command::Upgrade,
-                            pid: pid_t::from(self.pid) as u32,
-                            signal: None,
-                        };
                         monitor.upgrade_active_time = time::SystemTime::now();
-                        let sock_path = config.control_sock(&name);
-                        let res = send_ctrl_command(&sock_path, &upgrade_cmd)?;
-                        let _buf = serde_json::to_string(&res)?;
+                        upgrades.push(name.to_owned());
                         need_clean.push(name.to_owned());
                     }
                     Ok(false) => {
@@ -276,6 +769,10 @@ impl Daemon {
             }
         }
 
+        for name in upgrades {
+            let upgrade_cmd = CtrlCommand::new(Command::Upgrade, pid_t::from(self.pid) as u32);
+            self.post_command(&name, upgrade_cmd, None);
+        }
         self.clean_upgrade_process(need_clean);
         Ok(())
     }
@@ -283,11 +780,16 @@ impl Daemon {
     fn check_monitors(&mut self) -> Vec<String> {
         let mut exit_keys: Vec<String> = Vec::new();
         let mut restart_keys: Vec<String> = Vec::new();
+        let mut giveup_keys: Vec<String> = Vec::new();
         for (name, monitor) in &mut self.monitors {
             match monitor.try_wait() {
                 Ok(ExitStatus::Interrupt) => {
                     exit_keys.push(name.to_owned());
                 }
+                Ok(ExitStatus::Giveup) => {
+                    exit_keys.push(name.to_owned());
+                    giveup_keys.push(name.to_owned());
+                }
                 Ok(ExitStatus::ForceExit) => {
                     exit_keys.push(name.to_owned());
                 }
@@ -309,6 +811,12 @@ impl Daemon {
                 m.remove_ctrl_sock();
             }
         }
+        if !giveup_keys.is_empty() {
+            for key in giveup_keys {
+                self.state.workers.entry(key).or_default().gave_up = true;
+            }
+            self.save_state();
+        }
         for key in &restart_keys {
             if let Some(ref mut m) = self.monitors.remove(key) {
                 m.remove_ctrl_sock();
@@ -317,79 +825,1161 @@ impl Daemon {
         restart_keys
     }
 
-    fn check_monitor_processes(&mut self) -> Result<(), Error> {
-        let timeout = time::Duration::from_millis(500);
-        let restarts = self.check_monitors();
-        for name in &restarts {
-            if let Some(config) = self.config.workers.get(name) {
-                info!("wait respawn monitor process [{}]", name);
-                thread::sleep(timeout);
-                let mut monitor = MonitorProcess::new(name, config);
-                if monitor.spawn(name, config)? {
-                    self.monitors.insert(name.to_owned(), monitor);
+    /// Respawns the exited monitors after `restart_delay_sec`, without blocking the main loop.
+    fn check_monitor_processes(&mut self) {
+        let now = time::Instant::now();
+        for name in self.check_monitors() {
+            if let Some(config) = self.config.workers.get(&name) {
+                let delay = config.restart_delay();
+                info!(
+                    "wait respawn monitor process [{}] {:?}. pid [{}]",
+                    name, delay, self.pid
+                );
+                self.respawns.insert(name, now + delay);
+            }
+        }
+        let due: Vec<String> = self
+            .respawns
+            .iter()
+            .filter(|&(_, at)| *at <= now)
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        for name in &due {
+            self.respawns.remove(name);
+            // started, stopped or removed while waiting
+            if self.monitors.contains_key(name)
+                || self.stopping.contains_key(name)
+                || self.is_stopped(name)
+                || !self.config.workers.contains_key(name)
+            {
+                continue;
+            }
+            if let Err(e) = self.spawn_monitor(name) {
+                warn!(
+                    "fail spawn monitor [{}]. caused by: {} pid [{}]",
+                    name, e, self.pid
+                );
+            }
+        }
+    }
+
+    fn spawn_monitor(&mut self, name: &str) -> Result<(), Error> {
+        if let Some(config) = self.config.workers.get(name) {
+            let mut monitor = MonitorProcess::new(name, config)?;
+            monitor.sock_permissions = self.config.control_sock_permissions.clone();
+            monitor.spawn(
+                name,
+                config,
+                &self.reporters,
+                self.auth.as_ref(),
+                self.state.workers.get(name),
+            )?;
+            if let Some(state) = self.state.workers.get_mut(name) {
+                state.gave_up = false;
+            }
+            self.monitors.insert(name.to_owned(), monitor);
+        }
+        Ok(())
+    }
+
+    fn adopt_monitor(&mut self, name: &str, pid: u32, orphan: bool) -> Result<(), Error> {
+        if let Some(config) = self.config.workers.get(name) {
+            let mut monitor = MonitorProcess::new(name, config)?;
+            monitor.pid = Some(Pid::from_raw(pid as pid_t));
+            monitor.orphan = orphan;
+            if orphan {
+                info!(
+                    "re-attach [{}] monitor of the last run. pid [{}]",
+                    name, pid
+                );
+            } else {
+                info!("adopt [{}] monitor. pid [{}]", name, pid);
+            }
+            self.monitors.insert(name.to_owned(), monitor);
+        }
+        Ok(())
+    }
+
+    /// Finds the monitor that survived a crash of the daemon through its control socket.
+    fn discover_monitor(&self, name: &str) -> Option<u32> {
+        let sock_path = self.config.workers[name].control_sock(name);
+        if !unix_sock_exists(path::Path::new(&sock_path)) {
+            return None;
+        }
+        let cmd = CtrlCommand::new(Command::Metrics, pid_t::from(self.pid) as u32);
+        match send_ctrl_command(&sock_path, &cmd) {
+            Ok(res) => Some(res.pid),
+            // an abstract socket is gone with the monitor
+            Err(_) if is_abstract(path::Path::new(&sock_path)) => None,
+            Err(e) => {
+                // nobody listens. the monitor exited with the daemon
+                warn!(
+                    "remove stale control socket {}. caused by: {} pid [{}]",
+                    sock_path, e, self.pid
+                );
+                if let Err(e) = fs::remove_file(&sock_path) {
+                    warn!(
+                        "fail remove control socket. caused by: {} pid [{}]",
+                        e, self.pid
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// Warns about the processes in the state file that are not re-attached.
+    fn warn_stale_processes(&self, name: &str) {
+        let worker = match self.state.workers.get(name) {
+            Some(worker) => worker,
+            None => return,
+        };
+        for pid in worker.monitor_pid.iter().chain(worker.pids.iter()) {
+            // signal 0 only checks the existence
+            if signal::kill(Pid::from_raw(*pid as pid_t), None).is_ok() {
+                warn!(
+                    "process of [{}] from the last run may be still running. pid [{}]",
+                    name, pid
+                );
+            }
+        }
+    }
+
+    /// Signals the monitor to stop. It is reaped by `check_stopping` in the main loop, and
+    /// killed when it is alive after `timeout`.
+    fn stop_monitor(&mut self, name: &str, timeout: u64, respawn: bool) {
+        if let Some(stopping) = self.stopping.get_mut(name) {
+            stopping.respawn = respawn;
+            return;
+        }
+        if let Some(mut monitor) = self.monitors.remove(name) {
+            if let Some(pid) = monitor.pid {
+                info!("stop monitor [{}]. pid [{}]", name, pid);
+            }
+            monitor.signal(Signal::SIGINT);
+            let stopping = Stopping {
+                monitor,
+                kill_at: time::Instant::now() + time::Duration::from_secs(timeout),
+                killed: false,
+                respawn,
+                req: None,
+            };
+            self.stopping.insert(name.to_owned(), stopping);
+        }
+    }
+
+    /// Reaps the stopping monitors, and kills the ones alive after the deadline. The
+    /// monitors of restart and a changed config are respawned through `respawns`.
+    fn check_stopping(&mut self) {
+        let now = time::Instant::now();
+        let mut exited = Vec::new();
+        for (name, stopping) in &mut self.stopping {
+            match stopping.monitor.try_wait() {
+                Ok(ExitStatus::StillAlive) => {}
+                Ok(_) | Err(_) => {
+                    exited.push(name.to_owned());
+                    continue;
+                }
+            }
+            if now >= stopping.kill_at && !stopping.killed {
+                if let Some(pid) = stopping.monitor.pid {
+                    warn!("no reaction. kill monitor [{}]. pid [{}]", name, pid);
+                }
+                stopping.monitor.signal(Signal::SIGKILL);
+                stopping.killed = true;
+            }
+        }
+        if exited.is_empty() {
+            return;
+        }
+        for name in exited {
+            let stopping = match self.stopping.remove(&name) {
+                Some(stopping) => stopping,
+                None => continue,
+            };
+            stopping.monitor.remove_ctrl_sock();
+            info!("stopped [{}] monitor. pid [{}]", name, self.pid);
+            if stopping.respawn {
+                self.respawns.insert(name.to_owned(), now);
+            }
+            if let Some(mut req) = stopping.req {
+                if stopping.respawn {
+                    let deadline = now + time::Duration::from_secs(RESTART_WAIT_SECS);
+                    let restarting = Restarting {
+                        req,
+                        deadline,
+                        pids: None,
+                    };
+                    self.restarting.insert(name, restarting);
+                } else {
+                    let res = worker_response(Command::Stop, Ok(format!("[{}] stopped", name)));
+                    if let Err(e) = req.respond(self.reporters.audit.as_ref(), &res) {
+                        warn!("fail send response. caused by: {} pid [{}]", e, self.pid);
+                    }
                 }
             }
         }
+        self.check_monitor_processes();
+        self.notify_status();
+    }
+
+    /// Answers the restarts when the new monitor has processes, or after RESTART_WAIT_SECS.
+    /// The processes are asked on the query pool.
+    fn check_restarting(&mut self) {
+        let now = time::Instant::now();
+        let names: Vec<String> = self.restarting.keys().cloned().collect();
+        for name in names {
+            let deadline = self.restarting[&name].deadline;
+            let pids = self.restarting[&name].pids.clone();
+            let res = match self.monitors.get(&name).and_then(|m| m.pid) {
+                Some(pid) => match pids {
+                    Some(ref pids) if !pids.is_empty() => Ok(format!(
+                        "[{}] restarted. pid [{}] processes {:?}",
+                        name, pid, pids
+                    )),
+                    _ if now < deadline => {
+                        self.query_stats(&name);
+                        continue;
+                    }
+                    Some(pids) => Ok(format!(
+                        "[{}] restarted. pid [{}] processes {:?}",
+                        name, pid, pids
+                    )),
+                    None => Ok(format!("[{}] restarted. pid [{}]", name, pid)),
+                },
+                // not respawned yet
+                None if now < deadline && self.respawns.contains_key(&name) => continue,
+                None => Err(err_msg(format!("fail start [{}]", name))),
+            };
+            if let Some(mut restarting) = self.restarting.remove(&name) {
+                let res = worker_response(Command::Start, res);
+                if let Err(e) = restarting.req.respond(self.reporters.audit.as_ref(), &res) {
+                    warn!("fail send response. caused by: {} pid [{}]", e, self.pid);
+                }
+            }
+        }
+    }
+
+    /// Answers the stops and restarts still waiting, when the daemon exits before them.
+    /// The stopping monitors are stopped with the rest.
+    fn abort_pending(&mut self) {
+        let audit = self.reporters.audit.clone();
+        let mut reqs = Vec::new();
+        for (name, stopping) in self.stopping.drain() {
+            reqs.extend(stopping.req.map(|req| (name.to_owned(), req)));
+            self.monitors.insert(name, stopping.monitor);
+        }
+        for (name, restarting) in self.restarting.drain() {
+            reqs.push((name, restarting.req));
+        }
+        for (name, mut req) in reqs {
+            let msg = format!("[{}] interrupted by shutdown", name);
+            let res = worker_response(Command::Stop, Err(err_msg(msg)));
+            if let Err(e) = req.respond(audit.as_ref(), &res) {
+                warn!("fail send response. caused by: {} pid [{}]", e, self.pid);
+            }
+        }
+    }
+
+    fn is_stopped(&self, name: &str) -> bool {
+        self.state.workers.get(name).map_or(false, |s| s.stopped)
+    }
+
+    /// The daemon keeps running while a stopped or gave up worker can be started again.
+    fn has_workers(&self) -> bool {
+        !self.monitors.is_empty()
+            || !self.respawns.is_empty()
+            || !self.stopping.is_empty()
+            || self.config.workers.keys().any(|name| {
+                self.is_stopped(name) || self.state.workers.get(name).map_or(false, |s| s.gave_up)
+            })
+    }
+
+    /// Stops the monitor and the processes of the worker. The config is kept,
+    /// and the worker stays down over reloads until it is started.
+    fn stop_worker(&mut self, name: &str, timeout: u64) -> Result<(), Error> {
+        if !self.config.workers.contains_key(name) {
+            return Err(err_msg(format!("unknown worker [{}]", name)));
+        }
+        if self.stopping.contains_key(name) {
+            return Err(err_msg(format!("[{}] is stopping", name)));
+        }
+        if !self.monitors.contains_key(name) {
+            return Err(err_msg(format!("[{}] is not running", name)));
+        }
+        self.refresh_state();
+        info!("stop [{}] worker. pid [{}]", name, self.pid);
+        self.stop_monitor(name, timeout, false);
+        self.state.workers.entry(name.to_owned()).or_default().stopped = true;
+        self.save_state();
+        self.notify_status();
         Ok(())
     }
 
+    /// Runs the binary of the worker before the last upgrade again. The configured command
+    /// is taken as seen, so that auto upgrade does not upgrade it back right away.
+    fn rollback_worker(&mut self, name: &str, cmd: &CtrlCommand) -> Result<CommandResponse, Error> {
+        let config = match self.config.workers.get(name) {
+            Some(config) => config,
+            None => return Err(err_msg(format!("unknown worker [{}]", name))),
+        };
+        let monitor = match self.monitors.get_mut(name) {
+            Some(monitor) => monitor,
+            None => return Err(err_msg(format!("[{}] is not running", name))),
+        };
+        info!("rollback [{}] worker. pid [{}]", name, self.pid);
+        let res = send_ctrl_command(&config.control_sock(name), cmd)?;
+        if let Status::Ok = res.status {
+            let (cmd_path, cmd_mtime) = reloader::cmd_stat(config)?;
+            monitor.cmd_path = cmd_path;
+            monitor.cmd_mtime = cmd_mtime;
+        }
+        Ok(res)
+    }
+
+    /// Stops the worker if it is running and starts it again. A stopped worker is started.
+    /// A running worker is respawned by the main loop after the monitor has exited.
+    fn restart_worker(&mut self, name: &str, timeout: u64) -> Result<(), Error> {
+        if !self.config.workers.contains_key(name) {
+            return Err(err_msg(format!("unknown worker [{}]", name)));
+        }
+        if self.stopping.contains_key(name) {
+            return Err(err_msg(format!("[{}] is stopping", name)));
+        }
+        self.refresh_state();
+        info!("restart [{}] worker. pid [{}]", name, self.pid);
+        if let Some(state) = self.state.workers.get_mut(name) {
+            state.stopped = false;
+        }
+        if self.monitors.contains_key(name) {
+            self.stop_monitor(name, timeout, true);
+        } else {
+            self.spawn_monitor(name)?;
+        }
+        self.save_state();
+        self.notify_status();
+        Ok(())
+    }
+
+    /// Waits until the workers of depends_on are running, or completed for oneshot. The worker
+    /// is started anyway after depends_on_timeout_sec, as the dependencies may come up later.
+    fn wait_dependencies(&mut self, name: &str) {
+        let config = &self.config.workers[name];
+        let timeout = time::Duration::from_secs(config.depends_on_timeout_sec);
+        let cmd = CtrlCommand::new(Command::Status, pid_t::from(self.pid) as u32);
+        let now = time::Instant::now();
+        let delay = time::Duration::from_millis(100);
+        for dep in &config.depends_on {
+            if !self.monitors.contains_key(dep) {
+                warn!(
+                    "[{}] depends on [{}] which is not started. pid [{}]",
+                    name, dep, self.pid
+                );
+                continue;
+            }
+            // a oneshot worker is a job to complete before the start
+            let ready = match self.config.workers[dep].kind {
+                _ if self.config.workers[dep].schedule.is_some() => RunState::Scheduled,
+                WorkerKind::Oneshot => RunState::Completed,
+                WorkerKind::Service => RunState::Running,
+            };
+            info!(
+                "[{}] waits [{}] to be {}. pid [{}]",
+                name, dep, ready, self.pid
+            );
+            let sock_path = self.config.workers[dep].control_sock(dep);
+            loop {
+                let reached = send_ctrl_command(&sock_path, &cmd)
+                    .ok()
+                    .and_then(|res| serde_json::from_str::<WorkerStatus>(&res.message).ok())
+                    .map_or(false, |status| status.state == ready);
+                if reached {
+                    break;
+                }
+                if now.elapsed() >= timeout {
+                    warn!(
+                        "[{}] is not {} in {} secs. start [{}] anyway. pid [{}]",
+                        dep, ready, config.depends_on_timeout_sec, name, self.pid
+                    );
+                    break;
+                }
+                // the wait for the dependencies can be longer than the watchdog interval
+                if let Some(ref mut notifier) = self.notifier {
+                    notifier.watchdog();
+                }
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    fn start_worker(&mut self, name: &str) -> Result<String, Error> {
+        if !self.config.workers.contains_key(name) {
+            return Err(err_msg(format!("unknown worker [{}]", name)));
+        }
+        if let Some(pid) = self.monitors.get(name).and_then(|m| m.pid) {
+            if self.config.workers[name].kind == WorkerKind::Oneshot {
+                return self.rerun_worker(name);
+            }
+            return Err(err_msg(format!(
+                "[{}] is already running. pid [{}]",
+                name, pid
+            )));
+        }
+        if self.stopping.contains_key(name) {
+            return Err(err_msg(format!("[{}] is stopping", name)));
+        }
+        info!("start [{}] worker. pid [{}]", name, self.pid);
+        if let Some(state) = self.state.workers.get_mut(name) {
+            state.stopped = false;
+        }
+        self.spawn_monitor(name)?;
+        self.save_state();
+        self.notify_status();
+        match self.monitors.get(name).and_then(|m| m.pid) {
+            Some(pid) => Ok(format!("[{}] started. pid [{}]", name, pid)),
+            None => Err(err_msg(format!("fail start [{}]", name))),
+        }
+    }
+
+    /// Runs the processes of a oneshot worker again. The monitor stays up after a run.
+    fn rerun_worker(&mut self, name: &str) -> Result<String, Error> {
+        info!("run [{}] worker again. pid [{}]", name, self.pid);
+        let cmd = CtrlCommand::new(Command::Start, pid_t::from(self.pid) as u32);
+        let sock_path = self.config.workers[name].control_sock(name);
+        let res = send_ctrl_command(&sock_path, &cmd)?;
+        match res.status {
+            Status::Ok => Ok(format!("[{}] {}", name, res.message)),
+            _ => Err(err_msg(res.message.trim_start_matches("error: ").to_owned())),
+        }
+    }
+
+    pub fn reload(&mut self) -> Result<Vec<String>, Error> {
+        info!("reload config {}. pid [{}]", self.config.path, self.pid);
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify("RELOADING=1");
+        }
+        let res = self.reload_config();
+        if let Some(ref notifier) = self.notifier {
+            notifier.ready();
+        }
+        self.notify_status();
+        res
+    }
+
+    fn reload_config(&mut self) -> Result<Vec<String>, Error> {
+        let profile = self.config.profile.as_ref().map(|p| p.as_str());
+        let config = Config::from_file(&self.config.path, profile)?;
+        let config = self.keep_daemon_settings(config);
+        self.apply_config(config)
+    }
+
+    /// Applies a config sent over the control socket in the same way as a reload.
+    /// The config file is not written, so the next reload reads it again.
+    pub fn apply(&mut self, source: &ConfigSource) -> Result<Vec<String>, Error> {
+        let profile = source
+            .profile
+            .as_ref()
+            .or_else(|| self.config.profile.as_ref())
+            .map(|p| p.as_str());
+        let config =
+            Config::from_source(&self.config.path, &source.content, &source.format, profile)?;
+        let config = self.keep_daemon_settings(config);
+        if source.dry_run {
+            let changes = self.diff_config(&config);
+            Daemon::check_changes(&config, &changes)?;
+            return Ok(changes
+                .iter()
+                .map(|(name, change)| format!("[{}] {}", name, change))
+                .collect());
+        }
+        info!("apply config. pid [{}]", self.pid);
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify("RELOADING=1");
+        }
+        let res = self.apply_config(config);
+        if let Some(ref notifier) = self.notifier {
+            notifier.ready();
+        }
+        self.notify_status();
+        res
+    }
+
+    /// The settings of the daemon itself are not changed without a restart.
+    fn keep_daemon_settings(&self, mut config: Config) -> Config {
+        config.control_sock = self.config.control_sock.to_owned();
+        config.control_sock_permissions = self.config.control_sock_permissions.clone();
+        config.control_max_connections = self.config.control_max_connections;
+        config.control_rate_limit = self.config.control_rate_limit;
+        config.pidfile = self.config.pidfile.clone();
+        config.state_file = self.config.state_file.clone();
+        config.audit_log = self.config.audit_log.clone();
+        config.http_address = self.config.http_address.clone();
+        config.metrics_address = self.config.metrics_address.clone();
+        config.statsd = self.config.statsd.clone();
+        config.otlp = self.config.otlp.clone();
+        config.webhook = self.config.webhook.clone();
+        config.auth = self.config.auth.clone();
+        config
+    }
+
+    /// The changes of the workers from the running config, the current workers first.
+    fn diff_config(&self, config: &Config) -> Vec<(String, WorkerChange)> {
+        let mut changes = Vec::new();
+        let mut names: Vec<&String> = self.config.workers.keys().collect();
+        names.sort();
+        for name in names {
+            let change = match config.workers.get(name) {
+                None => WorkerChange::Removed,
+                Some(c) if *c == self.config.workers[name] => WorkerChange::Unchanged,
+                Some(_) if self.is_stopped(name) => WorkerChange::ChangedStopped,
+                Some(_) => WorkerChange::Changed,
+            };
+            changes.push((name.to_owned(), change));
+        }
+        let mut names: Vec<&String> = config
+            .workers
+            .keys()
+            .filter(|name| !self.config.workers.contains_key(*name))
+            .collect();
+        names.sort();
+        for name in names {
+            changes.push((name.to_owned(), WorkerChange::Added));
+        }
+        changes
+    }
+
+    /// Checks the commands of the added and changed workers. A missing command would fail
+    /// the spawn of the monitor after the config is applied.
+    fn check_changes(config: &Config, changes: &[(String, WorkerChange)]) -> Result<(), Error> {
+        let errors: Vec<String> = changes
+            .iter()
+            .filter_map(|(name, change)| match change {
+                WorkerChange::Removed | WorkerChange::Unchanged => None,
+                _ => reloader::cmd_stat(&config.workers[name])
+                    .err()
+                    .map(|e| format!("[{}] {}", name, e)),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(err_msg(errors.join("\n")))
+        }
+    }
+
+    /// Applies the config. Nothing is applied when a command is missing. The monitors of
+    /// the removed and changed workers are signalled at once and stopped by the main loop,
+    /// which respawns the changed ones.
+    fn apply_config(&mut self, config: Config) -> Result<Vec<String>, Error> {
+        let changes = self.diff_config(&config);
+        Daemon::check_changes(&config, &changes)?;
+        // the processes are stopped with the old config
+        let timeouts: HashMap<String, u64> = self
+            .config
+            .workers
+            .iter()
+            .map(|(name, c)| (name.to_owned(), c.kill_after_sec + STOP_MARGIN_SECS))
+            .collect();
+        self.config = config;
+        self.ctrl_socks.update(&self.config);
+        let mut report = Vec::new();
+        for (name, change) in changes {
+            let timeout = timeouts
+                .get(&name)
+                .cloned()
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+            let res = match change {
+                WorkerChange::Removed => {
+                    self.stop_monitor(&name, timeout, false);
+                    Ok(())
+                }
+                WorkerChange::Changed => {
+                    if self.monitors.contains_key(&name) || self.stopping.contains_key(&name) {
+                        self.stop_monitor(&name, timeout, true);
+                        Ok(())
+                    } else {
+                        self.spawn_monitor(&name)
+                    }
+                }
+                // removed by the last reload and still stopping
+                WorkerChange::Added if self.stopping.contains_key(&name) => {
+                    self.stop_monitor(&name, timeout, true);
+                    Ok(())
+                }
+                WorkerChange::Added => self.spawn_monitor(&name),
+                WorkerChange::ChangedStopped | WorkerChange::Unchanged => Ok(()),
+            };
+            // the rest of the changes are applied
+            match res {
+                Ok(()) => report.push(format!("[{}] {}", name, change)),
+                Err(e) => {
+                    warn!(
+                        "fail spawn monitor [{}]. caused by: {} pid [{}]",
+                        name, e, self.pid
+                    );
+                    report.push(format!("[{}] {}. fail start: {}", name, change, e));
+                }
+            }
+        }
+        info!("reloaded config {:?}. pid [{}]", report, self.pid);
+        Ok(report)
+    }
+
     fn clean_process(&mut self) {
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify("STOPPING=1");
+        }
+        self.abort_pending();
+        self.refresh_state();
         for mon in self.monitors.values_mut() {
             if let Err(_e) = mon.kill_all() {}
+            // monitors do not receive the terminal signal when detached
+            mon.signal(Signal::SIGINT);
         }
-        if let Err(e) = self.check_monitor_processes() {
-            error!("fail spwan monitor process. caused by: {}", e);
-        }
+        // the monitors are not respawned on shutdown
+        self.respawns.clear();
+        self.check_monitors();
         let delay = time::Duration::from_millis(500);
         while !self.monitors.is_empty() {
-            if let Err(e) = self.check_monitor_processes() {
-                error!("fail spwan monitor process. caused by: {}", e);
+            self.check_monitors();
+            thread::sleep(delay);
+        }
+        self.save_state();
+    }
+
+    fn load_state(&mut self) {
+        let path = match self.config.state_file {
+            Some(ref path) => path.to_owned(),
+            None => return,
+        };
+        let state = match State::load(path::Path::new(&path)) {
+            Ok(Some(state)) => state,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(
+                    "fail load state {}. caused by: {} pid [{}]",
+                    path, e, self.pid
+                );
+                return;
+            }
+        };
+        info!(
+            "restore state {} saved at {} by pid [{}]. pid [{}]",
+            path, state.saved_at, state.pid, self.pid
+        );
+        self.state = state;
+        for (name, config) in &self.config.workers {
+            if let Some(worker) = self.state.workers.get_mut(name) {
+                let cmd_mtime = reloader::cmd_path(config)
+                    .ok()
+                    .and_then(|path| mtime_secs(&path));
+                if worker.cmd_mtime.is_some() && worker.cmd_mtime != cmd_mtime {
+                    info!(
+                        "command of [{}] changed since the last run. pid [{}]",
+                        name, self.pid
+                    );
+                    worker.gave_up = false;
+                }
+                worker.cmd_mtime = cmd_mtime;
+            }
+        }
+    }
+
+    /// Collects the pids of the monitors. The pids and the counters of the running workers
+    /// are asked on the query pool, and the state has the last answers.
+    fn refresh_state(&mut self) {
+        if self.config.state_file.is_none() {
+            return;
+        }
+        for (name, monitor) in &self.monitors {
+            let worker = self
+                .state
+                .workers
+                .entry(name.to_owned())
+                .or_insert_with(WorkerState::default);
+            worker.monitor_pid = monitor.pid.map(|pid| pid_t::from(pid) as u32);
+            worker.cmd_mtime = mtime_secs(&monitor.cmd_path);
+        }
+        let names: Vec<String> = self.monitors.keys().cloned().collect();
+        for name in names {
+            self.query_stats(&name);
+        }
+    }
+
+    fn save_state(&mut self) {
+        // the status of the workers without a monitor is answered from the state
+        let down = self
+            .state
+            .workers
+            .iter()
+            .filter_map(|(name, state)| {
+                if state.gave_up {
+                    Some((name.to_owned(), RunState::GaveUp))
+                } else if state.stopped {
+                    Some((name.to_owned(), RunState::Stopped))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.ctrl_socks.update_down(down);
+        let path = match self.config.state_file {
+            Some(ref path) => path.to_owned(),
+            None => return,
+        };
+        let config = &self.config;
+        let monitors = &self.monitors;
+        self.state
+            .workers
+            .retain(|name, _| config.workers.contains_key(name));
+        for (name, worker) in &mut self.state.workers {
+            if !monitors.contains_key(name) {
+                worker.monitor_pid = None;
+                worker.pids.clear();
+            }
+        }
+        if let Err(e) = self.state.save(path::Path::new(&path)) {
+            warn!(
+                "fail save state {}. caused by: {} pid [{}]",
+                path, e, self.pid
+            );
+        }
+    }
+
+    /// Stops the monitors with SIGINT and waits. Monitors alive after the timeout are killed.
+    /// Returns the names of the killed monitors.
+    fn shutdown(&mut self, timeout: u64) -> Vec<String> {
+        info!("shutdown daemon. timeout [{}] pid [{}]", timeout, self.pid);
+        self.abort_pending();
+        self.refresh_state();
+        let span = self.reporters.tracer.as_ref().map(|tracer| {
+            let mut span = tracer.start("daemon.shutdown", None);
+            span.attr("timeout", timeout);
+            span
+        });
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify("STOPPING=1");
+        }
+        for mon in self.monitors.values_mut() {
+            mon.signal(Signal::SIGINT);
+        }
+        let now = time::SystemTime::now();
+        let delay = time::Duration::from_millis(100);
+        while !self.monitors.is_empty() {
+            match now.elapsed() {
+                Ok(elapsed) if elapsed.as_secs() < timeout => thread::sleep(delay),
+                _ => break,
             }
+            self.check_monitors();
+        }
+
+        let mut killed: Vec<String> = self.monitors.keys().cloned().collect();
+        killed.sort();
+        for mon in self.monitors.values_mut() {
+            warn!(
+                "no reaction. kill monitor [{}]. pid [{}]",
+                mon.name, self.pid
+            );
+            mon.signal(Signal::SIGKILL);
+        }
+        while !self.monitors.is_empty() {
+            self.check_monitors();
             thread::sleep(delay);
         }
+        self.save_state();
+        if let Some(ref webhook) = self.reporters.webhook {
+            let message = if killed.is_empty() {
+                "stopped all workers".to_owned()
+            } else {
+                format!("killed monitors {:?}", killed)
+            };
+            // the daemon exits after this. wait for the delivery
+            for handle in webhook.send(WebhookEvent::Shutdown, None, &message) {
+                let _ = handle.join();
+            }
+        }
+        if let Some(span) = span {
+            let error = if killed.is_empty() {
+                None
+            } else {
+                Some(format!("killed monitors {:?}", killed))
+            };
+            let tracer = self.reporters.tracer.as_ref().unwrap();
+            // the daemon exits after this. wait for the export
+            if tracer.end(span, error).join().is_err() {
+                warn!("fail export shutdown span. pid [{}]", self.pid);
+            }
+        }
+        killed
+    }
+
+    fn start(&mut self) -> Result<CtrlListener, Error> {
+        if let Some(ref path) = self.config.pidfile {
+            self.pidfile = Some(PidFile::create(path::Path::new(path))?);
+        }
+        // check before re-attaching the monitors of another daemon
+        Daemon::check_ctrl_sock(&self.config.control_sock)?;
+        self.reporters.statsd = self.config.statsd.clone();
+        self.auth = match self.config.auth {
+            Some(ref auth) => Some(Auth::new(auth)?),
+            None => None,
+        };
+        self.reporters.audit = self
+            .config
+            .audit_log
+            .as_ref()
+            .map(|path| AuditLog::new(path));
+        if let Some(ref otlp) = self.config.otlp {
+            self.reporters.tracer = Some(Tracer::new(otlp)?);
+        }
+        if let Some(ref webhook) = self.config.webhook {
+            self.reporters.webhook = Some(Webhook::new(webhook)?);
+        }
+        self.load_state();
+        let mut adopted = match self.handover {
+            Some(ref handover) => handover.monitors.clone(),
+            None => Default::default(),
+        };
+        for name in &self.config.start_order() {
+            if let Some(pid) = adopted.remove(name) {
+                self.adopt_monitor(name, pid, false)?;
+                continue;
+            }
+            if let Some(pid) = self.discover_monitor(name) {
+                self.adopt_monitor(name, pid, true)?;
+                continue;
+            }
+            self.warn_stale_processes(name);
+            if self.is_stopped(name) {
+                info!(
+                    "[{}] was stopped in the last run. it is not started until the start command. pid [{}]",
+                    name, self.pid
+                );
+                continue;
+            }
+            if self.state.workers.get(name).map_or(false, |s| s.gave_up) {
+                warn!(
+                    "[{}] gave up in the last run. it is not started until the command or the config is changed. pid [{}]",
+                    name, self.pid
+                );
+                continue;
+            }
+            if !self.monitors.contains_key(name) {
+                if self.config.workers[name].depends_on_timeout_sec > 0 {
+                    self.wait_dependencies(name);
+                }
+                self.spawn_monitor(name)?;
+            }
+        }
+        for (name, pid) in adopted {
+            warn!(
+                "[{}] is not in the config. stop the adopted monitor. pid [{}]",
+                name, pid
+            );
+            let pid = Pid::from_raw(pid as pid_t);
+            if let Err(e) = signal::kill(pid, signal::SIGINT).and_then(|_| waitpid(pid, None)) {
+                warn!("fail stop monitor. caused by: {} pid [{}]", e, self.pid);
+            }
+        }
+        // monitors exited while the binary was replaced
+        self.check_monitor_processes();
+        self.refresh_state();
+        self.save_state();
+        self.ctrl_socks.update(&self.config);
+        if let Some(ref addr) = self.config.http_address {
+            self.http = Some(Daemon::listen_http(addr)?);
+        }
+        if let Some(ref addr) = self.config.metrics_address {
+            self.metrics = Some(Daemon::listen_http(addr)?);
+        }
+        let listen_fd: ListenFd = self.config.control_sock.parse()?;
+        let tcp_ctrl_sock = match listen_fd {
+            ListenFd::TcpListener(_) => true,
+            _ => false,
+        };
+        let has_token = self.auth.as_ref().map_or(false, |auth| auth.has_token());
+        if (tcp_ctrl_sock || self.config.http_address.is_some()) && !has_token {
+            warn!(
+                "control api over tcp accepts commands from anyone who can connect. set token in [auth]. pid [{}]",
+                self.pid
+            );
+        }
+        self.inherited_ctrl_sock = match self.handover.take() {
+            Some(handover) => handover.inherited_ctrl_sock,
+            None => listen_fd.inherited_raw_fd().is_some(),
+        };
+        Daemon::listen_ctrl_sock(
+            &self.config.control_sock,
+            &self.config.control_sock_permissions,
+        )
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
         info!("start daemon. pid [{}]", self.pid);
-        for (name, config) in &mut self.config.workers {
-            if !self.monitors.contains_key(name) {
-                let mut monitor = MonitorProcess::new(name, config);
-                if monitor.spawn(name, config)? {
-                    self.monitors.insert(name.to_owned(), monitor);
+        let res = self.start();
+        if let Some(launcher) = self.launcher.take() {
+            launcher.notify(&res, self.pid);
+        }
+        let listener = res?;
+        if let Some(ref notifier) = self.notifier {
+            notifier.ready();
+        }
+        self.notify_status();
+        if self.has_workers() {
+            self.wait(&listener)?
+        }
+        Ok(())
+    }
+
+    fn send_reload(&mut self, req: &mut CtrlRequest) -> io::Result<()> {
+        let pid = pid_t::from(getpid());
+        let res = match self.reload() {
+            Ok(report) => CommandResponse {
+                status: Status::Ok,
+                command: Command::Reload,
+                pid: pid as u32,
+                message: report.join("\n"),
+            },
+            Err(e) => {
+                warn!("fail reload config. caused by: {} pid [{}]", e, pid);
+                CommandResponse {
+                    status: Status::Error,
+                    command: Command::Reload,
+                    pid: pid as u32,
+                    message: format!("error: {}", e),
+                }
+            }
+        };
+        req.respond(self.reporters.audit.as_ref(), &res)
+    }
+
+    fn send_apply(&mut self, req: &mut CtrlRequest) -> io::Result<()> {
+        let pid = pid_t::from(getpid());
+        let res = match req.cmd.config.take() {
+            Some(source) => self.apply(&source),
+            None => Err(err_msg("no config")),
+        };
+        let res = match res {
+            Ok(report) => CommandResponse {
+                status: Status::Ok,
+                command: Command::Apply,
+                pid: pid as u32,
+                message: report.join("\n"),
+            },
+            Err(e) => {
+                warn!("fail apply config. caused by: {} pid [{}]", e, pid);
+                CommandResponse {
+                    status: Status::Error,
+                    command: Command::Apply,
+                    pid: pid as u32,
+                    message: format!("error: {}", e),
                 }
             }
+        };
+        req.respond(self.reporters.audit.as_ref(), &res)
+    }
+
+    /// Health checks ping often, so the ping is not audited.
+    fn send_ping(&mut self, req: &mut CtrlRequest) -> io::Result<()> {
+        let latency = req.received_at.elapsed();
+        let res = PingResponse {
+            pid: pid_t::from(self.pid) as u32,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            uptime: self.started_at.elapsed().as_secs(),
+            monitors: self.monitors.len(),
+            workers: self.config.workers.len(),
+            latency: latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros()),
+        };
+        send_response(&mut req.stream, &res)
+    }
+
+    fn send_start_worker(&mut self, req: &mut CtrlRequest) -> io::Result<()> {
+        let name = req.cmd.worker.clone().unwrap_or_default();
+        let res = worker_response(Command::Start, self.start_worker(&name));
+        req.respond(self.reporters.audit.as_ref(), &res)
+    }
+
+    /// The response is deferred until the monitor has exited.
+    fn send_stop_worker(&mut self, mut req: CtrlRequest) -> io::Result<()> {
+        let name = req.cmd.worker.clone().unwrap_or_default();
+        let timeout = req.cmd.timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+        if let Err(e) = self.stop_worker(&name, timeout) {
+            let res = worker_response(Command::Stop, Err(e));
+            return req.respond(self.reporters.audit.as_ref(), &res);
+        }
+        if let Some(stopping) = self.stopping.get_mut(&name) {
+            stopping.req = Some(req);
         }
+        Ok(())
+    }
 
-        if self.is_daemon_process() {
-            let listener = Daemon::listen_ctrl_sock(&self.config.control_sock)?;
-            if !self.monitors.is_empty() {
-                self.wait(&listener)?
+    /// The response is deferred until the new monitor has processes.
+    fn send_restart_worker(&mut self, mut req: CtrlRequest) -> io::Result<()> {
+        let name = req.cmd.worker.clone().unwrap_or_default();
+        let timeout = req.cmd.timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+        if let Err(e) = self.restart_worker(&name, timeout) {
+            let res = worker_response(Command::Start, Err(e));
+            return req.respond(self.reporters.audit.as_ref(), &res);
+        }
+        match self.stopping.get_mut(&name) {
+            Some(stopping) => stopping.req = Some(req),
+            None => {
+                let deadline = time::Instant::now() + time::Duration::from_secs(RESTART_WAIT_SECS);
+                let restarting = Restarting {
+                    req,
+                    deadline,
+                    pids: None,
+                };
+                self.restarting.insert(name, restarting);
             }
         }
         Ok(())
     }
 
-    fn send_list(&mut self, stream: &mut UnixStream) -> io::Result<()> {
+    fn send_rollback_worker(&mut self, req: &mut CtrlRequest) -> io::Result<()> {
+        let name = req.cmd.worker.clone().unwrap_or_default();
+        let res = match req.cmd.command.clone() {
+            Some(cmd) => self
+                .rollback_worker(&name, &cmd)
+                .unwrap_or_else(|e| worker_response(Command::Rollback, Err(e))),
+            None => worker_response(Command::Rollback, Err(err_msg("no command"))),
+        };
+        req.respond(self.reporters.audit.as_ref(), &res)
+    }
+
+    fn send_shutdown(&mut self, timeout: u64, req: &mut CtrlRequest) {
         let pid = pid_t::from(getpid());
-        let mut v: Vec<String> = Vec::new();
-        for name in self.config.workers.keys() {
-            v.push(name.to_owned());
+        let killed = self.shutdown(timeout);
+        let res = if killed.is_empty() {
+            CommandResponse {
+                status: Status::Ok,
+                command: Command::Shutdown,
+                pid: pid as u32,
+                message: "stopped all workers".to_owned(),
+            }
+        } else {
+            CommandResponse {
+                status: Status::Error,
+                command: Command::Shutdown,
+                pid: pid as u32,
+                message: format!("killed {:?} after {} secs", killed, timeout),
+            }
+        };
+        if let Err(e) = req.respond(self.reporters.audit.as_ref(), &res) {
+            warn!("fail send response. caused by: {} pid [{}]", e, self.pid);
+        }
+        info!("exited daemon. pid [{}]", self.pid);
+    }
+
+    fn prepare_upexec(
+        &mut self,
+        binary: Option<String>,
+        fds: &[RawFd],
+    ) -> Result<(path::PathBuf, Handover), Error> {
+        // the handle of an upgrader process is lost on exec
+        if let Some(name) = self
+            .monitors
+            .iter()
+            .find(|(_, m)| m.upgrade_process.is_some())
+            .map(|(name, _)| name)
+        {
+            return Err(err_msg(format!("upgrader of [{}] is running", name)));
+        }
+        // the new daemon does not know the monitors being stopped
+        if let Some(name) = self.stopping.keys().next() {
+            return Err(err_msg(format!("[{}] is stopping", name)));
+        }
+        let binary = match binary {
+            Some(binary) => path::PathBuf::from(binary),
+            None => upexec::current_binary()?,
+        };
+        upexec::check_binary(&binary)?;
+        self.refresh_state();
+        self.save_state();
+        let mut fds: Vec<RawFd> = inherited_fds()
+            .into_iter()
+            .chain(fds.iter().cloned())
+            .collect();
+        fds.sort();
+        fds.dedup();
+        let monitors = self
+            .monitors
+            .iter()
+            .filter_map(|(name, m)| m.pid.map(|pid| (name.to_owned(), pid_t::from(pid) as u32)))
+            .collect();
+        let handover = Handover {
+            pid: pid_t::from(self.pid) as u32,
+            fds,
+            inherited_ctrl_sock: self.inherited_ctrl_sock,
+            monitors,
+        };
+        Ok((binary, handover))
+    }
+
+    /// Replaces the daemon with the new binary. The workers keep running.
+    fn send_upexec(
+        &mut self,
+        binary: Option<String>,
+        fds: &[RawFd],
+        req: &mut CtrlRequest,
+    ) -> io::Result<()> {
+        let pid = pid_t::from(getpid()) as u32;
+        let (binary, handover) = match self.prepare_upexec(binary, fds) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("fail upexec. caused by: {} pid [{}]", e, self.pid);
+                let res = CommandResponse {
+                    status: Status::Error,
+                    command: Command::Upexec,
+                    pid,
+                    message: format!("error: {}", e),
+                };
+                return req.respond(self.reporters.audit.as_ref(), &res);
+            }
+        };
+        let res = CommandResponse {
+            status: Status::Ok,
+            command: Command::Upexec,
+            pid,
+            message: format!("re-exec {:?}", binary),
+        };
+        // the binary is replaced even when the client did not wait for the answer
+        if let Err(e) = req.respond(self.reporters.audit.as_ref(), &res) {
+            warn!("fail send response. caused by: {} pid [{}]", e, self.pid);
         }
-        let res = ListResponse {
-            pid: pid as u32,
-            workers: v,
+        info!("re-exec daemon {:?}. pid [{}]", binary, self.pid);
+        let envs = match self.notifier {
+            Some(ref notifier) => {
+                notifier.notify("RELOADING=1");
+                notifier.env()
+            }
+            None => Vec::new(),
         };
-        let buf = serde_json::to_string(&res)?;
-        stream.write_all(buf.as_bytes())?;
-        stream.write_all(b"\n")?;
-        stream.flush()?;
+        let e = handover.exec(&binary, envs);
+        error!(
+            "fail re-exec daemon {:?}. caused by: {} pid [{}]",
+            binary, e, self.pid
+        );
+        if let Some(ref notifier) = self.notifier {
+            notifier.ready();
+        }
         Ok(())
     }
 }
 
 impl Drop for Daemon {
     fn drop(&mut self) {
+        if !self.is_daemon_process() {
+            return;
+        }
         let pid = getpid();
+        CHILD_PIPE.store(-1, Ordering::SeqCst);
+        for fd in &[self.child_pipe.0, self.child_pipe.1] {
+            if let Err(e) = close(*fd) {
+                warn!("fail close pipe. caused by: {} pid [{}]", e, pid);
+            }
+        }
         for (name, config) in &self.config.workers {
             let sock_path = config.control_sock(name);
             if path::Path::new(&sock_path).exists() {
@@ -400,13 +1990,49 @@ impl Drop for Daemon {
                 }
             }
         }
-        let path = &self.config.control_sock;
-        if path::Path::new(path).exists() {
-            if let Err(e) = fs::remove_file(path) {
+        // the socket passed from systemd is owned by the socket unit
+        if self.inherited_ctrl_sock {
+            return;
+        }
+        let path = match ctrl_sock_path(&self.config.control_sock) {
+            Some(path) => path,
+            None => return,
+        };
+        if path.exists() {
+            if let Err(e) = fs::remove_file(&path) {
                 warn!("fail remove control socket. caused by: {} pid [{}]", e, pid);
             } else {
-                info!("remove control socket {} pid [{}]", path, pid);
+                info!("remove control socket {:?} pid [{}]", path, pid);
             }
         }
     }
 }
+
+fn worker_response(command: Command, res: Result<String, Error>) -> CommandResponse {
+    let pid = pid_t::from(getpid()) as u32;
+    match res {
+        Ok(message) => CommandResponse {
+            status: Status::Ok,
+            command,
+            pid,
+            message,
+        },
+        Err(e) => {
+            warn!("fail {:?} worker. caused by: {} pid [{}]", command, e, pid);
+            CommandResponse {
+                status: Status::Error,
+                command,
+                pid,
+                message: format!("error: {}", e),
+            }
+        }
+    }
+}
+
+fn mtime_secs(path: &path::Path) -> Option<u64> {
+    let mtime = path.metadata().and_then(|m| m.modified()).ok()?;
+    mtime
+        .duration_since(time::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
@@ -0,0 +1,90 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::exit;
+
+use failure::Error;
+use libc;
+use nix::unistd::{close, dup2, fork, pipe, setsid, ForkResult, Pid};
+
+use sock::CtrlListener;
+
+/// Reports the startup status of the detached daemon to the launching terminal.
+pub struct Launcher {
+    pipe: File,
+}
+
+impl Launcher {
+    pub fn notify(mut self, res: &Result<CtrlListener, Error>, pid: Pid) {
+        let msg = match *res {
+            Ok(_) => format!("ok {}\n", pid),
+            Err(ref e) => format!("error {}\n", e),
+        };
+        if let Err(e) = self.pipe.write_all(msg.as_bytes()) {
+            warn!("fail notify launcher. caused by: {}", e);
+        }
+        if res.is_ok() {
+            if let Err(e) = redirect_stdio() {
+                warn!("fail redirect stdio. caused by: {}", e);
+            }
+        }
+    }
+
+    /// Tells the launching terminal that the daemon waits for the primary to die.
+    pub fn standby(mut self, pid: Pid) {
+        if let Err(e) = self.pipe.write_all(format!("standby {}\n", pid).as_bytes()) {
+            warn!("fail notify launcher. caused by: {}", e);
+        }
+        if let Err(e) = redirect_stdio() {
+            warn!("fail redirect stdio. caused by: {}", e);
+        }
+    }
+}
+
+fn redirect_stdio() -> Result<(), Error> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        dup2(null.as_raw_fd(), fd)?;
+    }
+    Ok(())
+}
+
+/// Double-forks and calls setsid. The launching process waits for the status and exits.
+pub fn detach() -> Result<Launcher, Error> {
+    let (reader, writer) = pipe()?;
+    match fork()? {
+        ForkResult::Parent { .. } => {
+            close(writer)?;
+            let reader = BufReader::new(unsafe { File::from_raw_fd(reader) });
+            let mut line = String::new();
+            for l in reader.lines().take(1) {
+                line = l?;
+            }
+            if line.starts_with("ok ") {
+                println!("started daemon. pid [{}]", &line[3..]);
+                exit(0);
+            } else if line.starts_with("standby ") {
+                println!("started standby daemon. pid [{}]", &line[8..]);
+                exit(0);
+            } else if line.starts_with("error ") {
+                eprintln!("fail start daemon. caused by: {}", &line[6..]);
+            } else {
+                eprintln!("fail start daemon. exited before startup");
+            }
+            exit(1);
+        }
+        ForkResult::Child => {
+            close(reader)?;
+            setsid()?;
+            if let ForkResult::Parent { .. } = fork()? {
+                unsafe { libc::_exit(0) };
+            }
+            Ok(Launcher {
+                pipe: unsafe { File::from_raw_fd(writer) },
+            })
+        }
+    }
+}
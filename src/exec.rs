@@ -0,0 +1,81 @@
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::{io, thread};
+
+use nix::unistd::getpid;
+
+use command::send_response;
+
+/// A frame streamed to the client of worker:exec after the response.
+/// The last one is the exit code.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExecOutput {
+    #[serde(rename = "stdout")]
+    Stdout(String),
+    #[serde(rename = "stderr")]
+    Stderr(String),
+    #[serde(rename = "exit")]
+    Exit(i32),
+}
+
+/// Streams the output and the exit code of a one-off command on its own thread,
+/// so that the monitor keeps supervising the processes.
+/// The command runs to the end even if the client goes away.
+pub fn stream_output(mut child: Child, stream: UnixStream) {
+    thread::spawn(move || {
+        let pid = child.id();
+        let stream = Arc::new(Mutex::new(stream));
+        let stderr = child.stderr.take().map(|stderr| {
+            let stream = stream.clone();
+            thread::spawn(move || forward_lines(stderr, &stream, ExecOutput::Stderr))
+        });
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, &stream, ExecOutput::Stdout);
+        }
+        if let Some(handle) = stderr {
+            let _ = handle.join();
+        }
+        let code = match child.wait() {
+            // the same code as a shell
+            Ok(status) => status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+            Err(e) => {
+                warn!(
+                    "fail wait one-off process. caused by: {} pid [{}]",
+                    e,
+                    getpid()
+                );
+                -1
+            }
+        };
+        info!("exited one-off process. exit_code [{}] pid [{}]", code, pid);
+        let _ = send(&stream, &ExecOutput::Exit(code));
+    });
+}
+
+/// Sends the output line by line. The pipe is drained after the client went away,
+/// or the command would block on a full pipe.
+fn forward_lines<R: Read>(reader: R, stream: &Mutex<UnixStream>, output: fn(String) -> ExecOutput) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let mut connected = true;
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if connected {
+            let msg = output(String::from_utf8_lossy(&line).into_owned());
+            connected = send(stream, &msg).is_ok();
+        }
+    }
+}
+
+fn send(stream: &Mutex<UnixStream>, msg: &ExecOutput) -> io::Result<()> {
+    send_response(&mut *stream.lock().unwrap(), msg)
+}
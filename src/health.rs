@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+use std::{io, thread};
+
+use failure::Error;
+
+use config::HealthCheckConfig;
+use http;
+use sock::{connect_unix, ListenFd};
+
+// a finished check is polled at this interval
+const WAIT_INTERVAL_MILLIS: u64 = 100;
+
+/// The health check of a worker, a command, an HTTP GET or a TCP connect. Each check runs
+/// on its own thread, so that a hung check does not block the monitor.
+pub struct HealthCheck {
+    config: HealthCheckConfig,
+    // the consecutive failures
+    failures: u64,
+    // the last check passed
+    passed: bool,
+    next_at: Instant,
+    running: Option<Receiver<Result<(), String>>>,
+}
+
+impl HealthCheck {
+    pub fn new(config: &HealthCheckConfig) -> Self {
+        HealthCheck {
+            config: config.clone(),
+            failures: 0,
+            passed: false,
+            next_at: Instant::now() + Duration::from_secs(config.interval),
+            running: None,
+        }
+    }
+
+    /// The readiness check of the new processes of an upgrade, which starts at once.
+    pub fn readiness(config: &HealthCheckConfig) -> Self {
+        let mut check = HealthCheck::new(config);
+        check.next_at = Instant::now();
+        check
+    }
+
+    pub fn passed(&self) -> bool {
+        self.passed
+    }
+
+    pub fn failure_threshold(&self) -> u64 {
+        self.config.failure_threshold
+    }
+
+    /// Starts the check when it is due, and returns the number of the consecutive failures
+    /// with the error when a check has failed.
+    pub fn poll(
+        &mut self,
+        working_directory: &Path,
+        environment: &HashMap<String, String>,
+    ) -> Option<(u64, String)> {
+        if let Some(res) = self.running.as_ref().map(|rx| rx.try_recv()) {
+            let res = match res {
+                Err(TryRecvError::Empty) => return None,
+                Ok(res) => res,
+                Err(TryRecvError::Disconnected) => Err("check thread exited".to_owned()),
+            };
+            self.running = None;
+            self.next_at = Instant::now() + Duration::from_secs(self.config.interval);
+            self.passed = res.is_ok();
+            match res {
+                Ok(()) => self.failures = 0,
+                Err(e) => {
+                    self.failures += 1;
+                    return Some((self.failures, e));
+                }
+            }
+            return None;
+        }
+        if Instant::now() >= self.next_at {
+            self.start(working_directory, environment);
+        }
+        None
+    }
+
+    /// Forgets the failures, and waits an interval for the processes started again.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.passed = false;
+        self.running = None;
+        self.next_at = Instant::now() + Duration::from_secs(self.config.interval);
+    }
+
+    fn start(&mut self, working_directory: &Path, environment: &HashMap<String, String>) {
+        let (tx, rx) = channel();
+        let timeout = Duration::from_secs(self.config.timeout);
+        let mut probe = if let Some(ref url) = self.config.url {
+            Probe::Http(url.clone(), self.config.expected_status)
+        } else if let Some(ref address) = self.config.address {
+            Probe::Tcp(address.clone())
+        } else {
+            let cmd = &self.config.cmd;
+            let mut command = Command::new(&cmd[0]);
+            command
+                .args(&cmd[1..])
+                .current_dir(working_directory)
+                .envs(environment)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null());
+            Probe::Command(command, cmd[0].clone())
+        };
+        thread::spawn(move || {
+            let _ = tx.send(probe.run(timeout));
+        });
+        self.running = Some(rx);
+    }
+}
+
+enum Probe {
+    // the command and its name
+    Command(Command, String),
+    // the url and the expected status
+    Http(String, u16),
+    Tcp(String),
+}
+
+impl Probe {
+    fn run(&mut self, timeout: Duration) -> Result<(), String> {
+        match *self {
+            Probe::Command(ref mut command, ref name) => command
+                .spawn()
+                .and_then(|child| wait_timeout(child, timeout))
+                .map_err(|e| format!("{}: {}", name, e))
+                .and_then(|code| match code {
+                    0 => Ok(()),
+                    code => Err(format!("{} exited {}", name, code)),
+                }),
+            Probe::Http(ref url, expected) => match http::get_status(url, timeout) {
+                Ok(status) if status == expected => Ok(()),
+                Ok(status) => Err(format!("{} responded {}", url, status)),
+                Err(e) => Err(format!("{}: {}", url, e)),
+            },
+            Probe::Tcp(ref address) => {
+                connect(address, timeout).map_err(|e| format!("{}: {}", address, e))
+            }
+        }
+    }
+}
+
+/// Connects to the address, and closes the connection at once.
+fn connect(address: &str, timeout: Duration) -> io::Result<()> {
+    let listen_fd: ListenFd = address
+        .parse()
+        .map_err(|e: Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    match listen_fd {
+        ListenFd::TcpListener(addr) => TcpStream::connect_timeout(&addr, timeout).map(|_| ()),
+        ListenFd::UnixListener(path) => connect_unix(path).map(|_| ()),
+        ListenFd::UdpSocket(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "udp is not supported",
+        )),
+    }
+}
+
+/// Waits the command, and kills it after `timeout`.
+pub fn wait_timeout(mut child: Child, timeout: Duration) -> io::Result<i32> {
+    let now = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            // the same code as a shell
+            return Ok(status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)));
+        }
+        if now.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("not exited in {} secs", timeout.as_secs()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(WAIT_INTERVAL_MILLIS));
+    }
+}
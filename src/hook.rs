@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use config::WorkerConfig;
+use health::wait_timeout;
+
+/// A lifecycle hook of a worker, a command run by the monitor around the spawn and the stop
+/// of each process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreStart => "pre_start",
+            Hook::PostStart => "post_start",
+            Hook::PreStop => "pre_stop",
+            Hook::PostStop => "post_stop",
+        }
+    }
+
+    pub fn command(self, config: &WorkerConfig) -> Option<&Vec<String>> {
+        match self {
+            Hook::PreStart => config.pre_start.as_ref(),
+            Hook::PostStart => config.post_start.as_ref(),
+            Hook::PreStop => config.pre_stop.as_ref(),
+            Hook::PostStop => config.post_stop.as_ref(),
+        }
+    }
+}
+
+/// Runs the hook of the worker to the end. It fails when the command exits non-zero, or runs
+/// beyond hook_timeout_sec and is killed. The output goes to the log of the daemon.
+pub fn run_hook(
+    hook: Hook,
+    config: &WorkerConfig,
+    environment: &HashMap<String, String>,
+) -> io::Result<()> {
+    let cmd = match hook.command(config) {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    let child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .current_dir(config.root_path(&config.working_directory))
+        .envs(environment)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cmd[0], e)))?;
+    match wait_timeout(child, Duration::from_secs(config.hook_timeout_sec))? {
+        0 => Ok(()),
+        code => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} hook exited {}", hook.name(), code),
+        )),
+    }
+}
@@ -0,0 +1,486 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::{io, time};
+
+use failure::{err_msg, Error};
+use httparse;
+use libc::pid_t;
+use nix::unistd::getpid;
+use serde::Serialize;
+use serde_json;
+
+use auth::Secret;
+use command::*;
+use ctrl::CtrlHandler;
+use pool::Pool;
+use signal::Signal;
+use sock::{self, CtrlStream, Peer};
+use upexec;
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+// a client must send the request within this time
+const READ_TIMEOUT_SECS: u64 = 30;
+// threads posting spans and webhooks. the posts to a slow endpoint wait in the queue
+const POST_THREADS: usize = 4;
+
+lazy_static! {
+    /// Runs `post_json` of the spans and the webhooks in the background.
+    pub static ref POSTS: Pool = Pool::new("post", POST_THREADS);
+}
+
+struct Request {
+    method: String,
+    path: String,
+    traceparent: Option<String>,
+    // `Authorization: Bearer <token>`
+    token: Option<Secret>,
+    body: Vec<u8>,
+}
+
+/// The optional JSON body of `POST /workers/<name>/<command>`, `POST /shutdown` and `POST /apply`.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RequestBody {
+    signal: Option<Signal>,
+    timeout: Option<u64>,
+    binary: Option<String>,
+    count: Option<u64>,
+    since: Option<u64>,
+    args: Option<Vec<String>>,
+    config: Option<ConfigSource>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Serves one HTTP request on a thread of the pool of the control connections.
+///
+/// The request is translated to a `DaemonCommand` and handled in the same way as
+/// the control socket.
+pub fn serve(handler: &CtrlHandler, stream: TcpStream) {
+    let slot = match handler.acquire() {
+        Some(slot) => slot,
+        None => return reject(stream),
+    };
+    let conn = handler.clone();
+    handler.execute(move || {
+        let _slot = slot;
+        if let Err(e) = handle(&conn, stream) {
+            warn!(
+                "fail handle http connection. caused by: {} pid [{}]",
+                e,
+                getpid()
+            );
+        }
+    });
+}
+
+/// Serves `GET /metrics` of the metrics listener on a thread of the same pool.
+pub fn serve_metrics(handler: &CtrlHandler, stream: TcpStream) {
+    let slot = match handler.acquire() {
+        Some(slot) => slot,
+        None => return reject(stream),
+    };
+    let conn = handler.clone();
+    handler.execute(move || {
+        let _slot = slot;
+        if let Err(e) = handle_metrics(&conn, stream) {
+            warn!(
+                "fail handle metrics connection. caused by: {} pid [{}]",
+                e,
+                getpid()
+            );
+        }
+    });
+}
+
+/// Answers 503 to a connection over the cap on the accept path without reading the
+/// request. The write does not block, so a client that does not read is just closed.
+/// The request is read and discarded afterwards, so the answer is not reset.
+fn reject(mut stream: TcpStream) {
+    debug!(
+        "reject http connection. too many connections pid [{}]",
+        getpid()
+    );
+    let res = stream
+        .set_nonblocking(true)
+        .and_then(|_| respond_error(&mut stream, 503, "too many connections"))
+        .and_then(|_| stream.shutdown(Shutdown::Write));
+    match res {
+        Ok(()) => sock::linger(stream),
+        Err(e) => debug!("fail answer 503. caused by: {} pid [{}]", e, getpid()),
+    }
+}
+
+fn handle_metrics(handler: &CtrlHandler, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(time::Duration::from_secs(READ_TIMEOUT_SECS)))?;
+    let req = match read_request(&mut stream) {
+        Ok(req) => req,
+        Err(e) => return respond_error(&mut stream, 400, &e.to_string()),
+    };
+    let path = req.path.splitn(2, '?').next().unwrap_or("");
+    match (req.method.as_str(), path) {
+        ("GET", "/metrics") => {
+            let body = handler.metrics();
+            respond_with(&mut stream, 200, METRICS_CONTENT_TYPE, &body)
+        }
+        (_, "/metrics") => respond_error(&mut stream, 405, "method not allowed"),
+        _ => respond_error(&mut stream, 404, "not found"),
+    }
+}
+
+fn handle(handler: &CtrlHandler, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(time::Duration::from_secs(READ_TIMEOUT_SECS)))?;
+    let req = match read_request(&mut stream) {
+        Ok(req) => req,
+        Err(e) => return respond_error(&mut stream, 400, &e.to_string()),
+    };
+    debug!("receive http request {} {}. pid [{}]", req.method, req.path, getpid());
+    let peer = match stream.peer_addr() {
+        Ok(addr) => Peer::from_addr(&addr),
+        Err(_) => Peer::default(),
+    };
+    let cmd = match route(&req) {
+        Ok(cmd) => cmd,
+        Err((code, msg)) => return respond_error(&mut stream, code, &msg),
+    };
+    if let Err(msg) = handler.check_rate(&peer) {
+        return respond_error(&mut stream, 429, &msg);
+    }
+    if !handler.authorize("http", &peer, &cmd) {
+        return respond_error(&mut stream, 401, "unauthorized");
+    }
+    if let CommandType::Upexec = cmd.command_type {
+        // the answer is sent before the daemon checks the binary
+        let binary = match cmd.binary {
+            Some(ref binary) => Ok(binary.into()),
+            None => upexec::current_binary(),
+        };
+        let res = binary
+            .map_err(Error::from)
+            .and_then(|binary| upexec::check_binary(&binary));
+        if let Err(e) = res {
+            return respond_error(&mut stream, 400, &e.to_string());
+        }
+    }
+    let early = match cmd.command_type {
+        CommandType::Shutdown => Some((Command::Shutdown, "shutting down")),
+        CommandType::Upexec => Some((Command::Upexec, "re-executing")),
+        _ => None,
+    };
+    if let Some((command, message)) = early {
+        // the daemon exits or execs without waiting for this thread, so answer first
+        let res = CommandResponse {
+            status: Status::Ok,
+            command,
+            pid: cmd.pid,
+            message: message.to_owned(),
+        };
+        respond(&mut stream, 202, &serde_json::to_string(&res)?)?;
+        execute(handler, &cmd, peer)?;
+        return Ok(());
+    }
+    let payload = match execute(handler, &cmd, peer) {
+        Ok(payload) => payload,
+        // the error frame of the daemon
+        Err(ref e) if e.kind() == io::ErrorKind::Other => {
+            let code = match cmd.command_type {
+                CommandType::CtrlWorker => 404,
+                _ => 500,
+            };
+            return respond_error(&mut stream, code, &e.to_string());
+        }
+        Err(e) => return Err(e),
+    };
+    let code = match serde_json::from_slice::<CommandResponse>(&payload) {
+        Ok(CommandResponse {
+            status: Status::Error,
+            ..
+        }) => 500,
+        _ => 200,
+    };
+    respond(&mut stream, code, &String::from_utf8_lossy(&payload))
+}
+
+/// Runs the command through the control protocol over a socket pair, so that
+/// reload and shutdown reach the main loop in the same way as from the control socket.
+fn execute(handler: &CtrlHandler, cmd: &DaemonCommand, peer: Peer) -> io::Result<Vec<u8>> {
+    let (mut client, server) = UnixStream::pair()?;
+    write_frame(&mut client, MessageType::DaemonCommand, cmd)?;
+    handler.handle_from(CtrlStream::Unix(server), peer, "http")?;
+    read_response(&mut client)
+}
+
+fn route(req: &Request) -> Result<DaemonCommand, (u16, String)> {
+    let pid = pid_t::from(getpid()) as u32;
+    let path = req.path.splitn(2, '?').next().unwrap_or("");
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let body: RequestBody = if req.body.is_empty() {
+        RequestBody::default()
+    } else {
+        serde_json::from_slice(&req.body).map_err(|e| (400, e.to_string()))?
+    };
+    let daemon_command = |command_type| DaemonCommand {
+        token: req.token.clone(),
+        ..DaemonCommand::new(command_type, pid)
+    };
+    let ctrl_command = |name: &str, command| DaemonCommand {
+        worker: Some(name.to_owned()),
+        command: Some(CtrlCommand {
+            signal: body.signal,
+            traceparent: req.traceparent.clone(),
+            count: body.count,
+            since: body.since,
+            args: body.args.clone(),
+            binary: body.binary.clone(),
+            ..CtrlCommand::new(command, pid)
+        }),
+        token: req.token.clone(),
+        ..DaemonCommand::new(CommandType::CtrlWorker, pid)
+    };
+
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["ping"]) => Ok(daemon_command(CommandType::Ping)),
+        ("GET", ["workers"]) => Ok(daemon_command(CommandType::List)),
+        ("GET", ["status"]) => Ok(DaemonCommand {
+            command: Some(CtrlCommand::new(Command::Status, pid)),
+            ..daemon_command(CommandType::Status)
+        }),
+        ("GET", ["workers", name]) => Ok(ctrl_command(name, Command::Status)),
+        ("POST", ["workers", name, "drain"]) => Ok(DaemonCommand {
+            command_type: CommandType::DrainWorker,
+            timeout: body.timeout,
+            ..ctrl_command(name, Command::Drain)
+        }),
+        ("POST", ["workers", name, "rollback"]) => Ok(DaemonCommand {
+            command_type: CommandType::RollbackWorker,
+            ..ctrl_command(name, Command::Rollback)
+        }),
+        ("POST", ["workers", name, command]) => match command.parse() {
+            // a raw stream does not fit in a request and a response
+            Ok(Command::Attach) | Ok(Command::Exec) => {
+                Err((400, format!("{} requires the control socket", command)))
+            }
+            Ok(command) => Ok(ctrl_command(name, command)),
+            Err(e) => Err((404, e.to_string())),
+        },
+        ("POST", ["reload"]) => Ok(daemon_command(CommandType::Reload)),
+        ("POST", ["apply"]) => Ok(DaemonCommand {
+            config: body.config.clone(),
+            ..daemon_command(CommandType::Apply)
+        }),
+        ("POST", ["shutdown"]) => Ok(DaemonCommand {
+            timeout: body.timeout,
+            ..daemon_command(CommandType::Shutdown)
+        }),
+        ("POST", ["upexec"]) => Ok(DaemonCommand {
+            binary: body.binary.clone(),
+            ..daemon_command(CommandType::Upexec)
+        }),
+        (_, ["ping"])
+        | (_, ["workers"])
+        | (_, ["status"])
+        | (_, ["workers", _])
+        | (_, ["workers", _, _])
+        | (_, ["reload"])
+        | (_, ["apply"])
+        | (_, ["shutdown"])
+        | (_, ["upexec"]) => Err((405, "method not allowed".to_owned())),
+        _ => Err((404, "not found".to_owned())),
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete request",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..size]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_SIZE => continue,
+            Ok(httparse::Status::Partial) => return Err(invalid("request too large")),
+            Err(e) => return Err(invalid(&e.to_string())),
+        };
+        let mut length = 0;
+        let mut traceparent = None;
+        let mut token = None;
+        for header in req.headers.iter() {
+            if header.name.eq_ignore_ascii_case("content-length") {
+                length = String::from_utf8_lossy(header.value)
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("invalid content-length"))?;
+            } else if header.name.eq_ignore_ascii_case("traceparent") {
+                traceparent = Some(String::from_utf8_lossy(header.value).into_owned());
+            } else if header.name.eq_ignore_ascii_case("authorization") {
+                let value = String::from_utf8_lossy(header.value);
+                if value.starts_with("Bearer ") {
+                    token = Some(Secret::new(value[7..].trim()));
+                }
+            }
+        }
+        if length > MAX_REQUEST_SIZE {
+            return Err(invalid("request too large"));
+        }
+        let method = req.method.unwrap_or("").to_owned();
+        let path = req.path.unwrap_or("").to_owned();
+        let mut body = buf[len..].to_vec();
+        while body.len() < length {
+            let size = stream.read(&mut chunk)?;
+            if size == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "incomplete body",
+                ));
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+        body.truncate(length);
+        return Ok(Request {
+            method,
+            path,
+            traceparent,
+            token,
+            body,
+        });
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn respond_error(stream: &mut TcpStream, code: u16, msg: &str) -> io::Result<()> {
+    let body = serde_json::to_string(&ErrorResponse {
+        error: msg.to_owned(),
+    })?;
+    respond(stream, code, &body)
+}
+
+fn respond(stream: &mut TcpStream, code: u16, body: &str) -> io::Result<()> {
+    respond_with(stream, code, "application/json", body)
+}
+
+fn respond_with(
+    stream: &mut TcpStream,
+    code: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let reason = match code {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Bad Gateway",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        code,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Splits `http://host:port/path` into the host and the path. https is not supported.
+pub fn parse_url(url: &str) -> Result<(String, String), Error> {
+    if !url.starts_with("http://") {
+        return Err(err_msg(format!(
+            "url {} not support. only http:// is supported",
+            url
+        )));
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(err_msg(format!("url {} has no host", url)));
+    }
+    Ok((host.to_owned(), path.to_owned()))
+}
+
+/// POSTs a JSON body and fails unless the server responds 2xx.
+pub fn post_json<T: Serialize>(url: &str, body: &T, timeout: time::Duration) -> io::Result<()> {
+    let (mut stream, host, path) = connect(url, timeout)?;
+    let body = serde_json::to_vec(body)?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+
+    match read_status(&mut stream)? {
+        code if code / 100 == 2 => Ok(()),
+        code => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} responded {}", url, code),
+        )),
+    }
+}
+
+/// GETs the url and returns the status code of the response.
+pub fn get_status(url: &str, timeout: time::Duration) -> io::Result<u16> {
+    let (mut stream, host, path) = connect(url, timeout)?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    )?;
+    stream.flush()?;
+    read_status(&mut stream)
+}
+
+fn connect(url: &str, timeout: time::Duration) -> io::Result<(TcpStream, String, String)> {
+    let (host, path) =
+        parse_url(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let addr = host.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("host {} not found", host),
+        )
+    })?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok((stream, host, path))
+}
+
+fn read_status(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    // "HTTP/1.1 200"
+    String::from_utf8_lossy(&status[9..])
+        .parse()
+        .map_err(|_| invalid("invalid status line"))
+}
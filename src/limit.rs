@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use sock::Peer;
+
+// buckets of peers idle for this time are dropped
+const IDLE_BUCKET_SECS: u64 = 60;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: time::Instant,
+}
+
+/// A connection counted against `control_max_connections` until it is dropped.
+pub struct Slot {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Caps the concurrent control connections and the commands per second of each peer,
+/// so that a polling script does not starve the monitors.
+#[derive(Clone)]
+pub struct Limiter {
+    max_connections: usize,
+    // commands per second. 0 is unlimited
+    rate: u64,
+    connections: Arc<AtomicUsize>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Limiter {
+    pub fn new(max_connections: usize, rate: u64) -> Limiter {
+        Limiter {
+            max_connections,
+            rate,
+            connections: Arc::new(AtomicUsize::new(0)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns `None` when the connections are at the cap.
+    pub fn acquire(&self) -> Option<Slot> {
+        let count = self.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot {
+            connections: self.connections.clone(),
+        };
+        if self.max_connections > 0 && count >= self.max_connections {
+            return None;
+        }
+        Some(slot)
+    }
+
+    /// Takes a token of the peer. Returns the milliseconds to wait when the bucket is empty.
+    pub fn check_rate(&self, peer: &Peer) -> Result<(), u64> {
+        if self.rate == 0 {
+            return Ok(());
+        }
+        let now = time::Instant::now();
+        let rate = self.rate as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, b| now.duration_since(b.updated_at).as_secs() < IDLE_BUCKET_SECS);
+        let bucket = buckets.entry(peer_key(peer)).or_insert(Bucket {
+            tokens: rate,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        // a burst of one second is allowed
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return Err(((1.0 - bucket.tokens) / rate * 1000.0).ceil() as u64);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Unix peers are limited per uid, since each run of a script has a new pid.
+/// Tcp peers are limited per address without the port.
+fn peer_key(peer: &Peer) -> String {
+    if let Some(uid) = peer.uid {
+        return format!("uid:{}", uid);
+    }
+    match peer.addr {
+        Some(ref addr) => addr
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| addr.to_owned()),
+        None => "unknown".to_owned(),
+    }
+}
@@ -1,12 +1,31 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::ffi::OsStr;
 use std::fs::{rename, File, OpenOptions};
 use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use failure::{err_msg, Error};
 
+// set by `run --foreground`. monitors inherit it on fork
+static FOREGROUND: AtomicBool = AtomicBool::new(false);
+
+// flush a partial line when the output has no newline for this long
+const MAX_LINE_LEN: usize = 8192;
+
+pub fn set_foreground(foreground: bool) {
+    FOREGROUND.store(foreground, Ordering::SeqCst);
+}
+
+/// Returns true when worker output without a log file goes to the daemon's stdout.
+pub fn is_foreground() -> bool {
+    FOREGROUND.load(Ordering::SeqCst)
+}
+
 #[derive(Debug)]
 pub enum RollingPolicy {
     SizeRollingPolicy { max_file_size: u64, max_backup: u32 },
@@ -143,13 +162,16 @@ impl FromStr for RollingLogFile {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<RollingLogFile, Error> {
-        let log_cfg: Vec<&str> = s.split(':').collect();
+        let log_cfg: Vec<&str> = s.splitn(4, ':').collect();
         let log_type = log_cfg[0];
         match log_type {
             "size" => {
                 // size:100000:5:/tmp.log
-                let max_file_size: u64 = log_cfg[1].parse().unwrap();
-                let max_backup: u32 = log_cfg[2].parse().unwrap();
+                if log_cfg.len() != 4 {
+                    return Err(err_msg(format!("invalid log config '{}'", s)));
+                }
+                let max_file_size: u64 = log_cfg[1].parse()?;
+                let max_backup: u32 = log_cfg[2].parse()?;
                 let path = log_cfg[3];
                 let policy = RollingPolicy::SizeRollingPolicy {
                     max_file_size,
@@ -162,3 +184,220 @@ impl FromStr for RollingLogFile {
         }
     }
 }
+
+/// Writes each line with a `[name] ` prefix, so that the output of many workers can share one stream.
+pub struct PrefixWriter<W: Write> {
+    prefix: Vec<u8>,
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> PrefixWriter<W> {
+    pub fn new(name: &str, inner: W) -> Self {
+        PrefixWriter {
+            prefix: format!("[{}] ", name).into_bytes(),
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    fn write_line(&mut self, end: usize) -> io::Result<()> {
+        let mut line = Vec::with_capacity(self.prefix.len() + end + 1);
+        line.extend_from_slice(&self.prefix);
+        line.extend(self.buf.drain(..end));
+        if line.last() != Some(&b'\n') {
+            line.push(b'\n');
+        }
+        // one write per line keeps lines from other monitors intact
+        self.inner.write_all(&line)
+    }
+}
+
+impl<W: Write> Write for PrefixWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            self.write_line(pos + 1)?;
+        }
+        if self.buf.len() >= MAX_LINE_LEN {
+            let len = self.buf.len();
+            self.write_line(len)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // partial lines are kept until the newline arrives
+        self.inner.flush()
+    }
+}
+
+/// A line of the output of a worker. `seq` increases over the life of the monitor.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogLine {
+    pub seq: u64,
+    // "stdout" or "stderr"
+    pub stream: String,
+    pub line: String,
+}
+
+/// The message of `worker:logs`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogChunk {
+    // the seq of the last line written. `logs -f` asks for the lines after it
+    pub last: u64,
+    pub lines: Vec<LogLine>,
+}
+
+/// The last lines of the output of a worker, kept by its monitor for the logs command.
+pub struct OutputBuffer {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+    last: u64,
+}
+
+impl OutputBuffer {
+    pub fn new(capacity: usize) -> Self {
+        OutputBuffer {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            last: 0,
+        }
+    }
+
+    fn push(&mut self, stream: &str, line: &[u8]) {
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        self.last += 1;
+        self.lines.push_back(LogLine {
+            seq: self.last,
+            stream: stream.to_owned(),
+            line: String::from_utf8_lossy(line).into_owned(),
+        });
+    }
+
+    /// Returns the lines after `since`, or the last `count` lines.
+    pub fn chunk(&self, count: usize, since: Option<u64>) -> LogChunk {
+        let lines = match since {
+            // the monitor is restarted. all lines are new
+            Some(since) if since > self.last => self.lines.iter().cloned().collect(),
+            Some(since) => self
+                .lines
+                .iter()
+                .filter(|l| l.seq > since)
+                .cloned()
+                .collect(),
+            None => {
+                let skip = self.lines.len().saturating_sub(count);
+                self.lines.iter().skip(skip).cloned().collect()
+            }
+        };
+        LogChunk {
+            last: self.last,
+            lines,
+        }
+    }
+}
+
+/// The last lines of the stderr of a process, kept for its crash report.
+pub struct LineTail {
+    lines: VecDeque<String>,
+    capacity: usize,
+    // the line not ended yet
+    partial: Vec<u8>,
+}
+
+impl LineTail {
+    pub fn new(capacity: usize) -> Self {
+        LineTail {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            partial: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, buf: &[u8]) {
+        self.partial.extend_from_slice(buf);
+        while let Some(pos) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..pos + 1).collect();
+            self.push_line(&line[..pos]);
+        }
+        if self.partial.len() >= MAX_LINE_LEN {
+            let line: Vec<u8> = self.partial.drain(..).collect();
+            self.push_line(&line);
+        }
+    }
+
+    fn push_line(&mut self, line: &[u8]) {
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        let line = String::from_utf8_lossy(line);
+        // a pty ends the lines with \r\n
+        self.lines.push_back(line.trim_end_matches('\r').to_owned());
+    }
+
+    /// The lines with the last one not ended, e.g. of a panic cut by the exit.
+    pub fn into_lines(mut self) -> Vec<String> {
+        if !self.partial.is_empty() {
+            let line: Vec<u8> = self.partial.drain(..).collect();
+            self.push_line(&line);
+        }
+        self.lines.into_iter().collect()
+    }
+}
+
+/// Keeps the lines in the output buffer and passes the output to the log file, if any.
+pub struct CaptureWriter {
+    buffer: Rc<RefCell<OutputBuffer>>,
+    stream: &'static str,
+    inner: Option<Box<dyn Write>>,
+    buf: Vec<u8>,
+}
+
+impl CaptureWriter {
+    pub fn new(
+        buffer: Rc<RefCell<OutputBuffer>>,
+        stream: &'static str,
+        inner: Option<Box<dyn Write>>,
+    ) -> Self {
+        CaptureWriter {
+            buffer,
+            stream,
+            inner,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut inner) = self.inner {
+            inner.write_all(buf)?;
+        }
+        self.buf.extend_from_slice(buf);
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..pos + 1).collect();
+            // a pty ends the lines with \r\n
+            let end = if pos > 0 && line[pos - 1] == b'\r' {
+                pos - 1
+            } else {
+                pos
+            };
+            self.buffer.borrow_mut().push(self.stream, &line[..end]);
+        }
+        if self.buf.len() >= MAX_LINE_LEN {
+            let line: Vec<u8> = self.buf.drain(..).collect();
+            self.buffer.borrow_mut().push(self.stream, &line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            Some(ref mut inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
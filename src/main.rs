@@ -3,6 +3,7 @@ extern crate clap;
 extern crate console;
 extern crate failure;
 extern crate glob;
+extern crate httparse;
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
@@ -12,30 +13,59 @@ extern crate regex;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate serde_path_to_error;
+extern crate serde_yaml;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate strsim;
 extern crate toml;
 
 mod app;
+mod audit;
+mod auth;
+mod cgroup;
+mod check;
 mod client;
 mod cmdline;
 mod command;
+mod completion;
 mod config;
+mod coredump;
+mod cron;
+mod ctrl;
 mod daemon;
+mod detach;
+mod exec;
+mod health;
+mod hook;
+mod http;
+mod limit;
 mod logs;
+mod metrics;
 mod monitor;
+mod notify;
+mod otlp;
+mod pidfile;
+mod pool;
 mod process;
 mod reloader;
 mod signal;
 mod sock;
+mod standby;
+mod state;
+mod statsd;
+mod top;
+mod upexec;
 mod utils;
+mod webhook;
 mod worker;
 
 fn main() {
     use nix::unistd::{getpid, getppid};
-    use std::env;
+    use std::{env, process};
     let want_bt = match env::var("RUST_BACKTRACE").as_ref().map(|x| x.as_str()) {
         Ok("1") | Ok("full") => true,
         _ => false,
@@ -59,6 +89,7 @@ fn main() {
             } else if cfg!(debug_assertions) {
                 error!("hint: you can set RUST_BACKTRACE=1 to get the entire backtrace.");
             }
+            process::exit(1);
         }
     }
 }
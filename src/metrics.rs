@@ -0,0 +1,120 @@
+use std::fmt::Write;
+
+/// Counters of a worker kept by its monitor. The daemon collects them with the
+/// `worker:metrics` command.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct WorkerStats {
+    pub processes: usize,
+    #[serde(default)]
+    pub pids: Vec<u32>,
+    pub restarts: u64,
+    pub upgrades: u64,
+    pub last_exit_code: Option<i32>,
+    // unix time in seconds
+    pub last_spawn_time: Option<i64>,
+    pub uptime: i64,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_family<F>(
+    buf: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    workers: &[(String, Option<WorkerStats>)],
+    value: F,
+) where
+    F: Fn(&Option<WorkerStats>) -> Option<i64>,
+{
+    let _ = writeln!(buf, "# HELP {} {}", name, help);
+    let _ = writeln!(buf, "# TYPE {} {}", name, kind);
+    for (worker, stats) in workers {
+        if let Some(v) = value(stats) {
+            let _ = writeln!(buf, "{}{{worker=\"{}\"}} {}", name, escape_label(worker), v);
+        }
+    }
+}
+
+/// Renders the prometheus text format. `None` stats means that the monitor of
+/// the worker did not respond.
+pub fn render(uptime: u64, workers: &[(String, Option<WorkerStats>)]) -> String {
+    let mut buf = String::new();
+    let _ = writeln!(
+        buf,
+        "# HELP firestarter_uptime_seconds Seconds since the daemon started."
+    );
+    let _ = writeln!(buf, "# TYPE firestarter_uptime_seconds gauge");
+    let _ = writeln!(buf, "firestarter_uptime_seconds {}", uptime);
+    write_family(
+        &mut buf,
+        "firestarter_worker_up",
+        "gauge",
+        "1 if the worker has running processes.",
+        workers,
+        |s| Some(s.as_ref().map_or(0, |s| (s.processes > 0) as i64)),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_processes",
+        "gauge",
+        "Number of running processes.",
+        workers,
+        |s| s.as_ref().map(|s| s.processes as i64),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_paused",
+        "gauge",
+        "1 if the processes are stopped by the pause command.",
+        workers,
+        |s| s.as_ref().map(|s| s.paused as i64),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_restarts_total",
+        "counter",
+        "Processes respawned after exit.",
+        workers,
+        |s| s.as_ref().map(|s| s.restarts as i64),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_upgrades_total",
+        "counter",
+        "Successful upgrades.",
+        workers,
+        |s| s.as_ref().map(|s| s.upgrades as i64),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_last_exit_code",
+        "gauge",
+        "Exit code of the last exited process. -1 if killed by a signal.",
+        workers,
+        |s| s.as_ref().and_then(|s| s.last_exit_code.map(i64::from)),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_last_spawn_timestamp_seconds",
+        "gauge",
+        "Unix time of the last process spawn.",
+        workers,
+        |s| s.as_ref().and_then(|s| s.last_spawn_time),
+    );
+    write_family(
+        &mut buf,
+        "firestarter_worker_uptime_seconds",
+        "gauge",
+        "Seconds since the worker started.",
+        workers,
+        |s| s.as_ref().map(|s| s.uptime),
+    );
+    buf
+}
@@ -1,31 +1,75 @@
-use std::collections::HashMap;
-use std::io::{copy, Write};
-use std::os::unix::io::{FromRawFd, RawFd};
-use std::os::unix::net::UnixListener;
-use std::process::{exit, Child};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::{exit, Child, Command as StdCommand};
 use std::string::String;
-use std::{env, fs, io, path, thread, time};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, fs, io, mem, path, thread, time};
 
 use failure::{err_msg, Error};
 use glob::glob;
 use libc;
 use mio::unix::EventedFd;
 use mio::{Events, Poll, PollOpt, Ready, Token};
+use nix;
+use nix::errno::Errno;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::{close, fork, getpid, ForkResult, Pid};
+use nix::unistd::{close, getpid, getppid, Pid};
+use serde_json;
 
 use app::{APP_NAME, APP_NAME_UPPER};
+use audit::{command_name, AuditLog};
+use auth::Auth;
+use cgroup::Cgroup;
+use exec;
 use command::*;
-use config::WorkerConfig;
+use config::{AckKind, HealthAction, LimitAction, StatsdConfig, WebhookEvent, WorkerConfig};
+use health::HealthCheck;
+use otlp::{Span, Tracer};
+use pool::Handle;
 use process::{process_exited, process_output};
 use reloader;
 use signal::{Signal, SignalSend};
-use sock::ListenFd;
-use utils::{format_duration, set_nonblock};
+use sock::{
+    count_connections, inherit_fds, inherited_fds, retain_inherited_fds, ListenFd, Peer,
+    SockOptions, SockPermissions,
+};
+use state::WorkerState;
+use statsd::Statsd;
+use utils::{child_processes, cvt, set_child_subreaper, set_nonblock};
+use webhook::Webhook;
 use worker::Worker;
 
+// set when a signal arrives outside of poll and does not interrupt it
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+// set when the monitor gives up respawning the processes
+static GAVE_UP: AtomicBool = AtomicBool::new(false);
+// set by SIGCHLD and cleared by the sweep of the orphans
+static CHILD_EXITED: AtomicBool = AtomicBool::new(false);
+// lines of `logs` without `-n`
+const DEFAULT_LOG_LINES: u64 = 10;
+// an attached client that does not read the output is detached
+const ATTACH_WRITE_TIMEOUT_SECS: u64 = 1;
+// seconds of `wait` when the client does not tell
+const DEFAULT_WAIT_TIMEOUT_SECS: u64 = 60;
+// the image of the daemon, even after the binary is replaced on disk
+const SELF_EXE: &str = "/proc/self/exe";
+// the generations of orphans killed when the worker stops, as the children of a killed
+// orphan are adopted in turn
+const ORPHAN_KILL_ROUNDS: usize = 16;
+/// The exit code of a monitor that gave up.
+pub const GIVEUP_EXIT_CODE: i32 = 3;
+
+pub fn gave_up() -> bool {
+    GAVE_UP.load(Ordering::SeqCst)
+}
+
 extern "C" fn handle_signal(signum: i32) {
+    SIGNALED.store(true, Ordering::SeqCst);
     let s = signum as libc::c_int;
     let sigint = signal::SIGINT as libc::c_int;
     let sigquit = signal::SIGQUIT as libc::c_int;
@@ -37,9 +81,37 @@ extern "C" fn handle_signal(signum: i32) {
     }
 }
 
+extern "C" fn handle_child_signal(_signum: i32) {
+    CHILD_EXITED.store(true, Ordering::SeqCst);
+}
+
+/// Where the monitors report the lifecycle of the workers and the commands they receive.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reporters {
+    pub statsd: Option<StatsdConfig>,
+    pub tracer: Option<Tracer>,
+    pub webhook: Option<Webhook>,
+    pub audit: Option<AuditLog>,
+}
+
+/// What the daemon hands down to the monitor process through a pipe. The monitor is
+/// a new image of the binary, so it shares nothing else with the daemon.
+#[derive(Serialize, Deserialize)]
+struct MonitorSpec {
+    name: String,
+    config: WorkerConfig,
+    reporters: Reporters,
+    auth: Option<Auth>,
+    restored: Option<WorkerState>,
+    sock_permissions: SockPermissions,
+    // the fds passed from systemd or upexec. they are not closed on exec
+    inherited_fds: Vec<RawFd>,
+}
+
 pub enum ExitStatus {
     StillAlive,
     Interrupt,
+    Giveup,
     ForceExit,
     Restart,
 }
@@ -53,11 +125,19 @@ pub struct MonitorProcess {
     pub cmd_mtime: time::SystemTime,
     pub upgrade_process: Option<Child>,
     pub upgrade_active_time: time::SystemTime,
+    // re-attached after a crash of the daemon. it is not a child of the daemon
+    pub orphan: bool,
+    // signaled to stop by the daemon
+    stopping: bool,
+    pub sock_permissions: SockPermissions,
 }
 
-fn close_fds() {
+fn close_fds(keep: &[RawFd]) {
     for i in 3..128 {
         let fd = i as RawFd;
+        if keep.contains(&fd) {
+            continue;
+        }
         // ignore
         if let Err(e) = close(fd) {
             trace!("fd close err {}", e);
@@ -73,13 +153,11 @@ impl Drop for MonitorProcess {
 }
 
 impl MonitorProcess {
-    pub fn new(name: &str, config: &WorkerConfig) -> Self {
+    pub fn new(name: &str, config: &WorkerConfig) -> io::Result<Self> {
         let sock_path = config.control_sock(&name);
-        let cmd_path = reloader::cmd_path(&config);
-        let metadata = cmd_path.metadata().unwrap();
-        let cmd_mtime = metadata.modified().unwrap();
+        let (cmd_path, cmd_mtime) = reloader::cmd_stat(&config)?;
 
-        MonitorProcess {
+        Ok(MonitorProcess {
             name: name.to_owned(),
             pid: None,
             sock_path,
@@ -88,7 +166,10 @@ impl MonitorProcess {
             cmd_mtime,
             upgrade_process: None,
             upgrade_active_time: time::SystemTime::now(),
-        }
+            orphan: false,
+            stopping: false,
+            sock_permissions: SockPermissions::default(),
+        })
     }
 
     pub fn is_upgrade_active_time(&self, timeout: u64) -> bool {
@@ -160,7 +241,7 @@ impl MonitorProcess {
         for addr in &config.socket_address {
             let listen_fd: ListenFd = addr.parse().unwrap();
             debug!("try listen sock {}. pid [{}]", addr, getpid());
-            let raw_fd = listen_fd.create_raw_fd(128)?;
+            let raw_fd = listen_fd.get_raw_fd(128, &config.socket_options(addr))?;
             info!(
                 "listen {}. pid [{}]",
                 listen_fd.describe_raw_fd(raw_fd)?,
@@ -175,8 +256,9 @@ impl MonitorProcess {
         let control_sock = &self.sock_path;
         let listen_fd: ListenFd = control_sock.parse().unwrap();
         match listen_fd {
-            ListenFd::UnixListener(_) => {
-                let raw_fd = listen_fd.create_raw_fd(1)?;
+            ListenFd::UnixListener(ref path) => {
+                let raw_fd = listen_fd.create_raw_fd(1, &SockOptions::default())?;
+                self.sock_permissions.apply(path)?;
                 info!(
                     "listen control socket {}. pid [{}]",
                     listen_fd.describe_raw_fd(raw_fd)?,
@@ -191,7 +273,7 @@ impl MonitorProcess {
     pub fn try_wait(&mut self) -> Result<ExitStatus, Error> {
         let self_pid = getpid();
         let flag = WaitPidFlag::WNOHANG;
-        match waitpid(None, Some(flag)) {
+        match waitpid(self.pid, Some(flag)) {
             Ok(WaitStatus::StillAlive) => Ok(ExitStatus::StillAlive),
             Ok(WaitStatus::Exited(pid, status)) => {
                 debug!(
@@ -201,6 +283,8 @@ impl MonitorProcess {
                 );
                 if status == 255 {
                     Ok(ExitStatus::Restart)
+                } else if status == GIVEUP_EXIT_CODE {
+                    Ok(ExitStatus::Giveup)
                 } else {
                     Ok(ExitStatus::Interrupt)
                 }
@@ -233,6 +317,7 @@ impl MonitorProcess {
                 }
             }
             Ok(_) => Ok(ExitStatus::ForceExit),
+            Err(nix::Error::Sys(Errno::ECHILD)) if self.orphan => Ok(self.try_wait_orphan()),
             Err(e) => Err(err_msg(format!(
                 "fail monitor process wait. caused by: {}. pid [{}]",
                 e, self_pid
@@ -240,25 +325,113 @@ impl MonitorProcess {
         }
     }
 
-    pub fn spawn(&mut self, name: &str, config: &WorkerConfig) -> io::Result<bool> {
+    /// The exit status of an orphan is not known. It is restarted unless the daemon stopped it.
+    fn try_wait_orphan(&self) -> ExitStatus {
+        let alive = self
+            .pid
+            .map_or(false, |pid| signal::kill(pid, None).is_ok());
+        if alive {
+            ExitStatus::StillAlive
+        } else if self.stopping {
+            ExitStatus::Interrupt
+        } else {
+            info!(
+                "exited orphan monitor [{}]. pid [{:?}]",
+                self.name, self.pid
+            );
+            ExitStatus::Restart
+        }
+    }
+
+    /// Starts the monitor with the `monitor` subcommand of the running binary. It is
+    /// not forked alone, since the locks held by the threads of the daemon are never
+    /// released in the child.
+    pub fn spawn(
+        &mut self,
+        name: &str,
+        config: &WorkerConfig,
+        reporters: &Reporters,
+        auth: Option<&Auth>,
+        restored: Option<&WorkerState>,
+    ) -> io::Result<()> {
+        let spec = MonitorSpec {
+            name: name.to_owned(),
+            config: config.clone(),
+            reporters: reporters.clone(),
+            auth: auth.cloned(),
+            restored: restored.cloned(),
+            sock_permissions: self.sock_permissions.clone(),
+            inherited_fds: inherited_fds(),
+        };
+        let payload = serde_json::to_vec(&spec)?;
+        let mut fds = [0; 2];
+        unsafe {
+            cvt(libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC))?;
+        }
+        let (reader, mut writer) = (fds[0], unsafe { File::from_raw_fd(fds[1]) });
+        let mut command = StdCommand::new(SELF_EXE);
+        if let Some(arg0) = env::args_os().next() {
+            command.arg0(arg0);
+        }
+        command
+            .arg("monitor")
+            .arg("--spec-fd")
+            .arg(reader.to_string())
+            .arg(name);
+        unsafe {
+            // only the read end is passed to the monitor
+            command.pre_exec(move || cvt(libc::fcntl(reader, libc::F_SETFD, 0)).map(|_| ()));
+        }
+        let res = command.spawn();
+        if let Err(e) = close(reader) {
+            trace!("fail close fd {}. caused by: {}", reader, e);
+        }
+        let mut child = res?;
+        // written after the spawn, since the spec can be larger than the pipe buffer
+        if let Err(e) = writer.write_all(&payload) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        self.pid = Some(Pid::from_raw(child.id() as libc::pid_t));
+        Ok(())
+    }
+
+    /// Runs the monitor started by `spawn` with the spec read from `spec_fd`. It exits
+    /// with `GIVEUP_EXIT_CODE` when the monitor gives up.
+    pub fn run(spec_fd: RawFd) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        unsafe { File::from_raw_fd(spec_fd) }.read_to_end(&mut buf)?;
+        let MonitorSpec {
+            name,
+            mut config,
+            reporters,
+            auth,
+            restored,
+            sock_permissions,
+            inherited_fds,
+        } = serde_json::from_slice(&buf)?;
+        config.set_run_upgrader();
+        inherit_fds(&inherited_fds);
+        let pid = getpid();
+        let mut monitor = MonitorProcess::new(&name, &config)?;
+        monitor.pid = Some(pid);
+        monitor.sock_permissions = sock_permissions;
+        let mut worker = Worker::new(&name, &config);
+        if let Some(state) = restored {
+            // continue the counters of the previous monitor
+            worker.restarts = state.restarts;
+            worker.upgrades = state.generation;
+        }
         let key = config.environment_base_name.to_owned();
-        match fork().expect("failed fork") {
-            ForkResult::Parent { child } => {
-                // parent
-                self.pid = Some(child);
-                Ok(true)
-            }
-            ForkResult::Child => {
-                let pid = getpid();
-                self.pid = Some(pid);
-                let mut worker = Worker::new(name, config);
-                if let Err(e) = self.start_monitoring(&key, &mut worker, config) {
-                    warn!("exited monitor. caused by: {} pid: [{}]", e, pid);
-                    return Err(e);
-                }
-                Ok(false)
-            }
+        if let Err(e) =
+            monitor.start_monitoring(&key, &mut worker, &config, &reporters, auth.as_ref())
+        {
+            warn!("exited monitor. caused by: {} pid: [{}]", e, pid);
         }
+        drop(worker);
+        drop(monitor);
+        exit(if gave_up() { GIVEUP_EXIT_CODE } else { 0 });
     }
 
     fn start_monitoring(
@@ -266,7 +439,10 @@ impl MonitorProcess {
         key: &str,
         worker: &mut Worker,
         config: &WorkerConfig,
+        reporters: &Reporters,
+        auth: Option<&Auth>,
     ) -> io::Result<bool> {
+        let launched_at = time::Instant::now();
         let sa = signal::SigAction::new(
             signal::SigHandler::Handler(handle_signal),
             signal::SaFlags::empty(),
@@ -279,6 +455,16 @@ impl MonitorProcess {
             signal::sigaction(signal::SIGABRT, &sa).unwrap();
             signal::sigaction(signal::SIGHUP, &sa).unwrap();
         }
+        // SIGCHLD interrupts poll, which goes on unless SIGNALED is set
+        let sa = signal::SigAction::new(
+            signal::SigHandler::Handler(handle_child_signal),
+            signal::SaFlags::SA_RESTART | signal::SaFlags::SA_NOCLDSTOP,
+            signal::SigSet::empty(),
+        );
+        unsafe {
+            signal::sigaction(signal::SIGCHLD, &sa).unwrap();
+        }
+        let _cgroup = Cgroup::setup(worker.name, config)?;
         if config.warmup_delay > 0 {
             let delay = time::Duration::from_secs(config.warmup_delay);
             thread::sleep(delay);
@@ -286,8 +472,25 @@ impl MonitorProcess {
 
         let pid = self.pid.unwrap();
         info!("launched [{}] monitor process. pid [{}]", worker.name, pid);
-        // 1. close all fd
-        close_fds();
+        // 1. close all fd except the sockets passed from systemd for this worker
+        let inherited: Vec<RawFd> = config
+            .socket_address
+            .iter()
+            .filter_map(|addr| addr.parse::<ListenFd>().ok())
+            .filter_map(|listen_fd| listen_fd.inherited_raw_fd())
+            .collect();
+        close_fds(&inherited);
+        retain_inherited_fds(&inherited);
+        let statsd = reporters
+            .statsd
+            .as_ref()
+            .and_then(|c| match Statsd::new(c, worker.name) {
+                Ok(statsd) => Some(statsd),
+                Err(e) => {
+                    warn!("fail open statsd socket. caused by: {} pid [{}]", e, pid);
+                    None
+                }
+            });
         // 2. listen fd
         let fds = self.listen_fds(config).unwrap();
         // child
@@ -316,11 +519,19 @@ impl MonitorProcess {
 
         let giveup = config.giveup;
         // 4. create monitor
-        let mut monitor = Monitor::new(ctrl_fd, giveup);
+        let mut monitor = Monitor::new(ctrl_fd, giveup, statsd, reporters, auth.cloned());
         monitor.watch_ctrl_fd(ctrl_fd)?;
+        monitor.listen_fds = fds.clone();
+        // adopt the orphans of the processes, e.g. of a double fork
+        if let Err(e) = set_child_subreaper() {
+            warn!("fail set child subreaper. caused by: {} pid [{}]", e, pid);
+        }
         // 5. spawn worker
-        if fds.is_empty() || worker.start_immediate() {
-            worker.run(&mut monitor)?;
+        if worker.config.schedule.is_some() {
+            // the processes are run by the schedule
+            worker.active = true;
+        } else if fds.is_empty() || worker.start_immediate() {
+            monitor.run_worker(worker, launched_at)?;
         } else {
             // watch_fd
             for fd in fds {
@@ -331,30 +542,27 @@ impl MonitorProcess {
         // 6. monitor.run
         if let Err(e) = monitor.start(worker) {
             // error occuer cleanup worker process
-            if let Err(e) = worker.signal_all(Signal::SIGTERM) {
-                warn!("fail send signal SIGTERM. caused by: {}", e);
-            }
-            if let Err(e) = monitor.wait_process_io(worker, 1) {
-                warn!("fail worker cleanup. caused by: {}", e);
-            }
+            monitor.stop_processes(worker);
             if let Ok(_var) = env::var(format!("{}_HANDLE_SIGNAL", APP_NAME_UPPER)) {
                 exit(-1);
             }
             return Err(e);
         }
+        // the processes have exited when the monitor gives up
+        monitor.kill_orphans(worker);
 
         Ok(false)
     }
 
     pub fn kill_all(&mut self) -> io::Result<()> {
         self.send_ctrl_command(&CtrlCommand {
-            command: Command::KillAll,
-            pid: 0,
             signal: Some(Signal::SIGKILL),
+            ..CtrlCommand::new(Command::KillAll, 0)
         })
     }
 
     pub fn signal(&mut self, signal: Signal) {
+        self.stopping = true;
         if let Some(pid) = self.pid {
             let pid = libc::pid_t::from(pid) as u32;
             debug!(
@@ -371,6 +579,7 @@ impl MonitorProcess {
     }
 }
 
+#[derive(PartialEq)]
 pub enum OutputKind {
     StdOut,
     StdErr,
@@ -381,6 +590,22 @@ struct IOEvent {
     pub fd: RawFd,
     pub reader: fs::File,
     pub kind: OutputKind,
+    pub pid: u32,
+}
+
+/// A client attached to the pty of a process. The raw bytes are bridged in both ways.
+struct Attachment {
+    stream: UnixStream,
+    pid: u32,
+}
+
+/// A client of `wait`, answered when the worker reaches the state or at the deadline.
+struct Waiter {
+    stream: UnixStream,
+    state: String,
+    // upgraded waits for the upgrade count to go over this
+    upgrades: u64,
+    deadline: time::Instant,
 }
 
 enum FdEvent {
@@ -389,13 +614,14 @@ enum FdEvent {
 }
 
 impl IOEvent {
-    fn new(token: Token, fd: RawFd, kind: OutputKind) -> Self {
+    fn new(token: Token, fd: RawFd, kind: OutputKind, pid: u32) -> Self {
         let reader: fs::File = unsafe { fs::File::from_raw_fd(fd) };
         IOEvent {
             token,
             fd,
             reader,
             kind,
+            pid,
         }
     }
 }
@@ -405,14 +631,41 @@ pub struct Monitor {
     token_count: usize,
     io_events: HashMap<Token, IOEvent>,
     fd_events: HashMap<Token, FdEvent>,
+    attachments: HashMap<Token, Attachment>,
+    // closed on drain, so that the socket stops listening when the processes close theirs
+    listen_fds: Vec<RawFd>,
     ctrl_sock: UnixListener,
     giveup: u64,
     active: bool,
     pid: Pid,
+    statsd: Option<Statsd>,
+    tracer: Option<Tracer>,
+    webhook: Option<Webhook>,
+    audit: Option<AuditLog>,
+    auth: Option<Auth>,
+    // respawn times within the restart storm window
+    restarted_at: VecDeque<time::Instant>,
+    // respawn times within restart_window_sec
+    respawned_at: VecDeque<time::Instant>,
+    // the time and the cpu time of the processes at the last sample
+    cpu_sampled: Option<(time::Instant, u64)>,
+    // the samples in a row over cpu_alert_percent
+    cpu_high: u64,
+    // the zombie orphans seen by the previous sweep
+    zombies: Vec<u32>,
+    // the one-off processes of exec, waited on their threads
+    execs: Vec<u32>,
+    waiters: Vec<Waiter>,
 }
 
 impl Monitor {
-    pub fn new(fd: RawFd, giveup: u64) -> Self {
+    pub fn new(
+        fd: RawFd,
+        giveup: u64,
+        statsd: Option<Statsd>,
+        reporters: &Reporters,
+        auth: Option<Auth>,
+    ) -> Self {
         let listener: UnixListener = unsafe { UnixListener::from_raw_fd(fd) };
         let pid = getpid();
         Monitor {
@@ -420,10 +673,280 @@ impl Monitor {
             token_count: 0,
             io_events: HashMap::new(),
             fd_events: HashMap::new(),
+            attachments: HashMap::new(),
+            listen_fds: Vec::new(),
             ctrl_sock: listener,
             giveup,
             active: false,
             pid,
+            statsd,
+            tracer: reporters.tracer.clone(),
+            webhook: reporters.webhook.clone(),
+            audit: reporters.audit.clone(),
+            auth,
+            restarted_at: VecDeque::new(),
+            respawned_at: VecDeque::new(),
+            cpu_sampled: None,
+            cpu_high: 0,
+            zombies: Vec::new(),
+            execs: Vec::new(),
+            waiters: Vec::new(),
+        }
+    }
+
+    fn incr(&self, name: &str) {
+        if let Some(ref statsd) = self.statsd {
+            statsd.incr(name);
+        }
+    }
+
+    fn timing(&self, name: &str, since: time::Instant) {
+        if let Some(ref statsd) = self.statsd {
+            statsd.timing(name, since.elapsed());
+        }
+    }
+
+    fn send_webhook(&self, event: WebhookEvent, worker: &Worker, message: &str) -> Vec<Handle> {
+        match self.webhook {
+            Some(ref webhook) => webhook.send(event, Some(worker.name), message),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sends `restart_storm` when the worker restarts too often.
+    fn record_restart(&mut self, worker: &Worker) {
+        let (limit, window) = match self.webhook {
+            Some(ref webhook) => webhook.restart_storm(),
+            None => return,
+        };
+        let now = time::Instant::now();
+        self.restarted_at.push_back(now);
+        while let Some(at) = self.restarted_at.front().cloned() {
+            if now.duration_since(at).as_secs() < window {
+                break;
+            }
+            self.restarted_at.pop_front();
+        }
+        if limit > 0 && self.restarted_at.len() as u64 >= limit {
+            let message = format!("restarted {} times within {} seconds", limit, window);
+            self.send_webhook(WebhookEvent::RestartStorm, worker, &message);
+            self.restarted_at.clear();
+        }
+    }
+
+    /// Runs the health check of the worker, and takes the action after `failure_threshold`
+    /// failures in a row.
+    fn check_health(&mut self, worker: &mut Worker) {
+        if worker.paused || worker.draining || worker.processes.is_empty() {
+            return;
+        }
+        let config = worker.config;
+        let check = match config.health_check {
+            Some(ref check) => check,
+            None => return,
+        };
+        let working_directory = config.root_path(&config.working_directory);
+        let (failures, e) = match worker
+            .health
+            .as_mut()
+            .and_then(|health| health.poll(&working_directory, &config.environment))
+        {
+            Some(res) => res,
+            None => return,
+        };
+        warn!(
+            "fail health check [{}] {}/{}. caused by: {} pid [{}]",
+            worker.name, failures, check.failure_threshold, e, self.pid
+        );
+        if failures < check.failure_threshold {
+            return;
+        }
+        if let Some(ref mut health) = worker.health {
+            health.reset();
+        }
+        let message = format!("health check failed {} times", failures);
+        self.incr("unhealthy");
+        self.send_webhook(WebhookEvent::Unhealthy, worker, &message);
+        match check.action {
+            HealthAction::Restart => {
+                let pids = worker.terminate_unhealthy();
+                info!(
+                    "restart unhealthy [{}] pid {:?}. pid [{}]",
+                    worker.name, pids, self.pid
+                );
+            }
+            HealthAction::Signal => {
+                let signal = check.signal.unwrap_or(Signal::SIGTERM);
+                let pids = worker.send_signal(signal);
+                info!(
+                    "send signal {:?} to unhealthy [{}] pid {:?}. pid [{}]",
+                    signal, worker.name, pids, self.pid
+                );
+            }
+            HealthAction::Alert => {}
+        }
+    }
+
+    /// Restarts the processes grown over `max_memory`, before the OOM killer picks a process.
+    fn check_memory(&mut self, worker: &mut Worker) {
+        for (pid, rss) in worker.check_memory() {
+            let message = format!(
+                "resident set {} bytes over max_memory {}. pid [{}]",
+                rss,
+                worker.config.max_memory.as_ref().map_or("", |s| s.as_str()),
+                pid
+            );
+            warn!(
+                "restart [{}] process over max_memory. rss [{}] pid [{}]",
+                worker.name, rss, pid
+            );
+            self.incr("memory_limit");
+            self.send_webhook(WebhookEvent::MemoryLimit, worker, &message);
+        }
+    }
+
+    /// Alerts, or restarts, the processes with open fds over `max_fds`, e.g. of a leak.
+    fn check_fds(&mut self, worker: &mut Worker) {
+        for (pid, fds) in worker.check_fds() {
+            let message = format!(
+                "{} open fds over max_fds {}. pid [{}]",
+                fds,
+                worker.config.max_fds.unwrap_or(0),
+                pid
+            );
+            let action = match worker.config.max_fds_action {
+                LimitAction::Restart => "restart",
+                LimitAction::Alert => "alert",
+            };
+            warn!(
+                "{} [{}] process over max_fds. fds [{}] pid [{}]",
+                action, worker.name, fds, pid
+            );
+            self.incr("fd_limit");
+            self.send_webhook(WebhookEvent::FdLimit, worker, &message);
+        }
+    }
+
+    /// Samples the cpu usage of the processes every `cpu_sample_sec`, and sends the cpu_high
+    /// event once it stays over `cpu_alert_percent` for `cpu_alert_intervals` samples.
+    fn check_cpu(&mut self, worker: &mut Worker) {
+        let config = worker.config;
+        let threshold = match config.cpu_alert_percent {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let now = time::Instant::now();
+        if let Some((at, _)) = self.cpu_sampled {
+            if now.duration_since(at).as_secs() < config.cpu_sample_sec {
+                return;
+            }
+        }
+        let cpu_time = worker.cpu_time();
+        let (at, previous) = match self.cpu_sampled.replace((now, cpu_time)) {
+            Some(sample) => sample,
+            None => return,
+        };
+        let elapsed = now.duration_since(at);
+        let elapsed = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        // the processes exited since the last sample take their cpu time with them
+        let percent = cpu_time.saturating_sub(previous) as f64 * 100.0 / elapsed as f64;
+        if let Some(ref statsd) = self.statsd {
+            statsd.gauge("cpu_percent", percent.round() as u64);
+        }
+        if percent < threshold {
+            if self.cpu_high >= config.cpu_alert_intervals {
+                info!(
+                    "cpu usage of [{}] back to {:.1}%. pid [{}]",
+                    worker.name, percent, self.pid
+                );
+            }
+            self.cpu_high = 0;
+            return;
+        }
+        self.cpu_high += 1;
+        if self.cpu_high != config.cpu_alert_intervals {
+            return;
+        }
+        let message = format!(
+            "cpu usage {:.1}% over {}% for {} samples",
+            percent, threshold, self.cpu_high
+        );
+        warn!("high cpu [{}]. {} pid [{}]", worker.name, message, self.pid);
+        self.incr("cpu_high");
+        self.send_webhook(WebhookEvent::CpuHigh, worker, &message);
+    }
+
+    /// Returns the reason to give up when the respawn exceeds `max_restarts` within
+    /// `restart_window_sec`.
+    fn exceed_max_restarts(&mut self, worker: &Worker) -> Option<String> {
+        let limit = worker.config.max_restarts;
+        if limit == 0 {
+            return None;
+        }
+        let window = worker.config.restart_window_sec;
+        let now = time::Instant::now();
+        while let Some(at) = self.respawned_at.front().cloned() {
+            if now.duration_since(at).as_secs() < window {
+                break;
+            }
+            self.respawned_at.pop_front();
+        }
+        if self.respawned_at.len() as u64 >= limit {
+            return Some(format!(
+                "restarted {} times within {} seconds",
+                limit, window
+            ));
+        }
+        self.respawned_at.push_back(now);
+        None
+    }
+
+    /// Stops respawning the processes. The monitor exits, and the worker is not started again
+    /// until the start command.
+    fn give_up(&mut self, worker: &Worker, message: &str) {
+        self.active = false;
+        GAVE_UP.store(true, Ordering::SeqCst);
+        // the monitor exits after this
+        for handle in self.send_webhook(WebhookEvent::Giveup, worker, message) {
+            let _ = handle.join();
+        }
+    }
+
+    fn start_span(
+        &self,
+        name: &str,
+        worker: &Worker,
+        traceparent: Option<&str>,
+    ) -> Option<Span> {
+        self.tracer.as_ref().map(|tracer| {
+            let mut span = tracer.start(name, traceparent);
+            span.attr("worker", worker.name);
+            span
+        })
+    }
+
+    fn end_span(&self, span: Option<Span>, error: Option<String>) {
+        if let (Some(tracer), Some(span)) = (self.tracer.as_ref(), span) {
+            tracer.end(span, error);
+        }
+    }
+
+    /// Spawns the processes of the worker. `since` is the start of the time to ready.
+    fn run_worker(&mut self, worker: &mut Worker, since: time::Instant) -> io::Result<Vec<u32>> {
+        let mut span = self.start_span("worker.spawn", worker, None);
+        match worker.run(self) {
+            Ok(pids) => {
+                if let Some(ref mut span) = span {
+                    span.attr("pids", format!("{:?}", pids));
+                }
+                self.end_span(span, None);
+                self.timing("ready", since);
+                Ok(pids)
+            }
+            Err(e) => {
+                self.end_span(span, Some(e.to_string()));
+                Err(e)
+            }
         }
     }
 
@@ -432,12 +955,13 @@ impl Monitor {
         Token(self.token_count)
     }
 
-    pub fn watch_io(&mut self, fd: RawFd, kind: OutputKind) -> io::Result<()> {
+    pub fn watch_io(&mut self, fd: RawFd, kind: OutputKind, pid: u32) -> io::Result<()> {
         let token = self.next_token();
         set_nonblock(fd)?;
         self.poll
             .register(&EventedFd(&fd), token, Ready::readable(), PollOpt::edge())?;
-        self.io_events.insert(token, IOEvent::new(token, fd, kind));
+        self.io_events
+            .insert(token, IOEvent::new(token, fd, kind, pid));
         Ok(())
     }
 
@@ -461,13 +985,41 @@ impl Monitor {
 
     fn exec_command(
         &mut self,
-        command: &Command,
-        signal: Option<Signal>,
+        cmd: &CtrlCommand,
         worker: &mut Worker,
     ) -> io::Result<CommandResponse> {
-        let name = worker.name.to_owned();
+        let CtrlCommand {
+            ref command,
+            signal,
+            ref traceparent,
+            count,
+            since,
+            ref args,
+            ref binary,
+            ..
+        } = *cmd;
+        let traceparent = traceparent.as_ref().map(|s| s.as_str());
         let self_pid = libc::pid_t::from(self.pid) as u32;
         debug!("exec_command {:?} pid [{}]", command, self.pid);
+        if worker.draining {
+            match command {
+                Command::Start
+                | Command::Inc
+                | Command::Scale
+                | Command::Upgrade
+                | Command::Rollback
+                | Command::RollingRestart
+                | Command::Resume => {
+                    return Ok(CommandResponse {
+                        status: Status::Error,
+                        command: command.clone(),
+                        pid: self_pid,
+                        message: format!("[{}] is draining", worker.name),
+                    })
+                }
+                _ => {}
+            }
+        }
         let res = match command {
             Command::KillAll => {
                 let pids = worker.kill()?;
@@ -488,7 +1040,7 @@ impl Monitor {
                 }
             }
             Command::Stop => {
-                let signal = signal.unwrap_or(Signal::SIGTERM);
+                let signal = signal.unwrap_or(worker.config.stop_signal);
                 let pids = worker.signal_all(signal)?;
                 CommandResponse {
                     status: Status::Ok,
@@ -507,7 +1059,7 @@ impl Monitor {
                 }
             }
             Command::Dec => {
-                let signal = signal.unwrap_or(Signal::SIGTERM);
+                let signal = signal.unwrap_or(worker.config.stop_signal);
                 let pid = worker.dec(signal)?;
                 CommandResponse {
                     status: Status::Ok,
@@ -516,14 +1068,191 @@ impl Monitor {
                     message: format!("decrements worker process pid [{}]", pid),
                 }
             }
+            Command::Signal => match signal {
+                Some(signal) => {
+                    let pids = worker.send_signal(signal);
+                    CommandResponse {
+                        status: Status::Ok,
+                        command: command.clone(),
+                        pid: self_pid,
+                        message: format!("send signal {:?} pid {:?}", signal, pids),
+                    }
+                }
+                None => CommandResponse {
+                    status: Status::Error,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: "signal requires a signal name".to_owned(),
+                },
+            },
+            Command::Logs => {
+                let count = count.unwrap_or(DEFAULT_LOG_LINES);
+                CommandResponse {
+                    status: Status::Ok,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: serde_json::to_string(&worker.output(count, since))?,
+                }
+            }
+            Command::Crashes => CommandResponse {
+                status: Status::Ok,
+                command: command.clone(),
+                pid: self_pid,
+                message: serde_json::to_string(&worker.crash_reports())?,
+            },
+            Command::Drain => {
+                if !worker.draining {
+                    let signal = signal.unwrap_or(worker.config.stop_signal);
+                    worker.draining = true;
+                    for fd in self.listen_fds.drain(..) {
+                        if let Err(e) = close(fd) {
+                            warn!("fail close fd {}. caused by: {} pid [{}]", fd, e, self.pid);
+                        }
+                    }
+                    let pids = worker.signal_all(signal)?;
+                    info!(
+                        "drain [{}]. send signal {:?} pid {:?}. pid [{}]",
+                        worker.name, signal, pids, self.pid
+                    );
+                }
+                let status = DrainStatus {
+                    connections: count_connections(&worker.config.socket_address),
+                    processes: worker.processes.len(),
+                };
+                CommandResponse {
+                    status: Status::Ok,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: serde_json::to_string(&status)?,
+                }
+            }
+            Command::Pause => {
+                let pids = worker.pause()?;
+                CommandResponse {
+                    status: Status::Ok,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: format!("pause processes pid {:?}", pids),
+                }
+            }
+            Command::Resume => {
+                let (pids, spawned) = worker.resume(self)?;
+                CommandResponse {
+                    status: Status::Ok,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: format!(
+                        "resume processes pid {:?} spawned pid {:?}",
+                        pids, spawned
+                    ),
+                }
+            }
+            Command::Scale => match count {
+                Some(count) if count > 0 => {
+                    let signal = signal.unwrap_or(worker.config.stop_signal);
+                    let (spawned, stopped) = worker.scale(self, count, signal)?;
+                    CommandResponse {
+                        status: Status::Ok,
+                        command: command.clone(),
+                        pid: self_pid,
+                        message: format!(
+                            "scale to {} processes. spawned pid {:?} stopped pid {:?}",
+                            count, spawned, stopped
+                        ),
+                    }
+                }
+                _ => CommandResponse {
+                    status: Status::Error,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: "scale requires a count of 1 or more".to_owned(),
+                },
+            },
             Command::Upgrade => {
                 let signal = signal.unwrap_or(Signal::SIGUSR2);
-                let (new, old) = worker.upgrade(self, signal)?;
+                let cmdline = worker.upgrade_cmdline(
+                    binary.as_ref().map(|b| b.as_str()),
+                    args.as_ref().map(|a| a.as_slice()),
+                )?;
+                let started_at = time::Instant::now();
+                let mut span = self.start_span("worker.upgrade", worker, traceparent);
+                let (new, old) = match worker.upgrade(self, signal, cmdline) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        self.end_span(span, Some(e.to_string()));
+                        self.send_webhook(WebhookEvent::UpgradeFailed, worker, &e.to_string());
+                        return Err(e);
+                    }
+                };
+                if let Some(ref mut span) = span {
+                    span.attr("new_pids", format!("{:?}", new));
+                    span.attr("old_pids", format!("{:?}", old));
+                }
+                self.end_span(span, None);
+                self.incr("upgrade");
+                self.timing("upgrade.duration", started_at);
+                let message = format!("upgrade processes new {:?} old {:?}", new, old);
+                self.send_webhook(WebhookEvent::Upgrade, worker, &message);
+                CommandResponse {
+                    status: Status::Ok,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message,
+                }
+            }
+            Command::Rollback => {
+                let signal = signal.unwrap_or(Signal::SIGUSR2);
+                let cmdline = worker.rollback_cmdline()?;
+                let mut span = self.start_span("worker.rollback", worker, traceparent);
+                let (new, old) = match worker.upgrade(self, signal, cmdline) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        self.end_span(span, Some(e.to_string()));
+                        self.send_webhook(WebhookEvent::UpgradeFailed, worker, &e.to_string());
+                        return Err(e);
+                    }
+                };
+                if let Some(ref mut span) = span {
+                    span.attr("new_pids", format!("{:?}", new));
+                    span.attr("old_pids", format!("{:?}", old));
+                }
+                self.end_span(span, None);
+                self.incr("rollback");
+                let message = format!(
+                    "rollback to {} new {:?} old {:?}",
+                    worker.cmdline.join(" "),
+                    new,
+                    old
+                );
+                self.send_webhook(WebhookEvent::Upgrade, worker, &message);
                 CommandResponse {
                     status: Status::Ok,
                     command: command.clone(),
                     pid: self_pid,
-                    message: format!("upgrade processes new {:?} old {:?}", new, old),
+                    message,
+                }
+            }
+            Command::RollingRestart => {
+                let signal = signal.unwrap_or(worker.config.stop_signal);
+                let mut span = self.start_span("worker.rolling_restart", worker, traceparent);
+                let (new, old) = match worker.rolling_restart(self, signal) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        self.end_span(span, Some(e.to_string()));
+                        return Err(e);
+                    }
+                };
+                if let Some(ref mut span) = span {
+                    span.attr("new_pids", format!("{:?}", new));
+                    span.attr("old_pids", format!("{:?}", old));
+                }
+                self.end_span(span, None);
+                self.incr("rolling_restart");
+                CommandResponse {
+                    status: Status::Ok,
+                    command: command.clone(),
+                    pid: self_pid,
+                    message: format!("restart processes new {:?} old {:?}", new, old),
                 }
             }
             Command::Ack => CommandResponse {
@@ -532,26 +1261,27 @@ impl Monitor {
                 pid: self_pid,
                 message: "received ack".to_string(),
             },
-            Command::Status => {
-                let active = if worker.is_alive() {
-                    "active"
-                } else {
-                    "stopped"
-                };
-
+            Command::Status => CommandResponse {
+                status: Status::Ok,
+                command: command.clone(),
+                pid: self_pid,
+                message: serde_json::to_string(&worker.status())?,
+            },
+            Command::ReopenLogs => {
+                worker.reopen_logs()?;
                 CommandResponse {
                     status: Status::Ok,
                     command: command.clone(),
                     pid: self_pid,
-                    message: format!(
-                        "[{}] {}\nprocesses {:?}\ntime {}",
-                        name,
-                        active,
-                        worker.process_pid(),
-                        format_duration(&worker.uptime()),
-                    ),
+                    message: "reopened log files".to_string(),
                 }
             }
+            Command::Metrics => CommandResponse {
+                status: Status::Ok,
+                command: command.clone(),
+                pid: self_pid,
+                message: serde_json::to_string(&worker.stats())?,
+            },
             cmd => CommandResponse {
                 status: Status::Error,
                 command: cmd.clone(),
@@ -563,13 +1293,7 @@ impl Monitor {
     }
 
     fn send_ctrl_command(&mut self, cmd: &CtrlCommand, worker: &mut Worker) -> CommandResponse {
-        let CtrlCommand {
-            ref command,
-            signal,
-            ..
-        } = cmd;
-
-        match self.exec_command(command, *signal, worker) {
+        match self.exec_command(cmd, worker) {
             Ok(res) => res,
             Err(e) => {
                 error!("fail exec command. caused by: {} pid [{}]", e, self.pid);
@@ -592,14 +1316,24 @@ impl Monitor {
                 "wait client connection [{}]. pid [{}]",
                 worker.name, self.pid
             );
-            self.poll.poll_interruptible(&mut events, None)?;
+            match self.poll.poll_interruptible(&mut events, None) {
+                Ok(_) => {}
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        && !SIGNALED.load(Ordering::SeqCst) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
             for event in &events {
                 let token = event.token();
                 if let Some(fd) = self.get_listen_event(token)? {
                     self.poll.deregister(&EventedFd(&fd))?;
                     // spawn
                     if !worker.active {
-                        worker.run(self)?;
+                        let activated_at = time::Instant::now();
+                        self.run_worker(worker, activated_at)?;
                     }
                 }
                 if let Err(e) = self.process_ctrl_event(worker, token) {
@@ -611,63 +1345,270 @@ impl Monitor {
     }
 
     fn process_log_event(&mut self, worker: &mut Worker, token: Token) -> io::Result<bool> {
-        let remove = if let Some(ref mut event) = self.io_events.get_mut(&token) {
-            let mut remove = false;
-            let size = match event.kind {
-                OutputKind::StdOut => {
-                    if let Some(ref mut writer) = worker.stdout_log {
-                        match copy(&mut event.reader, writer) {
-                            Ok(size) => {
-                                writer.flush()?;
-                                size
+        let mut buf = [0u8; 4096];
+        let mut detached = Vec::new();
+        // stdout and stderr are merged on a pty
+        let pty = worker.config.pty;
+        let mut captured = Vec::new();
+        let (closed, pid) = match self.io_events.get_mut(&token) {
+            Some(event) => {
+                let capture = event.kind == OutputKind::StdErr || pty;
+                let mut writer = match event.kind {
+                    OutputKind::StdOut => worker.stdout_log.as_mut(),
+                    OutputKind::StdErr => worker.stderr_log.as_mut(),
+                };
+                let closed = loop {
+                    match event.reader.read(&mut buf) {
+                        Ok(0) => break true,
+                        Ok(size) => {
+                            if let Some(ref mut writer) = writer {
+                                writer.write_all(&buf[..size])?;
+                            }
+                            if capture {
+                                captured.extend_from_slice(&buf[..size]);
                             }
-                            Err(e) => {
-                                if e.raw_os_error() == Some(libc::EWOULDBLOCK)
-                                    || e.raw_os_error() == Some(libc::EAGAIN)
+                            for (token, attachment) in &mut self.attachments {
+                                if attachment.pid == event.pid
+                                    && attachment.stream.write_all(&buf[..size]).is_err()
                                 {
-                                    writer.flush()?;
-                                    return Ok(false);
-                                } else {
-                                    return Err(e);
+                                    detached.push(*token);
                                 }
                             }
                         }
-                    } else {
-                        return Ok(false);
-                    }
-                }
-                OutputKind::StdErr => {
-                    if let Some(ref mut writer) = worker.stderr_log {
-                        match copy(&mut event.reader, writer) {
-                            Ok(size) => {
-                                writer.flush()?;
-                                size
-                            }
-                            Err(e) => {
-                                if e.raw_os_error() == Some(libc::EWOULDBLOCK)
-                                    || e.raw_os_error() == Some(libc::EAGAIN)
-                                {
-                                    writer.flush()?;
-                                    return Ok(false);
-                                } else {
-                                    return Err(e);
-                                }
+                        Err(e) => {
+                            if e.raw_os_error() == Some(libc::EWOULDBLOCK)
+                                || e.raw_os_error() == Some(libc::EAGAIN)
+                            {
+                                break false;
+                            } else if e.raw_os_error() == Some(libc::EIO) {
+                                // the master of a pty is closed by EIO after the process exited
+                                break true;
+                            } else {
+                                return Err(e);
                             }
                         }
-                    } else {
-                        return Ok(false);
                     }
+                };
+                if let Some(writer) = writer {
+                    writer.flush()?;
+                }
+                if closed {
+                    self.poll.deregister(&EventedFd(&event.fd))?;
+                }
+                (closed, event.pid)
+            }
+            None => return Ok(false),
+        };
+        if !captured.is_empty() {
+            worker.capture_stderr(pid, &captured);
+        }
+        if closed {
+            // the clients attached to the exited process
+            for (token, attachment) in &self.attachments {
+                if attachment.pid == pid {
+                    detached.push(*token);
                 }
+            }
+        }
+        for token in detached {
+            self.detach(token);
+        }
+        Ok(closed)
+    }
+
+    /// Answers the attach command. The connection is kept to bridge the pty of the oldest process.
+    fn attach(
+        &mut self,
+        worker: &mut Worker,
+        mut stream: UnixStream,
+        peer: &Peer,
+        cmd: &CtrlCommand,
+    ) -> io::Result<()> {
+        let pid = match (worker.config.pty, worker.attach_pid()) {
+            (false, _) => Err("attach requires pty = true"),
+            (true, None) => Err("no process to attach"),
+            (true, Some(pid)) => Ok(pid),
+        };
+        let res = CommandResponse {
+            status: if pid.is_ok() {
+                Status::Ok
+            } else {
+                Status::Error
+            },
+            command: Command::Attach,
+            pid: libc::pid_t::from(self.pid) as u32,
+            message: match pid {
+                Ok(pid) => format!("attach to pid [{}]", pid),
+                Err(msg) => msg.to_owned(),
+            },
+        };
+        self.audit_command(peer, cmd, &res, worker.name);
+        send_response(&mut stream, &res)?;
+        if let Ok(pid) = pid {
+            stream.set_write_timeout(Some(time::Duration::from_secs(ATTACH_WRITE_TIMEOUT_SECS)))?;
+            let token = self.next_token();
+            self.poll.register(
+                &EventedFd(&stream.as_raw_fd()),
+                token,
+                Ready::readable(),
+                PollOpt::level(),
+            )?;
+            info!(
+                "attach client to [{}] pid [{}]. pid [{}]",
+                worker.name, pid, self.pid
+            );
+            self.attachments.insert(token, Attachment { stream, pid });
+        }
+        Ok(())
+    }
+
+    /// Spawns a one-off command in the context of the worker.
+    /// The output and the exit code follow the response.
+    fn exec(
+        &mut self,
+        worker: &mut Worker,
+        mut stream: UnixStream,
+        peer: &Peer,
+        cmd: &CtrlCommand,
+    ) -> io::Result<()> {
+        let child = match cmd.args {
+            Some(ref args) if !args.is_empty() => worker
+                .exec_process(args)
+                .map(|child| (child, args.join(" ")))
+                .map_err(|e| format!("fail exec {}. caused by: {}", args.join(" "), e)),
+            _ => Err("exec requires a command".to_owned()),
+        };
+        let res = CommandResponse {
+            status: if child.is_ok() {
+                Status::Ok
+            } else {
+                Status::Error
+            },
+            command: Command::Exec,
+            pid: libc::pid_t::from(self.pid) as u32,
+            message: match child {
+                Ok((ref child, ref cmdline)) => format!("exec {} pid [{}]", cmdline, child.id()),
+                Err(ref msg) => msg.clone(),
+            },
+        };
+        self.audit_command(peer, cmd, &res, worker.name);
+        send_response(&mut stream, &res)?;
+        if let Ok((child, _)) = child {
+            self.execs.push(child.id());
+            exec::stream_output(child, stream);
+        }
+        Ok(())
+    }
+
+    /// Answers the wait command and holds the connection. The result follows the response
+    /// when the worker reaches the state, gives up or the timeout passes.
+    fn wait(
+        &mut self,
+        worker: &mut Worker,
+        mut stream: UnixStream,
+        peer: &Peer,
+        cmd: &CtrlCommand,
+    ) -> io::Result<()> {
+        let state = match cmd.args {
+            Some(ref args) if args.len() == 1 => args[0].clone(),
+            _ => String::new(),
+        };
+        let known = WAIT_STATES.contains(&state.as_str());
+        let res = CommandResponse {
+            status: if known { Status::Ok } else { Status::Error },
+            command: Command::Wait,
+            pid: libc::pid_t::from(self.pid) as u32,
+            message: if known {
+                format!("wait [{}] to be {}", worker.name, state)
+            } else {
+                format!("unknown state {}", state)
+            },
+        };
+        self.audit_command(peer, cmd, &res, worker.name);
+        send_response(&mut stream, &res)?;
+        if known {
+            let timeout = cmd.timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT_SECS);
+            self.waiters.push(Waiter {
+                stream,
+                state,
+                upgrades: cmd.since.unwrap_or(worker.upgrades),
+                deadline: time::Instant::now() + time::Duration::from_secs(timeout),
+            });
+            self.check_waiters(worker);
+        }
+        Ok(())
+    }
+
+    /// Answers the clients of `wait` whose state is reached, can not be reached any more or
+    /// timed out. The rest are closed when the monitor exits, and the clients ask the daemon.
+    fn check_waiters(&mut self, worker: &mut Worker) {
+        if self.waiters.is_empty() {
+            return;
+        }
+        let current = worker.status();
+        let now = time::Instant::now();
+        for mut waiter in mem::replace(&mut self.waiters, Vec::new()) {
+            let upgraded = waiter.state != "upgraded" || worker.upgrades > waiter.upgrades;
+            let res = if current.reached(&waiter.state) && upgraded {
+                serde_json::to_string(&current).map_err(|e| e.to_string())
+            } else if let Some(msg) = current.unreachable(&waiter.state) {
+                Err(msg)
+            } else if now >= waiter.deadline {
+                Err(format!(
+                    "timed out waiting [{}] to be {}. state {}",
+                    worker.name, waiter.state, current.state
+                ))
+            } else {
+                self.waiters.push(waiter);
+                continue;
+            };
+            let (status, message) = match res {
+                Ok(message) => (Status::Ok, message),
+                Err(message) => (Status::Error, message),
             };
-            if size == 0 {
-                self.poll.deregister(&EventedFd(&event.fd))?;
-                remove = true;
+            let res = CommandResponse {
+                status,
+                command: Command::Wait,
+                pid: libc::pid_t::from(self.pid) as u32,
+                message,
+            };
+            if let Err(e) = send_response(&mut waiter.stream, &res) {
+                debug!("fail answer wait. caused by: {} pid [{}]", e, self.pid);
             }
-            remove
-        } else {
-            false
+        }
+    }
+
+    /// Writes the input of an attached client to the pty. The client is detached on EOF.
+    fn process_attach_event(&mut self, worker: &mut Worker, token: Token) {
+        let mut buf = [0u8; 4096];
+        let (res, pid) = match self.attachments.get_mut(&token) {
+            Some(attachment) => (attachment.stream.read(&mut buf), attachment.pid),
+            None => return,
         };
-        Ok(remove)
+        match res {
+            Ok(0) => {}
+            Ok(size) => match worker.write_pty(pid, &buf[..size]) {
+                Ok(()) => return,
+                Err(e) => warn!("fail write pty. caused by: {} pid [{}]", e, self.pid),
+            },
+            Err(e) => warn!(
+                "fail read attached client. caused by: {} pid [{}]",
+                e, self.pid
+            ),
+        }
+        self.detach(token);
+    }
+
+    fn detach(&mut self, token: Token) {
+        if let Some(attachment) = self.attachments.remove(&token) {
+            let _ = self
+                .poll
+                .deregister(&EventedFd(&attachment.stream.as_raw_fd()));
+            info!(
+                "detach client from pid [{}]. pid [{}]",
+                attachment.pid, self.pid
+            );
+        }
     }
 
     fn get_listen_event(&mut self, token: Token) -> io::Result<Option<RawFd>> {
@@ -691,37 +1632,154 @@ impl Monitor {
         false
     }
 
-    fn get_ack_event(&mut self, token: Token) -> io::Result<Option<Signal>> {
+    /// Returns the signal for an old process when a new process sent the ack.
+    fn get_ack_event(
+        &mut self,
+        worker: &mut Worker,
+        token: Token,
+        default_signal: Signal,
+    ) -> io::Result<Option<Signal>> {
         if self.is_ctrl_event(token) {
-            let (stream, _addr) = &mut self.ctrl_sock.accept()?;
-            let cmd = read_command(stream)?;
-            let CtrlCommand {
-                ref command,
-                signal,
-                ..
-            } = cmd;
-
-            match command {
-                Command::Ack => return Ok(signal),
+            let (mut stream, _addr) = self.ctrl_sock.accept()?;
+            let cmd = read_command(&mut stream)?;
+            let peer = Peer::from_unix_fd(stream.as_raw_fd());
+            match cmd.command {
+                Command::Ack => {
+                    if self.ack(worker, &cmd, &peer) {
+                        return Ok(Some(cmd.signal.unwrap_or(default_signal)));
+                    }
+                }
+                Command::Heartbeat => self.heartbeat(worker, &cmd, &peer),
                 _ => warn!("now upgrading. ignore command. pid [{}]", self.pid),
             }
         }
         Ok(None)
     }
 
+    /// Marks the process that sent the ack as ready.
+    fn ack(&self, worker: &mut Worker, cmd: &CtrlCommand, peer: &Peer) -> bool {
+        if worker.config.ack != AckKind::Manual {
+            debug!(
+                "ignore ack. [{}] is not manual ack. pid [{}]",
+                worker.name, self.pid
+            );
+            return false;
+        }
+        let mut senders = vec![cmd.pid];
+        senders.extend(peer.pid.map(|pid| pid as u32));
+        match worker.ack_process(&senders) {
+            Some(pid) => {
+                info!(
+                    "ready process [{}] pid [{}]. pid [{}]",
+                    worker.name, pid, self.pid
+                );
+                true
+            }
+            None => {
+                warn!(
+                    "ignore ack from pid {:?}. no process of [{}] waits for it. pid [{}]",
+                    senders, worker.name, self.pid
+                );
+                false
+            }
+        }
+    }
+
+    fn heartbeat(&self, worker: &mut Worker, cmd: &CtrlCommand, peer: &Peer) {
+        let mut senders = vec![cmd.pid];
+        senders.extend(peer.pid.map(|pid| pid as u32));
+        match worker.heartbeat_process(&senders) {
+            Some(pid) => debug!(
+                "receive heartbeat [{}] pid [{}]. pid [{}]",
+                worker.name, pid, self.pid
+            ),
+            None => warn!(
+                "ignore heartbeat from pid {:?}. no process of [{}]. pid [{}]",
+                senders, worker.name, self.pid
+            ),
+        }
+    }
+
     fn process_ctrl_event(&mut self, worker: &mut Worker, token: Token) -> io::Result<()> {
         if self.is_ctrl_event(token) {
             let (mut stream, _addr) = self.ctrl_sock.accept()?;
-            let cmd = read_command(&stream)?;
-            let res = self.send_ctrl_command(&cmd, worker);
-            match res.command {
-                Command::Ack => debug!("ignore ack response.it is not an upgrade"),
-                _ => send_response(&mut stream, &res)?,
+            let cmd = read_command(&mut stream)?;
+            let peer = Peer::from_unix_fd(stream.as_raw_fd());
+            if !self.allow(&peer, &cmd) {
+                warn!(
+                    "deny {:?} from [{}] on [{}] control socket. pid [{}]",
+                    cmd.command, peer, worker.name, self.pid
+                );
+                let msg = "permission denied".to_owned();
+                write_frame(&mut stream, MessageType::Error, &msg)?;
+                let res = CommandResponse {
+                    status: Status::Error,
+                    command: cmd.command.clone(),
+                    pid: libc::pid_t::from(self.pid) as u32,
+                    message: msg,
+                };
+                self.audit_command(&peer, &cmd, &res, worker.name);
+                return Ok(());
+            }
+            if cmd.command == Command::Attach {
+                return self.attach(worker, stream, &peer, &cmd);
             }
+            if cmd.command == Command::Exec {
+                return self.exec(worker, stream, &peer, &cmd);
+            }
+            if cmd.command == Command::Wait {
+                return self.wait(worker, stream, &peer, &cmd);
+            }
+            if cmd.command == Command::Ack {
+                self.ack(worker, &cmd, &peer);
+                return Ok(());
+            }
+            if cmd.command == Command::Heartbeat {
+                self.heartbeat(worker, &cmd, &peer);
+                return Ok(());
+            }
+            let res = self.send_ctrl_command(&cmd, worker);
+            self.audit_command(&peer, &cmd, &res, worker.name);
+            send_response(&mut stream, &res)?;
         }
         Ok(())
     }
 
+    fn allow(&self, peer: &Peer, cmd: &CtrlCommand) -> bool {
+        match (&self.auth, &cmd.command) {
+            // workers may run as another user and ack the upgrade
+            (_, Command::Ack) | (_, Command::Heartbeat) | (None, _) => true,
+            (Some(auth), _) => auth.allow(peer, None),
+        }
+    }
+
+    /// Records commands sent directly to the control socket of the worker.
+    /// The daemon records the commands it forwards.
+    fn audit_command(&self, peer: &Peer, cmd: &CtrlCommand, res: &CommandResponse, name: &str) {
+        let audit = match self.audit {
+            Some(ref audit) => audit,
+            None => return,
+        };
+        match cmd.command {
+            // polling and the upgrade protocol of the workers are not audited
+            Command::Metrics | Command::Ack | Command::Heartbeat => return,
+            Command::Logs if cmd.since.is_some() => return,
+            _ => {}
+        }
+        if peer.pid == Some(libc::pid_t::from(getppid())) {
+            return;
+        }
+        let command = command_name(&cmd.command);
+        audit.record(
+            "worker",
+            peer,
+            command,
+            Some(name),
+            &res.status,
+            &res.message,
+        );
+    }
+
     pub fn start(&mut self, worker: &mut Worker) -> io::Result<()> {
         info!("start [{}] monitor. pid [{}]", worker.name, self.pid);
         self.active = true;
@@ -742,7 +1800,20 @@ impl Monitor {
 
         while self.active {
             let mut alive = true;
-            let size = self.poll.poll_interruptible(&mut events, timeout)?;
+            let size = match self.poll.poll_interruptible(&mut events, timeout) {
+                Ok(size) => size,
+                // the signals of the threads running the health checks interrupt poll too
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        && !SIGNALED.load(Ordering::SeqCst) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            if SIGNALED.swap(false, Ordering::SeqCst) {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
             for event in &events {
                 let token = event.token();
                 // catch err ?
@@ -756,34 +1827,85 @@ impl Monitor {
                         e, self.pid
                     );
                 }
+                self.process_attach_event(worker, token);
             }
+            // every change made by a command is seen, even when the next one reverts it
+            self.check_waiters(worker);
 
             if let Ok(elapsed) = now.elapsed() {
                 if elapsed.as_secs() > 1 {
-                    worker.check_live_processes();
+                    // a stopped process does not touch its watch file
+                    if !worker.paused {
+                        worker.check_live_processes();
+                        worker.check_heartbeats();
+                        worker.check_start_timeouts();
+                        self.check_memory(worker);
+                        self.check_cpu(worker);
+                        self.check_fds(worker);
+                    }
+                    // drain has its own timeout
+                    if !worker.draining {
+                        worker.check_kill_after();
+                    }
+                    self.reap_orphans(worker);
+                    if !worker.paused && !worker.draining && worker.scheduled_run_due() {
+                        if let Err(e) = self.run_worker(worker, time::Instant::now()) {
+                            warn!(
+                                "fail scheduled run [{}] worker. caused by: {} pid [{}]",
+                                worker.name, e, self.pid
+                            );
+                        }
+                    }
                     now = time::SystemTime::now();
                 }
             }
+            if !worker.paused {
+                worker.check_readiness();
+            }
+            self.check_health(worker);
             if alive && size > 0 {
                 continue;
             }
             let (_alive, respawn) = worker.health_check();
+            // the exited processes are respawned on resume, and never while draining
+            let respawn = if worker.paused || worker.draining {
+                worker.cancel_respawns();
+                0
+            } else {
+                respawn
+            };
+            for (pid, exit_code) in worker.take_crashes() {
+                let message = format!("process exited. exit_code [{}] pid [{}]", exit_code, pid);
+                self.send_webhook(WebhookEvent::Crash, worker, &message);
+            }
             for _ in 0..respawn {
+                if let Some(message) = self.exceed_max_restarts(worker) {
+                    error!("GIVEUP! {}. pid [{}]", message, self.pid);
+                    self.give_up(worker, &message);
+                    break;
+                }
                 if let Err(e) = worker.run_process(self) {
                     error!("respawn error. caused by: {} pid [{}]", e, self.pid);
                     fail += 1;
                     if self.giveup != 0 && fail >= self.giveup {
                         // giveup !!
-                        self.active = false;
                         error!("GIVEUP! the process can not started. pid [{}]", self.pid);
+                        let message = format!("respawn failed {} times. caused by: {}", fail, e);
+                        self.give_up(worker, &message);
+                        break;
                     }
                 } else {
+                    worker.restarts += 1;
+                    self.incr("restart");
+                    self.record_restart(worker);
                     // reset
                     fail = 0;
                 }
             }
+            self.check_waiters(worker);
         }
         worker.active = false;
+        self.check_waiters(worker);
         info!("exited [{}] monitor. pid [{}]", worker.name, self.pid);
         Ok(())
     }
@@ -799,6 +1921,9 @@ impl Monitor {
         let mut count = 0;
         while ack.is_empty() && count < 10 {
             if let Err(e) = self.poll.poll_interruptible(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted && !SIGNALED.load(Ordering::SeqCst) {
+                    continue;
+                }
                 // cleanup
                 worker.signal_all(Signal::SIGTERM)?;
                 if let Ok(_var) = env::var(format!("{}_HANDLE_SIGNAL", APP_NAME_UPPER)) {
@@ -812,8 +1937,9 @@ impl Monitor {
                 if self.process_log_event(worker, token)? {
                     self.io_events.remove(&token);
                 }
-                let signal = self.get_ack_event(token)?;
-                ack.push(signal.unwrap_or(default_signal));
+                if let Some(signal) = self.get_ack_event(worker, token, default_signal)? {
+                    ack.push(signal);
+                }
             }
 
             let mut i = 0;
@@ -845,6 +1971,9 @@ impl Monitor {
 
         loop {
             if let Err(e) = self.poll.poll_interruptible(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted && !SIGNALED.load(Ordering::SeqCst) {
+                    continue;
+                }
                 if let Err(e) = worker.signal_all(Signal::SIGTERM) {
                     warn!("fail send signal SIGTERM. caused by: {:?}", e);
                 }
@@ -906,7 +2035,223 @@ impl Monitor {
         }
     }
 
+    /// Waits until the readiness check of the new processes of an upgrade passes.
+    /// It fails when a new process exits or `readiness_timeout_sec` has passed.
+    pub fn wait_ready(
+        &mut self,
+        worker: &mut Worker,
+        check: &mut HealthCheck,
+        environment: &HashMap<String, String>,
+    ) -> io::Result<()> {
+        let config = worker.config;
+        let working_directory = config.root_path(&config.working_directory);
+        let mut events = Events::with_capacity(1024);
+        let started_at = time::Instant::now();
+        loop {
+            self.watch_new_processes(worker, &mut events)?;
+            if let Some((failures, e)) = check.poll(&working_directory, environment) {
+                warn!(
+                    "fail readiness check [{}] {} times. caused by: {} pid [{}]",
+                    worker.name, failures, e, self.pid
+                );
+            }
+            if check.passed() {
+                let pids = worker.process_pid();
+                info!(
+                    "pass readiness check [{}] pid {:?}. pid [{}]",
+                    worker.name, pids, self.pid
+                );
+                return Ok(());
+            }
+            if started_at.elapsed().as_secs() >= config.readiness_timeout_sec {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "readiness check not passed in {} secs",
+                        config.readiness_timeout_sec
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Watches the new processes of an upgrade for `secs`. It fails when a new process exits,
+    /// or the check fails failure_threshold times in a row.
+    pub fn bake(
+        &mut self,
+        worker: &mut Worker,
+        check: Option<&mut HealthCheck>,
+        environment: &HashMap<String, String>,
+        secs: u64,
+    ) -> io::Result<()> {
+        let config = worker.config;
+        let working_directory = config.root_path(&config.working_directory);
+        let mut events = Events::with_capacity(1024);
+        let started_at = time::Instant::now();
+        let mut check = check;
+        while started_at.elapsed().as_secs() < secs {
+            self.watch_new_processes(worker, &mut events)?;
+            if let Some(ref mut check) = check {
+                if let Some((failures, e)) = check.poll(&working_directory, environment) {
+                    warn!(
+                        "fail canary check [{}] {}/{}. caused by: {} pid [{}]",
+                        worker.name,
+                        failures,
+                        check.failure_threshold(),
+                        e,
+                        self.pid
+                    );
+                    if failures >= check.failure_threshold() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("canary check failed {} times", failures),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits a second for the output of the processes of an upgrade, and fails when one of
+    /// the new processes has exited.
+    fn watch_new_processes(&mut self, worker: &mut Worker, events: &mut Events) -> io::Result<()> {
+        let timeout = Some(time::Duration::from_secs(1));
+        match self.poll.poll_interruptible(events, timeout) {
+            Ok(_) => {}
+            Err(ref e)
+                if e.kind() == io::ErrorKind::Interrupted && !SIGNALED.load(Ordering::SeqCst) => {}
+            Err(e) => return Err(e),
+        }
+        for event in events.iter() {
+            let token = event.token();
+            if self.process_log_event(worker, token)? {
+                self.io_events.remove(&token);
+            }
+        }
+        for p in &mut worker.processes {
+            if p.try_wait().is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("new process {} exited", p.process_name()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the processes with `stop_signal` on the exit of the monitor, and kills
+    /// the processes alive after `kill_after_sec`.
+    fn stop_processes(&mut self, worker: &mut Worker) {
+        let signal = worker.config.stop_signal;
+        if let Err(e) = worker.signal_all(signal) {
+            warn!("fail send signal {:?}. caused by: {}", signal, e);
+        }
+        let started_at = time::Instant::now();
+        loop {
+            // SIGCHLD of the stopped processes interrupts the poll
+            match self.poll_process_io(worker, 1) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => warn!("fail worker cleanup. caused by: {}", e),
+                Ok(()) => {}
+            }
+            let timeout = worker.config.kill_after_sec;
+            if timeout == 0 || worker.reap_processes() == 0 {
+                break;
+            }
+            if started_at.elapsed().as_secs() >= timeout {
+                match worker.kill() {
+                    Ok(pids) => warn!(
+                        "no reaction. killed [{}] processes {:?}. pid [{}]",
+                        worker.name, pids, self.pid
+                    ),
+                    Err(e) => warn!("fail kill processes. caused by: {}", e),
+                }
+                break;
+            }
+        }
+        self.kill_orphans(worker);
+        worker.active = false;
+        self.check_waiters(worker);
+    }
+
+    /// Reaps the orphans adopted from the processes after SIGCHLD. The children of the
+    /// threads, e.g. of the health checks, and the one-off processes of exec are left to the
+    /// threads that wait them. A zombie is reaped when it is still not waited at the next
+    /// sweep.
+    fn reap_orphans(&mut self, worker: &mut Worker) {
+        if !CHILD_EXITED.swap(false, Ordering::SeqCst) && self.zombies.is_empty() {
+            return;
+        }
+        let tracked = worker.tracked_pids();
+        let self_pid = libc::pid_t::from(self.pid) as u32;
+        let children = child_processes(self_pid);
+        // an exec waited by its thread is no longer a child
+        self.execs
+            .retain(|pid| children.iter().any(|(child, _)| child == pid));
+        let execs = &self.execs;
+        let (reap, zombies): (Vec<u32>, Vec<u32>) = children
+            .into_iter()
+            .filter(|&(pid, state)| {
+                state == 'Z' && !tracked.contains(&pid) && !execs.contains(&pid)
+            })
+            .map(|(pid, _)| pid)
+            .partition(|pid| self.zombies.contains(pid));
+        for pid in reap {
+            let orphan = Pid::from_raw(pid as libc::pid_t);
+            match waitpid(orphan, Some(WaitPidFlag::WNOHANG)) {
+                Ok(_) => info!("reaped orphan process. pid [{}]", pid),
+                Err(e) => warn!("fail reap orphan process. caused by: {} pid [{}]", e, pid),
+            }
+        }
+        self.zombies = zombies;
+    }
+
+    /// Kills the orphans adopted from the processes, which would keep e.g. the listen
+    /// socket busy after the worker has stopped. An orphan that can not be signalled is
+    /// left alone.
+    pub fn kill_orphans(&mut self, worker: &mut Worker) {
+        let mut skipped = worker.tracked_pids();
+        skipped.extend(&self.execs);
+        let self_pid = libc::pid_t::from(self.pid) as u32;
+        for _ in 0..ORPHAN_KILL_ROUNDS {
+            let orphans: Vec<(u32, char)> = child_processes(self_pid)
+                .into_iter()
+                .filter(|(pid, _)| !skipped.contains(pid))
+                .collect();
+            if orphans.is_empty() {
+                return;
+            }
+            for (pid, state) in orphans {
+                let orphan = Pid::from_raw(pid as libc::pid_t);
+                if state != 'Z' {
+                    if let Err(e) = signal::kill(orphan, signal::SIGKILL) {
+                        warn!("fail kill orphan process. caused by: {} pid [{}]", e, pid);
+                        skipped.push(pid);
+                        continue;
+                    }
+                    warn!("killed [{}] orphan process. pid [{}]", worker.name, pid);
+                }
+                let _ = waitpid(orphan, None);
+            }
+        }
+        warn!(
+            "gave up kill [{}] orphan processes after {} rounds. pid [{}]",
+            worker.name, ORPHAN_KILL_ROUNDS, self.pid
+        );
+    }
+
     pub fn wait_process_io(&mut self, worker: &mut Worker, secs: u64) -> io::Result<()> {
+        let res = self.poll_process_io(worker, secs);
+        if res.is_err() {
+            if let Ok(_var) = env::var(format!("{}_HANDLE_SIGNAL", APP_NAME_UPPER)) {
+                exit(-1);
+            }
+        }
+        res
+    }
+
+    fn poll_process_io(&mut self, worker: &mut Worker, secs: u64) -> io::Result<()> {
         let mut events = Events::with_capacity(1024);
         let now = time::SystemTime::now();
         let timeout = Some(time::Duration::from_secs(secs));
@@ -933,12 +2278,10 @@ impl Monitor {
                         return Ok(());
                     }
                 }
-                Err(e) => {
-                    if let Ok(_var) = env::var(format!("{}_HANDLE_SIGNAL", APP_NAME_UPPER)) {
-                        exit(-1);
-                    }
-                    return Err(e);
-                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        && !SIGNALED.load(Ordering::SeqCst) => {}
+                Err(e) => return Err(e),
             }
         }
     }
@@ -0,0 +1,111 @@
+use std::os::unix::io::RawFd;
+use std::{env, time};
+
+use failure::Error;
+use nix::sys::socket::{self, AddressFamily, MsgFlags, SockAddr, SockFlag, SockType, UnixAddr};
+use nix::unistd::{close, getpid};
+
+/// Sends state notifications to systemd (`Type=notify`) through `NOTIFY_SOCKET`.
+#[derive(Debug)]
+pub struct Notifier {
+    path: String,
+    addr: SockAddr,
+    fd: RawFd,
+    watchdog: Option<time::Duration>,
+    last_watchdog: time::SystemTime,
+}
+
+impl Notifier {
+    /// Returns `None` when not launched by systemd. The variables are removed
+    /// so that worker processes do not notify in place of the daemon.
+    pub fn from_env() -> Result<Option<Notifier>, Error> {
+        let path = match env::var("NOTIFY_SOCKET") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        env::remove_var("NOTIFY_SOCKET");
+        let watchdog = watchdog_interval();
+        env::remove_var("WATCHDOG_USEC");
+        env::remove_var("WATCHDOG_PID");
+
+        let addr = if path.starts_with('@') {
+            UnixAddr::new_abstract(path[1..].as_bytes())?
+        } else {
+            UnixAddr::new(path.as_str())?
+        };
+        let fd = socket::socket(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        debug!("notify socket {} watchdog {:?}", path, watchdog);
+        Ok(Some(Notifier {
+            path,
+            addr: SockAddr::Unix(addr),
+            fd,
+            watchdog,
+            last_watchdog: time::SystemTime::now(),
+        }))
+    }
+
+    /// The variables that pass the socket down to the daemon re-executed by `upexec`.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![("NOTIFY_SOCKET".to_owned(), self.path.to_owned())];
+        if let Some(interval) = self.watchdog {
+            let usec = interval.as_secs() * 1_000_000 + u64::from(interval.subsec_micros());
+            env.push(("WATCHDOG_USEC".to_owned(), usec.to_string()));
+            env.push(("WATCHDOG_PID".to_owned(), getpid().to_string()));
+        }
+        env
+    }
+
+    pub fn notify(&self, state: &str) {
+        debug!("notify {:?}. pid [{}]", state, getpid());
+        if let Err(e) = socket::sendto(self.fd, state.as_bytes(), &self.addr, MsgFlags::empty()) {
+            warn!("fail notify {:?}. caused by: {}", state, e);
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify(&format!("READY=1\nMAINPID={}", getpid()));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// Pings the watchdog at half of `WATCHDOG_USEC`.
+    pub fn watchdog(&mut self) {
+        if let Some(interval) = self.watchdog {
+            match self.last_watchdog.elapsed() {
+                Ok(elapsed) if elapsed < interval / 2 => {}
+                _ => {
+                    self.notify("WATCHDOG=1");
+                    self.last_watchdog = time::SystemTime::now();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        if let Err(e) = close(self.fd) {
+            trace!("fail close notify socket. caused by: {}", e);
+        }
+    }
+}
+
+fn watchdog_interval() -> Option<time::Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid != getpid().to_string() {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(time::Duration::from_micros(usec))
+}
@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::Read;
+use std::time;
+
+use failure::Error;
+use nix::unistd::getpid;
+use serde_json::Value;
+
+use config::OtlpConfig;
+use http::{self, POSTS};
+use pool::Handle;
+
+const DEFAULT_TRACES_PATH: &str = "/v1/traces";
+// the export runs in the background, but do not keep a monitor waiting on exit
+const EXPORT_TIMEOUT_SECS: u64 = 2;
+
+/// Exports spans of the supervisor activity with OTLP/HTTP JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tracer {
+    endpoint: String,
+    service_name: String,
+}
+
+/// A span started by `Tracer::start`. It is exported by `Tracer::end`.
+#[derive(Debug)]
+pub struct Span {
+    name: String,
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: time::SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+impl Span {
+    pub fn attr<V: ToString>(&mut self, key: &str, value: V) {
+        self.attributes.push((key.to_owned(), value.to_string()));
+    }
+}
+
+fn random_hex(len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+        // ids only need to be unique enough to tell the spans apart
+        warn!("fail read /dev/urandom. caused by: {}", e);
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (nanos >> ((i % 4) * 8)) as u8 ^ (i as u8);
+        }
+    }
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_digit(16)) && s.chars().any(|c| c != '0')
+}
+
+/// Parses a W3C `traceparent` (`00-<trace id>-<parent id>-<flags>`).
+fn parse_traceparent(s: &str) -> Option<(String, String)> {
+    let v: Vec<&str> = s.trim().split('-').collect();
+    if v.len() == 4 && v[0] == "00" && is_hex(v[1], 32) && is_hex(v[2], 16) {
+        Some((v[1].to_lowercase(), v[2].to_lowercase()))
+    } else {
+        None
+    }
+}
+
+fn unix_nanos(t: time::SystemTime) -> String {
+    let d = t.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    (d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())).to_string()
+}
+
+impl Tracer {
+    pub fn new(config: &OtlpConfig) -> Result<Tracer, Error> {
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let (_host, path) = http::parse_url(endpoint)?;
+        let endpoint = if path == "/" {
+            format!("{}{}", endpoint, DEFAULT_TRACES_PATH)
+        } else {
+            endpoint.to_owned()
+        };
+        Ok(Tracer {
+            endpoint,
+            service_name: config.service_name.to_owned(),
+        })
+    }
+
+    /// Starts a span. With a `traceparent` of the client, the span joins the trace of the client.
+    pub fn start(&self, name: &str, traceparent: Option<&str>) -> Span {
+        let (trace_id, parent_span_id) = match traceparent.and_then(parse_traceparent) {
+            Some((trace_id, parent)) => (trace_id, Some(parent)),
+            None => (random_hex(16), None),
+        };
+        Span {
+            name: name.to_owned(),
+            trace_id,
+            span_id: random_hex(8),
+            parent_span_id,
+            start: time::SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Ends the span and exports it in the background. Join the handle to wait for the export.
+    pub fn end(&self, span: Span, error: Option<String>) -> Handle {
+        let body = self.encode(&span, time::SystemTime::now(), error);
+        let tracer = self.clone();
+        let timeout = time::Duration::from_secs(EXPORT_TIMEOUT_SECS);
+        POSTS.spawn(move || {
+            if let Err(e) = http::post_json(&tracer.endpoint, &body, timeout) {
+                warn!(
+                    "fail export span {}. caused by: {} pid [{}]",
+                    span.name,
+                    e,
+                    getpid()
+                );
+            }
+        })
+    }
+
+    fn encode(&self, span: &Span, end: time::SystemTime, error: Option<String>) -> Value {
+        let mut attributes = vec![json!({
+            "key": "process.pid",
+            "value": {"intValue": getpid().to_string()}
+        })];
+        for (key, value) in &span.attributes {
+            attributes.push(json!({"key": key, "value": {"stringValue": value}}));
+        }
+        let status = match error {
+            // STATUS_CODE_ERROR
+            Some(message) => json!({"code": 2, "message": message}),
+            // STATUS_CODE_OK
+            None => json!({"code": 1}),
+        };
+        let mut data = json!({
+            "traceId": span.trace_id,
+            "spanId": span.span_id,
+            "name": span.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(ref parent) = span.parent_span_id {
+            data["parentSpanId"] = json!(parent);
+        }
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": {"stringValue": self.service_name}
+                    }]
+                },
+                "scopeSpans": [{
+                    "scope": {"name": "firestarter"},
+                    "spans": [data]
+                }]
+            }]
+        })
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use failure::{err_msg, Error};
+use libc;
+use nix::unistd::{getpid, Pid};
+
+/// A pid file locked by the daemon while it is running.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    // holds the lock until the daemon exits
+    _file: File,
+    pid: Pid,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<PidFile, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o644)
+            .open(path)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        let old_pid = buf.trim();
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(err_msg(format!(
+                    "daemon already running. pid [{}] pidfile {:?}",
+                    old_pid, path
+                )));
+            }
+            return Err(e.into());
+        }
+        let pid = getpid();
+        // upexec keeps the pid, but the lock is released on exec
+        if !old_pid.is_empty() && old_pid != pid.to_string() {
+            // the lock is released when the daemon exits, so the pid is from a crashed daemon
+            warn!("remove stale pidfile {:?}. pid [{}]", path, old_pid);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(format!("{}\n", pid).as_bytes())?;
+        file.sync_all()?;
+        info!("create pidfile {:?}. pid [{}]", path, pid);
+        Ok(PidFile {
+            path: path.to_owned(),
+            _file: file,
+            pid,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if self.pid != getpid() {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "fail remove pidfile {:?}. caused by: {} pid [{}]",
+                self.path, e, self.pid
+            );
+        } else {
+            info!("remove pidfile {:?}. pid [{}]", self.path, self.pid);
+        }
+    }
+}
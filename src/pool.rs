@@ -0,0 +1,106 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, RecvError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nix::unistd::getpid;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Threads {
+    started: usize,
+    idle: usize,
+}
+
+/// Waits for a job run by `Pool::spawn`.
+pub struct Handle {
+    done: Receiver<()>,
+}
+
+impl Handle {
+    /// Returns an error when the job panicked.
+    pub fn join(self) -> Result<(), RecvError> {
+        self.done.recv()
+    }
+}
+
+/// Runs the jobs on at most `size` threads. The threads are started on demand and
+/// reused, and the jobs over the size wait for a thread in the order they came.
+#[derive(Clone)]
+pub struct Pool {
+    name: &'static str,
+    size: usize,
+    jobs: Sender<Job>,
+    queue: Arc<Mutex<Receiver<Job>>>,
+    threads: Arc<Mutex<Threads>>,
+}
+
+impl Pool {
+    pub fn new(name: &'static str, size: usize) -> Pool {
+        let (jobs, queue) = channel();
+        Pool {
+            name,
+            size: size.max(1),
+            jobs,
+            queue: Arc::new(Mutex::new(queue)),
+            threads: Arc::new(Mutex::new(Threads::default())),
+        }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let start = {
+            let mut threads = self.threads.lock().unwrap();
+            if threads.idle > 0 {
+                threads.idle -= 1;
+                false
+            } else if threads.started < self.size {
+                threads.started += 1;
+                true
+            } else {
+                false
+            }
+        };
+        if start {
+            let pool = self.clone();
+            let res = thread::Builder::new()
+                .name(format!("{}-pool", self.name))
+                .spawn(move || pool.work());
+            if let Err(e) = res {
+                self.threads.lock().unwrap().started -= 1;
+                warn!(
+                    "fail start {} thread. caused by: {} pid [{}]",
+                    self.name,
+                    e,
+                    getpid()
+                );
+            }
+        }
+        // the queue lives as long as the pool
+        let _ = self.jobs.send(Box::new(job));
+    }
+
+    /// Runs the job on the pool and returns the handle to wait for it.
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) -> Handle {
+        let (tx, done) = channel();
+        self.execute(move || {
+            job();
+            let _ = tx.send(());
+        });
+        Handle { done }
+    }
+
+    fn work(&self) {
+        loop {
+            let job = match self.queue.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            // a panic of a job does not take the thread with it
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                warn!("{} job panicked. pid [{}]", self.name, getpid());
+            }
+            self.threads.lock().unwrap().idle += 1;
+        }
+    }
+}
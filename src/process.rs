@@ -1,5 +1,8 @@
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::io::{copy, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
@@ -10,22 +13,58 @@ use libc;
 use nix::unistd::getpid;
 
 use app::APP_NAME_UPPER;
-use config::WorkerConfig;
-use utils::{get_process_watch_file, timeout_process};
+use cgroup;
+use config::{AckKind, WorkerConfig};
+use logs::is_foreground;
+use signal::send_signal;
+use utils::{
+    change_root, get_process_watch_file, open_pty, set_controlling_tty, set_cpu_affinity,
+    set_ioprio, set_nice, set_oom_score_adj, set_rlimit, set_session, switch_user, timeout_process,
+    Resource,
+};
+
+// an attached client gives up the input when the process does not read it within this time
+const PTY_WRITE_RETRY: u32 = 100;
+const PTY_WRITE_RETRY_MILLIS: u64 = 10;
 
 #[derive(Debug)]
 pub struct Process<'a> {
     pub id: u64,
+    // 0 to numprocesses - 1. a respawned process takes the index of the exited one
+    pub index: u64,
     pub name: &'a str,
-    pub cmdline: &'a [String],
+    pub cmdline: Vec<String>,
     environment: HashMap<String, String>,
     working_directory: &'a str,
     child: Option<Child>,
     stdout_pipe: bool,
     stderr_pipe: bool,
+    pty: bool,
+    // the master of the pseudo terminal. the slave is stdin, stdout and stderr of the process
+    master: Option<fs::File>,
     uid: Option<u32>,
     gid: Option<u32>,
+    chroot: Option<String>,
+    umask: Option<u32>,
+    rlimits: Vec<(Resource, libc::rlim_t)>,
+    cgroup: Option<PathBuf>,
+    cpu_affinity: Vec<usize>,
+    nice: Option<i32>,
+    ioprio: Option<(u32, u32)>,
+    oom_score_adj: Option<i32>,
     watch_file: Option<PathBuf>,
+    // the exit after a signal from the monitor is not a crash
+    signaled: bool,
+    // killed after kill_after_sec when still alive
+    signaled_at: Option<time::Instant>,
+    // check --spawn exits in place of the exec when this path is executable
+    check_path: Option<PathBuf>,
+    // counted as up. with manual ack, after the ack of the process. with a readiness check,
+    // after it passes
+    ready: bool,
+    // a process exited soon after the spawn backs off the respawn
+    spawned_at: Option<time::Instant>,
+    heartbeat_at: Option<time::Instant>,
 }
 
 impl<'a> PartialEq for Process<'a> {
@@ -40,6 +79,7 @@ impl<'a> Process<'a> {
         name: &'a str,
         working_directory: &'a str,
         environment: HashMap<String, String>,
+        cmdline: Vec<String>,
         config: &'a WorkerConfig,
     ) -> Self {
         let watch_file = if config.live_check_timeout > 0 {
@@ -50,16 +90,37 @@ impl<'a> Process<'a> {
 
         Process {
             id,
+            index: 0,
             name,
-            cmdline: &config.cmd,
+            cmdline,
             environment,
             working_directory,
             child: None,
-            stdout_pipe: config.stdout_log.is_some(),
-            stderr_pipe: config.stderr_log.is_some(),
+            stdout_pipe: config.stdout_log.is_some()
+                || is_foreground()
+                || config.log_buffer_lines > 0,
+            stderr_pipe: config.stderr_log.is_some()
+                || is_foreground()
+                || config.log_buffer_lines > 0,
+            pty: config.pty,
+            master: None,
             uid: config.uid,
             gid: config.gid,
+            chroot: config.chroot.clone(),
+            umask: config.umask(),
+            rlimits: config.rlimits().unwrap_or_default(),
+            cgroup: config.cgroup_path(name),
+            cpu_affinity: config.cpu_affinity.clone(),
+            nice: config.nice,
+            ioprio: config.ioprio(),
+            oom_score_adj: config.oom_score_adj,
             watch_file,
+            signaled: false,
+            signaled_at: None,
+            check_path: None,
+            ready: config.ack != AckKind::Manual && config.readiness_check.is_none(),
+            spawned_at: None,
+            heartbeat_at: None,
         }
     }
 
@@ -79,36 +140,122 @@ impl<'a> Process<'a> {
             ref mut environment,
             uid,
             gid,
+            ref chroot,
+            umask,
+            ref rlimits,
+            ref cgroup,
+            ref cpu_affinity,
+            nice,
+            ioprio,
             ref mut watch_file,
             ..
         } = self;
 
         let cmd: Vec<&str> = cmdline.iter().map(|c| c.as_ref()).collect();
-        let current_dir_path = Path::new(working_directory);
-        let path = current_dir_path.canonicalize()?;
-        let current_dir = path.as_path();
+        // working_directory is inside of the new root when chroot is set
+        let current_dir = match *chroot {
+            Some(_) => PathBuf::from(working_directory),
+            None => Path::new(working_directory).canonicalize()?,
+        };
 
         // new command
         let mut process = Command::new(&cmd[0]);
         // add args
         process.args(&cmd[1..]);
         // set current dir
-        process.current_dir(current_dir);
-        if self.stdout_pipe {
-            process.stdout(Stdio::piped());
-        } else {
-            process.stdout(Stdio::null());
+        if chroot.is_none() {
+            process.current_dir(&current_dir);
         }
-        if self.stderr_pipe {
-            process.stderr(Stdio::piped());
+        if self.pty {
+            let (master, slave) = open_pty()?;
+            self.master = Some(unsafe { fs::File::from_raw_fd(master) });
+            let slave = unsafe { fs::File::from_raw_fd(slave) };
+            process.stdin(slave.try_clone()?);
+            process.stdout(slave.try_clone()?);
+            process.stderr(slave);
+            unsafe {
+                process.pre_exec(set_controlling_tty);
+            }
         } else {
-            process.stderr(Stdio::null());
+            if self.stdout_pipe {
+                process.stdout(Stdio::piped());
+            } else {
+                process.stdout(Stdio::null());
+            }
+            if self.stderr_pipe {
+                process.stderr(Stdio::piped());
+            } else {
+                process.stderr(Stdio::null());
+            }
+            // the stop signals are sent to the process group, e.g. with the children of a shell
+            unsafe {
+                process.pre_exec(set_session);
+            }
+        }
+        if let Some(umask) = umask {
+            unsafe {
+                process.pre_exec(move || {
+                    libc::umask(umask as libc::mode_t);
+                    Ok(())
+                });
+            }
+        }
+        if !rlimits.is_empty() {
+            let rlimits = rlimits.clone();
+            unsafe {
+                process.pre_exec(move || {
+                    for &(resource, value) in &rlimits {
+                        set_rlimit(resource, value)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(ref cgroup) = cgroup {
+            let procs = cgroup::procs_path(cgroup)?;
+            unsafe {
+                process.pre_exec(move || cgroup::join(&procs));
+            }
         }
-        if let Some(uid) = uid {
-            process.uid(uid);
+        if !cpu_affinity.is_empty() {
+            let cpu_affinity = cpu_affinity.clone();
+            unsafe {
+                process.pre_exec(move || set_cpu_affinity(&cpu_affinity));
+            }
+        }
+        if let Some(nice) = nice {
+            unsafe {
+                process.pre_exec(move || set_nice(nice));
+            }
+        }
+        if let Some((class, level)) = ioprio {
+            unsafe {
+                process.pre_exec(move || set_ioprio(class, level));
+            }
         }
-        if let Some(gid) = gid {
-            process.gid(gid);
+        // std switches uid/gid before pre_exec, so drop privileges last by ourselves
+        let root = match *chroot {
+            Some(ref root) => Some((cstring(root)?, cstring(working_directory)?)),
+            None => None,
+        };
+        let check_path = match self.check_path {
+            Some(ref path) => Some(CString::new(path.as_os_str().as_bytes())?),
+            None => None,
+        };
+        unsafe {
+            process.pre_exec(move || {
+                if let Some((ref root, ref dir)) = root {
+                    change_root(root, dir)?;
+                }
+                switch_user(uid, gid)?;
+                if let Some(ref path) = check_path {
+                    if libc::access(path.as_ptr(), libc::X_OK) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    libc::_exit(0);
+                }
+                Ok(())
+            });
         }
         Process::create_watch_file(name, id, watch_file, environment)?;
         debug!("process cmd {:?}", cmd);
@@ -120,7 +267,18 @@ impl<'a> Process<'a> {
         match process.spawn() {
             Ok(mut child) => {
                 child.try_wait()?;
+                if let Some(adj) = self.oom_score_adj {
+                    if let Err(e) = set_oom_score_adj(child.id(), adj) {
+                        warn!(
+                            "fail set oom_score_adj {}. caused by: {} pid [{}]",
+                            adj,
+                            e,
+                            child.id()
+                        );
+                    }
+                }
                 self.child = Some(child);
+                self.spawned_at = Some(time::Instant::now());
                 Ok(())
             }
             Err(e) => {
@@ -172,7 +330,7 @@ impl<'a> Process<'a> {
                         status.signal(),
                         pid
                     );
-                    Some(-1)
+                    Some(status.code().unwrap_or(-1))
                 }
             }
             Ok(None) => None,
@@ -184,6 +342,21 @@ impl<'a> Process<'a> {
         }
     }
 
+    /// The signal that killed the process, once it is waited.
+    pub fn exit_signal(&mut self) -> Option<i32> {
+        match self.child.as_mut().map(|child| child.try_wait()) {
+            Some(Ok(Some(status))) => status.signal(),
+            _ => None,
+        }
+    }
+
+    pub fn core_dumped(&mut self) -> bool {
+        match self.child.as_mut().map(|child| child.try_wait()) {
+            Some(Ok(Some(status))) => status.core_dumped(),
+            _ => false,
+        }
+    }
+
     fn remove_watch_file(watch_file: &Option<PathBuf>) {
         if let Some(ref watch_file) = watch_file {
             if let Err(e) = fs::remove_file(watch_file) {
@@ -225,6 +398,8 @@ impl<'a> Process<'a> {
     pub fn kill(&mut self) -> io::Result<u32> {
         self.cleanup();
         if let Some(ref mut child) = self.child {
+            // the rest of the process group, e.g. the children of a shell wrapper
+            let _ = send_signal(-(child.id() as libc::pid_t), libc::SIGKILL);
             child.kill()?;
             return Ok(child.id());
         }
@@ -234,13 +409,157 @@ impl<'a> Process<'a> {
     pub fn child(&mut self) -> Option<&mut Child> {
         self.child.as_mut()
     }
+
+    /// Turns into a one-off command. The output is piped even without a log,
+    /// and neither a pty nor a watch file is used.
+    pub fn set_oneshot(&mut self) {
+        self.stdout_pipe = true;
+        self.stderr_pipe = true;
+        self.pty = false;
+        self.watch_file = None;
+    }
+
+    /// Spawns the process for `check --spawn` with the same user, root and limits, without
+    /// the output, the pty and the cgroup. With `path`, the process exits with 0 in place of
+    /// the exec once `path` is executable by the user.
+    pub fn set_check(&mut self, path: Option<PathBuf>) {
+        self.stdout_pipe = false;
+        self.stderr_pipe = false;
+        self.pty = false;
+        self.watch_file = None;
+        self.cgroup = None;
+        self.oom_score_adj = None;
+        self.check_path = path;
+    }
+
+    pub fn into_child(mut self) -> Option<Child> {
+        self.child.take()
+    }
+
+    /// Returns a new fd of the master of the pseudo terminal to read the output.
+    pub fn pty_output(&self) -> io::Result<Option<RawFd>> {
+        match self.master {
+            Some(ref master) => Ok(Some(master.try_clone()?.into_raw_fd())),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the input of an attached client to the pseudo terminal.
+    /// The master is non-blocking, since it shares the flags with the fd of the output.
+    pub fn write_pty(&mut self, buf: &[u8]) -> io::Result<()> {
+        let master = match self.master {
+            Some(ref mut master) => master,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "no pty")),
+        };
+        let mut buf = buf;
+        let mut retry = 0;
+        while !buf.is_empty() {
+            match master.write(buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(size) => buf = &buf[size..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && retry < PTY_WRITE_RETRY => {
+                    retry += 1;
+                    thread::sleep(time::Duration::from_millis(PTY_WRITE_RETRY_MILLIS));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_ready(&mut self) {
+        self.ready = true;
+    }
+
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    pub fn spawned_at(&self) -> Option<time::Instant> {
+        self.spawned_at
+    }
+
+    pub fn heartbeat(&mut self) {
+        self.heartbeat_at = Some(time::Instant::now());
+    }
+
+    /// Returns true when the process has sent no heartbeat for `timeout` seconds
+    /// since the spawn or the last heartbeat.
+    pub fn check_heartbeat_timeout(&self, timeout: u64) -> bool {
+        if timeout == 0 {
+            return false;
+        }
+        self.heartbeat_at
+            .or(self.spawned_at)
+            .map_or(false, |at| at.elapsed().as_secs() >= timeout)
+    }
+
+    pub fn set_signaled(&mut self) {
+        self.signaled = true;
+        if self.signaled_at.is_none() {
+            self.signaled_at = Some(time::Instant::now());
+        }
+    }
+
+    /// Returns true once when `timeout` seconds have passed since the process was signaled
+    /// to exit.
+    pub fn check_kill_after(&mut self, timeout: u64) -> bool {
+        match self.signaled_at {
+            Some(at) if at.elapsed().as_secs() >= timeout => {
+                self.signaled_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn signaled(&self) -> bool {
+        self.signaled
+    }
+}
+
+/// Reads `KEY=VALUE` lines. blank lines and `#` comments are skipped.
+pub fn read_env_file(path: &str) -> io::Result<Vec<(String, String)>> {
+    let mut buf = String::new();
+    fs::File::open(path)?.read_to_string(&mut buf)?;
+    let mut res = Vec::new();
+    for (i, line) in buf.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = if line.starts_with("export ") {
+            line[7..].trim()
+        } else {
+            line
+        };
+        let v: Vec<&str> = line.splitn(2, '=').collect();
+        if v.len() != 2 || v[0].trim().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("broken env line {}: {}", i + 1, line),
+            ));
+        }
+        let val = v[1].trim();
+        let val = if val.len() >= 2
+            && ((val.starts_with('"') && val.ends_with('"'))
+                || (val.starts_with('\'') && val.ends_with('\'')))
+        {
+            &val[1..val.len() - 1]
+        } else {
+            val
+        };
+        res.push((v[0].trim().to_owned(), val.to_owned()));
+    }
+    Ok(res)
 }
 
-pub fn run_upgrader(upgrader: &[String]) -> io::Result<Child> {
+pub fn run_upgrader(upgrader: &[String], working_directory: &str) -> io::Result<Child> {
     let self_pid = getpid();
     let mut process = Command::new(&upgrader[0]);
     info!("start upgrader. pid [{}]", self_pid);
     process.args(&upgrader[1..]);
+    process.current_dir(Path::new(working_directory).canonicalize()?);
     process.stdin(Stdio::null());
     process.stdout(Stdio::piped());
     process.stderr(Stdio::piped());
@@ -360,3 +679,7 @@ pub fn output_stderr_log(p: &mut Child, writer: &mut io::Write) -> io::Result<()
     }
     Ok(())
 }
+
+fn cstring(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
@@ -12,24 +12,39 @@ fn is_modified(path: &path::PathBuf, current_mtime: &time::SystemTime) -> io::Re
     }
 }
 
-pub fn cmd_path(config: &WorkerConfig) -> path::PathBuf {
+fn with_path(e: io::Error, path: &path::Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+pub fn cmd_path(config: &WorkerConfig) -> io::Result<path::PathBuf> {
     let cmd = &config.cmd[0];
     let cmd_path = path::Path::new(cmd);
     if cmd_path.is_absolute() {
-        cmd_path.to_owned()
+        Ok(config.root_path(cmd))
     } else {
-        let base = &config.working_directory;
-        let root = path::Path::new(base).canonicalize().unwrap();
-        root.join(cmd_path).canonicalize().unwrap()
+        let base = config.root_path(&config.working_directory);
+        let root = base.canonicalize().map_err(|e| with_path(e, &base))?;
+        let path = root.join(cmd_path);
+        path.canonicalize().map_err(|e| with_path(e, &path))
     }
 }
 
+/// The path and the mtime of the command. A missing command is an error.
+pub fn cmd_stat(config: &WorkerConfig) -> io::Result<(path::PathBuf, time::SystemTime)> {
+    let path = cmd_path(config)?;
+    let mtime = path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| with_path(e, &path))?;
+    Ok((path, mtime))
+}
+
 pub fn is_modified_cmd(
     config: &WorkerConfig,
     current_path: &path::PathBuf,
     current_mtime: &time::SystemTime,
 ) -> io::Result<bool> {
-    let path = cmd_path(config);
+    let path = cmd_path(config)?;
     if *current_path != path {
         return Ok(true);
     }
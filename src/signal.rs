@@ -6,44 +6,8 @@ use libc;
 
 use utils::cvt;
 
-pub fn send_sigkill(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGKILL)).map(|_| ()) }
-}
-
-pub fn send_sigterm(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGTERM)).map(|_| ()) }
-}
-
-pub fn send_sigint(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGINT)).map(|_| ()) }
-}
-
-pub fn send_sigquit(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGQUIT)).map(|_| ()) }
-}
-
-pub fn send_sighup(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGHUP)).map(|_| ()) }
-}
-
-pub fn send_sigwinch(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGWINCH)).map(|_| ()) }
-}
-
-pub fn send_sigttin(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGTTIN)).map(|_| ()) }
-}
-
-pub fn send_sigttou(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGTTOU)).map(|_| ()) }
-}
-
-pub fn send_sigusr1(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGUSR1)).map(|_| ()) }
-}
-
-pub fn send_sigusr2(pid: libc::pid_t) -> io::Result<()> {
-    unsafe { cvt(libc::kill(pid, libc::SIGUSR2)).map(|_| ()) }
+pub fn send_signal(pid: libc::pid_t, signum: libc::c_int) -> io::Result<()> {
+    unsafe { cvt(libc::kill(pid, signum)).map(|_| ()) }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
@@ -68,105 +32,149 @@ pub enum Signal {
     SIGUSR1,
     #[serde(rename = "SIGUSR2")]
     SIGUSR2,
+    #[serde(rename = "SIGSTOP")]
+    SIGSTOP,
+    #[serde(rename = "SIGCONT")]
+    SIGCONT,
+    #[serde(rename = "SIGABRT")]
+    SIGABRT,
+    #[serde(rename = "SIGALRM")]
+    SIGALRM,
+    #[serde(rename = "SIGBUS")]
+    SIGBUS,
+    #[serde(rename = "SIGCHLD")]
+    SIGCHLD,
+    #[serde(rename = "SIGFPE")]
+    SIGFPE,
+    #[serde(rename = "SIGILL")]
+    SIGILL,
+    #[serde(rename = "SIGIO")]
+    SIGIO,
+    #[serde(rename = "SIGPIPE")]
+    SIGPIPE,
+    #[serde(rename = "SIGPROF")]
+    SIGPROF,
+    #[serde(rename = "SIGPWR")]
+    SIGPWR,
+    #[serde(rename = "SIGSEGV")]
+    SIGSEGV,
+    #[serde(rename = "SIGSYS")]
+    SIGSYS,
+    #[serde(rename = "SIGTRAP")]
+    SIGTRAP,
+    #[serde(rename = "SIGTSTP")]
+    SIGTSTP,
+    #[serde(rename = "SIGURG")]
+    SIGURG,
+    #[serde(rename = "SIGVTALRM")]
+    SIGVTALRM,
+    #[serde(rename = "SIGXCPU")]
+    SIGXCPU,
+    #[serde(rename = "SIGXFSZ")]
+    SIGXFSZ,
+}
+
+// every signal that can be named. real-time signals are not supported
+const SIGNALS: &[(Signal, libc::c_int)] = &[
+    (Signal::SIGKILL, libc::SIGKILL),
+    (Signal::SIGTERM, libc::SIGTERM),
+    (Signal::SIGINT, libc::SIGINT),
+    (Signal::SIGQUIT, libc::SIGQUIT),
+    (Signal::SIGHUP, libc::SIGHUP),
+    (Signal::SIGWINCH, libc::SIGWINCH),
+    (Signal::SIGTTIN, libc::SIGTTIN),
+    (Signal::SIGTTOU, libc::SIGTTOU),
+    (Signal::SIGUSR1, libc::SIGUSR1),
+    (Signal::SIGUSR2, libc::SIGUSR2),
+    (Signal::SIGSTOP, libc::SIGSTOP),
+    (Signal::SIGCONT, libc::SIGCONT),
+    (Signal::SIGABRT, libc::SIGABRT),
+    (Signal::SIGALRM, libc::SIGALRM),
+    (Signal::SIGBUS, libc::SIGBUS),
+    (Signal::SIGCHLD, libc::SIGCHLD),
+    (Signal::SIGFPE, libc::SIGFPE),
+    (Signal::SIGILL, libc::SIGILL),
+    (Signal::SIGIO, libc::SIGIO),
+    (Signal::SIGPIPE, libc::SIGPIPE),
+    (Signal::SIGPROF, libc::SIGPROF),
+    (Signal::SIGPWR, libc::SIGPWR),
+    (Signal::SIGSEGV, libc::SIGSEGV),
+    (Signal::SIGSYS, libc::SIGSYS),
+    (Signal::SIGTRAP, libc::SIGTRAP),
+    (Signal::SIGTSTP, libc::SIGTSTP),
+    (Signal::SIGURG, libc::SIGURG),
+    (Signal::SIGVTALRM, libc::SIGVTALRM),
+    (Signal::SIGXCPU, libc::SIGXCPU),
+    (Signal::SIGXFSZ, libc::SIGXFSZ),
+];
+
+impl Signal {
+    pub fn signum(self) -> libc::c_int {
+        SIGNALS
+            .iter()
+            .find(|&&(signal, _)| signal == self)
+            .map(|&(_, signum)| signum)
+            .unwrap()
+    }
+}
+
+/// Sends the signal to the process group led by the process, or to the process alone when
+/// it leads no group, e.g. a process spawned before the groups were used.
+pub fn send_group_signal(pid: libc::pid_t, signum: libc::c_int) -> io::Result<()> {
+    match send_signal(-pid, signum) {
+        Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => send_signal(pid, signum),
+        res => res,
+    }
 }
 
 pub trait SignalSend {
     fn signal(&self, signal: Signal) -> io::Result<()>;
+
+    fn signal_group(&self, signal: Signal) -> io::Result<()>;
 }
 
 impl SignalSend for libc::pid_t {
     fn signal(&self, signal: Signal) -> io::Result<()> {
-        match signal {
-            Signal::SIGKILL => {
-                send_sigkill(*self)?;
-            }
-            Signal::SIGTERM => {
-                send_sigterm(*self)?;
-            }
-            Signal::SIGINT => {
-                send_sigint(*self)?;
-            }
-            Signal::SIGQUIT => {
-                send_sigquit(*self)?;
-            }
-            Signal::SIGHUP => {
-                send_sighup(*self)?;
-            }
-            Signal::SIGWINCH => {
-                send_sigwinch(*self)?;
-            }
-            Signal::SIGTTIN => {
-                send_sigttin(*self)?;
-            }
-            Signal::SIGTTOU => {
-                send_sigttou(*self)?;
-            }
-            Signal::SIGUSR1 => {
-                send_sigusr1(*self)?;
-            }
-            Signal::SIGUSR2 => {
-                send_sigusr2(*self)?;
-            }
-        }
-        Ok(())
+        send_signal(*self, signal.signum())
+    }
+
+    fn signal_group(&self, signal: Signal) -> io::Result<()> {
+        send_group_signal(*self, signal.signum())
     }
 }
 
 impl SignalSend for u32 {
     fn signal(&self, signal: Signal) -> io::Result<()> {
-        let pid = *self as libc::pid_t;
-        match signal {
-            Signal::SIGKILL => {
-                send_sigkill(pid)?;
-            }
-            Signal::SIGTERM => {
-                send_sigterm(pid)?;
-            }
-            Signal::SIGINT => {
-                send_sigint(pid)?;
-            }
-            Signal::SIGQUIT => {
-                send_sigquit(pid)?;
-            }
-            Signal::SIGHUP => {
-                send_sighup(pid)?;
-            }
-            Signal::SIGWINCH => {
-                send_sigwinch(pid)?;
-            }
-            Signal::SIGTTIN => {
-                send_sigttin(pid)?;
-            }
-            Signal::SIGTTOU => {
-                send_sigttou(pid)?;
-            }
-            Signal::SIGUSR1 => {
-                send_sigusr1(pid)?;
-            }
-            Signal::SIGUSR2 => {
-                send_sigusr2(pid)?;
-            }
-        }
-        Ok(())
+        send_signal(*self as libc::pid_t, signal.signum())
+    }
+
+    fn signal_group(&self, signal: Signal) -> io::Result<()> {
+        send_group_signal(*self as libc::pid_t, signal.signum())
     }
 }
 
+/// Accepts a name with or without the `SIG` prefix in any case, or a number.
+/// e.g. `SIGUSR1`, `usr1` or `10`
 impl FromStr for Signal {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Signal, Error> {
-        match s {
-            "SIGKILL" => Ok(Signal::SIGKILL),
-            "SIGTERM" => Ok(Signal::SIGTERM),
-            "SIGINT" => Ok(Signal::SIGINT),
-            "SIGQUIT" => Ok(Signal::SIGQUIT),
-            "SIGHUP" => Ok(Signal::SIGHUP),
-            "SIGWINCH" => Ok(Signal::SIGWINCH),
-            "SIGTTIN" => Ok(Signal::SIGTTIN),
-            "SIGTTOU" => Ok(Signal::SIGTTOU),
-            "SIGUSR1" => Ok(Signal::SIGUSR1),
-            "SIGUSR2" => Ok(Signal::SIGUSR2),
-            _ => Err(err_msg(format!("{} not support.", s))),
-        }
+        let found = match s.parse::<libc::c_int>() {
+            Ok(signum) => SIGNALS.iter().find(|&&(_, n)| n == signum),
+            Err(_) => {
+                let name = s.to_uppercase();
+                let name = if name.starts_with("SIG") {
+                    name
+                } else {
+                    format!("SIG{}", name)
+                };
+                SIGNALS
+                    .iter()
+                    .find(|&&(signal, _)| format!("{:?}", signal) == name)
+            }
+        };
+        found
+            .map(|&(signal, _)| signal)
+            .ok_or_else(|| err_msg(format!("{} not support.", s)))
     }
 }
@@ -1,16 +1,50 @@
-use std::fmt::Display;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use std::{env, fs, io, mem, thread, time};
+use std::fmt::{self, Display};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
 
 use failure::{err_msg, Error};
-use libc::close;
-use nix::sys::socket;
+use libc::{self, close};
+use nix;
+use nix::sys::socket::{self, sockopt};
+use nix::unistd::{self, getpid, Gid};
 use regex::Regex;
 
+/// The first fd passed by the systemd socket passing protocol.
+const SD_LISTEN_FDS_START: RawFd = 3;
+// a rejected client gets this long to send its request before it is closed
+const LINGER_MILLIS: u64 = 1000;
+const LINGER_POLL_MILLIS: u64 = 20;
+// rejected connections read at a time. the rest are closed at once
+const MAX_LINGERING: usize = 256;
+
+type Lingering = (Box<dyn Read + Send>, time::Instant);
+
 lazy_static! {
-    static ref SPLIT_PREFIX: Regex = Regex::new(r"^([a-zA-Z]+)::(.+)$").unwrap();
+    static ref SPLIT_PREFIX: Regex = Regex::new(r"^([a-zA-Z]+)(?:::|://)(.+)$").unwrap();
+    static ref INHERITED_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+    static ref LINGERING: Mutex<Sender<Lingering>> = {
+        let (tx, rx) = channel();
+        let res = thread::Builder::new()
+            .name("linger".to_owned())
+            .spawn(move || discard_lingering(rx));
+        if let Err(e) = res {
+            warn!(
+                "fail spawn linger thread. caused by: {} pid [{}]",
+                e,
+                getpid()
+            );
+        }
+        Mutex::new(tx)
+    };
 }
 
 #[derive(Debug)]
@@ -30,7 +64,7 @@ impl ListenFd {
             )))
         } else if let Ok(socket_addr) = s.parse() {
             Ok(ListenFd::TcpListener(socket_addr))
-        } else if s.contains('/') {
+        } else if s.contains('/') || s.starts_with('@') {
             ListenFd::new_unix_listener(s)
         } else {
             Err(err_msg(format!(
@@ -78,9 +112,29 @@ impl ListenFd {
         }
     }
 
-    /// Creates a raw fd from the fd spec.
-    pub fn create_raw_fd(&self, backlog: usize) -> Result<RawFd, Error> {
-        create_raw_fd(self, backlog)
+    /// Creates a raw fd from the fd spec. `backlog` is used unless the options set one.
+    pub fn create_raw_fd(&self, backlog: usize, options: &SockOptions) -> Result<RawFd, Error> {
+        create_raw_fd(self, backlog, options)
+    }
+
+    /// Returns the fd passed from systemd that is bound to the same address.
+    pub fn inherited_raw_fd(&self) -> Option<RawFd> {
+        let (addr, _fam, ty) = sock_info(self).ok()?;
+        let addr = addr.to_string();
+        INHERITED_FDS.lock().unwrap().iter().cloned().find(|fd| {
+            let same_type = raw_sock_type(*fd) == Some(ty as libc::c_int);
+            same_type && describe_addr(*fd)
+                .map(|a| a.to_string() == addr)
+                .unwrap_or(false)
+        })
+    }
+
+    /// Adopts the fd passed from systemd if any, otherwise creates a raw fd.
+    pub fn get_raw_fd(&self, backlog: usize, options: &SockOptions) -> Result<RawFd, Error> {
+        match self.inherited_raw_fd() {
+            Some(fd) => Ok(fd),
+            None => create_raw_fd(self, backlog, options),
+        }
     }
 
     pub fn describe_raw_fd(&self, raw_fd: RawFd) -> Result<String, Error> {
@@ -116,13 +170,455 @@ impl FromStr for ListenFd {
     }
 }
 
-pub fn create_raw_fd(fd: &ListenFd, backlog: usize) -> Result<RawFd, Error> {
+/// The control socket of the daemon.
+pub enum CtrlListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl CtrlListener {
+    pub fn accept(&self) -> io::Result<CtrlStream> {
+        Ok(match self {
+            CtrlListener::Unix(listener) => CtrlStream::Unix(listener.accept()?.0),
+            CtrlListener::Tcp(listener) => CtrlStream::Tcp(listener.accept()?.0),
+        })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            CtrlListener::Unix(listener) => listener.set_nonblocking(nonblocking),
+            CtrlListener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for CtrlListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            CtrlListener::Unix(listener) => listener.as_raw_fd(),
+            CtrlListener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Mode and group set on the unix control sockets after bind.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SockPermissions {
+    pub mode: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl SockPermissions {
+    pub fn apply(&self, path: &Path) -> Result<(), Error> {
+        // an abstract socket has no file to set
+        if is_abstract(path) {
+            return Ok(());
+        }
+        if let Some(gid) = self.gid {
+            unistd::chown(path, None, Some(Gid::from_raw(gid)))?;
+        }
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+/// Options set on a listen socket of a worker before bind.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SockOptions {
+    // SO_REUSEPORT. other sockets with it bind the same address, and the kernel balances the
+    // connections among them. it is ignored by unix sockets
+    #[serde(default)]
+    pub reuseport: bool,
+    // the listen backlog instead of the default of the socket. the kernel caps it at somaxconn
+    pub backlog: Option<usize>,
+    // SO_KEEPALIVE and the probes, inherited by the accepted connections. the probes not set
+    // follow the sysctl of the kernel
+    #[serde(default)]
+    pub keepalive: bool,
+    pub keepalive_idle_sec: Option<u32>,
+    pub keepalive_interval_sec: Option<u32>,
+    pub keepalive_count: Option<u32>,
+    // TCP_NODELAY, inherited by the accepted connections
+    #[serde(default)]
+    pub nodelay: bool,
+    // TCP_DEFER_ACCEPT. accept waits the first data from the client up to this
+    pub defer_accept_sec: Option<u32>,
+}
+
+impl SockOptions {
+    /// Returns the name of an option set that only tcp sockets support.
+    pub fn tcp_option(&self) -> Option<&'static str> {
+        if self.keepalive {
+            Some("keepalive")
+        } else if self.nodelay {
+            Some("nodelay")
+        } else if self.defer_accept_sec.is_some() {
+            Some("defer_accept_sec")
+        } else {
+            None
+        }
+    }
+
+    /// Returns the name of an option set that requires `keepalive = true`.
+    pub fn keepalive_option(&self) -> Option<&'static str> {
+        if self.keepalive {
+            None
+        } else if self.keepalive_idle_sec.is_some() {
+            Some("keepalive_idle_sec")
+        } else if self.keepalive_interval_sec.is_some() {
+            Some("keepalive_interval_sec")
+        } else if self.keepalive_count.is_some() {
+            Some("keepalive_count")
+        } else {
+            None
+        }
+    }
+}
+
+/// The sender of a control command. Unix sockets report the credentials by `SO_PEERCRED`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Peer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+}
+
+impl Peer {
+    pub fn from_unix_fd(fd: RawFd) -> Peer {
+        match socket::getsockopt(fd, sockopt::PeerCredentials) {
+            Ok(cred) => Peer {
+                pid: Some(cred.pid()),
+                uid: Some(cred.uid()),
+                gid: Some(cred.gid()),
+                addr: None,
+            },
+            Err(e) => {
+                debug!("fail get peer credentials. caused by: {}", e);
+                Peer::default()
+            }
+        }
+    }
+
+    pub fn from_addr(addr: &SocketAddr) -> Peer {
+        Peer {
+            addr: Some(addr.to_string()),
+            ..Peer::default()
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref addr) = self.addr {
+            return write!(f, "{}", addr);
+        }
+        match (self.pid, self.uid, self.gid) {
+            (Some(pid), Some(uid), Some(gid)) => write!(f, "pid:{} uid:{} gid:{}", pid, uid, gid),
+            _ => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A connection to the control socket of the daemon.
+pub enum CtrlStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl CtrlStream {
+    /// Connects to the address in the same format as the control socket of the daemon.
+    pub fn connect(addr: &str) -> io::Result<CtrlStream> {
+        let listen_fd: ListenFd = addr
+            .parse()
+            .map_err(|e: Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        match listen_fd {
+            ListenFd::TcpListener(addr) => Ok(CtrlStream::Tcp(TcpStream::connect(addr)?)),
+            ListenFd::UnixListener(ref path) if unix_sock_exists(path) => {
+                Ok(CtrlStream::Unix(connect_unix(path)?))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "require sock path",
+            )),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match self {
+            CtrlStream::Unix(stream) => stream.set_read_timeout(timeout),
+            CtrlStream::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            CtrlStream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            CtrlStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<CtrlStream> {
+        match self {
+            CtrlStream::Unix(stream) => Ok(CtrlStream::Unix(stream.try_clone()?)),
+            CtrlStream::Tcp(stream) => Ok(CtrlStream::Tcp(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            CtrlStream::Unix(stream) => stream.shutdown(how),
+            CtrlStream::Tcp(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn peer(&self) -> Peer {
+        match self {
+            CtrlStream::Unix(stream) => Peer::from_unix_fd(stream.as_raw_fd()),
+            CtrlStream::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => Peer::from_addr(&addr),
+                Err(_) => Peer::default(),
+            },
+        }
+    }
+}
+
+impl Read for CtrlStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            CtrlStream::Unix(stream) => stream.read(buf),
+            CtrlStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for CtrlStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CtrlStream::Unix(stream) => stream.write(buf),
+            CtrlStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CtrlStream::Unix(stream) => stream.flush(),
+            CtrlStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Returns true when the path of a unix socket is a name in the abstract namespace of Linux,
+/// such as `@name`. It is not a file, and is gone when the socket is closed.
+pub fn is_abstract(path: &Path) -> bool {
+    path.as_os_str().as_bytes().first() == Some(&b'@')
+}
+
+/// Returns true when a unix socket may listen at the path. An abstract name is not checked
+/// until connect.
+pub fn unix_sock_exists(path: &Path) -> bool {
+    is_abstract(path) || path.exists()
+}
+
+/// Connects to a unix socket, at a file or at an abstract name.
+pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+    let path = path.as_ref();
+    if !is_abstract(path) {
+        return UnixStream::connect(path);
+    }
+    let addr = unix_sock_addr(path).map_err(nix_io_error)?;
+    let sock = socket::socket(
+        socket::AddressFamily::Unix,
+        socket::SockType::Stream,
+        socket::SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .map_err(nix_io_error)?;
+    if let Err(e) = socket::connect(sock, &addr) {
+        unsafe { close(sock) };
+        return Err(nix_io_error(e));
+    }
+    Ok(unsafe { UnixStream::from_raw_fd(sock) })
+}
+
+/// Closes a rejected connection once its client has sent the request, or after
+/// `LINGER_MILLIS`. Closing with the request unread resets a TCP connection, and the
+/// client can lose the answer. The stream must be non-blocking.
+pub fn linger<S: Read + Send + 'static>(stream: S) {
+    let deadline = time::Instant::now() + time::Duration::from_millis(LINGER_MILLIS);
+    if let Ok(tx) = LINGERING.lock() {
+        let _ = tx.send((Box::new(stream), deadline));
+    }
+}
+
+fn discard_lingering(rx: Receiver<Lingering>) {
+    let mut streams: Vec<Lingering> = Vec::new();
+    loop {
+        if streams.is_empty() {
+            match rx.recv() {
+                Ok(lingering) => streams.push(lingering),
+                Err(_) => return,
+            }
+        }
+        while let Ok(lingering) = rx.try_recv() {
+            if streams.len() < MAX_LINGERING {
+                streams.push(lingering);
+            }
+        }
+        let now = time::Instant::now();
+        streams = streams
+            .into_iter()
+            .filter(|&(_, deadline)| now < deadline)
+            .filter_map(|(mut stream, deadline)| {
+                if discard_input(&mut *stream) {
+                    None
+                } else {
+                    Some((stream, deadline))
+                }
+            })
+            .collect();
+        if !streams.is_empty() {
+            thread::sleep(time::Duration::from_millis(LINGER_POLL_MILLIS));
+        }
+    }
+}
+
+/// Reads what the client has sent. True at the end of the stream or on an error.
+fn discard_input(stream: &mut dyn Read) -> bool {
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return true,
+        }
+    }
+}
+
+fn unix_sock_addr(path: &Path) -> nix::Result<socket::SockAddr> {
+    if is_abstract(path) {
+        let name = &path.as_os_str().as_bytes()[1..];
+        let addr = socket::UnixAddr::new_abstract(name)?;
+        Ok(socket::SockAddr::Unix(addr))
+    } else {
+        socket::SockAddr::new_unix(path)
+    }
+}
+
+fn nix_io_error(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// Returns the path when the control socket is a unix socket.
+pub fn ctrl_sock_path(addr: &str) -> Option<PathBuf> {
+    match addr.parse() {
+        Ok(ListenFd::UnixListener(path)) => Some(path),
+        _ => None,
+    }
+}
+
+/// Takes over the fds passed by `LISTEN_FDS` and `LISTEN_PID`.
+/// The variables are removed so that they are not inherited by the monitors.
+pub fn adopt_listen_fds() -> Vec<RawFd> {
+    let for_me = env::var("LISTEN_PID")
+        .map(|pid| pid == getpid().to_string())
+        .unwrap_or(false);
+    let count: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_me {
+        return Vec::new();
+    }
+    let fds: Vec<RawFd> = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect();
+    INHERITED_FDS.lock().unwrap().extend(&fds);
+    fds
+}
+
+/// Returns the inherited fds that are still open.
+pub fn inherited_fds() -> Vec<RawFd> {
+    INHERITED_FDS.lock().unwrap().clone()
+}
+
+/// Registers the fds handed down by `upexec`. They are adopted in the same way as `LISTEN_FDS`.
+pub fn inherit_fds(fds: &[RawFd]) {
+    INHERITED_FDS.lock().unwrap().extend(fds);
+}
+
+/// Forgets the inherited fds other than `keep` after they are closed.
+pub fn retain_inherited_fds(keep: &[RawFd]) {
+    INHERITED_FDS.lock().unwrap().retain(|fd| keep.contains(fd));
+}
+
+// the st column of /proc/net/tcp and /proc/net/unix
+const TCP_ESTABLISHED: &str = "01";
+const SS_CONNECTED: &str = "03";
+
+/// Counts the established connections on the addresses, including the connections
+/// not accepted yet. Tcp connections are matched by the port. Udp has no connections.
+pub fn count_connections(addrs: &[String]) -> usize {
+    let mut count = 0;
+    for addr in addrs {
+        count += match addr.parse() {
+            Ok(ListenFd::TcpListener(addr)) => {
+                let port = format!("{:04X}", addr.port());
+                ["/proc/net/tcp", "/proc/net/tcp6"]
+                    .iter()
+                    .map(|path| {
+                        count_proc_net(path, |cols| {
+                            cols.len() > 3
+                                && cols[3] == TCP_ESTABLISHED
+                                && cols[1].rsplit(':').next() == Some(port.as_str())
+                        })
+                    })
+                    .sum()
+            }
+            // the accepted sockets have the path of the listener
+            Ok(ListenFd::UnixListener(path)) => count_proc_net("/proc/net/unix", |cols| {
+                cols.len() > 7 && cols[5] == SS_CONNECTED && Path::new(cols[7]) == path
+            }),
+            _ => 0,
+        };
+    }
+    count
+}
+
+fn count_proc_net<F: Fn(&[&str]) -> bool>(path: &str, matches: F) -> usize {
+    let buf = match fs::read_to_string(path) {
+        Ok(buf) => buf,
+        Err(_) => return 0,
+    };
+    buf.lines()
+        .skip(1)
+        .filter(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            matches(&cols)
+        })
+        .count()
+}
+
+pub fn create_raw_fd(fd: &ListenFd, backlog: usize, options: &SockOptions) -> Result<RawFd, Error> {
     let (addr, fam, ty) = sock_info(fd)?;
     let sock = socket::socket(fam, ty, socket::SockFlag::empty(), None)?;
-    socket::setsockopt(sock, socket::sockopt::ReuseAddr, &true)?;
-    let rv = socket::bind(sock, &addr).map_err(From::from).and_then(|_| {
+    let rv = set_sock_options(sock, fam, options).and_then(|_| {
+        socket::bind(sock, &addr)?;
         if fd.should_listen() {
-            socket::listen(sock, backlog)?;
+            socket::listen(sock, options.backlog.unwrap_or(backlog))?;
         }
         Ok(())
     });
@@ -134,6 +630,77 @@ pub fn create_raw_fd(fd: &ListenFd, backlog: usize) -> Result<RawFd, Error> {
     rv.map(|_| sock)
 }
 
+fn set_sock_options(
+    sock: RawFd,
+    fam: socket::AddressFamily,
+    options: &SockOptions,
+) -> Result<(), Error> {
+    socket::setsockopt(sock, sockopt::ReuseAddr, &true)?;
+    if fam == socket::AddressFamily::Unix {
+        return Ok(());
+    }
+    if options.reuseport {
+        socket::setsockopt(sock, sockopt::ReusePort, &true)?;
+    }
+    if options.keepalive {
+        socket::setsockopt(sock, sockopt::KeepAlive, &true)?;
+        if let Some(idle) = options.keepalive_idle_sec {
+            socket::setsockopt(sock, sockopt::TcpKeepIdle, &idle)?;
+        }
+        if let Some(interval) = options.keepalive_interval_sec {
+            set_tcp_option(sock, libc::TCP_KEEPINTVL, interval)?;
+        }
+        if let Some(count) = options.keepalive_count {
+            set_tcp_option(sock, libc::TCP_KEEPCNT, count)?;
+        }
+    }
+    if options.nodelay {
+        socket::setsockopt(sock, sockopt::TcpNoDelay, &true)?;
+    }
+    if let Some(sec) = options.defer_accept_sec {
+        set_tcp_option(sock, libc::TCP_DEFER_ACCEPT, sec)?;
+    }
+    Ok(())
+}
+
+// the options that nix does not define
+fn set_tcp_option(sock: RawFd, name: libc::c_int, value: u32) -> io::Result<()> {
+    let value = value as libc::c_int;
+    let rv = unsafe {
+        libc::setsockopt(
+            sock,
+            libc::IPPROTO_TCP,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rv == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn raw_sock_type(fd: RawFd) -> Option<libc::c_int> {
+    let mut ty: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rv = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rv == 0 {
+        Some(ty)
+    } else {
+        None
+    }
+}
+
 pub fn describe_addr(raw_fd: RawFd) -> Result<impl Display, Error> {
     Ok(socket::getsockname(raw_fd)?)
 }
@@ -161,7 +728,7 @@ fn sock_info(
             socket::SockType::Datagram,
         ),
         ListenFd::UnixListener(path) => (
-            socket::SockAddr::new_unix(path)?,
+            unix_sock_addr(path)?,
             socket::AddressFamily::Unix,
             socket::SockType::Stream,
         ),
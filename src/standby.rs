@@ -0,0 +1,72 @@
+use std::fs::File;
+use std::io;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::{thread, time};
+
+use failure::Error;
+use libc;
+use nix::unistd::getpid;
+
+use config::Config;
+use sock::{connect_unix, ListenFd};
+
+const POLL_INTERVAL_SECS: u64 = 1;
+// a primary busy with a reload may miss a probe
+const MAX_FAILURES: u32 = 3;
+
+/// Blocks while the primary daemon is alive. When it dies, the standby starts as usual:
+/// the stale control socket is removed, the surviving monitors are re-attached
+/// and the missing workers are spawned from the state file.
+pub fn wait_primary(config: &Config) -> Result<(), Error> {
+    let listen_fd: ListenFd = config.control_sock.parse()?;
+    let pid = getpid();
+    info!(
+        "standby. watch primary control socket {} pid [{}]",
+        config.control_sock, pid
+    );
+    let mut failures = 0;
+    loop {
+        if is_primary_alive(&listen_fd, config.pidfile.as_ref().map(|s| s.as_str())) {
+            if failures > 0 {
+                info!("primary is back. pid [{}]", pid);
+            }
+            failures = 0;
+        } else {
+            failures += 1;
+            warn!(
+                "primary not responding. ({}/{}) pid [{}]",
+                failures, MAX_FAILURES, pid
+            );
+            if failures >= MAX_FAILURES {
+                info!("primary is gone. take over. pid [{}]", pid);
+                return Ok(());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(POLL_INTERVAL_SECS));
+    }
+}
+
+fn is_primary_alive(listen_fd: &ListenFd, pidfile: Option<&str>) -> bool {
+    let connected = match *listen_fd {
+        ListenFd::UnixListener(ref path) => connect_unix(path).is_ok(),
+        ListenFd::TcpListener(ref addr) => {
+            TcpStream::connect_timeout(addr, time::Duration::from_secs(POLL_INTERVAL_SECS)).is_ok()
+        }
+        ListenFd::UdpSocket(_) => false,
+    };
+    connected || pidfile.map_or(false, is_locked)
+}
+
+/// The primary holds the lock of the pidfile until it exits.
+fn is_locked(path: &str) -> bool {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+        // the lock is released when the file is closed
+        return false;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK)
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use chrono::Utc;
+use failure::Error;
+use libc::pid_t;
+use nix::unistd::getpid;
+use serde_json;
+
+/// Runtime state of the daemon saved to `state_file`. It is loaded on the next start.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct State {
+    pub pid: u32,
+    pub saved_at: String,
+    pub workers: BTreeMap<String, WorkerState>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct WorkerState {
+    pub monitor_pid: Option<u32>,
+    #[serde(default)]
+    pub pids: Vec<u32>,
+    // the number of upgrades
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub restarts: u64,
+    // unix time in seconds of the command binary
+    pub cmd_mtime: Option<u64>,
+    // the monitor gave up respawning the processes
+    #[serde(default)]
+    pub gave_up: bool,
+    // stopped by the stop command. it is not started until the start command
+    #[serde(default)]
+    pub stopped: bool,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<Option<State>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut buf = String::new();
+        File::open(path)?.read_to_string(&mut buf)?;
+        let state = serde_json::from_str(&buf)?;
+        Ok(Some(state))
+    }
+
+    /// Writes to a temporary file and renames it, so that a crash never leaves a broken file.
+    pub fn save(&mut self, path: &Path) -> Result<(), Error> {
+        self.pid = pid_t::from(getpid()) as u32;
+        self.saved_at = Utc::now().to_rfc3339();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        debug!("save state {:?}. pid [{}]", path, self.pid);
+        Ok(())
+    }
+}
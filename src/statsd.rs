@@ -0,0 +1,73 @@
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::{io, time};
+
+use nix::unistd::getpid;
+
+use config::StatsdConfig;
+
+/// Sends worker lifecycle metrics to statsd. Each monitor opens its own socket
+/// after closing the fds inherited from the daemon.
+pub struct Statsd {
+    sock: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+    worker: String,
+    tags: bool,
+}
+
+impl Statsd {
+    pub fn new(config: &StatsdConfig, worker: &str) -> io::Result<Statsd> {
+        let addr = config.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("statsd address {} not found", config.address),
+            )
+        })?;
+        let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let sock = UdpSocket::bind(bind)?;
+        sock.set_nonblocking(true)?;
+        Ok(Statsd {
+            sock,
+            addr,
+            prefix: config.prefix.to_owned(),
+            worker: worker.to_owned(),
+            tags: config.tags,
+        })
+    }
+
+    fn send(&self, name: &str, value: u64, kind: &str) {
+        let metric = if self.tags {
+            format!(
+                "{}.{}:{}|{}|#worker:{}",
+                self.prefix, name, value, kind, self.worker
+            )
+        } else {
+            format!(
+                "{}.{}.{}:{}|{}",
+                self.prefix, self.worker, name, value, kind
+            )
+        };
+        // metrics are best effort. a lost packet must not stop the monitor
+        if let Err(e) = self.sock.send_to(metric.as_bytes(), self.addr) {
+            debug!(
+                "fail send statsd metric {}. caused by: {} pid [{}]",
+                metric,
+                e,
+                getpid()
+            );
+        }
+    }
+
+    pub fn incr(&self, name: &str) {
+        self.send(name, 1, "c");
+    }
+
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(name, value, "g");
+    }
+
+    pub fn timing(&self, name: &str, elapsed: time::Duration) {
+        let ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        self.send(name, ms, "ms");
+    }
+}
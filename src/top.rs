@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Instant;
+
+use chrono::Duration;
+
+use command::WorkerStatus;
+use utils::format_duration;
+
+/// The table of `top`. The CPU usage of a process is the CPU time spent since the previous
+/// refresh, so it is shown from the second refresh.
+#[derive(Default)]
+pub struct Top {
+    // cpu time in milliseconds of each pid at the previous refresh
+    samples: HashMap<u32, u64>,
+    sampled_at: Option<Instant>,
+}
+
+impl Top {
+    pub fn render(&mut self, workers: &[WorkerStatus]) -> String {
+        let now = Instant::now();
+        let elapsed = self.sampled_at.map(|t| {
+            let d = now.duration_since(t);
+            d.as_secs() * 1000 + u64::from(d.subsec_millis())
+        });
+        let mut samples = HashMap::new();
+        let mut buf = String::new();
+        let processes: usize = workers.iter().map(|w| w.pids.len()).sum();
+        let _ = writeln!(buf, "{} workers, {} processes", workers.len(), processes);
+        let _ = writeln!(
+            buf,
+            "{:<20} {:>8} {:<10} {:>6} {:>8} {:>8} {:>10}",
+            "WORKER", "PID", "STATE", "CPU%", "RSS", "RESTARTS", "UPTIME"
+        );
+        for w in workers {
+            let uptime = format_duration(&Duration::seconds(w.uptime));
+            if w.pids.is_empty() {
+                let _ = writeln!(
+                    buf,
+                    "{:<20} {:>8} {:<10} {:>6} {:>8} {:>8} {:>10}",
+                    w.name, "-", w.state, "-", "-", w.restarts, "-"
+                );
+                continue;
+            }
+            for pid in &w.pids {
+                let usage = w.usage.iter().find(|u| u.pid == *pid);
+                let cpu = match (usage, self.samples.get(pid), elapsed) {
+                    (Some(usage), Some(prev), Some(elapsed)) if elapsed > 0 => format!(
+                        "{:.1}",
+                        usage.cpu_time.saturating_sub(*prev) as f64 * 100.0 / elapsed as f64
+                    ),
+                    _ => "-".to_owned(),
+                };
+                if let Some(usage) = usage {
+                    samples.insert(*pid, usage.cpu_time);
+                }
+                let _ = writeln!(
+                    buf,
+                    "{:<20} {:>8} {:<10} {:>6} {:>8} {:>8} {:>10}",
+                    w.name,
+                    pid,
+                    w.state,
+                    cpu,
+                    usage.map_or_else(|| "-".to_owned(), |u| format_size(u.rss)),
+                    w.restarts,
+                    uptime
+                );
+            }
+        }
+        self.samples = samples;
+        self.sampled_at = Some(now);
+        buf
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    let mut size = bytes as f64 / 1024.0;
+    for unit in &UNITS {
+        if size < 1024.0 {
+            return format!("{:.1}{}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}P", size)
+}
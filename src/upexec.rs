@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, io};
+
+use failure::{err_msg, Error};
+use libc::pid_t;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::getpid;
+use serde_json;
+
+use app::{APP_NAME, APP_NAME_UPPER};
+
+/// What the daemon hands down to the new binary on `upexec`. The pid does not
+/// change on exec, so the monitors stay children of the new daemon.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handover {
+    pub pid: u32,
+    // listeners kept open across exec
+    pub fds: Vec<RawFd>,
+    // the control socket is owned by the socket unit of systemd
+    pub inherited_ctrl_sock: bool,
+    // monitor pids by the worker name
+    pub monitors: BTreeMap<String, u32>,
+}
+
+fn handover_env() -> String {
+    format!("{}_UPEXEC", APP_NAME_UPPER)
+}
+
+fn handover_path(pid: u32) -> PathBuf {
+    env::temp_dir().join(format!("{}-upexec-{}.json", APP_NAME, pid))
+}
+
+/// The path of the running binary. A binary replaced by a deploy is reported with ` (deleted)`.
+pub fn current_binary() -> io::Result<PathBuf> {
+    let path = env::current_exe()?;
+    match path.to_str() {
+        Some(s) if s.ends_with(" (deleted)") => Ok(PathBuf::from(&s[..s.len() - 10])),
+        _ => Ok(path),
+    }
+}
+
+/// Checks that the binary can be executed before the daemon answers the client.
+pub fn check_binary(binary: &Path) -> Result<(), Error> {
+    let metadata = binary
+        .metadata()
+        .map_err(|e| err_msg(format!("{:?} not found. caused by: {}", binary, e)))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(err_msg(format!("{:?} is not executable", binary)));
+    }
+    Ok(())
+}
+
+impl Handover {
+    /// Returns the handover when the daemon is re-executed by `upexec`.
+    pub fn take() -> Result<Option<Handover>, Error> {
+        let path = match env::var(handover_env()) {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        // monitors spawned later must not see it
+        env::remove_var(handover_env());
+        let mut buf = String::new();
+        let res = File::open(&path).and_then(|mut f| f.read_to_string(&mut buf));
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("fail remove handover {}. caused by: {}", path, e);
+        }
+        res?;
+        let handover: Handover = serde_json::from_str(&buf)?;
+        let pid = pid_t::from(getpid()) as u32;
+        if handover.pid != pid {
+            return Err(err_msg(format!(
+                "handover {} is for pid [{}]. pid [{}]",
+                path, handover.pid, pid
+            )));
+        }
+        Ok(Some(handover))
+    }
+
+    /// Replaces the daemon with `binary` run with the same arguments.
+    /// It returns only when the exec fails.
+    pub fn exec(&self, binary: &Path, envs: Vec<(String, String)>) -> Error {
+        let path = handover_path(self.pid);
+        let res = self.write(&path).and_then(|_| {
+            for fd in &self.fds {
+                fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            let _ = fs::remove_file(&path);
+            return e;
+        }
+        let e = Command::new(binary)
+            .args(env::args_os().skip(1))
+            .env(handover_env(), &path)
+            .envs(envs)
+            .exec();
+        let _ = fs::remove_file(&path);
+        e.into()
+    }
+
+    fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut file = File::create(path)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
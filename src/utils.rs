@@ -1,7 +1,10 @@
-use std::path::PathBuf;
-use std::{env, io, time};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, mem, ptr, time};
 
 use chrono::Duration;
+use failure::{err_msg, Error};
 use libc;
 
 use app::APP_NAME;
@@ -71,3 +74,293 @@ pub fn timeout_process(timeout: u64, name: &str, id: u64) -> io::Result<bool> {
     };
     Ok(ret)
 }
+
+pub fn access(path: &Path, mode: libc::c_int) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Resolves a command the same way as spawning it from `working_directory`.
+pub fn find_executable(cmd: &str, working_directory: &str) -> Option<PathBuf> {
+    let cmd_path = Path::new(cmd);
+    if cmd.contains('/') {
+        let path = if cmd_path.is_absolute() {
+            cmd_path.to_owned()
+        } else {
+            Path::new(working_directory).join(cmd_path)
+        };
+        if path.is_file() && access(&path, libc::X_OK) {
+            return Some(path);
+        }
+        return None;
+    }
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(cmd_path))
+        .find(|path| path.is_file() && access(path, libc::X_OK))
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub type Resource = libc::c_int;
+
+pub fn rlimit_resource(name: &str) -> Option<Resource> {
+    let resource = match name {
+        "as" => libc::RLIMIT_AS,
+        "core" => libc::RLIMIT_CORE,
+        "cpu" => libc::RLIMIT_CPU,
+        "data" => libc::RLIMIT_DATA,
+        "fsize" => libc::RLIMIT_FSIZE,
+        "memlock" => libc::RLIMIT_MEMLOCK,
+        "nofile" => libc::RLIMIT_NOFILE,
+        "nproc" => libc::RLIMIT_NPROC,
+        "rss" => libc::RLIMIT_RSS,
+        "stack" => libc::RLIMIT_STACK,
+        _ => return None,
+    };
+    Some(resource)
+}
+
+pub fn set_rlimit(resource: Resource, value: libc::rlim_t) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value,
+    };
+    unsafe { cvt(libc::setrlimit(resource, &limit)).map(|_| ()) }
+}
+
+pub fn set_cpu_affinity(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        cvt(libc::sched_setaffinity(
+            0,
+            mem::size_of::<libc::cpu_set_t>(),
+            &set,
+        )).map(|_| ())
+    }
+}
+
+pub fn set_nice(nice: i32) -> io::Result<()> {
+    unsafe { cvt(libc::setpriority(libc::PRIO_PROCESS as _, 0, nice)).map(|_| ()) }
+}
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+pub fn set_ioprio(class: u32, level: u32) -> io::Result<()> {
+    let ioprio = (class << IOPRIO_CLASS_SHIFT) | level;
+    unsafe {
+        cvt(libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            ioprio as libc::c_int,
+        ) as i32).map(|_| ())
+    }
+}
+
+/// Changes the root directory and moves into `dir` inside of it.
+pub fn change_root(root: &CString, dir: &CString) -> io::Result<()> {
+    unsafe {
+        cvt(libc::chroot(root.as_ptr()))?;
+        cvt(libc::chdir(b"/\0".as_ptr() as *const libc::c_char))?;
+        cvt(libc::chdir(dir.as_ptr()))?;
+    }
+    Ok(())
+}
+
+/// Opens a pseudo terminal. Returns the master and the slave, both close-on-exec.
+pub fn open_pty() -> io::Result<(libc::c_int, libc::c_int)> {
+    let mut master = 0;
+    let mut slave = 0;
+    unsafe {
+        cvt(libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        ))?;
+        cvt(libc::fcntl(master, libc::F_SETFD, libc::FD_CLOEXEC))?;
+        cvt(libc::fcntl(slave, libc::F_SETFD, libc::FD_CLOEXEC))?;
+    }
+    Ok((master, slave))
+}
+
+/// Starts a new session, so that the process leads a process group of its own.
+pub fn set_session() -> io::Result<()> {
+    unsafe { cvt(libc::setsid()).map(|_| ()) }
+}
+
+/// Starts a new session with stdin as the controlling terminal.
+pub fn set_controlling_tty() -> io::Result<()> {
+    unsafe {
+        cvt(libc::setsid())?;
+        cvt(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+    }
+    Ok(())
+}
+
+/// Drops privileges the same way as `CommandExt::uid` and `CommandExt::gid`.
+pub fn switch_user(uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    unsafe {
+        if let Some(gid) = gid {
+            cvt(libc::setgid(gid as libc::gid_t))?;
+        }
+        if let Some(uid) = uid {
+            if libc::getuid() == 0 {
+                let _ = libc::setgroups(0, ptr::null());
+            }
+            cvt(libc::setuid(uid as libc::uid_t))?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a group name or a numeric gid.
+pub fn group_id(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+    if grp.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "group not found"));
+    }
+    Ok(unsafe { (*grp).gr_gid } as u32)
+}
+
+/// Returns the pid and its descendants, parents first. The tree is read from `/proc`.
+pub fn process_tree(pid: u32) -> Vec<u32> {
+    let parents = process_stats();
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            parents
+                .iter()
+                .filter(|(_, _, ppid)| *ppid == parent)
+                .map(|(child, _, _)| *child),
+        );
+        i += 1;
+    }
+    tree
+}
+
+/// Returns the children of the main thread of the process with the state of each, e.g.
+/// `Z` of a zombie. The children of the other threads are left out. Without
+/// `/proc/<pid>/task/<pid>/children` of CONFIG_PROC_CHILDREN, all of `/proc` is scanned
+/// for the children of the process.
+pub fn child_processes(pid: u32) -> Vec<(u32, char)> {
+    let children = match fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid)) {
+        Ok(children) => children,
+        Err(_) => {
+            return process_stats()
+                .into_iter()
+                .filter(|(_, _, ppid)| *ppid == pid)
+                .map(|(child, state, _)| (child, state))
+                .collect()
+        }
+    };
+    children
+        .split_whitespace()
+        .filter_map(|s| s.parse().ok())
+        .filter_map(|child| process_state(child).map(|state| (child, state)))
+        .collect()
+}
+
+fn process_state(pid: u32) -> Option<char> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name in parentheses may contain spaces
+    stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.chars().next())
+}
+
+// the pid, the state and the ppid of each process in /proc
+fn process_stats() -> Vec<(u32, char, u32)> {
+    let mut stats = Vec::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            let stat = match fs::read_to_string(entry.path().join("stat")) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            // the command name in parentheses may contain spaces
+            let mut fields = match stat.rfind(')') {
+                Some(i) => stat[i + 1..].split_whitespace(),
+                None => continue,
+            };
+            let state = fields.next().and_then(|s| s.chars().next());
+            let ppid = fields.next().and_then(|s| s.parse().ok());
+            if let (Some(state), Some(ppid)) = (state, ppid) {
+                stats.push((pid, state, ppid));
+            }
+        }
+    }
+    stats
+}
+
+/// Makes the process the subreaper of its descendants, so that an orphan, e.g. of a double
+/// fork, is re-parented to it instead of init.
+pub fn set_child_subreaper() -> io::Result<()> {
+    unsafe { cvt(libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0)).map(|_| ()) }
+}
+
+/// Parses a size in bytes with an optional suffix of K, M, G or T in units of 1024,
+/// e.g. `512M` or `1.5G`.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let (number, scale) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    match number.parse::<f64>() {
+        Ok(value) if value >= 0.0 => Ok((value * scale as f64) as u64),
+        _ => Err(err_msg(format!("invalid size {}", s))),
+    }
+}
+
+/// Returns the user and system time in milliseconds and the resident set size in bytes
+/// of the process, read from `/proc/<pid>/stat`.
+pub fn process_usage(pid: u32) -> Option<(u64, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the fields after the command name. utime is the 14th field of the line
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss: u64 = fields.get(21)?.parse().ok()?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    if ticks == 0 {
+        return None;
+    }
+    Some(((utime + stime) * 1000 / ticks, rss * page_size))
+}
+
+/// Returns the number of the open file descriptors of the process in `/proc/<pid>/fd`.
+pub fn process_fds(pid: u32) -> Option<u64> {
+    fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()
+        .map(|entries| entries.count() as u64)
+}
+
+pub fn set_oom_score_adj(pid: u32, adj: i32) -> io::Result<()> {
+    fs::write(format!("/proc/{}/oom_score_adj", pid), adj.to_string())
+}
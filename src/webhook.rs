@@ -0,0 +1,90 @@
+use std::time;
+
+use chrono::Utc;
+use failure::Error;
+use libc::pid_t;
+use nix::unistd::getpid;
+use serde_json;
+
+use config::{WebhookConfig, WebhookEvent};
+use http::{self, POSTS};
+use pool::Handle;
+
+// a slow endpoint must not hold a thread of the posts for long
+const POST_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    worker: Option<&'a str>,
+    message: &'a str,
+    pid: u32,
+    time: String,
+}
+
+/// POSTs lifecycle events of the daemon and the workers as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    config: WebhookConfig,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Result<Webhook, Error> {
+        for url in &config.urls {
+            http::parse_url(url)?;
+        }
+        Ok(Webhook {
+            config: config.clone(),
+        })
+    }
+
+    /// The number of restarts and the window in seconds of a restart storm.
+    pub fn restart_storm(&self) -> (u64, u64) {
+        (self.config.restart_storm, self.config.restart_storm_sec)
+    }
+
+    /// Sends the event to every url in the background. Join the handles to wait for the delivery.
+    pub fn send(&self, event: WebhookEvent, worker: Option<&str>, message: &str) -> Vec<Handle> {
+        if !self.config.events.contains(&event) {
+            return Vec::new();
+        }
+        let pid = pid_t::from(getpid()) as u32;
+        let payload = Payload {
+            event,
+            worker,
+            message,
+            pid,
+            time: Utc::now().to_rfc3339(),
+        };
+        let body = match serde_json::to_value(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(
+                    "fail encode webhook payload. caused by: {} pid [{}]",
+                    e, pid
+                );
+                return Vec::new();
+            }
+        };
+        info!("send webhook {:?} {:?}. pid [{}]", event, worker, pid);
+        self.config
+            .urls
+            .iter()
+            .map(|url| {
+                let url = url.to_owned();
+                let body = body.clone();
+                POSTS.spawn(move || {
+                    let timeout = time::Duration::from_secs(POST_TIMEOUT_SECS);
+                    if let Err(e) = http::post_json(&url, &body, timeout) {
+                        warn!(
+                            "fail send webhook to {}. caused by: {} pid [{}]",
+                            url,
+                            e,
+                            getpid()
+                        );
+                    }
+                })
+            })
+            .collect()
+    }
+}
@@ -1,50 +1,158 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::mem;
 use std::ops::Sub;
 use std::os::unix::io::AsRawFd;
-use std::{io, thread, time};
+use std::process::Child;
+use std::rc::Rc;
+use std::{cmp, env, io, path, thread, time};
 
 use chrono::{DateTime, Duration, Utc};
 use nix::unistd::getpid;
 
-use config::{AckKind, RestartStrategy, RunUpgrader, WorkerConfig};
-use logs::RollingLogFile;
+use app::APP_NAME_UPPER;
+use command::{CrashReport, ProcessUsage, RunState, WorkerStatus};
+use config::{
+    AckKind, LimitAction, RestartStrategy, RunUpgrader, ScheduleOverlap, WorkerConfig, WorkerKind,
+};
+use coredump::{find_core, move_core};
+use cron::Schedule;
+use health::HealthCheck;
+use hook::{run_hook, Hook};
+use logs::{
+    is_foreground, CaptureWriter, LineTail, LogChunk, OutputBuffer, PrefixWriter, RollingLogFile,
+};
+use metrics::WorkerStats;
 use monitor::{Monitor, OutputKind};
-use process::{output_stderr_log, output_stdout_log, process_exited, run_upgrader, Process};
+use process::{
+    output_stderr_log, output_stdout_log, process_exited, read_env_file, run_upgrader, Process,
+};
 use signal::{Signal, SignalSend};
+use utils::{find_executable, process_fds, process_tree, process_usage};
+
+// the crash reports kept by the monitor for the crashes command
+const MAX_CRASH_REPORTS: usize = 10;
+
+/// The command line of the processes with the binary resolved when they were spawned.
+/// A symlink switched by a deploy afterwards does not change it.
+#[derive(Clone)]
+struct Release {
+    cmdline: Vec<String>,
+    // the binary outside of the chroot
+    path: path::PathBuf,
+    mtime: Option<time::SystemTime>,
+}
 
 // #[derive(Debug)]
 pub struct Worker<'a> {
     pub id: u64,
     pub name: &'a str,
     pub config: &'a WorkerConfig,
+    // the command line of the processes. an upgrade may run another binary than the configured one
+    pub cmdline: Vec<String>,
+    release: Option<Release>,
+    // the release before the last upgrade. rollback runs it again
+    previous_release: Option<Release>,
     pub processes: Vec<Process<'a>>,
+    // signaled by dec or scale. reaped by health_check
+    stopping: Vec<Process<'a>>,
     pub stdout_log: Option<Box<io::Write>>,
     pub stderr_log: Option<Box<io::Write>>,
+    // the last lines of the output for the logs command
+    output: Rc<RefCell<OutputBuffer>>,
     pub active: bool,
+    // the process trees are stopped by SIGSTOP
+    pub paused: bool,
+    // the processes finish their work and are not respawned until the worker is stopped
+    pub draining: bool,
     pub num_processes: u64,
     extra_env: Vec<String>,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
     started_at: Option<DateTime<Utc>>,
+    pub restarts: u64,
+    pub upgrades: u64,
+    last_exit_code: Option<i32>,
+    last_spawned_at: Option<DateTime<Utc>>,
+    // (pid, exit code) of the processes exited unexpectedly
+    crashes: Vec<(u32, i32)>,
+    // the last lines of stderr of each process, and the reports of the last crashes
+    stderr_tails: HashMap<u32, LineTail>,
+    crash_reports: VecDeque<CrashReport>,
+    // the processes exited and not respawned by the restart policy
+    exited: u64,
+    // a process of the last run of a oneshot worker exited with an error
+    failed: bool,
+    schedule: Option<Schedule>,
+    last_run: Option<DateTime<Utc>>,
+    next_run: Option<DateTime<Utc>>,
+    // a run of the schedule waits for the previous one to exit
+    queued: bool,
+    // the processes exited soon after the spawn in a row. the respawn backs off
+    quick_exits: u32,
+    // the respawns waiting for the backoff
+    pending_respawns: usize,
+    respawn_at: Option<time::Instant>,
+    pub health: Option<HealthCheck>,
+    // the readiness check of the starting processes without manual ack
+    readiness: Option<HealthCheck>,
+    // terminated by the health check, silent beyond heartbeat_timeout_sec or over max_memory
+    // or max_fds. respawned whatever the restart policy, and killed if they are still alive
+    // on the next failure
+    unhealthy: Vec<u32>,
+    // alerted over max_fds, and not alerted again while they stay over
+    fds_alerted: Vec<u32>,
 }
 
 impl<'a> Worker<'a> {
     pub fn new(name: &'a str, config: &'a WorkerConfig) -> Self {
         let num_processes = config.numprocesses;
         let now = Utc::now();
+        let schedule: Option<Schedule> = config.schedule.as_ref().and_then(|s| s.parse().ok());
+        let next_run = schedule.as_ref().and_then(|s| s.next_after(now));
         Worker {
             id: 0,
             name,
             config,
+            cmdline: config.cmd.clone(),
+            release: None,
+            previous_release: None,
             processes: Vec::new(),
+            stopping: Vec::new(),
             stdout_log: None,
             stderr_log: None,
+            output: Rc::new(RefCell::new(OutputBuffer::new(
+                config.log_buffer_lines as usize,
+            ))),
             active: false,
+            paused: false,
+            draining: false,
             num_processes,
             extra_env: Vec::new(),
             created_at: now,
             updated_at: now,
             started_at: None,
+            restarts: 0,
+            upgrades: 0,
+            last_exit_code: None,
+            last_spawned_at: None,
+            crashes: Vec::new(),
+            stderr_tails: HashMap::new(),
+            crash_reports: VecDeque::new(),
+            exited: 0,
+            failed: false,
+            schedule,
+            last_run: None,
+            next_run,
+            queued: false,
+            quick_exits: 0,
+            pending_respawns: 0,
+            respawn_at: None,
+            health: config.health_check.as_ref().map(HealthCheck::new),
+            readiness: None,
+            unhealthy: Vec::new(),
+            fds_alerted: Vec::new(),
         }
     }
 
@@ -59,6 +167,42 @@ impl<'a> Worker<'a> {
         Ok(Box::new(log))
     }
 
+    /// Reopens the log files, e.g. after they are moved by logrotate.
+    pub fn reopen_logs(&mut self) -> io::Result<()> {
+        if self.config.stdout_log.is_some() {
+            info!("reopen [{}] stdout log. pid [{}]", self.name, getpid());
+            self.stdout_log = self.open_output(&self.config.stdout_log, "stdout")?;
+        }
+        if self.config.stderr_log.is_some() {
+            info!("reopen [{}] stderr log. pid [{}]", self.name, getpid());
+            self.stderr_log = self.open_output(&self.config.stderr_log, "stderr")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the writer of the output. The lines are kept in the output buffer
+    /// unless `log_buffer_lines` is 0.
+    fn open_output(
+        &self,
+        log: &Option<String>,
+        stream: &'static str,
+    ) -> io::Result<Option<Box<io::Write>>> {
+        let inner: Option<Box<io::Write>> = match *log {
+            Some(ref s) => Some(Worker::get_log_writer(s)?),
+            None if is_foreground() => Some(Box::new(PrefixWriter::new(self.name, io::stdout()))),
+            None => None,
+        };
+        if self.config.log_buffer_lines == 0 {
+            return Ok(inner);
+        }
+        let writer = CaptureWriter::new(self.output.clone(), stream, inner);
+        Ok(Some(Box::new(writer)))
+    }
+
+    pub fn output(&self, count: u64, since: Option<u64>) -> LogChunk {
+        self.output.borrow().chunk(count as usize, since)
+    }
+
     pub fn run(&mut self, monitor: &mut Monitor) -> io::Result<Vec<u32>> {
         let pid = getpid();
         debug!(
@@ -67,20 +211,23 @@ impl<'a> Worker<'a> {
         );
         let mut res = Vec::new();
         if self.stdout_log.is_none() {
-            if let Some(ref s) = self.config.stdout_log {
-                let w = Worker::get_log_writer(s)?;
-                self.stdout_log = Some(w);
-            }
+            self.stdout_log = self.open_output(&self.config.stdout_log, "stdout")?;
         }
 
         if self.stderr_log.is_none() {
-            if let Some(ref s) = self.config.stderr_log {
-                let w = Worker::get_log_writer(s)?;
-                self.stderr_log = Some(w);
-            }
+            self.stderr_log = self.open_output(&self.config.stderr_log, "stderr")?;
         }
 
         info!("start [{}] worker. pid [{}]", self.name, pid);
+        if self.config.kind == WorkerKind::Oneshot {
+            if !self.processes.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("[{}] is still running", self.name),
+                ));
+            }
+            self.failed = false;
+        }
         let mut num: usize = self.num_processes as usize;
         num -= self.processes.len();
         info!(
@@ -118,22 +265,120 @@ impl<'a> Worker<'a> {
         }
     }
 
+    /// Spawns or stops processes until the worker has `count` processes.
+    /// An upgrade spawns the same number. Returns the spawned and the stopped pids.
+    pub fn scale(
+        &mut self,
+        monitor: &mut Monitor,
+        count: u64,
+        signal: Signal,
+    ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        let pid = getpid();
+        info!(
+            "scale [{}] worker {} -> {}. pid [{}]",
+            self.name, self.num_processes, count, pid
+        );
+        self.num_processes = count;
+        let mut spawned = Vec::new();
+        let mut stopped = Vec::new();
+        // a worker waiting for the first connection spawns them on start
+        if !self.active {
+            return Ok((spawned, stopped));
+        }
+        while (self.processes.len() as u64) < count {
+            spawned.push(self.run_process(monitor)?);
+        }
+        while (self.processes.len() as u64) > count {
+            stopped.push(self.signal_one_process(signal)?);
+        }
+        Ok((spawned, stopped))
+    }
+
+    /// Stops the processes and their descendants with SIGSTOP. A parent is stopped
+    /// before its children, so that it does not fork a child that keeps running.
+    pub fn pause(&mut self) -> io::Result<Vec<u32>> {
+        if self.paused {
+            return Err(io::Error::new(io::ErrorKind::Other, "already paused"));
+        }
+        info!("pause [{}] worker. pid [{}]", self.name, getpid());
+        let pids = self.signal_trees(Signal::SIGSTOP);
+        self.paused = true;
+        Ok(pids)
+    }
+
+    /// Continues the paused processes. Processes exited while paused are respawned.
+    /// Returns the continued and the spawned pids.
+    pub fn resume(&mut self, monitor: &mut Monitor) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        if !self.paused {
+            return Err(io::Error::new(io::ErrorKind::Other, "not paused"));
+        }
+        info!("resume [{}] worker. pid [{}]", self.name, getpid());
+        let pids = self.signal_trees(Signal::SIGCONT);
+        self.paused = false;
+        // a stopped process sends no heartbeat
+        for p in &mut self.processes {
+            p.heartbeat();
+        }
+        let mut spawned = Vec::new();
+        if self.active {
+            while (self.processes.len() as u64) < self.num_processes {
+                spawned.push(self.run_process(monitor)?);
+            }
+        }
+        Ok((pids, spawned))
+    }
+
+    fn signal_trees(&mut self, signal: Signal) -> Vec<u32> {
+        let mut pids = Vec::new();
+        for pid in self.process_pid() {
+            for pid in process_tree(pid) {
+                if let Err(e) = pid.signal(signal) {
+                    warn!(
+                        "fail send signal {:?} to pid [{}]. caused by: {}",
+                        signal, pid, e
+                    );
+                } else {
+                    pids.push(pid);
+                }
+            }
+        }
+        pids
+    }
+
+    /// A stopped process does not handle a signal until it is continued.
+    fn continue_paused(&mut self) {
+        if self.paused {
+            info!("continue paused [{}] worker. pid [{}]", self.name, getpid());
+            self.signal_trees(Signal::SIGCONT);
+            self.paused = false;
+        }
+    }
+
     pub fn run_process(&mut self, monitor: &mut Monitor) -> io::Result<u32> {
         match self.spawn_process() {
             Ok(mut p) => {
-                if let Some(ref mut child) = p.child() {
+                let pid = p.pid().unwrap();
+                // the output of a pty is read even without a writer, or the process blocks
+                if let Some(master) = p.pty_output()? {
+                    monitor.watch_io(master, OutputKind::StdOut, pid)?;
+                } else if let Some(ref mut child) = p.child() {
                     if self.stdout_log.is_some() {
                         let stdout = child.stdout.as_ref().unwrap().as_raw_fd();
-                        monitor.watch_io(stdout, OutputKind::StdOut)?;
+                        monitor.watch_io(stdout, OutputKind::StdOut, pid)?;
                     };
 
                     if self.stderr_log.is_some() {
                         let stderr = child.stderr.as_ref().unwrap().as_raw_fd();
-                        monitor.watch_io(stderr, OutputKind::StdErr)?;
+                        monitor.watch_io(stderr, OutputKind::StdErr, pid)?;
                     };
                 }
                 info!("spawned process {}", p.process_name());
-                let pid = p.pid().unwrap();
+                self.try_hook(Hook::PostStart, p.index, Some(pid), None);
+                self.last_spawned_at = Some(Utc::now());
+                // start runs the processes not respawned again
+                if self.processes.len() as u64 + self.exited >= self.num_processes {
+                    self.exited = self.exited.saturating_sub(1);
+                }
                 self.processes.push(p);
                 Ok(pid)
             }
@@ -145,44 +390,239 @@ impl<'a> Worker<'a> {
         restarter: RestartStrategy,
         p: &mut Process,
         respawn: &mut usize,
-    ) -> (bool) {
-        p.try_wait()
-            .map(|exit_code| {
-                info!(
-                    "detect exited process {}. exit_code [{}]",
-                    p.process_name(),
-                    exit_code
-                );
-                if restarter.need_respawn(exit_code) {
-                    *respawn += 1;
-                    warn!("respawn process scheduled. {}", p.process_name());
-                }
-                true
-            })
-            .unwrap_or(false)
+        unhealthy: &[u32],
+    ) -> Option<i32> {
+        p.try_wait().map(|exit_code| {
+            info!(
+                "detect exited process {}. exit_code [{}]",
+                p.process_name(),
+                exit_code
+            );
+            let restarted = p.pid().map_or(false, |pid| unhealthy.contains(&pid));
+            if restarted || restarter.need_respawn(exit_code, p.signaled()) {
+                *respawn += 1;
+                warn!("respawn process scheduled. {}", p.process_name());
+            }
+            exit_code
+        })
     }
 
     pub fn health_check(&mut self) -> (usize, usize) {
+        let mut i = 0;
+        while i != self.stopping.len() {
+            if let Some(exit_code) = self.stopping[i].try_wait() {
+                let mut p = self.stopping.remove(i);
+                self.try_hook(Hook::PostStop, p.index, p.pid(), Some(exit_code));
+            } else {
+                i += 1;
+            }
+        }
         if self.processes.is_empty() {
             self.started_at = None;
-            return (0, 0);
+            return (0, self.due_respawns());
         }
 
         let restarter = self.config.restart;
         let respawn = &mut 0;
         let mut i = 0;
         while i != self.processes.len() {
-            if Worker::process_health_check(restarter, &mut self.processes[i], respawn) {
-                let _p = self.processes.remove(i);
+            let scheduled = *respawn;
+            if let Some(exit_code) = Worker::process_health_check(
+                restarter,
+                &mut self.processes[i],
+                respawn,
+                &self.unhealthy,
+            ) {
+                let mut p = self.processes.remove(i);
+                self.try_hook(Hook::PostStop, p.index, p.pid(), Some(exit_code));
+                if *respawn == scheduled {
+                    self.exited += 1;
+                    self.failed |= exit_code != 0;
+                } else {
+                    self.back_off(&p);
+                }
+                self.last_exit_code = Some(exit_code);
+                if exit_code != 0 && !p.signaled() {
+                    self.crashes.push((p.pid().unwrap_or(0), exit_code));
+                    self.report_crash(&mut p, exit_code);
+                }
             } else {
                 i += 1;
             }
         }
-        (self.processes.len(), *respawn)
+        // the tails of the processes reaped elsewhere, e.g. by an upgrade
+        let pids = self.tracked_pids();
+        self.stderr_tails.retain(|pid, _| pids.contains(pid));
+        (self.processes.len(), self.due_respawns())
+    }
+
+    /// Keeps the output of stderr of the process for its crash report.
+    pub fn capture_stderr(&mut self, pid: u32, buf: &[u8]) {
+        let lines = self.config.crash_stderr_lines as usize;
+        if lines == 0 {
+            return;
+        }
+        self.stderr_tails
+            .entry(pid)
+            .or_insert_with(|| LineTail::new(lines))
+            .push(buf);
+    }
+
+    fn report_crash(&mut self, p: &mut Process, exit_code: i32) {
+        let pid = p.pid().unwrap_or(0);
+        let stderr = self
+            .stderr_tails
+            .remove(&pid)
+            .map_or_else(Vec::new, |tail| tail.into_lines());
+        if self.crash_reports.len() >= MAX_CRASH_REPORTS {
+            self.crash_reports.pop_front();
+        }
+        let core = if self.config.core_dump && p.core_dumped() {
+            self.collect_core(pid, &p.cmdline[0])
+        } else {
+            None
+        };
+        self.crash_reports.push_back(CrashReport {
+            pid,
+            time: Utc::now().timestamp(),
+            exit_code,
+            signal: p.exit_signal(),
+            stderr,
+            core: core.map(|path| path.to_string_lossy().into_owned()),
+        });
+    }
+
+    /// Finds the core file of the crashed process, and moves it to `core_dump_dir`.
+    fn collect_core(&self, pid: u32, exe: &str) -> Option<path::PathBuf> {
+        let config = self.config;
+        let working_directory = config.root_path(&config.working_directory);
+        let core = match find_core(pid, exe, &working_directory) {
+            Some(core) => core,
+            None => {
+                warn!(
+                    "fail find core of [{}]. see core_pattern of the kernel pid [{}]",
+                    self.name, pid
+                );
+                return None;
+            }
+        };
+        let dir = match config.core_dump_dir {
+            Some(ref dir) => dir,
+            None => return Some(core),
+        };
+        match move_core(&core, path::Path::new(dir), self.name, pid) {
+            Ok(dest) => Some(dest),
+            Err(e) => {
+                warn!(
+                    "fail move core {:?} to {}. caused by: {} pid [{}]",
+                    core, dir, e, pid
+                );
+                Some(core)
+            }
+        }
+    }
+
+    pub fn crash_reports(&self) -> Vec<CrashReport> {
+        self.crash_reports.iter().cloned().collect()
+    }
+
+    /// Delays the respawn of a process exited before `restart_backoff_max_sec`.
+    /// The delay doubles on each exit in a row up to `restart_backoff_max_sec`, with jitter.
+    fn back_off(&mut self, p: &Process) {
+        let max = time::Duration::from_secs(self.config.restart_backoff_max_sec);
+        let quick = p.spawned_at().map_or(false, |at| at.elapsed() < max);
+        self.quick_exits = if quick { self.quick_exits + 1 } else { 0 };
+        self.pending_respawns += 1;
+        let base = self.config.restart_backoff_sec * 1000;
+        let delay = if self.quick_exits == 0 || base == 0 {
+            0
+        } else {
+            let exp = cmp::min(self.quick_exits - 1, 32);
+            jitter(cmp::min(
+                base.saturating_mul(1u64 << exp),
+                max.as_secs() * 1000,
+            ))
+        };
+        let at = time::Instant::now() + time::Duration::from_millis(delay);
+        if delay > 0 {
+            info!(
+                "back off respawn [{}] worker {} ms. exited {} times in a row. pid [{}]",
+                self.name,
+                delay,
+                self.quick_exits,
+                getpid()
+            );
+        }
+        self.respawn_at = Some(self.respawn_at.map_or(at, |t| cmp::max(t, at)));
+    }
+
+    /// Returns the number of the processes to respawn now. The processes spawned by start
+    /// or resume in the meantime are not respawned twice.
+    fn due_respawns(&mut self) -> usize {
+        match self.respawn_at {
+            Some(at) if at <= time::Instant::now() => {}
+            _ => return 0,
+        }
+        let missing = (self.num_processes as usize).saturating_sub(self.processes.len());
+        let respawn = cmp::min(self.pending_respawns, missing);
+        self.pending_respawns = 0;
+        self.respawn_at = None;
+        respawn
+    }
+
+    /// The exited processes are respawned on resume, and never while draining.
+    pub fn cancel_respawns(&mut self) {
+        self.pending_respawns = 0;
+        self.respawn_at = None;
+    }
+
+    /// Terminates the processes for the restart action of the health check. The processes
+    /// terminated by the last action and still alive are killed.
+    pub fn terminate_unhealthy(&mut self) -> Vec<u32> {
+        let mut pids = Vec::new();
+        for p in &mut self.processes {
+            if let Some(pid) = p.pid() {
+                let signal = if self.unhealthy.contains(&pid) {
+                    Signal::SIGKILL
+                } else {
+                    self.config.stop_signal
+                };
+                if let Err(e) = pid.signal_group(signal) {
+                    warn!(
+                        "fail send signal {:?} to pid [{}]. caused by: {}",
+                        signal, pid, e
+                    );
+                } else {
+                    info!("send signal {:?} to unhealthy pid [{}]", signal, pid);
+                    pids.push(pid);
+                }
+            }
+        }
+        self.unhealthy = pids.clone();
+        pids
+    }
+
+    /// Returns the processes exited unexpectedly since the last call.
+    pub fn take_crashes(&mut self) -> Vec<(u32, i32)> {
+        mem::replace(&mut self.crashes, Vec::new())
     }
 
-    pub fn is_alive(&self) -> bool {
-        !self.processes.is_empty()
+    /// Returns the pid of the process an attached client talks to. It is the oldest process.
+    pub fn attach_pid(&mut self) -> Option<u32> {
+        self.processes.first_mut().and_then(|p| p.pid())
+    }
+
+    /// Writes the input of an attached client to the pty of the process.
+    pub fn write_pty(&mut self, pid: u32, buf: &[u8]) -> io::Result<()> {
+        for p in &mut self.processes {
+            if p.pid() == Some(pid) {
+                return p.write_pty(buf);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("process exited. pid [{}]", pid),
+        ))
     }
 
     pub fn process_pid(&mut self) -> Vec<u32> {
@@ -196,13 +636,215 @@ impl<'a> Worker<'a> {
         ret
     }
 
+    /// The pids of the processes, and of the processes stopping.
+    pub fn tracked_pids(&mut self) -> Vec<u32> {
+        self.processes
+            .iter_mut()
+            .chain(self.stopping.iter_mut())
+            .filter_map(|p| p.pid())
+            .collect()
+    }
+
+    /// Marks the process that sent the ack as ready. The sender is one of the processes or
+    /// a descendant, e.g. of a shell. Returns the pid of the process.
+    pub fn ack_process(&mut self, senders: &[u32]) -> Option<u32> {
+        for p in &mut self.processes {
+            if p.ready() {
+                continue;
+            }
+            let pid = match p.pid() {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if is_sender(pid, senders) {
+                p.set_ready();
+                return Some(pid);
+            }
+        }
+        None
+    }
+
+    /// Records the heartbeat of the process that sent it, or one of its children.
+    pub fn heartbeat_process(&mut self, senders: &[u32]) -> Option<u32> {
+        for p in &mut self.processes {
+            if let Some(pid) = p.pid() {
+                if is_sender(pid, senders) {
+                    p.heartbeat();
+                    return Some(pid);
+                }
+            }
+        }
+        None
+    }
+
     pub fn start_immediate(&mut self) -> bool {
         self.config.start_immediate
     }
 
     fn spawn_process(&mut self) -> io::Result<Process<'a>> {
         self.id += 1;
+        let index = self.free_index();
+        self.run_hook(Hook::PreStart, index, None, None)?;
+        let mut penv = self.process_env()?;
+        penv.insert(
+            format!("{}_INSTANCE", self.config.environment_base_name),
+            index.to_string(),
+        );
+        if self.cmdline.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fail command not found",
+            ));
+        }
+        let mut p = Process::new(
+            self.id,
+            self.name,
+            &self.config.working_directory,
+            penv,
+            self.cmdline.clone(),
+            &self.config,
+        );
+        p.index = index;
+        p.spawn()?;
+        if self.release.is_none() {
+            self.release = self.resolve_release();
+        }
+        Ok(p)
+    }
+
+    /// Runs a lifecycle hook of the process at `index` with the metadata of the process in the
+    /// environment.
+    fn run_hook(
+        &self,
+        hook: Hook,
+        index: u64,
+        pid: Option<u32>,
+        exit_code: Option<i32>,
+    ) -> io::Result<()> {
+        if hook.command(self.config).is_none() {
+            return Ok(());
+        }
+        let base = &self.config.environment_base_name;
+        let mut environment = self.process_env()?;
+        environment.insert(format!("{}_WORKER", base), self.name.to_owned());
+        environment.insert(format!("{}_HOOK", base), hook.name().to_owned());
+        environment.insert(format!("{}_INSTANCE", base), index.to_string());
+        if let Some(pid) = pid {
+            environment.insert(format!("{}_PID", base), pid.to_string());
+        }
+        if let Some(exit_code) = exit_code {
+            environment.insert(format!("{}_EXIT_CODE", base), exit_code.to_string());
+        }
+        info!(
+            "run {} hook [{}] worker. instance [{}] pid [{}]",
+            hook.name(),
+            self.name,
+            index,
+            getpid()
+        );
+        run_hook(hook, self.config, &environment)
+    }
+
+    /// Runs a hook other than pre_start. A failure is only logged.
+    fn try_hook(&self, hook: Hook, index: u64, pid: Option<u32>, exit_code: Option<i32>) {
+        if let Err(e) = self.run_hook(hook, index, pid, exit_code) {
+            warn!(
+                "fail {} hook [{}] worker. caused by: {} pid [{}]",
+                hook.name(),
+                self.name,
+                e,
+                getpid()
+            );
+        }
+    }
+
+    /// The lowest instance index not used by the running processes.
+    fn free_index(&self) -> u64 {
+        (0..)
+            .find(|i| self.processes.iter().all(|p| p.index != *i))
+            .unwrap()
+    }
+
+    /// Spawns a one-off command with the environment, the working directory and the user
+    /// of the processes. The listen fds are inherited in the same way.
+    pub fn exec_process(&mut self, args: &[String]) -> io::Result<Child> {
+        let penv = self.process_env()?;
+        let mut p = Process::new(
+            0,
+            self.name,
+            &self.config.working_directory,
+            penv,
+            args.to_vec(),
+            &self.config,
+        );
+        p.set_oneshot();
+        p.spawn()?;
+        info!("spawned one-off process {}", p.process_name());
+        Ok(p.into_child().unwrap())
+    }
+
+    /// Spawns the command for `check --spawn` and returns without waiting. Without `args`,
+    /// the process exits in place of the exec once the command is executable by the user.
+    /// With `args`, the command runs with them appended, e.g. `--check`.
+    pub fn check_process(&self, args: Option<&[String]>) -> io::Result<Child> {
+        let penv = self.process_env()?;
+        if self.cmdline.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fail command not found",
+            ));
+        }
+        let mut cmdline = self.cmdline.clone();
+        let check_path = match args {
+            Some(args) => {
+                cmdline.extend(args.iter().cloned());
+                None
+            }
+            None => Some(self.check_path(&penv)?),
+        };
+        let mut p = Process::new(
+            0,
+            self.name,
+            &self.config.working_directory,
+            penv,
+            cmdline,
+            &self.config,
+        );
+        p.set_check(check_path);
+        p.spawn()?;
+        Ok(p.into_child().unwrap())
+    }
+
+    /// The command as seen by the process after the chroot. A command without `/` is looked up
+    /// in PATH of the environment of the processes.
+    fn check_path(&self, penv: &HashMap<String, String>) -> io::Result<path::PathBuf> {
+        let cmd = &self.cmdline[0];
+        if cmd.contains('/') {
+            return Ok(path::PathBuf::from(cmd));
+        }
+        let paths = penv
+            .get("PATH")
+            .map(OsString::from)
+            .or_else(|| env::var_os("PATH"))
+            .unwrap_or_default();
+        env::split_paths(&paths)
+            .map(|dir| dir.join(cmd))
+            .find(|path| self.config.root_path(&path.to_string_lossy()).is_file())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} not found in PATH", cmd),
+                )
+            })
+    }
+
+    fn process_env(&self) -> io::Result<HashMap<String, String>> {
         let mut penv: HashMap<String, String> = HashMap::new();
+        if let Some(ref env_file) = self.config.env_file {
+            for (k, v) in read_env_file(env_file)? {
+                penv.insert(k, v);
+            }
+        }
         for mut env in &self.config.environments {
             let v: Vec<&str> = env.splitn(2, '=').collect();
             if v.len() == 2 {
@@ -211,6 +853,9 @@ impl<'a> Worker<'a> {
                 warn!("skip broken env configuration. {:?}", v);
             }
         }
+        for (k, v) in &self.config.environment {
+            penv.insert(k.to_owned(), v.to_owned());
+        }
         for mut env in &self.extra_env {
             let v: Vec<&str> = env.splitn(2, '=').collect();
             if v.len() == 2 {
@@ -219,34 +864,30 @@ impl<'a> Worker<'a> {
                 warn!("skip broken env configuration. {:?}", v);
             }
         }
-        if self.config.cmd.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "fail command not found",
-            ));
-        }
-        let mut p = Process::new(
-            self.id,
-            self.name,
-            &self.config.working_directory,
-            penv,
-            &self.config,
-        );
-        p.spawn()?;
-        Ok(p)
+        Ok(penv)
     }
 
     pub fn signal_one_process(&mut self, signal: Signal) -> io::Result<u32> {
         let mut ret = 0;
-        if let Some(mut p) = self.processes.pop() {
+        // the highest index, so that the rest keep 0 to N - 1
+        let last = (0..self.processes.len()).max_by_key(|i| self.processes[*i].index);
+        if let Some(mut p) = last.map(|i| self.processes.remove(i)) {
             if let Some(pid) = p.pid() {
                 ret = pid;
-                if let Err(e) = pid.signal(signal) {
+                self.try_hook(Hook::PreStop, p.index, Some(pid), None);
+                p.set_signaled();
+                if let Err(e) = pid.signal_group(signal) {
                     warn!("fail send signal process. caused by: {}", e);
                 } else {
                     info!("send signal process. pid [{}]", pid);
                 }
+                if self.paused {
+                    for pid in process_tree(pid) {
+                        let _ = pid.signal(Signal::SIGCONT);
+                    }
+                }
             }
+            self.stopping.push(p);
         }
         Ok(ret)
     }
@@ -266,25 +907,35 @@ impl<'a> Worker<'a> {
 
     pub fn kill(&mut self) -> io::Result<Vec<u32>> {
         debug!("kill worker processes {}", self.processes.len());
+        self.continue_paused();
         let mut res = Vec::new();
-        let mut i = 0;
-        while i != self.processes.len() {
-            if let Some(pid) = Worker::kill_process(&mut self.processes[i]) {
+        let stopping = mem::replace(&mut self.stopping, Vec::new());
+        self.processes.extend(stopping);
+        while let Some(mut p) = self.processes.pop() {
+            if let Some(pid) = Worker::kill_process(&mut p) {
                 res.push(pid);
             }
-            self.processes.remove(i);
-            i += 1;
         }
         self.updated_at = Utc::now();
         Ok(res)
     }
 
     pub fn signal_all(&mut self, sig: Signal) -> io::Result<Vec<u32>> {
+        self.continue_paused();
+        let targets: Vec<(u64, Option<u32>)> = self
+            .processes
+            .iter_mut()
+            .map(|p| (p.index, p.pid()))
+            .collect();
+        for (index, pid) in targets {
+            self.try_hook(Hook::PreStop, index, pid, None);
+        }
         let mut pids: Vec<u32> = Vec::new();
         for p in &mut self.processes {
             let pid = p.pid().unwrap();
             debug!("send signal {:?}. {}", sig, p.process_name());
-            if let Err(e) = pid.signal(sig) {
+            p.set_signaled();
+            if let Err(e) = pid.signal_group(sig) {
                 warn!(
                     "fail send signal {:?} to pid [{}]. caused by: {}",
                     sig, pid, e
@@ -298,6 +949,25 @@ impl<'a> Worker<'a> {
         Ok(pids)
     }
 
+    /// Delivers a signal for the application, e.g. SIGUSR1 to reopen its logs.
+    /// Unlike `signal_all`, the processes are not expected to exit.
+    pub fn send_signal(&mut self, sig: Signal) -> Vec<u32> {
+        let mut pids: Vec<u32> = Vec::new();
+        for p in &mut self.processes {
+            let pid = p.pid().unwrap();
+            if let Err(e) = pid.signal(sig) {
+                warn!(
+                    "fail send signal {:?} to pid [{}]. caused by: {}",
+                    sig, pid, e
+                );
+            } else {
+                info!("send signal {:?} to pid [{}]", sig, pid);
+                pids.push(pid);
+            }
+        }
+        pids
+    }
+
     pub fn cleanup_process(&mut self, p: &mut Process) -> io::Result<()> {
         if let Some(ref mut p) = p.child() {
             if let Some(ref mut writer) = self.stdout_log {
@@ -338,6 +1008,280 @@ impl<'a> Worker<'a> {
         Ok(new)
     }
 
+    /// Waits the readiness check of the new processes before the old ones are signaled.
+    /// Unless it passes, the upgrade is aborted: the new processes are killed and the old
+    /// ones are kept.
+    fn wait_readiness(
+        &mut self,
+        monitor: &mut Monitor,
+        old_processes: &mut Vec<Process<'a>>,
+    ) -> io::Result<()> {
+        let config = match self.config.readiness_check {
+            Some(ref config) => config,
+            None => return Ok(()),
+        };
+        let self_pid = getpid();
+        let mut check = HealthCheck::readiness(config);
+        let pids: Vec<String> = self
+            .process_pid()
+            .iter()
+            .map(|pid| pid.to_string())
+            .collect();
+        let mut environment = self.config.environment.clone();
+        environment.insert(format!("{}_NEW_PIDS", APP_NAME_UPPER), pids.join(","));
+        info!(
+            "upgrading. wait readiness check [{}] worker. pid [{}]",
+            self.name, self_pid
+        );
+        let res = monitor.wait_ready(self, &mut check, &environment);
+        if res.is_ok() && self.config.ack != AckKind::Manual {
+            for p in &mut self.processes {
+                p.set_ready();
+            }
+        }
+        if let Err(ref e) = res {
+            warn!(
+                "abort upgrade [{}] worker. caused by: {} pid [{}]",
+                self.name, e, self_pid
+            );
+            self.kill_new_processes();
+            self.processes.append(old_processes);
+        }
+        res
+    }
+
+    /// Kills the processes and waits for them, such as the new processes of an aborted upgrade.
+    fn kill_new_processes(&mut self) {
+        while let Some(mut p) = self.processes.pop() {
+            Worker::kill_process(&mut p);
+            if let Some(child) = p.child() {
+                let _ = child.wait();
+            }
+            if let Err(e) = self.cleanup_process(&mut p) {
+                warn!("fail cleanup process {}. caused by {}", p.process_name(), e);
+            }
+        }
+    }
+
+    /// Replaces the processes `max_parallel` at a time. Each batch of new processes has to be
+    /// ready before as many old processes are signaled, so the worker never runs fewer ready
+    /// processes than before. With `canary`, the first batch is a single process baked for
+    /// canary_bake_sec. A batch not ready aborts the rest: the new processes are killed,
+    /// the old ones left keep running, and the ones stopped by the earlier batches are
+    /// respawned with the command line before.
+    fn run_rolling(
+        &mut self,
+        monitor: &mut Monitor,
+        signal: Signal,
+        max_parallel: u64,
+        canary: bool,
+    ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        let mut old = Vec::new();
+        let self_pid = getpid();
+        let mut old_processes = self.move_old_process();
+        let num = self.num_processes as usize;
+        let mut canary = canary;
+        while self.processes.len() < num {
+            let count = if canary {
+                1
+            } else {
+                cmp::min(max_parallel as usize, num - self.processes.len())
+            };
+            let mut batch = Vec::with_capacity(count);
+            let mut res = Ok(());
+            for _ in 0..count {
+                match self.run_process(monitor) {
+                    Ok(pid) => batch.push(pid),
+                    Err(e) => {
+                        res = Err(e);
+                        break;
+                    }
+                }
+            }
+            info!(
+                "rolling. wait batch {:?} of {} processes [{}] worker. pid [{}]",
+                batch, num, self.name, self_pid
+            );
+            let mut res = res.and_then(|_| self.wait_batch(monitor, &batch, signal));
+            if canary {
+                res = res.and_then(|_| self.bake_canary(monitor, batch[0]));
+                canary = false;
+            }
+            if let Err(e) = res {
+                warn!(
+                    "abort rolling [{}] worker. caused by: {} pid [{}]",
+                    self.name, e, self_pid
+                );
+                self.kill_new_processes();
+                self.processes.append(&mut old_processes);
+                self.pending_respawns += num.saturating_sub(self.processes.len());
+                self.respawn_at = Some(time::Instant::now());
+                return Err(e);
+            }
+            let rest = old_processes.len().saturating_sub(count);
+            let batch_old = old_processes.split_off(rest);
+            self.stop_old_processes(batch_old, signal, &mut old);
+        }
+        // the processes over numprocesses
+        self.stop_old_processes(old_processes, signal, &mut old);
+        Ok((self.process_pid(), old))
+    }
+
+    /// Watches the canary for canary_bake_sec with the health check, or with the readiness
+    /// check when the worker has no health check.
+    fn bake_canary(&mut self, monitor: &mut Monitor, pid: u32) -> io::Result<()> {
+        let config = self.config;
+        let mut check = config
+            .health_check
+            .as_ref()
+            .or_else(|| config.readiness_check.as_ref())
+            .map(HealthCheck::readiness);
+        let mut environment = config.environment.clone();
+        environment.insert(format!("{}_NEW_PIDS", APP_NAME_UPPER), pid.to_string());
+        info!(
+            "canary. bake process [{}] {} secs [{}] worker. pid [{}]",
+            pid,
+            config.canary_bake_sec,
+            self.name,
+            getpid()
+        );
+        monitor.bake(self, check.as_mut(), &environment, config.canary_bake_sec)
+    }
+
+    /// Starts the whole new generation alongside the old one. Once it is ready, both serve on
+    /// the shared sockets for overlap_sec before the old generation is signaled. A new process
+    /// that exits in the meantime aborts the upgrade, and the old generation keeps running.
+    fn run_blue_green(
+        &mut self,
+        monitor: &mut Monitor,
+        signal: Signal,
+    ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        let mut old = Vec::new();
+        let self_pid = getpid();
+        let mut old_processes = self.move_old_process();
+        let res = self
+            .spawn_upgrade_processes(monitor)
+            .and_then(|new| self.overlap_generations(monitor, &new, signal));
+        if let Err(e) = res {
+            warn!(
+                "abort upgrade [{}] worker. caused by: {} pid [{}]",
+                self.name, e, self_pid
+            );
+            self.kill_new_processes();
+            self.processes.append(&mut old_processes);
+            return Err(e);
+        }
+        self.stop_old_processes(old_processes, signal, &mut old);
+        Ok((self.process_pid(), old))
+    }
+
+    fn overlap_generations(
+        &mut self,
+        monitor: &mut Monitor,
+        new: &[u32],
+        signal: Signal,
+    ) -> io::Result<()> {
+        self.wait_batch(monitor, new, signal)?;
+        info!(
+            "blue-green. overlap generations {} secs [{}] worker. pid [{}]",
+            self.config.overlap_sec,
+            self.name,
+            getpid()
+        );
+        let environment = self.config.environment.clone();
+        monitor.bake(self, None, &environment, self.config.overlap_sec)
+    }
+
+    /// Signals the old processes. They are reaped, or killed after kill_after_sec, by the
+    /// main loop.
+    fn stop_old_processes(
+        &mut self,
+        processes: Vec<Process<'a>>,
+        signal: Signal,
+        old: &mut Vec<u32>,
+    ) {
+        for mut p in processes {
+            if let Some(pid) = p.pid() {
+                p.set_signaled();
+                if let Err(e) = pid.signal_group(signal) {
+                    warn!("fail send signal old process. caused by: {}", e);
+                } else {
+                    debug!("send signal {:?} {}", signal, p.process_name());
+                    old.push(pid);
+                }
+            }
+            self.stopping.push(p);
+        }
+    }
+
+    /// Waits until the processes of `batch` are ready: the readiness check passes, each
+    /// sends its ack with manual ack, and ack_timeout passes with timer ack.
+    fn wait_batch(
+        &mut self,
+        monitor: &mut Monitor,
+        batch: &[u32],
+        signal: Signal,
+    ) -> io::Result<()> {
+        let config = self.config;
+        if let Some(ref check_config) = config.readiness_check {
+            let mut check = HealthCheck::readiness(check_config);
+            let pids: Vec<String> = batch.iter().map(|pid| pid.to_string()).collect();
+            let mut environment = config.environment.clone();
+            environment.insert(format!("{}_NEW_PIDS", APP_NAME_UPPER), pids.join(","));
+            monitor.wait_ready(self, &mut check, &environment)?;
+            for p in &mut self.processes {
+                if config.ack != AckKind::Manual
+                    && p.pid().map_or(false, |pid| batch.contains(&pid))
+                {
+                    p.set_ready();
+                }
+            }
+        }
+        match config.ack {
+            AckKind::Manual => {
+                let started_at = time::Instant::now();
+                while !self.batch_ready(batch) {
+                    if let Some(pid) = self.batch_exited(batch) {
+                        return Err(new_process_exited(pid));
+                    }
+                    if started_at.elapsed().as_secs() >= config.readiness_timeout_sec {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("ack not received in {} secs", config.readiness_timeout_sec),
+                        ));
+                    }
+                    monitor.wait_ack(self, signal)?;
+                }
+            }
+            AckKind::Timer => thread::sleep(time::Duration::from_secs(config.ack_timeout)),
+            AckKind::None => {}
+        }
+        match self.batch_exited(batch) {
+            Some(pid) => Err(new_process_exited(pid)),
+            None => Ok(()),
+        }
+    }
+
+    fn batch_ready(&mut self, batch: &[u32]) -> bool {
+        let processes = &mut self.processes;
+        batch.iter().all(|pid| {
+            processes
+                .iter_mut()
+                .any(|p| p.pid() == Some(*pid) && p.ready())
+        })
+    }
+
+    /// The first process of `batch` exited. wait_ack forgets the exited processes.
+    fn batch_exited(&mut self, batch: &[u32]) -> Option<u32> {
+        let processes = &mut self.processes;
+        batch.iter().cloned().find(|pid| {
+            processes
+                .iter_mut()
+                .position(|p| p.pid() == Some(*pid))
+                .map_or(true, |i| process_exited(&mut processes[i]))
+        })
+    }
+
     fn run_timer_ack(
         &mut self,
         monitor: &mut Monitor,
@@ -347,6 +1291,7 @@ impl<'a> Worker<'a> {
         let self_pid = getpid();
         let mut old_processes = self.move_old_process();
         self.spawn_upgrade_processes(monitor)?;
+        self.wait_readiness(monitor, &mut old_processes)?;
         info!(
             "upgrading. wait ack [{:?}] [{}] worker. pid [{}]",
             self.config.ack, self.name, self_pid
@@ -380,7 +1325,7 @@ impl<'a> Worker<'a> {
         for p in &mut old_processes {
             if let Some(pid) = p.pid() {
                 debug!("send signal {:?} {}", default_signal, p.process_name(),);
-                pid.signal(default_signal)?;
+                pid.signal_group(default_signal)?;
                 old.push(pid);
             }
         }
@@ -406,6 +1351,7 @@ impl<'a> Worker<'a> {
         let self_pid = getpid();
         let mut old_processes = self.move_old_process();
         self.spawn_upgrade_processes(monitor)?;
+        self.wait_readiness(monitor, &mut old_processes)?;
         info!(
             "upgrading. wait ack [{:?}] [{}] worker. pid [{}]",
             self.config.ack, self.name, self_pid
@@ -461,7 +1407,7 @@ impl<'a> Worker<'a> {
         for p in &mut self.processes {
             if let Some(pid) = p.pid() {
                 debug!("send signal {:?} to {}", signal, p.process_name());
-                pid.signal(signal)?;
+                pid.signal_group(signal)?;
                 old.push(pid);
             }
         }
@@ -479,10 +1425,91 @@ impl<'a> Worker<'a> {
         Ok((self.process_pid(), old))
     }
 
+    /// Returns the command line of an upgrade. The binary and the arguments replace the
+    /// configured ones until the next upgrade without them.
+    pub fn upgrade_cmdline(
+        &self,
+        binary: Option<&str>,
+        args: Option<&[String]>,
+    ) -> io::Result<Vec<String>> {
+        let mut cmdline = self.config.cmd.clone();
+        if let Some(binary) = binary {
+            if self.find_binary(binary).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not an executable", binary),
+                ));
+            }
+            cmdline[0] = binary.to_owned();
+        }
+        if let Some(args) = args {
+            cmdline.truncate(1);
+            cmdline.extend(args.iter().cloned());
+        }
+        Ok(cmdline)
+    }
+
+    /// Returns the command line before the last upgrade with the binary it ran.
+    /// The binary must be the same file as then.
+    pub fn rollback_cmdline(&self) -> io::Result<Vec<String>> {
+        let release = match self.previous_release {
+            Some(ref release) => release,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no previous binary to roll back to",
+                ))
+            }
+        };
+        let mtime = release.path.metadata().and_then(|m| m.modified()).ok();
+        if mtime.is_none() || mtime != release.mtime {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "previous binary {} was removed or replaced",
+                    release.path.display()
+                ),
+            ));
+        }
+        Ok(release.cmdline.clone())
+    }
+
+    // the paths are inside of the new root when chroot is set
+    fn find_binary(&self, cmd: &str) -> Option<path::PathBuf> {
+        if path::Path::new(cmd).is_absolute() {
+            find_executable(&self.config.root_path(cmd).to_string_lossy(), "")
+        } else {
+            let dir = self.config.root_path(&self.config.working_directory);
+            find_executable(cmd, &dir.to_string_lossy())
+        }
+    }
+
+    fn resolve_release(&self) -> Option<Release> {
+        let path = self
+            .find_binary(self.cmdline.get(0)?)?
+            .canonicalize()
+            .ok()?;
+        let binary = match self.config.chroot {
+            Some(ref root) => {
+                let root = path::Path::new(root).canonicalize().ok()?;
+                path::Path::new("/").join(path.strip_prefix(&root).ok()?)
+            }
+            None => path.clone(),
+        };
+        let mut cmdline = self.cmdline.clone();
+        cmdline[0] = binary.to_string_lossy().into_owned();
+        Some(Release {
+            cmdline,
+            mtime: path.metadata().and_then(|m| m.modified()).ok(),
+            path,
+        })
+    }
+
     pub fn upgrade(
         &mut self,
         monitor: &mut Monitor,
         signal: Signal,
+        cmdline: Vec<String>,
     ) -> io::Result<(Vec<u32>, Vec<u32>)> {
         let self_pid = getpid();
         if !self.active {
@@ -495,10 +1522,16 @@ impl<'a> Worker<'a> {
             return Ok((new_pid, old_pid));
         }
 
+        if self.paused {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "worker is paused. resume it first",
+            ));
+        }
         info!("start upgrade [{}] worker. pid [{}]", self.name, self_pid);
         if self.config.run_upgrader == RunUpgrader::OnUpgrade {
             if let Some(ref upgrader) = self.config.upgrader {
-                let mut proc = run_upgrader(upgrader)?;
+                let mut proc = run_upgrader(upgrader, &self.config.working_directory)?;
                 if !monitor.wait_on_upgrader(self, &mut proc)? {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
@@ -508,13 +1541,42 @@ impl<'a> Worker<'a> {
             }
         }
 
+        if cmdline != self.cmdline {
+            info!(
+                "upgrade [{}] worker to {:?}. pid [{}]",
+                self.name, cmdline, self_pid
+            );
+        }
+        let previous = mem::replace(&mut self.cmdline, cmdline);
+        let release = self.release.take();
         let result = match self.config.ack {
-            AckKind::Timer => self.run_timer_ack(monitor, signal)?,
-            AckKind::Manual => self.run_manual_ack(monitor, signal)?,
-            AckKind::None => self.run_no_ack(monitor, signal)?,
+            _ if self.config.max_parallel > 0 || self.config.canary_bake_sec > 0 => {
+                // the rest are replaced at once after the canary without max_parallel
+                let max_parallel = match self.config.max_parallel {
+                    0 => self.num_processes,
+                    n => n,
+                };
+                let canary = self.config.canary_bake_sec > 0;
+                self.run_rolling(monitor, signal, max_parallel, canary)
+            }
+            _ if self.config.overlap_sec > 0 => self.run_blue_green(monitor, signal),
+            AckKind::Timer => self.run_timer_ack(monitor, signal),
+            AckKind::Manual => self.run_manual_ack(monitor, signal),
+            AckKind::None => self.run_no_ack(monitor, signal),
+        };
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                // respawn the failed processes with the command line before the upgrade
+                self.cmdline = previous;
+                self.release = release;
+                return Err(e);
+            }
         };
+        self.previous_release = release;
 
         self.updated_at = Utc::now();
+        self.upgrades += 1;
         info!(
             "success upgrade [{}] worker. new_pid {:?} old_pid {:?}. pid [{}]",
             self.name, result.0, result.1, self_pid
@@ -522,6 +1584,82 @@ impl<'a> Worker<'a> {
         Ok(result)
     }
 
+    /// Replaces the processes with the same command line in batches of max_parallel, or one
+    /// by one when it is not set.
+    pub fn rolling_restart(
+        &mut self,
+        monitor: &mut Monitor,
+        signal: Signal,
+    ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        if !self.active {
+            return Err(io::Error::new(io::ErrorKind::Other, "worker is not active"));
+        }
+        if self.paused {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "worker is paused. resume it first",
+            ));
+        }
+        info!(
+            "start rolling restart [{}] worker. pid [{}]",
+            self.name,
+            getpid()
+        );
+        let max_parallel = cmp::max(self.config.max_parallel, 1);
+        self.run_rolling(monitor, signal, max_parallel, false)
+    }
+
+    /// Returns true when a run of the schedule is due. A run due while the previous one is
+    /// still running is skipped, queued until it exits, or kills it by schedule_overlap.
+    pub fn scheduled_run_due(&mut self) -> bool {
+        let now = Utc::now();
+        let due = self.next_run.map_or(false, |at| at <= now);
+        if due {
+            self.next_run = self.schedule.as_ref().and_then(|s| s.next_after(now));
+        }
+        let run = if self.processes.is_empty() {
+            due || self.queued
+        } else if !due {
+            false
+        } else {
+            match self.config.schedule_overlap {
+                ScheduleOverlap::Skip => {
+                    warn!(
+                        "skip scheduled run [{}] worker. the previous run is running. pid [{}]",
+                        self.name,
+                        getpid()
+                    );
+                    false
+                }
+                ScheduleOverlap::Queue => {
+                    info!(
+                        "queue scheduled run [{}] worker. the previous run is running. pid [{}]",
+                        self.name,
+                        getpid()
+                    );
+                    self.queued = true;
+                    false
+                }
+                ScheduleOverlap::KillPrevious => {
+                    warn!(
+                        "kill the previous run [{}] worker. pid [{}]",
+                        self.name,
+                        getpid()
+                    );
+                    // the children holding the output pipes are killed too
+                    self.signal_trees(Signal::SIGKILL);
+                    self.kill_new_processes();
+                    true
+                }
+            }
+        };
+        if run {
+            self.queued = false;
+            self.last_run = Some(now);
+        }
+        run
+    }
+
     pub fn uptime(&mut self) -> Duration {
         if let Some(start) = self.started_at {
             let now = Utc::now();
@@ -531,6 +1669,326 @@ impl<'a> Worker<'a> {
         }
     }
 
+    pub fn stats(&mut self) -> WorkerStats {
+        WorkerStats {
+            processes: self.processes.len(),
+            pids: self.process_pid(),
+            restarts: self.restarts,
+            upgrades: self.upgrades,
+            last_exit_code: self.last_exit_code,
+            last_spawn_time: self.last_spawned_at.map(|t| t.timestamp()),
+            uptime: self.uptime().num_seconds(),
+            paused: self.paused,
+        }
+    }
+
+    pub fn status(&mut self) -> WorkerStatus {
+        let state = if self.paused {
+            RunState::Paused
+        } else if self.draining {
+            RunState::Draining
+        } else if !self.active {
+            RunState::Stopped
+        } else if self.schedule.is_some() && self.processes.is_empty() && self.respawn_at.is_none()
+        {
+            RunState::Scheduled
+        } else if (self.processes.len() as u64) < self.num_processes {
+            if self.processes.len() as u64 + self.exited >= self.num_processes {
+                match self.config.kind {
+                    WorkerKind::Oneshot if self.processes.is_empty() && self.failed => {
+                        RunState::Failed
+                    }
+                    WorkerKind::Oneshot if self.processes.is_empty() => RunState::Completed,
+                    _ => RunState::Exited,
+                }
+            } else {
+                RunState::Restarting
+            }
+        } else if self.processes.iter().any(|p| !p.ready()) {
+            RunState::Starting
+        } else {
+            RunState::Running
+        };
+        // the same path as the command, without resolving symlinks
+        let cmd = &self.cmdline[0];
+        let binary = if path::Path::new(cmd).is_absolute() {
+            self.config.root_path(cmd)
+        } else {
+            self.config
+                .root_path(&self.config.working_directory)
+                .join(cmd)
+        };
+        let binary_mtime = binary
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+        let outdated = match (self.release.as_ref(), self.resolve_release()) {
+            (Some(running), Some(current)) => {
+                running.path != current.path || running.mtime != current.mtime
+            }
+            _ => false,
+        };
+        let pids = self.process_pid();
+        let usage = pids
+            .iter()
+            .filter_map(|&pid| {
+                process_usage(pid).map(|(cpu_time, rss)| ProcessUsage {
+                    pid,
+                    cpu_time,
+                    rss,
+                    fds: process_fds(pid).unwrap_or(0),
+                })
+            })
+            .collect();
+        WorkerStatus {
+            name: self.name.to_owned(),
+            state,
+            pids,
+            num_processes: self.num_processes,
+            start_time: self.started_at.map(|t| t.timestamp()),
+            uptime: self.uptime().num_seconds(),
+            restarts: self.restarts,
+            last_exit_code: self.last_exit_code,
+            binary: Some(binary.to_string_lossy().into_owned()),
+            binary_mtime,
+            previous_binary: self
+                .previous_release
+                .as_ref()
+                .map(|r| r.path.to_string_lossy().into_owned()),
+            outdated,
+            usage,
+            last_run: self.last_run.map(|t| t.timestamp()),
+            next_run: self.next_run.map(|t| t.timestamp()),
+        }
+    }
+
+    /// Kills the processes silent beyond `heartbeat_timeout_sec`. They are respawned
+    /// whatever the restart policy.
+    pub fn check_heartbeats(&mut self) {
+        for p in &mut self.processes {
+            if !p.check_heartbeat_timeout(self.config.heartbeat_timeout_sec) {
+                continue;
+            }
+            if let Some(pid) = p.pid() {
+                if self.unhealthy.contains(&pid) {
+                    continue;
+                }
+                if let Err(e) = p.kill() {
+                    warn!("fail kill process. caused by: {}", e);
+                } else {
+                    warn!(
+                        "kill process. no heartbeat in {} secs pid [{}]",
+                        self.config.heartbeat_timeout_sec, pid
+                    );
+                    self.unhealthy.push(pid);
+                }
+            }
+        }
+    }
+
+    /// The user and system time in milliseconds spent by the processes and their
+    /// descendants alive.
+    pub fn cpu_time(&mut self) -> u64 {
+        self.process_pid()
+            .into_iter()
+            .flat_map(process_tree)
+            .filter_map(process_usage)
+            .map(|(cpu_time, _)| cpu_time)
+            .sum()
+    }
+
+    /// Signals the processes whose resident set with their descendants is over `max_memory`
+    /// to stop. They are respawned whatever the restart policy, and killed after
+    /// `kill_after_sec`. Returns the pids with the resident sets in bytes.
+    pub fn check_memory(&mut self) -> Vec<(u32, u64)> {
+        let limit = match self.config.max_memory_bytes() {
+            Some(limit) => limit,
+            None => return Vec::new(),
+        };
+        let signal = self.config.stop_signal;
+        let mut over = Vec::new();
+        for p in &mut self.processes {
+            let pid = match p.pid() {
+                Some(pid) if !p.signaled() && !self.unhealthy.contains(&pid) => pid,
+                _ => continue,
+            };
+            let rss: u64 = process_tree(pid)
+                .into_iter()
+                .filter_map(process_usage)
+                .map(|(_, rss)| rss)
+                .sum();
+            if rss <= limit || !Worker::stop_over_limit(p, pid, signal) {
+                continue;
+            }
+            self.unhealthy.push(pid);
+            over.push((pid, rss));
+        }
+        over
+    }
+
+    /// Finds the processes whose open fds with their descendants are over `max_fds`. With
+    /// the restart action, they are signaled to stop like the ones over `max_memory`. A
+    /// process is reported once while it stays over. Returns the pids with the open fds.
+    pub fn check_fds(&mut self) -> Vec<(u32, u64)> {
+        let limit = match self.config.max_fds {
+            Some(limit) => limit,
+            None => return Vec::new(),
+        };
+        let restart = self.config.max_fds_action == LimitAction::Restart;
+        let signal = self.config.stop_signal;
+        let mut over = Vec::new();
+        let mut alerted = Vec::new();
+        for p in &mut self.processes {
+            let pid = match p.pid() {
+                Some(pid) if !p.signaled() && !self.unhealthy.contains(&pid) => pid,
+                _ => continue,
+            };
+            let fds: u64 = process_tree(pid).into_iter().filter_map(process_fds).sum();
+            if fds <= limit {
+                continue;
+            }
+            if self.fds_alerted.contains(&pid) {
+                alerted.push(pid);
+                continue;
+            }
+            if restart {
+                if !Worker::stop_over_limit(p, pid, signal) {
+                    continue;
+                }
+                self.unhealthy.push(pid);
+            } else {
+                alerted.push(pid);
+            }
+            over.push((pid, fds));
+        }
+        self.fds_alerted = alerted;
+        over
+    }
+
+    fn stop_over_limit(p: &mut Process, pid: u32, signal: Signal) -> bool {
+        p.set_signaled();
+        if let Err(e) = pid.signal_group(signal) {
+            warn!(
+                "fail send signal {:?} to pid [{}]. caused by: {}",
+                signal, pid, e
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Marks the starting processes ready when the readiness check passes. With manual ack,
+    /// the processes are ready on their acks instead.
+    pub fn check_readiness(&mut self) {
+        let config = self.config;
+        let check_config = match config.readiness_check {
+            Some(ref check) if config.ack != AckKind::Manual => check,
+            _ => return,
+        };
+        if self.processes.iter().all(|p| p.ready()) {
+            self.readiness = None;
+            return;
+        }
+        let working_directory = config.root_path(&config.working_directory);
+        let passed = {
+            let check = self
+                .readiness
+                .get_or_insert_with(|| HealthCheck::readiness(check_config));
+            if let Some((failures, e)) = check.poll(&working_directory, &config.environment) {
+                info!(
+                    "fail readiness check [{}] {} times. caused by: {} pid [{}]",
+                    self.name,
+                    failures,
+                    e,
+                    getpid()
+                );
+            }
+            check.passed()
+        };
+        if passed {
+            for p in &mut self.processes {
+                p.set_ready();
+            }
+            self.readiness = None;
+            let pids = self.process_pid();
+            info!(
+                "pass readiness check [{}] pid {:?}. pid [{}]",
+                self.name,
+                pids,
+                getpid()
+            );
+        }
+    }
+
+    /// Kills the processes not ready within `start_timeout_sec` after the spawn. The exit
+    /// counts as a failed start for the restart policy and the backoff.
+    pub fn check_start_timeouts(&mut self) {
+        let timeout = self.config.start_timeout_sec;
+        if timeout == 0 {
+            return;
+        }
+        for p in &mut self.processes {
+            let expired = p
+                .spawned_at()
+                .map_or(false, |at| at.elapsed().as_secs() >= timeout);
+            if p.ready() || !expired {
+                continue;
+            }
+            if let Some(pid) = p.pid() {
+                if let Err(e) = p.kill() {
+                    warn!("fail kill process. caused by: {}", e);
+                } else {
+                    warn!("kill process. not ready in {} secs pid [{}]", timeout, pid);
+                }
+            }
+        }
+    }
+
+    /// Kills the processes alive `kill_after_sec` after they were signaled to exit.
+    pub fn check_kill_after(&mut self) {
+        let timeout = self.config.kill_after_sec;
+        if timeout == 0 {
+            return;
+        }
+        for p in self.processes.iter_mut().chain(self.stopping.iter_mut()) {
+            if !p.check_kill_after(timeout) {
+                continue;
+            }
+            if let Some(pid) = p.pid() {
+                if let Err(e) = p.kill() {
+                    warn!("fail kill process. caused by: {}", e);
+                } else {
+                    warn!(
+                        "kill process. alive {} secs after signal pid [{}]",
+                        timeout, pid
+                    );
+                }
+            }
+        }
+    }
+
+    /// Forgets the exited processes, and returns the number of the processes alive.
+    pub fn reap_processes(&mut self) -> usize {
+        let mut exited = Vec::new();
+        for processes in &mut [&mut self.processes, &mut self.stopping] {
+            let mut i = 0;
+            while i != processes.len() {
+                if let Some(exit_code) = processes[i].try_wait() {
+                    let mut p = processes.remove(i);
+                    exited.push((p.index, p.pid(), exit_code));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        for (index, pid, exit_code) in exited {
+            self.try_hook(Hook::PostStop, index, pid, Some(exit_code));
+        }
+        self.processes.len() + self.stopping.len()
+    }
+
     pub fn check_live_processes(&mut self) {
         for p in &mut self.processes {
             if p.check_live_timeout(self.config.live_check_timeout) {
@@ -546,3 +2004,25 @@ impl<'a> Worker<'a> {
         }
     }
 }
+
+// a process may send the command from a child, e.g. a shell script
+fn is_sender(pid: u32, senders: &[u32]) -> bool {
+    senders.iter().any(|s| *s == pid) || process_tree(pid).iter().any(|c| senders.contains(c))
+}
+
+/// Returns a random delay between a half of `millis` and `millis`,
+/// so that the workers crashed together do not respawn together.
+fn jitter(millis: u64) -> u64 {
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+    millis / 2 + nanos % (millis / 2 + 1)
+}
+
+fn new_process_exited(pid: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("new process [{}] exited", pid),
+    )
+}